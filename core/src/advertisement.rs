@@ -0,0 +1,111 @@
+//! Manufacturer data broadcast in advertisements
+use crate::{readings::DetailedReadings, DecodeError, Reader};
+
+/// Bluetooth company identifier of SAF Tehnika, the maker of the Aranet4, which starts the
+/// manufacturer specific data of its advertisements
+pub const MANUFACTURER_ID: u16 = 0x0702;
+/// Flag bit set when Smart Home integration is on and the advertisement carries readings
+pub const INTEGRATIONS_FLAG: u8 = 1 << 5;
+
+/// Manufacturer data of an Aranet4 with Smart Home integration enabled.
+///
+/// Layout: flags u8, seven bytes of version information, [`DetailedReadings`], then a
+/// counter u8.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Advertisement {
+    /// Flags, including [`INTEGRATIONS_FLAG`]
+    pub flags: u8,
+    /// Firmware and hardware version, kept as sent
+    pub version: [u8; 7],
+    pub readings: DetailedReadings,
+    /// Increases with every measurement, wrapping around
+    pub counter: u8,
+}
+
+impl Advertisement {
+    /// Size of the encoded manufacturer data in bytes, without the manufacturer identifier
+    pub const SIZE: usize = 8 + DetailedReadings::SIZE + 1;
+
+    /// Decode manufacturer specific data following [`MANUFACTURER_ID`].
+    ///
+    /// Data without readings, as sent with Smart Home integration off, is
+    /// [`DecodeError::Invalid`] in `flags`.
+    pub fn decode(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let mut reader = Reader::new(bytes);
+        let flags = reader.u8("flags")?;
+        if flags & INTEGRATIONS_FLAG == 0 {
+            return Err(DecodeError::Invalid {
+                field: "flags",
+                value: flags,
+            });
+        }
+        let mut version = [0; 7];
+        for byte in &mut version {
+            *byte = reader.u8("version")?;
+        }
+        Ok(Self {
+            flags,
+            version,
+            readings: DetailedReadings::read(&mut reader)?,
+            counter: reader.u8("counter")?,
+        })
+    }
+    pub fn encode(&self) -> [u8; Self::SIZE] {
+        let mut bytes = [0; Self::SIZE];
+        bytes[0] = self.flags | INTEGRATIONS_FLAG;
+        bytes[1..8].copy_from_slice(&self.version);
+        bytes[8..Self::SIZE - 1].copy_from_slice(&self.readings.encode());
+        bytes[Self::SIZE - 1] = self.counter;
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::readings::TEST_PACKET;
+
+    fn data() -> [u8; 22] {
+        let mut data = [0; 22];
+        // flags and version
+        data[..8].copy_from_slice(&[0x22, 0x13, 0x04, 0x01, 0x00, 0x0c, 0x0f, 0x01]);
+        data[8..17].copy_from_slice(&TEST_PACKET);
+        data[17..].copy_from_slice(&[0x2c, 0x01, 0x3c, 0x00, 0x07]);
+        data
+    }
+
+    #[test]
+    fn decode() {
+        let advertisement = Advertisement::decode(&data()).unwrap();
+        assert_eq!(advertisement.readings.readings.co2, 464);
+        assert_eq!(advertisement.readings.interval, 300);
+        assert_eq!(advertisement.readings.since_update, 60);
+        assert_eq!(advertisement.counter, 7);
+        assert_eq!(advertisement.encode(), data());
+
+        assert_eq!(
+            Advertisement::decode(&[0x02, 0x13, 0x04, 0x01, 0x00, 0x0c, 0x0f]),
+            Err(DecodeError::Invalid {
+                field: "flags",
+                value: 0x02
+            })
+        );
+        assert_eq!(
+            Advertisement::decode(&data()[..21]).unwrap_err().field(),
+            "counter"
+        );
+    }
+
+    #[test]
+    fn round_trip() {
+        // other flag bits and the version bytes are kept as sent
+        let mut data = data();
+        data[0] = 0xff;
+        data[1..8].copy_from_slice(&[1, 2, 3, 4, 5, 6, 7]);
+        let advertisement = Advertisement::decode(&data).unwrap();
+        assert_eq!(advertisement.flags, 0xff);
+        assert_eq!(advertisement.version, [1, 2, 3, 4, 5, 6, 7]);
+        assert_eq!(advertisement.encode(), data);
+    }
+}
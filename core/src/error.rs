@@ -0,0 +1,34 @@
+use core::fmt;
+
+/// Why a packet could not be decoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The packet ends before `field`, which starts at byte `offset`
+    Truncated { field: &'static str, offset: usize },
+    /// `field` holds a value the protocol does not define
+    Invalid { field: &'static str, value: u8 },
+}
+
+impl DecodeError {
+    /// Name of the field that could not be decoded
+    pub fn field(&self) -> &'static str {
+        match self {
+            DecodeError::Truncated { field, .. } | DecodeError::Invalid { field, .. } => field,
+        }
+    }
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::Truncated { field, offset } => {
+                write!(f, "packet ends before `{}` at byte {}", field, offset)
+            }
+            DecodeError::Invalid { field, value } => {
+                write!(f, "invalid `{}` value {:#04x}", field, value)
+            }
+        }
+    }
+}
+
+impl core::error::Error for DecodeError {}
@@ -0,0 +1,376 @@
+//! History requests and the packets answering them
+use crate::{settings::Command, DecodeError, Reader};
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+
+/// A parameter stored in the sensor history
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+#[repr(u8)]
+pub enum LogParameter {
+    Temperature = 1,
+    Humidity = 2,
+    Pressure = 3,
+    Co2 = 4,
+}
+
+impl LogParameter {
+    /// The parameter with the given protocol code
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            1 => Some(LogParameter::Temperature),
+            2 => Some(LogParameter::Humidity),
+            3 => Some(LogParameter::Pressure),
+            4 => Some(LogParameter::Co2),
+            _ => None,
+        }
+    }
+    /// Size in bytes of a single history sample of this parameter
+    pub fn sample_size(&self) -> usize {
+        match self {
+            LogParameter::Humidity => 1,
+            _ => 2,
+        }
+    }
+    fn read(reader: &mut Reader) -> Result<Self, DecodeError> {
+        let byte = reader.u8("parameter")?;
+        Self::from_byte(byte).ok_or(DecodeError::Invalid {
+            field: "parameter",
+            value: byte,
+        })
+    }
+}
+
+/// Request for the history of one parameter, written to the command characteristic.
+///
+/// Layout: [`Command::REQUEST_HISTORY`], parameter u8, first index u16.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HistoryRequest {
+    pub parameter: LogParameter,
+    /// Index of the first measurement to send, starting at 1
+    pub first_index: u16,
+}
+
+impl HistoryRequest {
+    /// Size of the encoded request in bytes
+    pub const SIZE: usize = 4;
+
+    pub fn encode(&self) -> [u8; Self::SIZE] {
+        let [low, high] = self.first_index.to_le_bytes();
+        [Command::REQUEST_HISTORY, self.parameter as u8, low, high]
+    }
+    pub fn decode(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let mut reader = Reader::new(bytes);
+        let command = reader.u8("command")?;
+        if command != Command::REQUEST_HISTORY {
+            return Err(DecodeError::Invalid {
+                field: "command",
+                value: command,
+            });
+        }
+        Ok(Self {
+            parameter: LogParameter::read(&mut reader)?,
+            first_index: reader.u16("first_index")?,
+        })
+    }
+}
+
+/// Header of every history packet.
+///
+/// Layout, little-endian: parameter u8, interval u16, total measurements u16, seconds since
+/// the last measurement u16, index of the first measurement in the packet u16, number of
+/// measurements in the packet u8.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HistoryHeader {
+    pub parameter: LogParameter,
+    /// Seconds between measurements
+    pub interval: u16,
+    pub total_measurements: u16,
+    pub time_since_last_measurement: u16,
+    pub first_measure_index: u16,
+    /// Measurements in this packet; zero marks the end of the history
+    pub num_measurements: u8,
+}
+
+impl HistoryHeader {
+    /// Size of the encoded header in bytes
+    pub const SIZE: usize = 10;
+    /// Most samples a single packet can carry
+    pub const MAX_SAMPLES: usize = u8::MAX as usize;
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, DecodeError> {
+        Self::read(&mut Reader::new(bytes))
+    }
+    fn read(reader: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(Self {
+            parameter: LogParameter::read(reader)?,
+            interval: reader.u16("interval")?,
+            total_measurements: reader.u16("total_measurements")?,
+            time_since_last_measurement: reader.u16("time_since_last_measurement")?,
+            first_measure_index: reader.u16("first_measure_index")?,
+            num_measurements: reader.u8("num_measurements")?,
+        })
+    }
+    pub fn encode(&self) -> [u8; Self::SIZE] {
+        let mut bytes = [0; Self::SIZE];
+        bytes[0] = self.parameter as u8;
+        let fields = [
+            self.interval,
+            self.total_measurements,
+            self.time_since_last_measurement,
+            self.first_measure_index,
+        ];
+        for (i, field) in fields.into_iter().enumerate() {
+            bytes[1 + 2 * i..3 + 2 * i].copy_from_slice(&field.to_le_bytes());
+        }
+        bytes[9] = self.num_measurements;
+        bytes
+    }
+    /// Seconds from the measurement at `first_measure_index` to when the header was sent.
+    ///
+    /// The newest measurement, at index `total_measurements`, was taken
+    /// `time_since_last_measurement` seconds before then.
+    pub fn age_of_first(&self) -> i64 {
+        let newer_measurements =
+            i64::from(self.total_measurements) - i64::from(self.first_measure_index);
+        i64::from(self.time_since_last_measurement) + i64::from(self.interval) * newer_measurements
+    }
+    /// Encode a whole history packet: this header, with the number of measurements set to
+    /// the number of `samples`, followed by the samples. Humidity samples are written as
+    /// their low byte.
+    ///
+    /// # Panics
+    ///
+    /// If there are more than [`HistoryHeader::MAX_SAMPLES`] samples, which the count
+    /// field cannot hold.
+    #[cfg(feature = "alloc")]
+    pub fn with_samples(&self, samples: &[u16]) -> Vec<u8> {
+        let num_measurements = u8::try_from(samples.len()).unwrap_or_else(|_| {
+            panic!("{} samples do not fit in one history packet", samples.len())
+        });
+        let header = Self {
+            num_measurements,
+            ..*self
+        };
+        let mut packet = Vec::with_capacity(Self::SIZE + samples.len() * 2);
+        packet.extend_from_slice(&header.encode());
+        for &sample in samples {
+            match self.parameter {
+                LogParameter::Humidity => packet.push(sample as u8),
+                _ => packet.extend_from_slice(&sample.to_le_bytes()),
+            }
+        }
+        packet
+    }
+}
+
+/// A history packet: a header and the raw samples following it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HistoryPacket<'a> {
+    pub header: HistoryHeader,
+    /// Encoded samples, `num_measurements` of them or fewer if the packet was cut short
+    pub samples: &'a [u8],
+}
+
+impl<'a> HistoryPacket<'a> {
+    pub fn decode(bytes: &'a [u8]) -> Result<Self, DecodeError> {
+        let mut reader = Reader::new(bytes);
+        let header = HistoryHeader::read(&mut reader)?;
+        let samples = reader.rest();
+        let size = header.parameter.sample_size();
+        let length = (usize::from(header.num_measurements) * size).min(samples.len() / size * size);
+        Ok(Self {
+            header,
+            samples: &samples[..length],
+        })
+    }
+    /// The samples, with humidity widened to u16
+    pub fn values(&self) -> impl Iterator<Item = u16> + 'a {
+        samples(self.header.parameter, self.samples)
+    }
+}
+
+/// Decode the samples of `parameter` concatenated in `bytes`, with humidity widened to u16
+pub fn samples(parameter: LogParameter, bytes: &[u8]) -> impl Iterator<Item = u16> + '_ {
+    bytes
+        .chunks_exact(parameter.sample_size())
+        .map(|sample| match *sample {
+            [value] => value.into(),
+            [low, high] => u16::from_le_bytes([low, high]),
+            _ => unreachable!("samples are one or two bytes"),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PARAMETERS: [LogParameter; 4] = [
+        LogParameter::Temperature,
+        LogParameter::Humidity,
+        LogParameter::Pressure,
+        LogParameter::Co2,
+    ];
+
+    const HEADER: HistoryHeader = HistoryHeader {
+        parameter: LogParameter::Temperature,
+        interval: 10,
+        total_measurements: 20,
+        time_since_last_measurement: 30,
+        first_measure_index: 40,
+        num_measurements: 50,
+    };
+
+    #[test]
+    fn header_layout() {
+        let bytes = HEADER.encode();
+        assert_eq!(bytes, [1u8, 10, 0, 20, 0, 30, 0, 40, 0, 50]);
+        assert_eq!(HistoryHeader::decode(&bytes), Ok(HEADER));
+        assert_eq!(
+            HistoryHeader::decode(&[5, 10, 0, 20, 0, 30, 0, 40, 0, 50]),
+            Err(DecodeError::Invalid {
+                field: "parameter",
+                value: 5
+            })
+        );
+        assert_eq!(
+            HistoryHeader::decode(&bytes[..9]).unwrap_err().field(),
+            "num_measurements"
+        );
+    }
+
+    #[test]
+    fn request_layout() {
+        let request = HistoryRequest {
+            parameter: LogParameter::Co2,
+            first_index: 0x0102,
+        };
+        assert_eq!(request.encode(), [0x61, 4, 0x02, 0x01]);
+        assert_eq!(HistoryRequest::decode(&request.encode()), Ok(request));
+        assert_eq!(
+            HistoryRequest::decode(&[0x90, 4, 1, 0]).unwrap_err(),
+            DecodeError::Invalid {
+                field: "command",
+                value: 0x90
+            }
+        );
+    }
+
+    #[test]
+    fn packet_samples() {
+        let header = HistoryHeader {
+            parameter: LogParameter::Co2,
+            num_measurements: 3,
+            ..HEADER
+        };
+        let mut bytes = [0; 16];
+        bytes[..10].copy_from_slice(&header.encode());
+        bytes[10..].copy_from_slice(&[0xf4, 0x01, 0x58, 0x02, 0xbc, 0x02]);
+        let packet = HistoryPacket::decode(&bytes).unwrap();
+        assert!(packet.values().eq([500, 600, 700]));
+        // a packet cut short keeps only its whole samples
+        let packet = HistoryPacket::decode(&bytes[..15]).unwrap();
+        assert!(packet.values().eq([500, 600]));
+        assert_eq!(HEADER.age_of_first(), 30 - 10 * 20);
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn encode_packet() {
+        let header = HistoryHeader {
+            parameter: LogParameter::Humidity,
+            ..HEADER
+        };
+        let bytes = header.with_samples(&[40, 41]);
+        let packet = HistoryPacket::decode(&bytes).unwrap();
+        assert_eq!(packet.header.num_measurements, 2);
+        assert_eq!(packet.samples, &[40, 41]);
+    }
+
+    #[test]
+    fn header_round_trip() {
+        // distinct bytes in every field catch swapped fields and byte order
+        let header = HistoryHeader {
+            parameter: LogParameter::Pressure,
+            interval: 0x0201,
+            total_measurements: 0x0403,
+            time_since_last_measurement: 0x0605,
+            first_measure_index: 0x0807,
+            num_measurements: 0x09,
+        };
+        assert_eq!(header.encode(), [3, 1, 2, 3, 4, 5, 6, 7, 8, 9]);
+        for parameter in PARAMETERS {
+            let header = HistoryHeader {
+                parameter,
+                interval: u16::MAX,
+                total_measurements: u16::MAX,
+                num_measurements: u8::MAX,
+                ..header
+            };
+            assert_eq!(HistoryHeader::decode(&header.encode()), Ok(header));
+        }
+    }
+
+    #[test]
+    fn request_round_trip() {
+        // every parameter byte either decodes to a request encoding back to it, or fails
+        for byte in 0..=u8::MAX {
+            let bytes = [Command::REQUEST_HISTORY, byte, 0xff, 0x7f];
+            match HistoryRequest::decode(&bytes) {
+                Ok(request) => {
+                    assert_eq!(request.first_index, 0x7fff);
+                    assert_eq!(request.encode(), bytes);
+                }
+                Err(e) => assert_eq!(
+                    e,
+                    DecodeError::Invalid {
+                        field: "parameter",
+                        value: byte
+                    }
+                ),
+            }
+        }
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn packet_round_trip() {
+        for parameter in PARAMETERS {
+            let samples: &[u16] = match parameter {
+                LogParameter::Humidity => &[0, 41, 0xff],
+                _ => &[0, 0x01f4, 0xffff],
+            };
+            let bytes = HistoryHeader {
+                parameter,
+                ..HEADER
+            }
+            .with_samples(samples);
+            assert_eq!(
+                bytes.len(),
+                HistoryHeader::SIZE + samples.len() * parameter.sample_size()
+            );
+            let packet = HistoryPacket::decode(&bytes).unwrap();
+            assert_eq!(packet.header.num_measurements, 3);
+            assert!(packet.values().eq(samples.iter().copied()));
+        }
+        let full = HEADER.with_samples(&[0; HistoryHeader::MAX_SAMPLES]);
+        assert_eq!(
+            HistoryPacket::decode(&full)
+                .unwrap()
+                .header
+                .num_measurements,
+            255
+        );
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    #[should_panic(expected = "256 samples do not fit")]
+    fn packet_sample_limit() {
+        HEADER.with_samples(&[0; HistoryHeader::MAX_SAMPLES + 1]);
+    }
+}
@@ -0,0 +1,34 @@
+//! Byte-level encoders and decoders for the Aranet4 Bluetooth protocol.
+//!
+//! Nothing here talks to a radio: decoders take the bytes of a characteristic value or an
+//! advertisement, however they were received, and encoders produce the bytes to write. The
+//! crate is `no_std`; the `alloc` feature adds helpers that build whole packets in a `Vec`.
+//!
+//! Every multi-byte field is a little-endian integer and packets have no padding:
+//!
+//! | Packet | Layout | Size |
+//! |---|---|---|
+//! | [`readings::Readings`] | CO2 u16, temperature u16, pressure u16, humidity u8, battery u8, status u8 | 9 |
+//! | [`readings::DetailedReadings`] | readings, interval u16, seconds since update u16 | 13 |
+//! | [`advertisement::Advertisement`] | flags u8, version 7 bytes, detailed readings, counter u8 | 22 |
+//! | [`history::HistoryRequest`] | `0x61`, parameter u8, first index u16 | 4 |
+//! | [`history::HistoryHeader`] | parameter u8, interval u16, total u16, seconds since update u16, first index u16, count u8 | 10 |
+//! | [`history::HistoryPacket`] | header, then count samples of u16, or u8 for humidity | 10 + samples |
+//! | [`settings::Setting`] | command u8, value u8 | 2 |
+//!
+//! Decoders read fields one at a time with a [`Reader`] and ignore trailing bytes, so newer
+//! firmware may append fields. Encoders write fixed-size arrays.
+#![no_std]
+
+#[cfg(feature = "alloc")]
+extern crate alloc;
+
+pub mod advertisement;
+mod error;
+pub mod history;
+mod reader;
+pub mod readings;
+pub mod settings;
+
+pub use error::DecodeError;
+pub use reader::Reader;
@@ -0,0 +1,56 @@
+use crate::DecodeError;
+
+/// Reads little-endian fields from the front of a packet
+#[derive(Debug, Clone)]
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, offset: 0 }
+    }
+    pub fn u8(&mut self, field: &'static str) -> Result<u8, DecodeError> {
+        let [byte] = self.take::<1>(field)?;
+        Ok(byte)
+    }
+    pub fn u16(&mut self, field: &'static str) -> Result<u16, DecodeError> {
+        self.take(field).map(u16::from_le_bytes)
+    }
+    /// The bytes not read yet
+    pub fn rest(&self) -> &'a [u8] {
+        &self.bytes[self.offset..]
+    }
+    fn take<const N: usize>(&mut self, field: &'static str) -> Result<[u8; N], DecodeError> {
+        let bytes = self
+            .bytes
+            .get(self.offset..self.offset + N)
+            .ok_or(DecodeError::Truncated {
+                field,
+                offset: self.offset,
+            })?;
+        self.offset += N;
+        Ok(bytes.try_into().expect("slice of N bytes"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_truncated_field() {
+        let mut reader = Reader::new(&[0x10, 0x02, 0x05]);
+        assert_eq!(reader.u16("co2_level"), Ok(0x0210));
+        assert_eq!(reader.rest(), &[0x05]);
+        assert_eq!(
+            reader.u16("temperature"),
+            Err(DecodeError::Truncated {
+                field: "temperature",
+                offset: 2
+            })
+        );
+        assert_eq!(reader.u8("temperature"), Ok(0x05));
+    }
+}
@@ -0,0 +1,182 @@
+//! Current readings, as read from the readings characteristics
+use crate::{DecodeError, Reader};
+
+/// Temperature in °C from its raw value, in twentieths of a degree
+pub fn temperature_celsius(raw: u16) -> f32 {
+    raw as f32 / 20.0
+}
+
+/// Pressure in hPa from its raw value, in tenths of a hectopascal
+pub fn pressure_hpa(raw: u16) -> f32 {
+    raw as f32 / 10.0
+}
+
+/// Current readings, with temperature and pressure in their raw units.
+///
+/// Layout, little-endian: CO2 u16, temperature u16, pressure u16, humidity u8, battery u8,
+/// status u8.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Readings {
+    /// CO2 level in ppm
+    pub co2: u16,
+    /// Temperature in twentieths of a °C, see [`temperature_celsius`]
+    pub temperature: u16,
+    /// Pressure in tenths of a hPa, see [`pressure_hpa`]
+    pub pressure: u16,
+    /// Relative humidity in percent
+    pub humidity: u8,
+    /// Battery level in percent
+    pub battery: u8,
+    /// Colour of the status light: 1 green, 2 yellow, 3 red
+    pub status: u8,
+}
+
+impl Readings {
+    /// Size of the encoded readings in bytes
+    pub const SIZE: usize = 9;
+
+    /// Decode the readings at the start of `bytes`
+    pub fn decode(bytes: &[u8]) -> Result<Self, DecodeError> {
+        Self::read(&mut Reader::new(bytes))
+    }
+    pub(crate) fn read(reader: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(Self {
+            co2: reader.u16("co2_level")?,
+            temperature: reader.u16("temperature")?,
+            pressure: reader.u16("pressure")?,
+            humidity: reader.u8("humidity")?,
+            battery: reader.u8("battery")?,
+            status: reader.u8("status_color")?,
+        })
+    }
+    pub fn encode(&self) -> [u8; Self::SIZE] {
+        let [co2, co2_high] = self.co2.to_le_bytes();
+        let [temperature, temperature_high] = self.temperature.to_le_bytes();
+        let [pressure, pressure_high] = self.pressure.to_le_bytes();
+        [
+            co2,
+            co2_high,
+            temperature,
+            temperature_high,
+            pressure,
+            pressure_high,
+            self.humidity,
+            self.battery,
+            self.status,
+        ]
+    }
+    pub fn temperature_celsius(&self) -> f32 {
+        temperature_celsius(self.temperature)
+    }
+    pub fn pressure_hpa(&self) -> f32 {
+        pressure_hpa(self.pressure)
+    }
+}
+
+/// Current readings with the measurement interval and their age, as read from the detailed
+/// readings characteristic.
+///
+/// Layout: [`Readings`], then interval u16 and seconds since the measurement u16.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DetailedReadings {
+    pub readings: Readings,
+    /// Seconds between measurements
+    pub interval: u16,
+    /// Seconds since the readings were measured
+    pub since_update: u16,
+}
+
+impl DetailedReadings {
+    /// Size of the encoded readings in bytes
+    pub const SIZE: usize = Readings::SIZE + 4;
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, DecodeError> {
+        Self::read(&mut Reader::new(bytes))
+    }
+    pub(crate) fn read(reader: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(Self {
+            readings: Readings::read(reader)?,
+            interval: reader.u16("interval")?,
+            since_update: reader.u16("seconds_since_update")?,
+        })
+    }
+    pub fn encode(&self) -> [u8; Self::SIZE] {
+        let mut bytes = [0; Self::SIZE];
+        bytes[..Readings::SIZE].copy_from_slice(&self.readings.encode());
+        bytes[Readings::SIZE..Readings::SIZE + 2].copy_from_slice(&self.interval.to_le_bytes());
+        bytes[Readings::SIZE + 2..].copy_from_slice(&self.since_update.to_le_bytes());
+        bytes
+    }
+}
+
+/// Readings of 464 ppm, 19 °C, 975.6 hPa, 36% humidity and 90% battery
+#[cfg(test)]
+pub(crate) const TEST_PACKET: [u8; Readings::SIZE] =
+    [0xd0, 0x01, 0x7c, 0x01, 0x1c, 0x26, 0x24, 0x5a, 0x01];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_readings() {
+        let readings = Readings::decode(&TEST_PACKET).unwrap();
+        assert_eq!(readings.co2, 464);
+        assert_eq!(readings.temperature_celsius(), 19.0);
+        assert_eq!(readings.pressure_hpa(), 975.6);
+        assert_eq!(
+            (readings.humidity, readings.battery, readings.status),
+            (36, 90, 1)
+        );
+        assert_eq!(readings.encode(), TEST_PACKET);
+        assert_eq!(
+            Readings::decode(&TEST_PACKET[..8]),
+            Err(DecodeError::Truncated {
+                field: "status_color",
+                offset: 8
+            })
+        );
+    }
+
+    #[test]
+    fn decode_detailed() {
+        let mut packet = [0; 13];
+        packet[..9].copy_from_slice(&TEST_PACKET);
+        packet[9..].copy_from_slice(&[0x2c, 0x01, 0x3c, 0x00]);
+        let detailed = DetailedReadings::decode(&packet).unwrap();
+        assert_eq!(detailed.readings.co2, 464);
+        assert_eq!((detailed.interval, detailed.since_update), (300, 60));
+        assert_eq!(detailed.encode(), packet);
+    }
+
+    #[test]
+    fn round_trip() {
+        let detailed = DetailedReadings {
+            readings: Readings {
+                co2: 0x0201,
+                temperature: 0x0403,
+                pressure: 0x0605,
+                humidity: 7,
+                battery: 8,
+                status: 9,
+            },
+            interval: 0x0b0a,
+            since_update: 0x0d0c,
+        };
+        let bytes = detailed.encode();
+        assert_eq!(bytes, [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13]);
+        assert_eq!(DetailedReadings::decode(&bytes), Ok(detailed));
+        assert_eq!(Readings::decode(&bytes), Ok(detailed.readings));
+
+        let max = Readings {
+            co2: u16::MAX,
+            temperature: u16::MAX,
+            pressure: u16::MAX,
+            humidity: u8::MAX,
+            battery: u8::MAX,
+            status: u8::MAX,
+        };
+        assert_eq!(max.encode(), [0xff; Readings::SIZE]);
+        assert_eq!(Readings::decode(&max.encode()), Ok(max));
+    }
+}
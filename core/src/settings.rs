@@ -0,0 +1,149 @@
+//! Commands changing the sensor settings
+use crate::{DecodeError, Reader};
+use core::time::Duration;
+
+/// Command codes, the first byte of a write to the command characteristic
+#[non_exhaustive]
+pub struct Command;
+
+impl Command {
+    pub const REQUEST_HISTORY: u8 = 0x61;
+    pub const SET_INTERVAL: u8 = 0x90;
+    pub const SET_SMART_HOME_INTEGRATION: u8 = 0x91;
+    pub const SET_BLUETOOTH_RANGE: u8 = 0x92;
+}
+
+/// Time between measurements supported by the sensor
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum MeasurementInterval {
+    OneMinute = 1,
+    TwoMinutes = 2,
+    FiveMinutes = 5,
+    TenMinutes = 10,
+}
+
+impl MeasurementInterval {
+    /// The interval with the given number of minutes, if the sensor supports it
+    pub fn from_minutes(minutes: u64) -> Option<Self> {
+        match minutes {
+            1 => Some(MeasurementInterval::OneMinute),
+            2 => Some(MeasurementInterval::TwoMinutes),
+            5 => Some(MeasurementInterval::FiveMinutes),
+            10 => Some(MeasurementInterval::TenMinutes),
+            _ => None,
+        }
+    }
+    pub fn as_duration(&self) -> Duration {
+        Duration::from_secs(*self as u64 * 60)
+    }
+}
+
+/// Bluetooth transmit power of the sensor
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum BluetoothRange {
+    Standard = 0,
+    Extended = 1,
+}
+
+/// A change of setting, written to the command characteristic.
+///
+/// Layout: command code u8, value u8.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Setting {
+    /// Change the time between measurements, which clears the history
+    Interval(MeasurementInterval),
+    /// Broadcast readings in advertisements
+    SmartHomeIntegration(bool),
+    BluetoothRange(BluetoothRange),
+}
+
+impl Setting {
+    /// Size of the encoded command in bytes
+    pub const SIZE: usize = 2;
+
+    pub fn encode(&self) -> [u8; Self::SIZE] {
+        match *self {
+            Setting::Interval(interval) => [Command::SET_INTERVAL, interval as u8],
+            Setting::SmartHomeIntegration(enabled) => {
+                [Command::SET_SMART_HOME_INTEGRATION, enabled.into()]
+            }
+            Setting::BluetoothRange(range) => [Command::SET_BLUETOOTH_RANGE, range as u8],
+        }
+    }
+    pub fn decode(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let mut reader = Reader::new(bytes);
+        let command = reader.u8("command")?;
+        let value = reader.u8("value")?;
+        let invalid = |field| DecodeError::Invalid { field, value };
+        match command {
+            Command::SET_INTERVAL => MeasurementInterval::from_minutes(value.into())
+                .map(Setting::Interval)
+                .ok_or(invalid("value")),
+            Command::SET_SMART_HOME_INTEGRATION => match value {
+                0 | 1 => Ok(Setting::SmartHomeIntegration(value == 1)),
+                _ => Err(invalid("value")),
+            },
+            Command::SET_BLUETOOTH_RANGE => match value {
+                0 => Ok(Setting::BluetoothRange(BluetoothRange::Standard)),
+                1 => Ok(Setting::BluetoothRange(BluetoothRange::Extended)),
+                _ => Err(invalid("value")),
+            },
+            _ => Err(DecodeError::Invalid {
+                field: "command",
+                value: command,
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn setting_layout() {
+        let settings = [
+            (
+                Setting::Interval(MeasurementInterval::FiveMinutes),
+                [0x90, 5],
+            ),
+            (Setting::SmartHomeIntegration(true), [0x91, 1]),
+            (Setting::BluetoothRange(BluetoothRange::Extended), [0x92, 1]),
+        ];
+        for (setting, bytes) in settings {
+            assert_eq!(setting.encode(), bytes);
+            assert_eq!(Setting::decode(&bytes), Ok(setting));
+        }
+        assert_eq!(
+            Setting::decode(&[0x90, 3]),
+            Err(DecodeError::Invalid {
+                field: "value",
+                value: 3
+            })
+        );
+        assert_eq!(Setting::decode(&[0x91]).unwrap_err().field(), "value");
+    }
+
+    #[test]
+    fn round_trip() {
+        // every two-byte command either decodes to a setting encoding back to it, or fails
+        let mut decoded = 0;
+        for command in 0..=u8::MAX {
+            for value in 0..=u8::MAX {
+                if let Ok(setting) = Setting::decode(&[command, value]) {
+                    assert_eq!(setting.encode(), [command, value]);
+                    decoded += 1;
+                }
+            }
+        }
+        assert_eq!(decoded, 4 + 2 + 2);
+        for minutes in [1, 2, 5, 10] {
+            let interval = MeasurementInterval::from_minutes(minutes).unwrap();
+            assert_eq!(interval.as_duration().as_secs(), minutes * 60);
+            let setting = Setting::Interval(interval);
+            assert_eq!(Setting::decode(&setting.encode()), Ok(setting));
+        }
+    }
+}
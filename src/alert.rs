@@ -0,0 +1,680 @@
+//! Alerts on the readings of a [`Fleet`](crate::fleet::Fleet)
+//!
+//! An [`AlertEngine`] checks every update against a set of [`Rule`]s and emits an
+//! [`AlertEvent`] when an alert is raised or resolved. Rules can require a condition to hold
+//! for a while before raising, resolve only past a hysteresis band, and wait out a cooldown
+//! before raising again. Time comes from a [`Clock`], so tests can use a [`FakeClock`].
+use crate::{fleet::SensorStatus, readings::SensorReadings};
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Local};
+#[cfg(feature = "serde")]
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+use thiserror::Error;
+use tokio::sync::{
+    broadcast::{self, error::RecvError},
+    mpsc,
+};
+use tokio_util::sync::CancellationToken;
+
+#[derive(Debug, Error)]
+pub enum AlertError {
+    #[error("Alert sink failed")]
+    Sink(#[source] Box<dyn std::error::Error + Send + Sync>),
+    #[error("Alert sink closed")]
+    Closed,
+}
+
+/// Source of the current time
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Local>;
+}
+
+/// The system clock
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Local> {
+        Local::now()
+    }
+}
+
+/// A clock that only moves when told to. Clones share the same time.
+#[derive(Debug, Clone)]
+pub struct FakeClock(Arc<Mutex<DateTime<Local>>>);
+
+impl FakeClock {
+    pub fn new(time: DateTime<Local>) -> Self {
+        Self(Arc::new(Mutex::new(time)))
+    }
+    pub fn set(&self, time: DateTime<Local>) {
+        *self.0.lock().expect("clock poisoned") = time;
+    }
+    pub fn advance(&self, by: Duration) {
+        *self.0.lock().expect("clock poisoned") += by;
+    }
+}
+
+impl Clock for FakeClock {
+    fn now(&self) -> DateTime<Local> {
+        *self.0.lock().expect("clock poisoned")
+    }
+}
+
+/// A value of [`SensorReadings`] a rule can watch
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Metric {
+    /// CO2 in ppm
+    Co2,
+    /// Temperature in Fahrenheit
+    Temperature,
+    /// Relative humidity in percent
+    Humidity,
+    /// Pressure in hPa
+    Pressure,
+    /// Battery in percent
+    Battery,
+}
+
+impl Metric {
+    pub fn value(&self, readings: &SensorReadings) -> f64 {
+        match self {
+            Metric::Co2 => readings.co2_level.into(),
+            Metric::Temperature => readings.temperature.into(),
+            Metric::Humidity => readings.humidity.into(),
+            Metric::Pressure => readings.pressure.into(),
+            Metric::Battery => readings.battery.into(),
+        }
+    }
+}
+
+/// What a [`Rule`] raises an alert on
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Condition {
+    /// `metric` above `threshold`, resolved once it is back at or below `clear`
+    Above {
+        metric: Metric,
+        threshold: f64,
+        clear: f64,
+    },
+    /// `metric` below `threshold`, resolved once it is back at or above `clear`
+    Below {
+        metric: Metric,
+        threshold: f64,
+        clear: f64,
+    },
+    /// No successful reading for `after`
+    NoData { after: Duration },
+}
+
+/// A named condition, with how long it has to hold and how often it may be raised
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rule {
+    pub name: String,
+    pub condition: Condition,
+    /// How long the condition has to hold before the alert is raised
+    pub sustain: Duration,
+    /// Least time between two raises of this alert for the same sensor
+    pub cooldown: Duration,
+}
+
+impl Rule {
+    fn new(name: impl Into<String>, condition: Condition) -> Self {
+        Self {
+            name: name.into(),
+            condition,
+            sustain: Duration::zero(),
+            cooldown: Duration::zero(),
+        }
+    }
+    /// Alert while `metric` is above `threshold`
+    pub fn above(name: impl Into<String>, metric: Metric, threshold: f64) -> Self {
+        Self::new(
+            name,
+            Condition::Above {
+                metric,
+                threshold,
+                clear: threshold,
+            },
+        )
+    }
+    /// Alert while `metric` is below `threshold`
+    pub fn below(name: impl Into<String>, metric: Metric, threshold: f64) -> Self {
+        Self::new(
+            name,
+            Condition::Below {
+                metric,
+                threshold,
+                clear: threshold,
+            },
+        )
+    }
+    /// Alert when a sensor has not been read successfully for `after`
+    pub fn no_data(name: impl Into<String>, after: Duration) -> Self {
+        Self::new(name, Condition::NoData { after })
+    }
+    /// Resolve only once the value is `band` past the threshold, back towards normal
+    pub fn hysteresis(mut self, band: f64) -> Self {
+        match &mut self.condition {
+            Condition::Above {
+                threshold, clear, ..
+            } => *clear = *threshold - band,
+            Condition::Below {
+                threshold, clear, ..
+            } => *clear = *threshold + band,
+            Condition::NoData { .. } => {}
+        }
+        self
+    }
+    /// Raise only once the condition has held for `duration`
+    pub fn sustained(mut self, duration: Duration) -> Self {
+        self.sustain = duration;
+        self
+    }
+    pub fn cooldown(mut self, cooldown: Duration) -> Self {
+        self.cooldown = cooldown;
+        self
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize), serde(rename_all = "snake_case"))]
+pub enum AlertKind {
+    Raised,
+    Resolved,
+}
+
+/// An alert being raised or resolved
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct AlertEvent {
+    pub kind: AlertKind,
+    /// Name of the rule
+    pub rule: String,
+    /// Name of the sensor, or its address if it has none
+    pub sensor: String,
+    /// Bluetooth address of the sensor, empty if unknown
+    pub address: String,
+    /// The watched value, `None` for [`Condition::NoData`]
+    pub value: Option<f64>,
+    /// When the alert was raised or resolved
+    pub time: DateTime<Local>,
+    /// When the condition started to hold
+    pub since: DateTime<Local>,
+}
+
+/// Where alert events go
+#[async_trait]
+pub trait AlertSink: Send + Sync {
+    async fn send(&self, event: &AlertEvent) -> Result<(), AlertError>;
+}
+
+#[async_trait]
+impl AlertSink for mpsc::Sender<AlertEvent> {
+    async fn send(&self, event: &AlertEvent) -> Result<(), AlertError> {
+        mpsc::Sender::send(self, event.clone())
+            .await
+            .map_err(|_| AlertError::Closed)
+    }
+}
+
+/// An event a sink failed to deliver
+#[derive(Debug)]
+pub struct SinkFailure {
+    /// Index of the sink in the slice given to [`AlertEngine::run`]
+    pub sink: usize,
+    pub event: AlertEvent,
+    pub error: AlertError,
+}
+
+/// State of one rule for one sensor
+#[derive(Debug, Default)]
+struct RuleState {
+    /// When the condition started to hold
+    since: Option<DateTime<Local>>,
+    active: bool,
+    last_raised: Option<DateTime<Local>>,
+}
+
+/// Latest known state of one sensor
+struct SensorState {
+    status: SensorStatus,
+    first_seen: DateTime<Local>,
+}
+
+/// Name of a sensor in events, or its address if it has none
+fn display_name(status: &SensorStatus) -> &str {
+    if status.name.is_empty() {
+        &status.address
+    } else {
+        &status.name
+    }
+}
+
+/// Checks sensor updates against a set of rules
+pub struct AlertEngine<C: Clock = SystemClock> {
+    rules: Vec<Rule>,
+    clock: C,
+    sensors: HashMap<String, SensorState>,
+    states: HashMap<(String, usize), RuleState>,
+}
+
+impl AlertEngine {
+    pub fn new(rules: Vec<Rule>) -> Self {
+        Self::with_clock(rules, SystemClock)
+    }
+}
+
+impl<C: Clock> AlertEngine<C> {
+    pub fn with_clock(rules: Vec<Rule>, clock: C) -> Self {
+        Self {
+            rules,
+            clock,
+            sensors: HashMap::new(),
+            states: HashMap::new(),
+        }
+    }
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+    /// Alerts currently raised, as (sensor name, rule) pairs
+    pub fn active(&self) -> Vec<(&str, &str)> {
+        let mut active: Vec<_> = self
+            .states
+            .iter()
+            .filter(|(_, state)| state.active)
+            .map(|((sensor, rule), _)| {
+                let name = display_name(&self.sensors[sensor].status);
+                (name, self.rules[*rule].name.as_str())
+            })
+            .collect();
+        active.sort_unstable();
+        active
+    }
+    /// Check a new state of a sensor against every rule
+    pub fn evaluate(&mut self, status: &SensorStatus) -> Vec<AlertEvent> {
+        let now = self.clock.now();
+        // names are set by users and may repeat, so state follows the address
+        let sensor = if status.address.is_empty() {
+            status.name.clone()
+        } else {
+            status.address.clone()
+        };
+        self.sensors
+            .entry(sensor.clone())
+            .and_modify(|state| state.status = status.clone())
+            .or_insert_with(|| SensorState {
+                status: status.clone(),
+                first_seen: now,
+            });
+        self.check(&sensor, now)
+    }
+    /// Check every known sensor again without an update, so missing data is noticed and
+    /// sustained conditions are raised on time
+    pub fn tick(&mut self) -> Vec<AlertEvent> {
+        let now = self.clock.now();
+        let mut sensors: Vec<String> = self.sensors.keys().cloned().collect();
+        sensors.sort_unstable();
+        sensors
+            .iter()
+            .flat_map(|sensor| self.check(sensor, now))
+            .collect()
+    }
+    fn check(&mut self, sensor: &str, now: DateTime<Local>) -> Vec<AlertEvent> {
+        let known = &self.sensors[sensor];
+        let status = &known.status;
+        // readings of a failed poll are stale; only missing data is checked then
+        let readings = status.readings.as_ref().filter(|_| status.up);
+        let last_data = status.last_success.unwrap_or(known.first_seen);
+        let mut events = vec![];
+        for (index, rule) in self.rules.iter().enumerate() {
+            let state = self.states.entry((sensor.to_string(), index)).or_default();
+            // whether the condition holds, judged by the raise or the clear level
+            let (value, holds) = match rule.condition {
+                Condition::Above {
+                    metric,
+                    threshold,
+                    clear,
+                } => match readings.map(|r| metric.value(r)) {
+                    Some(v) => (Some(v), v > if state.active { clear } else { threshold }),
+                    None => continue,
+                },
+                Condition::Below {
+                    metric,
+                    threshold,
+                    clear,
+                } => match readings.map(|r| metric.value(r)) {
+                    Some(v) => (Some(v), v < if state.active { clear } else { threshold }),
+                    None => continue,
+                },
+                Condition::NoData { after } => (None, now - last_data >= after),
+            };
+            let event = |kind, since| AlertEvent {
+                kind,
+                rule: rule.name.clone(),
+                sensor: display_name(status).to_string(),
+                address: status.address.clone(),
+                value,
+                time: now,
+                since,
+            };
+            match (holds, state.active) {
+                (true, false) => {
+                    let since = *state.since.get_or_insert(match rule.condition {
+                        Condition::NoData { after } => last_data + after,
+                        _ => now,
+                    });
+                    let cooled_down = state
+                        .last_raised
+                        .is_none_or(|raised| now - raised >= rule.cooldown);
+                    if now - since >= rule.sustain && cooled_down {
+                        state.active = true;
+                        state.last_raised = Some(now);
+                        events.push(event(AlertKind::Raised, since));
+                    }
+                }
+                (false, true) => {
+                    let since = state.since.take().unwrap_or(now);
+                    state.active = false;
+                    events.push(event(AlertKind::Resolved, since));
+                }
+                (false, false) => state.since = None,
+                (true, true) => {}
+            }
+        }
+        events
+    }
+    /// Check every update from a [`Fleet`](crate::fleet::Fleet), and all sensors every
+    /// `check_every`, until `cancel` is cancelled or the fleet is dropped. Events go to every
+    /// sink in turn. Each failed delivery is reported on `failures`, along with the event, and
+    /// evaluation carries on; only a closed sink ends the loop, once the events at hand have
+    /// been tried on every sink.
+    pub async fn run(
+        &mut self,
+        mut updates: broadcast::Receiver<SensorStatus>,
+        sinks: &[Box<dyn AlertSink>],
+        failures: mpsc::UnboundedSender<SinkFailure>,
+        check_every: std::time::Duration,
+        cancel: CancellationToken,
+    ) -> Result<(), AlertError> {
+        let mut ticks = tokio::time::interval(check_every);
+        loop {
+            let events = tokio::select! {
+                _ = cancel.cancelled() => break,
+                _ = ticks.tick() => self.tick(),
+                update = updates.recv() => match update {
+                    Ok(status) => self.evaluate(&status),
+                    // missed updates are superseded by the next ones
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => break,
+                },
+            };
+            let mut closed = false;
+            for event in &events {
+                for (index, sink) in sinks.iter().enumerate() {
+                    if let Err(error) = sink.send(event).await {
+                        closed |= matches!(error, AlertError::Closed);
+                        // nobody listening for failures is fine
+                        let _ = failures.send(SinkFailure {
+                            sink: index,
+                            event: event.clone(),
+                            error,
+                        });
+                    }
+                }
+            }
+            if closed {
+                return Err(AlertError::Closed);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn start() -> DateTime<Local> {
+        Local.with_ymd_and_hms(2024, 4, 25, 18, 0, 0).unwrap()
+    }
+
+    fn status(co2: u16, battery: u8) -> SensorStatus {
+        SensorStatus {
+            address: "AA:BB:CC:DD:EE:FF".to_string(),
+            name: "office".to_string(),
+            readings: Some(SensorReadings::new(co2, 70.0, 1000.0, 40, battery, 1)),
+            last_success: Some(start()),
+            up: true,
+            ..Default::default()
+        }
+    }
+
+    fn kinds(events: &[AlertEvent]) -> Vec<(AlertKind, &str)> {
+        events.iter().map(|e| (e.kind, e.rule.as_str())).collect()
+    }
+
+    #[test]
+    fn sustained_with_hysteresis() {
+        let clock = FakeClock::new(start());
+        let mut engine = AlertEngine::with_clock(
+            vec![Rule::above("co2-high", Metric::Co2, 1200.0)
+                .sustained(Duration::minutes(10))
+                .hysteresis(100.0)],
+            clock.clone(),
+        );
+        assert!(engine.evaluate(&status(1300, 90)).is_empty());
+        clock.advance(Duration::minutes(5));
+        assert!(engine.evaluate(&status(1250, 90)).is_empty());
+        clock.advance(Duration::minutes(5));
+        let raised = engine.evaluate(&status(1250, 90));
+        assert_eq!(
+            raised,
+            [AlertEvent {
+                kind: AlertKind::Raised,
+                rule: "co2-high".to_string(),
+                sensor: "office".to_string(),
+                address: "AA:BB:CC:DD:EE:FF".to_string(),
+                value: Some(1250.0),
+                time: start() + Duration::minutes(10),
+                since: start(),
+            }]
+        );
+        assert_eq!(engine.active(), [("office", "co2-high")]);
+
+        // within the hysteresis band it stays raised
+        clock.advance(Duration::minutes(1));
+        assert!(engine.evaluate(&status(1150, 90)).is_empty());
+        clock.advance(Duration::minutes(1));
+        let resolved = engine.evaluate(&status(1100, 90));
+        assert_eq!(kinds(&resolved), [(AlertKind::Resolved, "co2-high")]);
+        assert_eq!(resolved[0].since, start());
+        assert!(engine.active().is_empty());
+
+        // a dip below the threshold restarts the wait
+        assert!(engine.evaluate(&status(1300, 90)).is_empty());
+        clock.advance(Duration::minutes(8));
+        assert!(engine.evaluate(&status(1190, 90)).is_empty());
+        clock.advance(Duration::minutes(8));
+        assert!(engine.evaluate(&status(1300, 90)).is_empty());
+    }
+
+    #[test]
+    fn cooldown() {
+        let clock = FakeClock::new(start());
+        let mut engine = AlertEngine::with_clock(
+            vec![Rule::below("battery-low", Metric::Battery, 15.0).cooldown(Duration::hours(1))],
+            clock.clone(),
+        );
+        assert_eq!(
+            kinds(&engine.evaluate(&status(400, 14))),
+            [(AlertKind::Raised, "battery-low")]
+        );
+        clock.advance(Duration::minutes(10));
+        assert_eq!(
+            kinds(&engine.evaluate(&status(400, 15))),
+            [(AlertKind::Resolved, "battery-low")]
+        );
+        clock.advance(Duration::minutes(10));
+        assert!(engine.evaluate(&status(400, 14)).is_empty());
+        clock.advance(Duration::minutes(40));
+        let raised = engine.evaluate(&status(400, 13));
+        assert_eq!(kinds(&raised), [(AlertKind::Raised, "battery-low")]);
+        assert_eq!(raised[0].since, start() + Duration::minutes(20));
+    }
+
+    #[test]
+    fn no_data() {
+        let clock = FakeClock::new(start());
+        let mut engine = AlertEngine::with_clock(
+            vec![
+                Rule::no_data("stale", Duration::minutes(30)),
+                Rule::above("co2-high", Metric::Co2, 1000.0),
+            ],
+            clock.clone(),
+        );
+        assert!(engine.evaluate(&status(500, 90)).is_empty());
+        clock.advance(Duration::minutes(20));
+        let failed = SensorStatus {
+            up: false,
+            readings: status(5000, 90).readings,
+            ..status(500, 90)
+        };
+        // the stale readings of a failed poll are not checked
+        assert!(engine.evaluate(&failed).is_empty());
+        clock.advance(Duration::minutes(15));
+        let raised = engine.tick();
+        assert_eq!(kinds(&raised), [(AlertKind::Raised, "stale")]);
+        assert_eq!(raised[0].value, None);
+        assert_eq!(raised[0].since, start() + Duration::minutes(30));
+
+        let fresh = SensorStatus {
+            last_success: Some(clock.now()),
+            ..status(500, 90)
+        };
+        assert_eq!(
+            kinds(&engine.evaluate(&fresh)),
+            [(AlertKind::Resolved, "stale")]
+        );
+    }
+
+    #[tokio::test]
+    async fn live_stream_to_sinks() {
+        let (updates, receiver) = broadcast::channel(8);
+        let (first, mut first_events) = mpsc::channel(8);
+        let (second, mut second_events) = mpsc::channel(8);
+        let sinks: Vec<Box<dyn AlertSink>> = vec![Box::new(first), Box::new(second)];
+        let cancel = CancellationToken::new();
+        let mut engine = AlertEngine::with_clock(
+            vec![Rule::above("co2-high", Metric::Co2, 1000.0)],
+            FakeClock::new(start()),
+        );
+        let (failures, _) = mpsc::unbounded_channel();
+        let run = {
+            let cancel = cancel.clone();
+            async move {
+                engine
+                    .run(
+                        receiver,
+                        &sinks,
+                        failures,
+                        std::time::Duration::from_secs(60),
+                        cancel,
+                    )
+                    .await
+            }
+        };
+        let handle = tokio::spawn(run);
+
+        updates.send(status(1200, 90)).unwrap();
+        let event = first_events.recv().await.unwrap();
+        assert_eq!(event.kind, AlertKind::Raised);
+        assert_eq!(second_events.recv().await.unwrap(), event);
+
+        cancel.cancel();
+        handle.await.unwrap().unwrap();
+    }
+
+    #[test]
+    fn sensors_sharing_a_name() {
+        let mut engine = AlertEngine::with_clock(
+            vec![Rule::above("co2-high", Metric::Co2, 1000.0)],
+            FakeClock::new(start()),
+        );
+        let other = |co2| SensorStatus {
+            address: "11:22:33:44:55:66".to_string(),
+            ..status(co2, 90)
+        };
+        let raised = engine.evaluate(&status(1200, 90));
+        assert_eq!(kinds(&raised), [(AlertKind::Raised, "co2-high")]);
+        // the other sensor is fine, which does not resolve the first one's alert
+        assert!(engine.evaluate(&other(500)).is_empty());
+        let raised = engine.evaluate(&other(1200));
+        assert_eq!(raised[0].sensor, "office");
+        assert_eq!(raised[0].address, "11:22:33:44:55:66");
+        assert_eq!(
+            engine.active(),
+            [("office", "co2-high"), ("office", "co2-high")]
+        );
+    }
+
+    /// Sink rejecting every event
+    struct FailingSink;
+
+    #[async_trait]
+    impl AlertSink for FailingSink {
+        async fn send(&self, _: &AlertEvent) -> Result<(), AlertError> {
+            Err(AlertError::Sink("unreachable".into()))
+        }
+    }
+
+    #[tokio::test]
+    async fn sink_failures_are_reported_until_closed() {
+        let (updates, receiver) = broadcast::channel(8);
+        let (working, mut events) = mpsc::channel(8);
+        let sinks: Vec<Box<dyn AlertSink>> = vec![Box::new(FailingSink), Box::new(working)];
+        let (failures, mut failed) = mpsc::unbounded_channel();
+        let mut engine = AlertEngine::with_clock(
+            vec![Rule::above("co2-high", Metric::Co2, 1000.0)],
+            FakeClock::new(start()),
+        );
+        let handle = tokio::spawn(async move {
+            engine
+                .run(
+                    receiver,
+                    &sinks,
+                    failures,
+                    std::time::Duration::from_secs(60),
+                    CancellationToken::new(),
+                )
+                .await
+        });
+
+        updates.send(status(1200, 90)).unwrap();
+        let raised = events.recv().await.unwrap();
+        let failure = failed.recv().await.unwrap();
+        assert_eq!(failure.sink, 0);
+        assert_eq!(failure.event, raised);
+        assert!(matches!(failure.error, AlertError::Sink(_)));
+
+        // still evaluating after the failure
+        updates.send(status(800, 90)).unwrap();
+        let resolved = events.recv().await.unwrap();
+        assert_eq!(resolved.kind, AlertKind::Resolved);
+        assert_eq!(failed.recv().await.unwrap().event, resolved);
+
+        drop(events);
+        updates.send(status(1200, 90)).unwrap();
+        assert!(matches!(handle.await.unwrap(), Err(AlertError::Closed)));
+        let failures: Vec<SinkFailure> = std::iter::from_fn(|| failed.try_recv().ok()).collect();
+        assert_eq!(failures.len(), 2);
+        assert_eq!(failures[1].sink, 1);
+        assert_eq!(failures[1].event.kind, AlertKind::Raised);
+        assert!(matches!(failures[1].error, AlertError::Closed));
+    }
+}
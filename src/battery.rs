@@ -0,0 +1,260 @@
+//! Battery life forecasts from the battery level over time
+//!
+//! The battery level only drops by whole percents, and how fast depends on the sensor
+//! settings. A [`BatteryTracker`] therefore measures the drain against a rough model of the
+//! expected drain for the settings at the time: on the default five-minute interval without
+//! Smart Home integration, a set of batteries is taken to last two years, with measuring
+//! taking 60% of the power. Shorter intervals measure more often, and Smart Home integration
+//! doubles the power spent between measurements on advertising. The measured drain is the
+//! fitted ratio of actual to expected drain, so it stays valid when the settings change.
+use crate::sensor::settings::MeasurementInterval;
+use chrono::{DateTime, Duration, Local};
+
+/// Expected life in days on the reference settings
+const REFERENCE_LIFE_DAYS: f64 = 730.0;
+/// Share of the reference drain spent on measurements
+const MEASURING_SHARE: f64 = 0.6;
+/// Factor on the rest of the drain with Smart Home integration on
+const SMART_HOME_FACTOR: f64 = 2.0;
+/// Rise in percent taken to mean the batteries were replaced
+const REPLACEMENT_RISE: u8 = 10;
+/// Least expected drain, in percent, between the first and last sample for a measured forecast
+const MIN_EXPECTED_DRAIN: f64 = 2.0;
+/// Least drop in percent between the first and last sample for a measured forecast
+const MIN_MEASURED_DROP: u8 = 2;
+/// Samples closer together than this are not kept, unless the level changed
+const SAMPLE_SPACING: i64 = 3600;
+
+/// Sensor settings that affect battery life
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PowerSettings {
+    pub interval: MeasurementInterval,
+    pub smart_home: bool,
+}
+
+impl Default for PowerSettings {
+    fn default() -> Self {
+        Self {
+            interval: MeasurementInterval::FiveMinutes,
+            smart_home: false,
+        }
+    }
+}
+
+impl PowerSettings {
+    /// Expected drain in percent per day
+    pub fn expected_drain(&self) -> f64 {
+        let reference = 100.0 / REFERENCE_LIFE_DAYS;
+        let measuring = MEASURING_SHARE * 5.0 / (self.interval as u8) as f64;
+        let idle = (1.0 - MEASURING_SHARE)
+            * if self.smart_home {
+                SMART_HOME_FACTOR
+            } else {
+                1.0
+            };
+        reference * (measuring + idle)
+    }
+}
+
+/// When the batteries of a sensor are expected to run out
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BatteryForecast {
+    /// Latest battery level in percent
+    #[cfg_attr(feature = "serde", serde(rename = "battery_percent"))]
+    pub percent: u8,
+    /// Drain in percent per day on the current settings
+    #[cfg_attr(feature = "serde", serde(rename = "drain_percent_per_day"))]
+    pub drain_per_day: f64,
+    pub days_remaining: f64,
+    pub empty_at: DateTime<Local>,
+    /// Whether the drain was measured, rather than only expected from the settings
+    pub measured: bool,
+}
+
+impl BatteryForecast {
+    /// Whether the batteries are expected to run out within `days`
+    pub fn runs_out_within(&self, days: f64) -> bool {
+        self.days_remaining <= days
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    time: DateTime<Local>,
+    percent: u8,
+    /// Expected drain in percent since the first sample
+    expected: f64,
+}
+
+/// Battery levels of one sensor over time
+#[derive(Debug, Clone, Default)]
+pub struct BatteryTracker {
+    settings: PowerSettings,
+    samples: Vec<Sample>,
+}
+
+impl BatteryTracker {
+    pub fn new(settings: PowerSettings) -> Self {
+        Self {
+            settings,
+            samples: vec![],
+        }
+    }
+    pub fn settings(&self) -> PowerSettings {
+        self.settings
+    }
+    /// Change the settings used from now on; earlier samples keep theirs
+    pub fn set_settings(&mut self, settings: PowerSettings) {
+        self.settings = settings;
+    }
+    /// Record the battery level at `time`. Levels older than the latest one are ignored.
+    pub fn record(&mut self, time: DateTime<Local>, percent: u8) {
+        let expected = match self.samples.last() {
+            None => 0.0,
+            Some(last) if time <= last.time => return,
+            Some(last) if percent >= last.percent.saturating_add(REPLACEMENT_RISE) => {
+                self.samples.clear();
+                0.0
+            }
+            Some(last)
+                if percent == last.percent && (time - last.time).num_seconds() < SAMPLE_SPACING =>
+            {
+                return
+            }
+            Some(last) => last.expected + self.settings.expected_drain() * days(time - last.time),
+        };
+        self.samples.push(Sample {
+            time,
+            percent,
+            expected,
+        });
+    }
+    /// Forecast from the samples so far, `None` before the first one
+    pub fn forecast(&self) -> Option<BatteryForecast> {
+        let last = self.samples.last()?;
+        let ratio = self.measured_ratio();
+        let drain_per_day = ratio.unwrap_or(1.0) * self.settings.expected_drain();
+        let days_remaining = f64::from(last.percent) / drain_per_day;
+        Some(BatteryForecast {
+            percent: last.percent,
+            drain_per_day,
+            days_remaining,
+            empty_at: last.time + Duration::seconds((days_remaining * 86400.0) as i64),
+            measured: ratio.is_some(),
+        })
+    }
+    /// Least squares slope of the level against the expected drain, as a positive ratio of
+    /// actual to expected drain, once enough drain has been seen to measure it
+    fn measured_ratio(&self) -> Option<f64> {
+        let (first, last) = (self.samples.first()?, self.samples.last()?);
+        if last.expected - first.expected < MIN_EXPECTED_DRAIN
+            || first.percent < last.percent.saturating_add(MIN_MEASURED_DROP)
+        {
+            return None;
+        }
+        let n = self.samples.len() as f64;
+        let mean_x = self.samples.iter().map(|s| s.expected).sum::<f64>() / n;
+        let mean_y = self
+            .samples
+            .iter()
+            .map(|s| f64::from(s.percent))
+            .sum::<f64>()
+            / n;
+        let (sxy, sxx) = self.samples.iter().fold((0.0, 0.0), |(sxy, sxx), s| {
+            let dx = s.expected - mean_x;
+            (sxy + dx * (f64::from(s.percent) - mean_y), sxx + dx * dx)
+        });
+        let ratio = -sxy / sxx;
+        (ratio > 0.0).then_some(ratio)
+    }
+}
+
+fn days(duration: Duration) -> f64 {
+    duration.num_seconds() as f64 / 86400.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn start() -> DateTime<Local> {
+        Local.with_ymd_and_hms(2024, 1, 1, 12, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn expected_drain() {
+        let reference = PowerSettings::default();
+        assert!((reference.expected_drain() * 730.0 - 100.0).abs() < 1e-9);
+        let one_minute = PowerSettings {
+            interval: MeasurementInterval::OneMinute,
+            ..reference
+        };
+        let smart_home = PowerSettings {
+            smart_home: true,
+            ..reference
+        };
+        assert!(one_minute.expected_drain() > smart_home.expected_drain());
+        assert!(smart_home.expected_drain() > reference.expected_drain());
+    }
+
+    #[test]
+    fn nominal_until_measured() {
+        let mut tracker = BatteryTracker::default();
+        assert_eq!(tracker.forecast(), None);
+        tracker.record(start(), 80);
+        let forecast = tracker.forecast().unwrap();
+        assert!(!forecast.measured);
+        assert!((forecast.days_remaining - 584.0).abs() < 0.01);
+        assert_eq!(forecast.empty_at, start() + Duration::days(584));
+    }
+
+    #[test]
+    fn measured_drain() {
+        // draining one percent every five days, faster than expected
+        let mut tracker = BatteryTracker::default();
+        for day in 0..=40 {
+            tracker.record(start() + Duration::days(day), 90 - (day / 5) as u8);
+        }
+        let forecast = tracker.forecast().unwrap();
+        assert!(forecast.measured);
+        assert_eq!(forecast.percent, 82);
+        assert!((forecast.drain_per_day - 0.2).abs() < 0.01);
+        assert!((forecast.days_remaining - 410.0).abs() < 20.0);
+
+        // the same ratio applies on settings that drain faster
+        tracker.set_settings(PowerSettings {
+            interval: MeasurementInterval::OneMinute,
+            smart_home: true,
+        });
+        let faster = tracker.forecast().unwrap();
+        let factor =
+            tracker.settings().expected_drain() / PowerSettings::default().expected_drain();
+        assert!((faster.drain_per_day / forecast.drain_per_day - factor).abs() < 1e-9);
+    }
+
+    #[test]
+    fn unvalidated_levels() {
+        // the battery byte comes off the wire unchecked
+        let mut tracker = BatteryTracker::default();
+        for day in 0..=40 {
+            tracker.record(start() + Duration::days(day), 254);
+        }
+        let forecast = tracker.forecast().unwrap();
+        assert!(!forecast.measured);
+        assert_eq!(forecast.percent, 254);
+    }
+
+    #[test]
+    fn replacement_and_spacing() {
+        let mut tracker = BatteryTracker::default();
+        tracker.record(start(), 12);
+        tracker.record(start() + Duration::minutes(5), 12);
+        tracker.record(start() - Duration::minutes(5), 11);
+        assert_eq!(tracker.samples.len(), 1);
+        tracker.record(start() + Duration::days(1), 100);
+        assert_eq!(tracker.samples.len(), 1);
+        assert_eq!(tracker.forecast().unwrap().percent, 100);
+    }
+}
@@ -0,0 +1,325 @@
+//! Command-line arguments
+use crate::output::Format;
+use aranet4::sensor::{
+    settings::{BluetoothRange, MeasurementInterval},
+    SensorSelector,
+};
+use chrono::{DateTime, Local};
+use clap::{Parser, Subcommand, ValueEnum};
+
+#[derive(Debug, Parser)]
+#[command(
+    name = "aranet4",
+    version,
+    about = "Read and configure Aranet4 CO2 sensors"
+)]
+pub struct Cli {
+    /// Bluetooth address of the sensor, e.g. AA:BB:CC:DD:EE:FF
+    #[arg(long, short, global = true, conflicts_with = "name")]
+    pub address: Option<String>,
+    /// Connect to the first sensor whose name contains NAME
+    #[arg(long, short, global = true)]
+    pub name: Option<String>,
+    /// Output format
+    #[arg(long, short, global = true, value_enum, default_value_t = Format::Human)]
+    pub format: Format,
+    /// Seconds to wait for each Bluetooth operation
+    #[arg(long, global = true, default_value_t = 10)]
+    pub timeout: u64,
+    /// Record the Bluetooth traffic with the sensor to FILE, e.g. for a bug report. Not used
+    /// by the exporter.
+    #[arg(long, global = true, value_name = "FILE")]
+    pub record: Option<std::path::PathBuf>,
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+impl Cli {
+    /// The sensor picked by `--address` or `--name`
+    pub fn selector(&self) -> SensorSelector {
+        match (&self.address, &self.name) {
+            (Some(address), _) => SensorSelector::Address(address.clone()),
+            (None, Some(name)) => SensorSelector::Name(name.clone()),
+            (None, None) => SensorSelector::Any,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Subcommand)]
+pub enum Command {
+    /// List nearby sensors
+    Scan {
+        /// Seconds to scan for
+        #[arg(long, default_value_t = 5)]
+        duration: u64,
+    },
+    /// Print the current readings
+    Read,
+    /// Download the measurement history
+    History {
+        /// Skip measurements before this time, as RFC 3339 or an age such as 90m, 12h or 7d
+        #[arg(long, value_parser = parse_time)]
+        since: Option<DateTime<Local>>,
+        /// Skip measurements after this time, as RFC 3339 or an age such as 90m, 12h or 7d
+        #[arg(long, value_parser = parse_time)]
+        until: Option<DateTime<Local>>,
+        /// Parameters to print, all by default
+        #[arg(long = "param", short, value_enum, value_delimiter = ',')]
+        params: Vec<Parameter>,
+    },
+    /// Print readings as they are measured
+    Watch {
+        /// Seconds between reads, defaults to the measurement interval
+        #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+        interval: Option<u64>,
+    },
+    /// Print device information and configuration
+    Info,
+    /// Change a sensor setting
+    #[command(subcommand)]
+    Set(Setting),
+    /// Serve the readings of one or more sensors to Prometheus
+    Exporter {
+        /// Address to serve /metrics on
+        #[arg(long, default_value = "0.0.0.0:9105")]
+        listen: std::net::SocketAddr,
+        /// Sensor to poll, by Bluetooth address or part of its name. Repeat for several sensors;
+        /// defaults to the sensor picked by --address or --name
+        #[arg(long = "sensor", short = 's')]
+        sensors: Vec<String>,
+        /// Seconds between polls
+        #[arg(long, default_value_t = 60, value_parser = clap::value_parser!(u64).range(1..))]
+        interval: u64,
+    },
+    /// Print the Aranet4 readings in a btsnoop capture, e.g. btsnoop_hci.log or btmon -w
+    Capture {
+        /// Capture file to read
+        file: std::path::PathBuf,
+    },
+}
+
+#[derive(Debug, PartialEq, Subcommand)]
+pub enum Setting {
+    /// Minutes between measurements: 1, 2, 5 or 10. Clears the sensor history.
+    Interval {
+        #[arg(value_parser = parse_interval)]
+        minutes: MeasurementInterval,
+    },
+    /// Broadcast readings in advertisements for Smart Home integrations
+    SmartHome {
+        #[arg(value_enum)]
+        state: Toggle,
+    },
+    /// Bluetooth transmit range
+    Range {
+        #[arg(value_enum)]
+        range: Range,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Toggle {
+    On,
+    Off,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Range {
+    Standard,
+    Extended,
+}
+impl From<Range> for BluetoothRange {
+    fn from(range: Range) -> Self {
+        match range {
+            Range::Standard => BluetoothRange::Standard,
+            Range::Extended => BluetoothRange::Extended,
+        }
+    }
+}
+
+/// A measured quantity that can be selected for output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Parameter {
+    Co2,
+    Temperature,
+    Humidity,
+    Pressure,
+}
+impl Parameter {
+    pub const ALL: [Parameter; 4] = [
+        Parameter::Co2,
+        Parameter::Temperature,
+        Parameter::Humidity,
+        Parameter::Pressure,
+    ];
+}
+
+/// Interpret `value` as a Bluetooth address if it looks like one, otherwise as a name
+pub fn sensor_selector(value: &str) -> SensorSelector {
+    let is_address = value.len() == 17
+        && value
+            .split(':')
+            .all(|octet| octet.len() == 2 && octet.chars().all(|c| c.is_ascii_hexdigit()));
+    if is_address {
+        SensorSelector::Address(value.to_string())
+    } else {
+        SensorSelector::Name(value.to_string())
+    }
+}
+
+fn parse_time(value: &str) -> Result<DateTime<Local>, String> {
+    parse_time_at(value, Local::now())
+}
+
+/// Parse an RFC 3339 time or an age relative to `now`
+fn parse_time_at(value: &str, now: DateTime<Local>) -> Result<DateTime<Local>, String> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Ok(time.with_timezone(&Local));
+    }
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .ok_or_else(|| format!("missing unit in {:?}", value))?;
+    let (amount, unit) = value.split_at(split);
+    let amount: i64 = amount
+        .parse()
+        .map_err(|_| format!("invalid time {:?}", value))?;
+    let age = match unit {
+        "s" => chrono::Duration::seconds(amount),
+        "m" => chrono::Duration::minutes(amount),
+        "h" => chrono::Duration::hours(amount),
+        "d" => chrono::Duration::days(amount),
+        _ => return Err(format!("unknown unit {:?}, expected s, m, h or d", unit)),
+    };
+    Ok(now - age)
+}
+
+fn parse_interval(value: &str) -> Result<MeasurementInterval, String> {
+    value
+        .parse()
+        .ok()
+        .and_then(MeasurementInterval::from_minutes)
+        .ok_or_else(|| format!("{:?} is not one of 1, 2, 5 or 10 minutes", value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Cli, clap::Error> {
+        Cli::try_parse_from(std::iter::once("aranet4").chain(args.iter().copied()))
+    }
+
+    #[test]
+    fn sensor_selection() {
+        let cli = parse(&["read"]).unwrap();
+        assert_eq!(cli.selector(), SensorSelector::Any);
+        let cli = parse(&["read", "--address", "AA:BB:CC:DD:EE:FF"]).unwrap();
+        assert_eq!(
+            cli.selector(),
+            SensorSelector::Address("AA:BB:CC:DD:EE:FF".to_string())
+        );
+        let cli = parse(&["-n", "Aranet4 1A2B3", "info"]).unwrap();
+        assert_eq!(
+            cli.selector(),
+            SensorSelector::Name("Aranet4 1A2B3".to_string())
+        );
+        assert!(parse(&["read", "-a", "AA:BB:CC:DD:EE:FF", "-n", "Aranet4"]).is_err());
+    }
+
+    #[test]
+    fn history_arguments() {
+        let cli = parse(&[
+            "history",
+            "--since",
+            "2024-04-25T18:00:00+00:00",
+            "-p",
+            "co2,humidity",
+            "--format",
+            "csv",
+        ])
+        .unwrap();
+        assert_eq!(cli.format, Format::Csv);
+        match cli.command {
+            Command::History {
+                since,
+                until,
+                params,
+            } => {
+                assert_eq!(
+                    since.map(|t| t.to_rfc3339()),
+                    Some(
+                        DateTime::parse_from_rfc3339("2024-04-25T18:00:00+00:00")
+                            .unwrap()
+                            .with_timezone(&Local)
+                            .to_rfc3339()
+                    )
+                );
+                assert_eq!(until, None);
+                assert_eq!(params, vec![Parameter::Co2, Parameter::Humidity]);
+            }
+            c => panic!("unexpected command {:?}", c),
+        }
+    }
+
+    #[test]
+    fn relative_times() {
+        let now = Local::now();
+        assert_eq!(
+            parse_time_at("90m", now),
+            Ok(now - chrono::Duration::minutes(90))
+        );
+        assert_eq!(
+            parse_time_at("7d", now),
+            Ok(now - chrono::Duration::days(7))
+        );
+        assert!(parse_time_at("7", now).is_err());
+        assert!(parse_time_at("7w", now).is_err());
+    }
+
+    #[test]
+    fn exporter_arguments() {
+        let cli = parse(&["exporter", "-s", "Aranet4 1A2B3", "-s", "AA:BB:CC:DD:EE:FF"]).unwrap();
+        assert_eq!(
+            cli.command,
+            Command::Exporter {
+                listen: "0.0.0.0:9105".parse().unwrap(),
+                sensors: vec!["Aranet4 1A2B3".to_string(), "AA:BB:CC:DD:EE:FF".to_string()],
+                interval: 60,
+            }
+        );
+        assert!(parse(&["exporter", "--interval", "0"]).is_err());
+        assert!(parse(&["watch", "--interval", "0"]).is_err());
+        assert_eq!(
+            sensor_selector("aa:bb:cc:dd:ee:ff"),
+            SensorSelector::Address("aa:bb:cc:dd:ee:ff".to_string())
+        );
+        assert_eq!(
+            sensor_selector("Office"),
+            SensorSelector::Name("Office".to_string())
+        );
+    }
+
+    #[test]
+    fn settings() {
+        let cli = parse(&["set", "interval", "5"]).unwrap();
+        assert_eq!(
+            cli.command,
+            Command::Set(Setting::Interval {
+                minutes: MeasurementInterval::FiveMinutes
+            })
+        );
+        assert!(parse(&["set", "interval", "3"]).is_err());
+        let cli = parse(&["set", "smart-home", "on"]).unwrap();
+        assert_eq!(
+            cli.command,
+            Command::Set(Setting::SmartHome { state: Toggle::On })
+        );
+        let cli = parse(&["set", "range", "extended"]).unwrap();
+        assert_eq!(
+            cli.command,
+            Command::Set(Setting::Range {
+                range: Range::Extended
+            })
+        );
+    }
+}
@@ -0,0 +1,160 @@
+//! Command-line interface to Aranet4 sensors
+mod args;
+mod output;
+
+use aranet4::{
+    btsnoop::Capture,
+    fleet::Fleet,
+    sensor::{recording::Recorder, Sensor, SensorManager, SensorSelector, Timeouts},
+};
+use args::{Cli, Command, Parameter, Setting, Toggle};
+use chrono::Local;
+use clap::Parser;
+use output::Result;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    let cli = Cli::parse();
+    if let Err(e) = run(cli).await {
+        eprint!("Error: {}", e);
+        let mut source = e.source();
+        while let Some(cause) = source {
+            eprint!(": {}", cause);
+            source = cause.source();
+        }
+        eprintln!();
+        std::process::exit(1);
+    }
+}
+
+async fn connect(cli: &Cli) -> Result<Sensor> {
+    let sensor = connect_to(cli, &cli.selector()).await?;
+    Ok(match &cli.record {
+        Some(path) => sensor.record(Recorder::create(path)?),
+        None => sensor,
+    })
+}
+
+async fn connect_to(cli: &Cli, selector: &SensorSelector) -> Result<Sensor> {
+    let timeouts = Timeouts {
+        operation: Duration::from_secs(cli.timeout),
+        ..Timeouts::default()
+    };
+    Ok(SensorManager::connect_with_timeouts(selector, timeouts).await?)
+}
+
+async fn run(cli: Cli) -> Result<()> {
+    let mut out = std::io::stdout().lock();
+    match &cli.command {
+        Command::Scan { duration } => {
+            let sensors = SensorManager::scan(Duration::from_secs(*duration)).await?;
+            output::write_scan(&mut out, cli.format, &sensors)?;
+        }
+        Command::Read => {
+            let sensor = connect(&cli).await?;
+            let readings = sensor.read_current_values().await?;
+            output::write_readings(&mut out, cli.format, Local::now(), &readings, true)?;
+        }
+        Command::History {
+            since,
+            until,
+            params,
+        } => {
+            let sensor = connect(&cli).await?;
+            let history = sensor.get_historical_data().await?;
+            let records: Vec<_> = history
+                .as_timestamped_records()
+                .into_iter()
+                .filter(|r| since.is_none_or(|since| r.time >= since))
+                .filter(|r| until.is_none_or(|until| r.time <= until))
+                .collect();
+            let params = if params.is_empty() {
+                &Parameter::ALL[..]
+            } else {
+                &params[..]
+            };
+            output::write_history(&mut out, cli.format, &records, params)?;
+        }
+        Command::Watch { interval } => {
+            let sensor = connect(&cli).await?;
+            let period = match interval {
+                Some(seconds) => Duration::from_secs(*seconds),
+                // a sensor reporting no interval is polled every second rather than in a spin
+                None => sensor
+                    .measurement_interval()
+                    .await?
+                    .max(Duration::from_secs(1)),
+            };
+            let mut ticks = tokio::time::interval(period);
+            let mut header = true;
+            loop {
+                ticks.tick().await;
+                let readings = sensor.read_current_values().await?;
+                output::write_readings(&mut out, cli.format, Local::now(), &readings, header)?;
+                header = false;
+            }
+        }
+        Command::Info => {
+            let sensor = connect(&cli).await?;
+            let info = sensor.device_info().await?;
+            let interval = sensor.measurement_interval().await?;
+            let stored = sensor.total_readings().await?;
+            output::write_info(&mut out, cli.format, &info, interval, stored)?;
+        }
+        Command::Set(setting) => {
+            let sensor = connect(&cli).await?;
+            match setting {
+                Setting::Interval { minutes } => sensor.set_measurement_interval(*minutes).await?,
+                Setting::SmartHome { state } => {
+                    sensor
+                        .set_smart_home_integration(*state == Toggle::On)
+                        .await?
+                }
+                Setting::Range { range } => sensor.set_bluetooth_range((*range).into()).await?,
+            }
+        }
+        Command::Exporter {
+            listen,
+            sensors,
+            interval,
+        } => {
+            let selectors = if sensors.is_empty() {
+                vec![cli.selector()]
+            } else {
+                sensors.iter().map(|s| args::sensor_selector(s)).collect()
+            };
+            let mut fleet = Fleet::new();
+            for selector in &selectors {
+                let sensor = connect_to(&cli, selector).await?;
+                let name = match selector {
+                    SensorSelector::Name(name) => name.clone(),
+                    _ => sensor
+                        .device_info()
+                        .await?
+                        .name
+                        .or_else(|| sensor.address())
+                        .unwrap_or_default(),
+                };
+                fleet.add(name, sensor);
+            }
+            let listener = tokio::net::TcpListener::bind(listen).await?;
+            let cancel = CancellationToken::new();
+            let server = aranet4::prometheus::serve(listener, fleet.status(), cancel.clone());
+            tokio::select! {
+                result = server => result?,
+                _ = fleet.run(Duration::from_secs(*interval), cancel.clone()) => {}
+                _ = tokio::signal::ctrl_c() => cancel.cancel(),
+            }
+        }
+        Command::Capture { file } => {
+            let capture = Capture::open(file)?;
+            if capture.truncated {
+                eprintln!("Warning: capture ends in the middle of a packet");
+            }
+            output::write_timeline(&mut out, cli.format, &capture.timeline())?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,469 @@
+//! Formatting of command output
+use crate::args::Parameter;
+use aranet4::{
+    btsnoop::{Source, TimelineEntry},
+    history::{
+        export::csv::{self as history_csv, Column, CsvOptions},
+        record::TimestampedRecord,
+    },
+    readings::SensorReadings,
+    sensor::{settings::DeviceInfo, DiscoveredSensor},
+};
+use chrono::{DateTime, Local};
+use clap::ValueEnum;
+use serde_json::{json, Map, Value};
+use std::error::Error;
+use std::io::Write;
+use std::time::Duration;
+
+pub type Result<T> = std::result::Result<T, Box<dyn Error>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    /// Text meant for people
+    Human,
+    /// JSON, one document per command or one line per reading when watching
+    Json,
+    /// CSV with a header row
+    Csv,
+}
+
+pub fn write_scan(
+    out: &mut impl Write,
+    format: Format,
+    sensors: &[DiscoveredSensor],
+) -> Result<()> {
+    match format {
+        Format::Human => {
+            for sensor in sensors {
+                write!(
+                    out,
+                    "{}  {}",
+                    sensor.address,
+                    sensor.name.as_deref().unwrap_or("<unnamed>")
+                )?;
+                if let Some(rssi) = sensor.rssi {
+                    write!(out, "  {} dBm", rssi)?;
+                }
+                writeln!(out)?;
+            }
+        }
+        Format::Json => writeln!(out, "{}", serde_json::to_string(sensors)?)?,
+        Format::Csv => {
+            let mut writer = csv::Writer::from_writer(out);
+            writer.write_record(["address", "name", "rssi"])?;
+            for sensor in sensors {
+                writer.write_record([
+                    sensor.address.clone(),
+                    sensor.name.clone().unwrap_or_default(),
+                    sensor.rssi.map(|r| r.to_string()).unwrap_or_default(),
+                ])?;
+            }
+            writer.flush()?;
+        }
+    }
+    Ok(())
+}
+
+/// Write one set of readings, with a CSV header if `header` is set
+pub fn write_readings(
+    out: &mut impl Write,
+    format: Format,
+    time: DateTime<Local>,
+    readings: &SensorReadings,
+    header: bool,
+) -> Result<()> {
+    match format {
+        // the labels and units of the human history output
+        Format::Human => writeln!(
+            out,
+            "{}  CO2: {}ppm, Temperature: {}F, Humidity: {}%, Pressure: {}hPa, Battery: {}%",
+            time.format("%Y-%m-%d %H:%M:%S"),
+            readings.co2_level,
+            readings.temperature,
+            readings.humidity,
+            readings.pressure,
+            readings.battery
+        )?,
+        Format::Json => {
+            let mut value = serde_json::to_value(readings)?;
+            value["time"] = json!(time.to_rfc3339());
+            writeln!(out, "{}", value)?;
+        }
+        Format::Csv => {
+            let mut writer = csv::Writer::from_writer(out);
+            if header {
+                writer.write_record([
+                    "time",
+                    "co2_ppm",
+                    "temperature_f",
+                    "humidity_percent",
+                    "pressure_hpa",
+                    "battery_percent",
+                ])?;
+            }
+            writer.write_record([
+                time.to_rfc3339(),
+                readings.co2_level.to_string(),
+                readings.temperature.to_string(),
+                readings.humidity.to_string(),
+                readings.pressure.to_string(),
+                readings.battery.to_string(),
+            ])?;
+            writer.flush()?;
+        }
+    }
+    Ok(())
+}
+
+/// Write history records, limited to `params`
+pub fn write_history(
+    out: &mut impl Write,
+    format: Format,
+    records: &[TimestampedRecord],
+    params: &[Parameter],
+) -> Result<()> {
+    match format {
+        Format::Human => {
+            for record in records {
+                let values: Vec<String> = params
+                    .iter()
+                    .map(|p| {
+                        let r = &record.record;
+                        match p {
+                            Parameter::Co2 => format!("CO2: {}ppm", r.co2),
+                            Parameter::Temperature => format!("Temperature: {}F", r.temperature),
+                            Parameter::Humidity => format!("Humidity: {}%", r.humidity),
+                            Parameter::Pressure => format!("Pressure: {}hPa", r.pressure),
+                        }
+                    })
+                    .collect();
+                writeln!(
+                    out,
+                    "{}  {}",
+                    record.time.format("%Y-%m-%d %H:%M:%S"),
+                    values.join(", ")
+                )?;
+            }
+        }
+        Format::Json => {
+            let rows: Vec<Value> = records
+                .iter()
+                .map(|record| {
+                    let r = &record.record;
+                    let mut row = Map::new();
+                    row.insert("time".to_string(), json!(record.time.to_rfc3339()));
+                    for p in params {
+                        let (key, value) = match p {
+                            Parameter::Co2 => ("co2_ppm", json!(r.co2)),
+                            Parameter::Temperature => ("temperature_f", json!(r.temperature)),
+                            Parameter::Humidity => ("humidity_percent", json!(r.humidity)),
+                            Parameter::Pressure => ("pressure_hpa", json!(r.pressure)),
+                        };
+                        row.insert(key.to_string(), value);
+                    }
+                    Value::Object(row)
+                })
+                .collect();
+            writeln!(out, "{}", Value::Array(rows))?;
+        }
+        Format::Csv => {
+            let columns = std::iter::once(Column::Time)
+                .chain(params.iter().map(|p| match p {
+                    Parameter::Co2 => Column::Co2,
+                    Parameter::Temperature => Column::Temperature,
+                    Parameter::Humidity => Column::Humidity,
+                    Parameter::Pressure => Column::Pressure,
+                }))
+                .collect();
+            let options = CsvOptions {
+                columns,
+                ..CsvOptions::default()
+            };
+            history_csv::write_records(out, records, &options)?;
+        }
+    }
+    Ok(())
+}
+
+/// Write the readings found in a capture
+pub fn write_timeline(
+    out: &mut impl Write,
+    format: Format,
+    entries: &[TimelineEntry],
+) -> Result<()> {
+    let source = |entry: &TimelineEntry| match entry.source {
+        Source::Advertisement => "advertisement",
+        Source::GattRead => "read",
+    };
+    match format {
+        Format::Human => {
+            for entry in entries {
+                writeln!(
+                    out,
+                    "{}  {}  {:<13}  {}",
+                    entry.time.format("%Y-%m-%d %H:%M:%S%.3f"),
+                    entry.address.as_deref().unwrap_or("unknown"),
+                    source(entry),
+                    entry.readings
+                )?;
+            }
+        }
+        Format::Json => {
+            let rows: Vec<Value> = entries
+                .iter()
+                .map(|entry| {
+                    let mut row = serde_json::to_value(&entry.readings)?;
+                    row["time"] = json!(entry.time.to_rfc3339());
+                    row["address"] = json!(entry.address);
+                    row["source"] = json!(source(entry));
+                    row["seconds_since_update"] = json!(entry.since_update.map(|d| d.as_secs()));
+                    Ok(row)
+                })
+                .collect::<serde_json::Result<_>>()?;
+            writeln!(out, "{}", Value::Array(rows))?;
+        }
+        Format::Csv => {
+            let mut writer = csv::Writer::from_writer(out);
+            writer.write_record([
+                "time",
+                "address",
+                "source",
+                "co2_ppm",
+                "temperature_f",
+                "humidity_percent",
+                "pressure_hpa",
+                "battery_percent",
+                "seconds_since_update",
+            ])?;
+            for entry in entries {
+                let readings = &entry.readings;
+                writer.write_record([
+                    entry.time.to_rfc3339(),
+                    entry.address.clone().unwrap_or_default(),
+                    source(entry).to_string(),
+                    readings.co2_level.to_string(),
+                    readings.temperature.to_string(),
+                    readings.humidity.to_string(),
+                    readings.pressure.to_string(),
+                    readings.battery.to_string(),
+                    entry
+                        .since_update
+                        .map(|d| d.as_secs().to_string())
+                        .unwrap_or_default(),
+                ])?;
+            }
+            writer.flush()?;
+        }
+    }
+    Ok(())
+}
+
+pub fn write_info(
+    out: &mut impl Write,
+    format: Format,
+    info: &DeviceInfo,
+    interval: Duration,
+    stored_readings: u16,
+) -> Result<()> {
+    let fields = [
+        ("name", "Name", info.name.clone()),
+        ("manufacturer", "Manufacturer", info.manufacturer.clone()),
+        ("model", "Model", info.model.clone()),
+        ("serial_number", "Serial number", info.serial_number.clone()),
+        (
+            "hardware_revision",
+            "Hardware revision",
+            info.hardware_revision.clone(),
+        ),
+        (
+            "software_revision",
+            "Software revision",
+            info.software_revision.clone(),
+        ),
+        (
+            "interval_seconds",
+            "Interval (seconds)",
+            Some(interval.as_secs().to_string()),
+        ),
+        (
+            "stored_readings",
+            "Stored readings",
+            Some(stored_readings.to_string()),
+        ),
+    ];
+    match format {
+        Format::Human => {
+            for (_, label, value) in fields {
+                writeln!(
+                    out,
+                    "{:<19}{}",
+                    format!("{}:", label),
+                    value.as_deref().unwrap_or("unknown")
+                )?;
+            }
+        }
+        Format::Json => {
+            let mut value = serde_json::to_value(info)?;
+            value["interval_seconds"] = json!(interval.as_secs());
+            value["stored_readings"] = json!(stored_readings);
+            writeln!(out, "{}", value)?;
+        }
+        Format::Csv => {
+            let mut writer = csv::Writer::from_writer(out);
+            writer.write_record(["field", "value"])?;
+            for (key, _, value) in fields {
+                writer.write_record([key, value.as_deref().unwrap_or_default()])?;
+            }
+            writer.flush()?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aranet4::history::record::DataRecord;
+    use chrono::TimeZone;
+
+    fn render(f: impl FnOnce(&mut Vec<u8>) -> Result<()>) -> String {
+        let mut out = vec![];
+        f(&mut out).expect("write output");
+        String::from_utf8(out).unwrap()
+    }
+
+    fn readings() -> SensorReadings {
+        serde_json::from_value(json!({
+            "co2_ppm": 464,
+            "temperature_f": 70.5,
+            "pressure_hpa": 1001.2,
+            "humidity_percent": 36,
+            "battery_percent": 90,
+            "status_color": 1,
+        }))
+        .unwrap()
+    }
+
+    fn time() -> DateTime<Local> {
+        Local.with_ymd_and_hms(2024, 4, 25, 18, 8, 4).unwrap()
+    }
+
+    #[test]
+    fn readings_formats() {
+        let human = render(|out| write_readings(out, Format::Human, time(), &readings(), true));
+        assert_eq!(
+            human,
+            "2024-04-25 18:08:04  CO2: 464ppm, Temperature: 70.5F, Humidity: 36%, Pressure: 1001.2hPa, Battery: 90%\n"
+        );
+
+        let json = render(|out| write_readings(out, Format::Json, time(), &readings(), true));
+        let value: Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value["co2_ppm"], 464);
+        assert_eq!(value["time"], time().to_rfc3339());
+
+        let csv = render(|out| write_readings(out, Format::Csv, time(), &readings(), true));
+        let mut lines = csv.lines();
+        assert_eq!(
+            lines.next(),
+            Some("time,co2_ppm,temperature_f,humidity_percent,pressure_hpa,battery_percent")
+        );
+        assert_eq!(
+            lines.next(),
+            Some(format!("{},464,70.5,36,1001.2,90", time().to_rfc3339()).as_str())
+        );
+        let no_header = render(|out| write_readings(out, Format::Csv, time(), &readings(), false));
+        assert_eq!(no_header.lines().count(), 1);
+    }
+
+    #[test]
+    fn history_formats() {
+        let records = vec![TimestampedRecord {
+            time: time(),
+            record: DataRecord {
+                temperature: 69.5,
+                humidity: 30,
+                pressure: 987.5,
+                co2: 373,
+            },
+        }];
+        let params = [Parameter::Co2, Parameter::Humidity];
+        let human = render(|out| write_history(out, Format::Human, &records, &params));
+        assert_eq!(human, "2024-04-25 18:08:04  CO2: 373ppm, Humidity: 30%\n");
+
+        let json = render(|out| write_history(out, Format::Json, &records, &params));
+        let value: Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(
+            value,
+            json!([{"time": time().to_rfc3339(), "co2_ppm": 373, "humidity_percent": 30}])
+        );
+
+        let csv = render(|out| write_history(out, Format::Csv, &records, &params));
+        assert_eq!(
+            csv,
+            format!(
+                "time,co2_ppm,humidity_percent\n{},373,30\n",
+                time().to_rfc3339()
+            )
+        );
+    }
+
+    #[test]
+    fn timeline_formats() {
+        let entries = vec![TimelineEntry {
+            time: time(),
+            address: Some("AA:BB:CC:DD:EE:FF".to_string()),
+            source: Source::Advertisement,
+            readings: readings(),
+            since_update: Some(Duration::from_secs(60)),
+        }];
+        let human = render(|out| write_timeline(out, Format::Human, &entries));
+        assert!(human
+            .starts_with("2024-04-25 18:08:04.000  AA:BB:CC:DD:EE:FF  advertisement  CO2: 464ppm"));
+        let json = render(|out| write_timeline(out, Format::Json, &entries));
+        let value: Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value[0]["source"], "advertisement");
+        assert_eq!(value[0]["seconds_since_update"], 60);
+        let csv = render(|out| write_timeline(out, Format::Csv, &entries));
+        assert_eq!(
+            csv.lines().nth(1),
+            Some(
+                format!(
+                    "{},AA:BB:CC:DD:EE:FF,advertisement,464,70.5,36,1001.2,90,60",
+                    time().to_rfc3339()
+                )
+                .as_str()
+            )
+        );
+    }
+
+    #[test]
+    fn scan_and_info_formats() {
+        let sensors = vec![DiscoveredSensor {
+            address: "AA:BB:CC:DD:EE:FF".to_string(),
+            name: Some("Aranet4 1A2B3".to_string()),
+            rssi: Some(-67),
+        }];
+        let human = render(|out| write_scan(out, Format::Human, &sensors));
+        assert_eq!(human, "AA:BB:CC:DD:EE:FF  Aranet4 1A2B3  -67 dBm\n");
+        let csv = render(|out| write_scan(out, Format::Csv, &sensors));
+        assert_eq!(
+            csv,
+            "address,name,rssi\nAA:BB:CC:DD:EE:FF,Aranet4 1A2B3,-67\n"
+        );
+
+        let info = DeviceInfo {
+            serial_number: Some("12345".to_string()),
+            ..DeviceInfo::default()
+        };
+        let human =
+            render(|out| write_info(out, Format::Human, &info, Duration::from_secs(300), 2016));
+        assert!(human.contains("Serial number:     12345\n"));
+        assert!(human.contains("Model:             unknown\n"));
+        let json =
+            render(|out| write_info(out, Format::Json, &info, Duration::from_secs(300), 2016));
+        let value: Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value["serial_number"], "12345");
+        assert_eq!(value["interval_seconds"], 300);
+    }
+}
@@ -0,0 +1,663 @@
+//! Aranet4 readings from btsnoop captures, such as Android's `btsnoop_hci.log` or `btmon -w`
+//!
+//! A [`Capture`] holds the HCI packets of a btsnoop file, and [`Capture::timeline`] picks out
+//! the readings of Aranet4 sensors in it: advertising reports carrying Aranet manufacturer
+//! data, and ATT read responses of the current readings characteristics. Read responses are
+//! matched to their characteristic through the read request before them and the
+//! characteristic discovery earlier in the capture, so only sessions captured from before
+//! the connection are found. Timestamps are taken to be UTC, as written by BlueZ and Android.
+use crate::{
+    readings::{Advertisement, SensorReadings, MANUFACTURER_ID},
+    sensor::protocol::AranetService,
+};
+use aranet4_core::readings::{DetailedReadings, Readings};
+use chrono::{DateTime, Local};
+use std::{collections::HashMap, path::Path, time::Duration};
+use thiserror::Error;
+use uuid::Uuid;
+
+const MAGIC: &[u8; 8] = b"btsnoop\0";
+const VERSION: u32 = 1;
+const FILE_HEADER_SIZE: usize = 16;
+const RECORD_HEADER_SIZE: usize = 24;
+/// Microseconds from midnight, January 1st of year 0 to the Unix epoch
+const EPOCH_OFFSET: i64 = 0x00dc_ddb3_0f2f_8000;
+
+/// HCI event codes and LE meta subevents
+const EVENT_DISCONNECTION_COMPLETE: u8 = 0x05;
+const EVENT_LE_META: u8 = 0x3e;
+const LE_CONNECTION_COMPLETE: u8 = 0x01;
+const LE_ADVERTISING_REPORT: u8 = 0x02;
+const LE_ENHANCED_CONNECTION_COMPLETE: u8 = 0x0a;
+const LE_EXTENDED_ADVERTISING_REPORT: u8 = 0x0d;
+/// Advertising data type of manufacturer specific data
+const AD_MANUFACTURER_DATA: u8 = 0xff;
+/// L2CAP channel of the attribute protocol
+const ATT_CID: u16 = 0x0004;
+/// ATT opcodes
+const ATT_ERROR_RESPONSE: u8 = 0x01;
+const ATT_READ_BY_TYPE_REQUEST: u8 = 0x08;
+const ATT_READ_BY_TYPE_RESPONSE: u8 = 0x09;
+const ATT_READ_REQUEST: u8 = 0x0a;
+const ATT_READ_RESPONSE: u8 = 0x0b;
+/// Attribute type of characteristic declarations
+const CHARACTERISTIC_DECLARATION: u16 = 0x2803;
+/// Bluetooth base UUID, into which 16-bit UUIDs are placed
+const BASE_UUID: u128 = 0x00000000_0000_1000_8000_00805f9b34fb;
+
+#[derive(Debug, Error)]
+pub enum CaptureError {
+    #[error("Cannot read capture")]
+    Io(#[from] std::io::Error),
+    #[error("Not a btsnoop capture")]
+    NotBtsnoop,
+    #[error("Unsupported btsnoop version {0}")]
+    UnsupportedVersion(u32),
+    #[error("Unsupported btsnoop datalink type {0}")]
+    UnsupportedDatalink(u32),
+    #[error("Invalid timestamp in record {0}")]
+    InvalidTime(usize),
+}
+
+/// How the HCI packets of a capture are framed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Datalink {
+    /// HCI packets without a type, which the record flags give instead (1001)
+    Unencapsulated,
+    /// HCI packets starting with their UART packet type, as written by Android (1002)
+    Uart,
+    /// Linux monitor packets, as written by `btmon` (2001)
+    Monitor,
+}
+
+impl Datalink {
+    fn from_code(code: u32) -> Option<Self> {
+        match code {
+            1001 => Some(Datalink::Unencapsulated),
+            1002 => Some(Datalink::Uart),
+            2001 => Some(Datalink::Monitor),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// From the host to the controller
+    Sent,
+    /// From the controller to the host
+    Received,
+}
+
+/// An HCI packet, without its UART packet type
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HciPacket {
+    Command(Vec<u8>),
+    Acl(Vec<u8>),
+    Event(Vec<u8>),
+    /// Synchronous data, vendor diagnostics and monitor notes
+    Other,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub time: DateTime<Local>,
+    /// Index of the controller, for captures of several
+    pub adapter: u16,
+    pub direction: Direction,
+    pub packet: HciPacket,
+}
+
+/// The packets of a btsnoop file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Capture {
+    pub datalink: Datalink,
+    pub records: Vec<Record>,
+    /// Whether the file ended inside a record, as when it was copied during the capture
+    pub truncated: bool,
+}
+
+impl Capture {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, CaptureError> {
+        Self::parse(&std::fs::read(path)?)
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<Self, CaptureError> {
+        if bytes.len() < FILE_HEADER_SIZE || &bytes[..8] != MAGIC {
+            return Err(CaptureError::NotBtsnoop);
+        }
+        let version = be_u32(&bytes[8..]);
+        if version != VERSION {
+            return Err(CaptureError::UnsupportedVersion(version));
+        }
+        let code = be_u32(&bytes[12..]);
+        let datalink = Datalink::from_code(code).ok_or(CaptureError::UnsupportedDatalink(code))?;
+
+        let mut records = vec![];
+        let mut rest = &bytes[FILE_HEADER_SIZE..];
+        while rest.len() >= RECORD_HEADER_SIZE {
+            let included = be_u32(&rest[4..]) as usize;
+            let Some(data) = rest.get(RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + included) else {
+                break;
+            };
+            let flags = be_u32(&rest[8..]);
+            let micros = i64::from_be_bytes(rest[16..24].try_into().expect("eight bytes"));
+            let time = micros
+                .checked_sub(EPOCH_OFFSET)
+                .and_then(DateTime::from_timestamp_micros)
+                .ok_or(CaptureError::InvalidTime(records.len()))?
+                .with_timezone(&Local);
+            records.push(Record {
+                time,
+                ..decode_record(datalink, flags, data)
+            });
+            rest = &rest[RECORD_HEADER_SIZE + included..];
+        }
+        Ok(Self {
+            datalink,
+            records,
+            truncated: !rest.is_empty(),
+        })
+    }
+
+    /// Aranet4 readings in the capture, in the order they were received
+    pub fn timeline(&self) -> Vec<TimelineEntry> {
+        let mut decoder = Decoder::default();
+        for record in &self.records {
+            decoder.record(record);
+        }
+        decoder.timeline
+    }
+}
+
+fn be_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes(bytes[..4].try_into().expect("four bytes"))
+}
+
+fn le_u16(bytes: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_le_bytes([*bytes.get(at)?, *bytes.get(at + 1)?]))
+}
+
+/// The record without its time
+fn decode_record(datalink: Datalink, flags: u32, data: &[u8]) -> Record {
+    let received = flags & 1 == 1;
+    let (adapter, direction, packet) = match datalink {
+        Datalink::Unencapsulated => {
+            let packet = match (flags & 2 == 2, received) {
+                (true, true) => HciPacket::Event(data.to_vec()),
+                (true, false) => HciPacket::Command(data.to_vec()),
+                (false, _) => HciPacket::Acl(data.to_vec()),
+            };
+            (0, received, packet)
+        }
+        Datalink::Uart => {
+            let body = data.get(1..).unwrap_or_default().to_vec();
+            let packet = match data.first() {
+                Some(1) => HciPacket::Command(body),
+                Some(2) => HciPacket::Acl(body),
+                Some(4) => HciPacket::Event(body),
+                _ => HciPacket::Other,
+            };
+            (0, received, packet)
+        }
+        Datalink::Monitor => {
+            let adapter = (flags >> 16) as u16;
+            let (received, packet) = match flags & 0xffff {
+                2 => (false, HciPacket::Command(data.to_vec())),
+                3 => (true, HciPacket::Event(data.to_vec())),
+                4 => (false, HciPacket::Acl(data.to_vec())),
+                5 => (true, HciPacket::Acl(data.to_vec())),
+                _ => (true, HciPacket::Other),
+            };
+            (adapter, received, packet)
+        }
+    };
+    Record {
+        time: DateTime::<Local>::default(),
+        adapter,
+        direction: if direction {
+            Direction::Received
+        } else {
+            Direction::Sent
+        },
+        packet,
+    }
+}
+
+/// Where readings in a capture came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum Source {
+    Advertisement,
+    /// A read of one of the current readings characteristics
+    GattRead,
+}
+
+/// Readings of one sensor at one point of a capture
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TimelineEntry {
+    /// Time the packet was captured
+    pub time: DateTime<Local>,
+    /// Address of the sensor, unless the capture started after it connected
+    pub address: Option<String>,
+    pub source: Source,
+    pub readings: SensorReadings,
+    /// Time since the readings were measured, if the packet tells
+    #[cfg_attr(
+        feature = "serde",
+        serde(
+            rename = "seconds_since_update",
+            with = "crate::serde_seconds::option_std_duration"
+        )
+    )]
+    pub since_update: Option<Duration>,
+}
+
+/// State of one LE connection
+#[derive(Debug, Default)]
+struct Connection {
+    address: Option<String>,
+    /// Characteristic UUID of each value handle
+    characteristics: HashMap<u16, Uuid>,
+    /// Handle of the read waiting for its response
+    pending_read: Option<u16>,
+    /// Attribute type of the read by type request waiting for its response
+    pending_type: Option<u16>,
+    /// Incomplete L2CAP frames sent and received
+    fragments: [Vec<u8>; 2],
+}
+
+#[derive(Default)]
+struct Decoder {
+    connections: HashMap<(u16, u16), Connection>,
+    timeline: Vec<TimelineEntry>,
+}
+
+impl Decoder {
+    fn record(&mut self, record: &Record) {
+        // malformed packets are skipped
+        let _ = match &record.packet {
+            HciPacket::Event(event) => self.event(record, event),
+            HciPacket::Acl(acl) => self.acl(record, acl),
+            HciPacket::Command(_) | HciPacket::Other => None,
+        };
+    }
+
+    fn event(&mut self, record: &Record, event: &[u8]) -> Option<()> {
+        let params = event.get(2..)?;
+        match *event.first()? {
+            EVENT_DISCONNECTION_COMPLETE => {
+                let handle = le_u16(params, 1)? & 0x0fff;
+                self.connections.remove(&(record.adapter, handle));
+            }
+            EVENT_LE_META => match *params.first()? {
+                LE_CONNECTION_COMPLETE | LE_ENHANCED_CONNECTION_COMPLETE
+                    if *params.get(1)? == 0 =>
+                {
+                    let handle = le_u16(params, 2)? & 0x0fff;
+                    let address = address(params.get(6..12)?);
+                    self.connections.insert(
+                        (record.adapter, handle),
+                        Connection {
+                            address: Some(address),
+                            ..Connection::default()
+                        },
+                    );
+                }
+                LE_ADVERTISING_REPORT => self.advertising_reports(record, &params[1..], false)?,
+                LE_EXTENDED_ADVERTISING_REPORT => {
+                    self.advertising_reports(record, &params[1..], true)?
+                }
+                _ => {}
+            },
+            _ => {}
+        }
+        Some(())
+    }
+
+    fn advertising_reports(
+        &mut self,
+        record: &Record,
+        reports: &[u8],
+        extended: bool,
+    ) -> Option<()> {
+        let count = *reports.first()?;
+        let mut rest = &reports[1..];
+        for _ in 0..count {
+            // event type, address type and address, then the fields before the data length
+            let (address_at, length_at) = if extended { (3, 23) } else { (2, 8) };
+            let address = address(rest.get(address_at..address_at + 6)?);
+            let length = *rest.get(length_at)? as usize;
+            let data = rest.get(length_at + 1..length_at + 1 + length)?;
+            if let Some(advertisement) = manufacturer_data(data).and_then(Advertisement::decode) {
+                self.timeline.push(TimelineEntry {
+                    time: record.time,
+                    address: Some(address),
+                    source: Source::Advertisement,
+                    readings: advertisement.readings,
+                    since_update: Some(advertisement.since_update),
+                });
+            }
+            // legacy reports end in the signal strength
+            let end = length_at + 1 + length + usize::from(!extended);
+            rest = rest.get(end..)?;
+        }
+        Some(())
+    }
+
+    /// Reassemble L2CAP frames from ACL fragments and pass on complete ATT ones
+    fn acl(&mut self, record: &Record, acl: &[u8]) -> Option<()> {
+        let header = le_u16(acl, 0)?;
+        let (handle, boundary) = (header & 0x0fff, header >> 12 & 0x3);
+        let data = acl.get(4..4 + usize::from(le_u16(acl, 2)?))?;
+        let connection = self
+            .connections
+            .entry((record.adapter, handle))
+            .or_default();
+        let fragments = &mut connection.fragments[record.direction as usize];
+        if boundary == 0b01 {
+            fragments.extend_from_slice(data);
+        } else {
+            *fragments = data.to_vec();
+        }
+        let length = usize::from(le_u16(fragments, 0)?);
+        if fragments.len() < 4 + length {
+            return Some(());
+        }
+        let frame = std::mem::take(fragments);
+        if le_u16(&frame, 2)? == ATT_CID {
+            let pdu = &frame[4..4 + length];
+            if let Some(entry) = Self::att(connection, record, pdu) {
+                self.timeline.push(entry);
+            }
+        }
+        Some(())
+    }
+
+    fn att(connection: &mut Connection, record: &Record, pdu: &[u8]) -> Option<TimelineEntry> {
+        match (record.direction, *pdu.first()?) {
+            (Direction::Sent, ATT_READ_REQUEST) => connection.pending_read = le_u16(pdu, 1),
+            (Direction::Sent, ATT_READ_BY_TYPE_REQUEST) => {
+                // a 16-bit attribute type follows the handle range
+                connection.pending_type = le_u16(pdu, 5).filter(|_| pdu.len() == 7)
+            }
+            (Direction::Received, ATT_ERROR_RESPONSE) => {
+                connection.pending_read = None;
+                connection.pending_type = None;
+            }
+            (Direction::Received, ATT_READ_BY_TYPE_RESPONSE)
+                if connection.pending_type.take() == Some(CHARACTERISTIC_DECLARATION) =>
+            {
+                characteristic_declarations(connection, &pdu[1..]);
+            }
+            (Direction::Received, ATT_READ_RESPONSE) => {
+                let handle = connection.pending_read.take()?;
+                let uuid = *connection.characteristics.get(&handle)?;
+                return read_response(connection, record, uuid, &pdu[1..]);
+            }
+            _ => {}
+        }
+        None
+    }
+}
+
+/// Record the value handles and UUIDs of discovered characteristics
+fn characteristic_declarations(connection: &mut Connection, response: &[u8]) {
+    let Some((&length, entries)) = response.split_first() else {
+        return;
+    };
+    // each entry: declaration handle, properties, value handle and a 16 or 128-bit UUID
+    for entry in entries.chunks_exact(usize::from(length).max(1)) {
+        let uuid = match entry.len() {
+            7 => le_u16(entry, 5).map(|short| Uuid::from_u128(BASE_UUID | u128::from(short) << 96)),
+            21 => entry[5..]
+                .try_into()
+                .ok()
+                .map(|bytes: [u8; 16]| Uuid::from_u128(u128::from_le_bytes(bytes))),
+            _ => None,
+        };
+        if let (Some(handle), Some(uuid)) = (le_u16(entry, 3), uuid) {
+            connection.characteristics.insert(handle, uuid);
+        }
+    }
+}
+
+fn read_response(
+    connection: &Connection,
+    record: &Record,
+    uuid: Uuid,
+    value: &[u8],
+) -> Option<TimelineEntry> {
+    let (readings, since_update) = match uuid {
+        AranetService::READ_CURRENT_READINGS => (Readings::decode(value).ok()?, None),
+        AranetService::READ_CURRENT_READINGS_DETAILED => {
+            let detailed = DetailedReadings::decode(value).ok()?;
+            let since_update = Duration::from_secs(detailed.since_update.into());
+            (detailed.readings, Some(since_update))
+        }
+        _ => return None,
+    };
+    Some(TimelineEntry {
+        time: record.time,
+        address: connection.address.clone(),
+        source: Source::GattRead,
+        readings: readings.into(),
+        since_update,
+    })
+}
+
+/// Manufacturer data of the Aranet manufacturer in advertising data, without its identifier
+fn manufacturer_data(mut data: &[u8]) -> Option<&[u8]> {
+    while let Some((&length, rest)) = data.split_first() {
+        let structure = rest.get(..usize::from(length))?;
+        if let [AD_MANUFACTURER_DATA, low, high, value @ ..] = structure {
+            if u16::from_le_bytes([*low, *high]) == MANUFACTURER_ID {
+                return Some(value);
+            }
+        }
+        data = &rest[usize::from(length)..];
+    }
+    None
+}
+
+/// Format a little-endian Bluetooth address
+fn address(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .rev()
+        .map(|byte| format!("{:02X}", byte))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sensor::transport::testing::READINGS;
+    use chrono::TimeZone;
+
+    const ADDRESS: [u8; 6] = [0x33, 0x22, 0x11, 0x0e, 0x6e, 0xd4];
+
+    fn start() -> DateTime<Local> {
+        Local.with_ymd_and_hms(2024, 3, 1, 12, 0, 0).unwrap()
+    }
+
+    /// A btsnoop file with one record for each `(seconds, flags, data)`
+    fn capture(datalink: u32, records: &[(i64, u32, Vec<u8>)]) -> Vec<u8> {
+        let mut file = MAGIC.to_vec();
+        file.extend_from_slice(&VERSION.to_be_bytes());
+        file.extend_from_slice(&datalink.to_be_bytes());
+        for (seconds, flags, data) in records {
+            let micros = (start().timestamp() + seconds) * 1_000_000 + EPOCH_OFFSET;
+            for field in [data.len() as u32, data.len() as u32, *flags, 0] {
+                file.extend_from_slice(&field.to_be_bytes());
+            }
+            file.extend_from_slice(&micros.to_be_bytes());
+            file.extend_from_slice(data);
+        }
+        file
+    }
+
+    fn event(code: u8, params: &[u8]) -> Vec<u8> {
+        [&[4, code, params.len() as u8][..], params].concat()
+    }
+
+    fn advertising_report(manufacturer: &[u8]) -> Vec<u8> {
+        let mut data = vec![
+            2,
+            0x01,
+            0x06,
+            manufacturer.len() as u8 + 3,
+            0xff,
+            0x02,
+            0x07,
+        ];
+        data.extend_from_slice(manufacturer);
+        let mut params = vec![LE_ADVERTISING_REPORT, 1, 0x00, 0x00];
+        params.extend_from_slice(&ADDRESS);
+        params.push(data.len() as u8);
+        params.extend_from_slice(&data);
+        params.push(0xc4);
+        event(EVENT_LE_META, &params)
+    }
+
+    /// ACL packets carrying an ATT PDU on connection 0x40, split after `split` bytes
+    fn att(pdu: &[u8], split: usize) -> Vec<Vec<u8>> {
+        let frame = [
+            &(pdu.len() as u16).to_le_bytes()[..],
+            &ATT_CID.to_le_bytes(),
+            pdu,
+        ]
+        .concat();
+        let (first, second) = frame.split_at(split.min(frame.len()));
+        let mut packets = vec![];
+        for (boundary, part) in [(0x2040u16, first), (0x1040, second)] {
+            if !part.is_empty() {
+                packets.push(
+                    [
+                        &[2][..],
+                        &boundary.to_le_bytes(),
+                        &(part.len() as u16).to_le_bytes(),
+                        part,
+                    ]
+                    .concat(),
+                );
+            }
+        }
+        packets
+    }
+
+    #[test]
+    fn uart_capture_timeline() {
+        let mut advertisement = vec![0x22, 0x13, 0x04, 0x01, 0x00, 0x0c, 0x0f, 0x01];
+        advertisement.extend_from_slice(&READINGS);
+        advertisement.extend_from_slice(&[0x2c, 0x01, 0x3c, 0x00, 0x07, 0x00]);
+        let mut connection = vec![LE_CONNECTION_COMPLETE, 0, 0x40, 0x00, 0, 0];
+        connection.extend_from_slice(&ADDRESS);
+
+        let mut declarations = vec![ATT_READ_BY_TYPE_RESPONSE, 21, 0x0f, 0x00, 0x02, 0x10, 0x00];
+        declarations
+            .extend_from_slice(&AranetService::READ_CURRENT_READINGS.as_u128().to_le_bytes());
+        let mut records = vec![
+            (0, 1, advertising_report(&advertisement)),
+            // integration off, no readings
+            (1, 1, advertising_report(&advertisement[..7])),
+            (2, 1, event(EVENT_LE_META, &connection)),
+        ];
+        for (seconds, flags, pdu) in [
+            (
+                3,
+                0,
+                vec![ATT_READ_BY_TYPE_REQUEST, 1, 0, 0xff, 0xff, 0x03, 0x28],
+            ),
+            (3, 1, declarations),
+            (4, 0, vec![ATT_READ_REQUEST, 0x10, 0x00]),
+            (4, 1, [&[ATT_READ_RESPONSE][..], &READINGS].concat()),
+            // a read of another characteristic is not decoded
+            (5, 0, vec![ATT_READ_REQUEST, 0x20, 0x00]),
+            (5, 1, [&[ATT_READ_RESPONSE][..], &READINGS].concat()),
+        ] {
+            for packet in att(&pdu, 6) {
+                records.push((seconds, flags, packet));
+            }
+        }
+        let mut file = capture(1002, &records);
+        // cut off in the middle of a record
+        file.extend_from_slice(&[0; 30]);
+
+        let capture = Capture::parse(&file).unwrap();
+        assert_eq!(capture.datalink, Datalink::Uart);
+        assert!(capture.truncated);
+        assert_eq!(capture.records[0].direction, Direction::Received);
+        let timeline = capture.timeline();
+        assert_eq!(timeline.len(), 2);
+        assert_eq!(timeline[0].time, start());
+        assert_eq!(timeline[0].source, Source::Advertisement);
+        assert_eq!(timeline[0].address.as_deref(), Some("D4:6E:0E:11:22:33"));
+        assert_eq!(timeline[0].since_update, Some(Duration::from_secs(60)));
+        assert_eq!(timeline[1].time, start() + chrono::Duration::seconds(4));
+        assert_eq!(timeline[1].source, Source::GattRead);
+        assert_eq!(timeline[1].address.as_deref(), Some("D4:6E:0E:11:22:33"));
+        assert_eq!(timeline[1].readings.co2_level, 464);
+        assert_eq!(timeline[1].since_update, None);
+    }
+
+    #[test]
+    fn monitor_capture() {
+        let mut declarations = vec![ATT_READ_BY_TYPE_RESPONSE, 21, 0x0f, 0x00, 0x02, 0x10, 0x00];
+        declarations.extend_from_slice(
+            &AranetService::READ_CURRENT_READINGS_DETAILED
+                .as_u128()
+                .to_le_bytes(),
+        );
+        let mut detailed = vec![ATT_READ_RESPONSE];
+        detailed.extend_from_slice(&READINGS);
+        detailed.extend_from_slice(&[0x2c, 0x01, 0x10, 0x00]);
+        let mut records = vec![];
+        // ACL sent on adapter 1 is opcode 4, received opcode 5, without UART packet types
+        for (opcode, pdu) in [
+            (
+                4,
+                vec![ATT_READ_BY_TYPE_REQUEST, 1, 0, 0xff, 0xff, 0x03, 0x28],
+            ),
+            (5, declarations),
+            (4, vec![ATT_READ_REQUEST, 0x10, 0x00]),
+            (5, detailed),
+        ] {
+            for packet in att(&pdu, usize::MAX) {
+                records.push((0, 1 << 16 | opcode, packet[1..].to_vec()));
+            }
+        }
+        let capture = Capture::parse(&capture(2001, &records)).unwrap();
+        assert!(!capture.truncated);
+        assert!(capture.records.iter().all(|r| r.adapter == 1));
+        let timeline = capture.timeline();
+        assert_eq!(timeline.len(), 1);
+        assert_eq!(timeline[0].address, None);
+        assert_eq!(timeline[0].since_update, Some(Duration::from_secs(16)));
+    }
+
+    #[test]
+    fn invalid_files() {
+        assert!(matches!(
+            Capture::parse(b"not a capture at all"),
+            Err(CaptureError::NotBtsnoop)
+        ));
+        assert!(matches!(
+            Capture::parse(&capture(1003, &[])),
+            Err(CaptureError::UnsupportedDatalink(1003))
+        ));
+        let mut corrupt = capture(1002, &[(0, 0, vec![]), (1, 0, vec![])]);
+        let second = FILE_HEADER_SIZE + 2 * RECORD_HEADER_SIZE - 8;
+        corrupt[second..second + 8].copy_from_slice(&i64::MIN.to_be_bytes());
+        assert!(matches!(
+            Capture::parse(&corrupt),
+            Err(CaptureError::InvalidTime(1))
+        ));
+    }
+}
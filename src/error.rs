@@ -1,5 +1,10 @@
 //! Error type for sensor readings
+use crate::{history::readings::HistoryReadings, sensor::LogParameter};
+use std::fmt;
+use std::time::Duration;
 use thiserror::Error;
+use uuid::Uuid;
+
 #[derive(Debug, Error)]
 pub enum SensorError {
     #[error("Bluetooth Error")]
@@ -10,12 +15,179 @@ pub enum SensorError {
     CannotFindAddress(String),
     #[error("Unable to find sensor by name")]
     CannotFindAddressByName,
-    #[error("Cannot find characteristics")]
-    CannotFindCharacteristics,
-    #[error("Packet Deserialization Error")]
-    ByteReadError(#[from] std::io::Error),
+    #[error("Cannot find characteristic {uuid} needed to {operation}")]
+    CannotFindCharacteristic { uuid: Uuid, operation: Operation },
+    #[error("Cannot parse `{field}` from {uuid} while trying to {operation}, packet: [{packet}]")]
+    MalformedPacket {
+        uuid: Uuid,
+        operation: Operation,
+        field: &'static str,
+        packet: Packet,
+        #[source]
+        source: std::io::Error,
+    },
     #[error("Cannot parse bluetooth address: {}", .0)]
     BluetoothAddressParseError(#[from] btleplug::api::ParseBDAddrError),
-    #[error("Protocol Error")]
-    ProtocolError,
+    #[error("History packet for {parameter:?} is too short: expected at least {expected} bytes, got {actual}, packet: [{packet}]")]
+    TruncatedHistoryPacket {
+        uuid: Uuid,
+        parameter: LogParameter,
+        expected: usize,
+        actual: usize,
+        packet: Packet,
+    },
+    #[error("Requested {expected:?} history but sensor sent {actual:?}, packet: [{packet}]")]
+    UnexpectedHistoryParameter {
+        expected: LogParameter,
+        actual: LogParameter,
+        packet: Packet,
+    },
+    #[error("Sensor returned no {parameter:?} history from {uuid}")]
+    MissingHistoryHeader { uuid: Uuid, parameter: LogParameter },
+    #[error("Timed out after {after:?} trying to {operation}")]
+    Timeout {
+        operation: Operation,
+        after: Duration,
+    },
+    #[error("Operation was cancelled")]
+    Cancelled,
+    #[error("History download interrupted: {cause}")]
+    HistoryInterrupted {
+        #[source]
+        cause: Box<SensorError>,
+        /// The history received before the interruption
+        partial: Box<HistoryReadings>,
+    },
+    #[error("Replay expected {expected} but the sensor tried to {actual}")]
+    ReplayMismatch { expected: String, actual: String },
+}
+
+impl SensorError {
+    /// Whether retrying the operation that produced this error may succeed.
+    ///
+    /// Dropped connections, sensors that are temporarily out of range and history packets cut
+    /// short in transit are transient. A missing adapter, an unparseable address, a device that
+    /// lacks a required characteristic or a packet the firmware always sends malformed will fail
+    /// the same way again, as may any Bluetooth error btleplug does not classify.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            SensorError::BluetoothError(e) => matches!(
+                e,
+                btleplug::Error::DeviceNotFound
+                    | btleplug::Error::NotConnected
+                    | btleplug::Error::TimedOut(_)
+            ),
+            SensorError::CannotFindAddress(_)
+            | SensorError::CannotFindAddressByName
+            | SensorError::TruncatedHistoryPacket { .. }
+            | SensorError::MissingHistoryHeader { .. }
+            | SensorError::Timeout { .. } => true,
+            SensorError::HistoryInterrupted { cause, .. } => cause.is_transient(),
+            SensorError::CreationError
+            | SensorError::Cancelled
+            | SensorError::CannotFindCharacteristic { .. }
+            | SensorError::MalformedPacket { .. }
+            | SensorError::UnexpectedHistoryParameter { .. }
+            | SensorError::BluetoothAddressParseError(_)
+            | SensorError::ReplayMismatch { .. } => false,
+        }
+    }
+}
+
+/// The sensor operation during which an error occurred
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    Connect,
+    DiscoverServices,
+    ReadCurrentValues,
+    ReadLastUpdateTime,
+    ReadDeviceInfo,
+    ReadMeasurementInterval,
+    ReadTotalReadings,
+    WriteSetting,
+    RequestHistory(LogParameter),
+    ReadHistory(LogParameter),
+    DownloadHistory,
+}
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Operation::Connect => write!(f, "connect"),
+            Operation::DiscoverServices => write!(f, "discover services"),
+            Operation::ReadCurrentValues => write!(f, "read current values"),
+            Operation::ReadLastUpdateTime => write!(f, "read last update time"),
+            Operation::ReadDeviceInfo => write!(f, "read device information"),
+            Operation::ReadMeasurementInterval => write!(f, "read measurement interval"),
+            Operation::ReadTotalReadings => write!(f, "read total readings"),
+            Operation::WriteSetting => write!(f, "change a setting"),
+            Operation::RequestHistory(p) => write!(f, "request {:?} history", p),
+            Operation::ReadHistory(p) => write!(f, "read {:?} history", p),
+            Operation::DownloadHistory => write!(f, "download history"),
+        }
+    }
+}
+
+/// Raw bytes received from the sensor, displayed as a hex dump
+#[derive(Clone, PartialEq, Eq)]
+pub struct Packet(pub Vec<u8>);
+impl fmt::Display for Packet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, byte) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, " ")?;
+            }
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+impl fmt::Debug for Packet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Packet[{}]", self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sensor::protocol::AranetService;
+
+    #[test]
+    fn packet_hex_dump() {
+        let packet = Packet(vec![0x01, 0xab, 0x00, 0xff]);
+        assert_eq!(packet.to_string(), "01 ab 00 ff");
+        assert_eq!(format!("{:?}", packet), "Packet[01 ab 00 ff]");
+        assert_eq!(Packet(vec![]).to_string(), "");
+    }
+
+    #[test]
+    fn transient_classification() {
+        let truncated = SensorError::TruncatedHistoryPacket {
+            uuid: AranetService::READ_HISTORY_READINGS,
+            parameter: LogParameter::Co2,
+            expected: 10,
+            actual: 3,
+            packet: Packet(vec![4, 1, 0]),
+        };
+        assert!(truncated.is_transient());
+        assert!(SensorError::BluetoothError(btleplug::Error::NotConnected).is_transient());
+        assert!(!SensorError::BluetoothError(btleplug::Error::PermissionDenied).is_transient());
+        let other = btleplug::Error::Other("org.bluez.Error.NotPermitted".into());
+        assert!(!SensorError::BluetoothError(other).is_transient());
+        let unexpected = SensorError::UnexpectedHistoryParameter {
+            expected: LogParameter::Co2,
+            actual: LogParameter::Humidity,
+            packet: Packet(vec![2]),
+        };
+        assert!(!unexpected.is_transient());
+        let missing = SensorError::CannotFindCharacteristic {
+            uuid: AranetService::WRITE_CMD,
+            operation: Operation::RequestHistory(LogParameter::Co2),
+        };
+        assert!(!missing.is_transient());
+        assert_eq!(
+            missing.to_string(),
+            "Cannot find characteristic f0cd1402-95da-4f4b-9ac8-aa55d312af0c needed to request Co2 history"
+        );
+    }
 }
@@ -0,0 +1,262 @@
+//! Polling the current readings of several sensors
+use crate::{
+    battery::{BatteryForecast, BatteryTracker, PowerSettings},
+    readings::SensorReadings,
+    sensor::{settings::MeasurementInterval, Sensor},
+};
+use chrono::{DateTime, Local};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio_util::sync::CancellationToken;
+
+/// Shortest time between polls accepted by [`Fleet::run`]
+const MIN_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Latest known state of one sensor in a [`Fleet`]
+#[derive(Debug, Clone, Default)]
+pub struct SensorStatus {
+    /// Bluetooth address of the sensor, empty if unknown
+    pub address: String,
+    /// Name the sensor was added to the fleet with
+    pub name: String,
+    /// Serial number, read after the first successful poll
+    pub serial: Option<String>,
+    /// Readings from the last successful poll
+    pub readings: Option<SensorReadings>,
+    /// Time of the last successful poll
+    pub last_success: Option<DateTime<Local>>,
+    /// Whether the most recent poll succeeded
+    pub up: bool,
+    /// Number of failed polls
+    pub errors: u64,
+    /// Description of the most recent failure
+    pub last_error: Option<String>,
+    /// When the batteries are expected to run out, from the levels read so far
+    pub battery: Option<BatteryForecast>,
+}
+
+/// Shared view of the state of every sensor in a [`Fleet`]
+#[derive(Debug, Clone, Default)]
+pub struct FleetStatus(Arc<RwLock<Vec<SensorStatus>>>);
+
+impl FleetStatus {
+    /// Copy of the current state of every sensor, in the order they were added
+    pub fn snapshot(&self) -> Vec<SensorStatus> {
+        self.0.read().expect("fleet status poisoned").clone()
+    }
+    fn update(&self, index: usize, f: impl FnOnce(&mut SensorStatus)) -> SensorStatus {
+        let mut statuses = self.0.write().expect("fleet status poisoned");
+        f(&mut statuses[index]);
+        statuses[index].clone()
+    }
+}
+
+/// A set of sensors polled together
+pub struct Fleet {
+    sensors: Vec<Sensor>,
+    status: FleetStatus,
+    batteries: Mutex<Vec<BatteryTracker>>,
+    updates: broadcast::Sender<SensorStatus>,
+}
+
+impl Default for Fleet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Fleet {
+    pub fn new() -> Self {
+        let (updates, _) = broadcast::channel(64);
+        Self {
+            sensors: vec![],
+            status: FleetStatus::default(),
+            batteries: Mutex::new(vec![]),
+            updates,
+        }
+    }
+    /// Add a sensor, labelled with `name`
+    pub fn add(&mut self, name: impl Into<String>, sensor: Sensor) {
+        self.status
+            .0
+            .write()
+            .expect("fleet status poisoned")
+            .push(SensorStatus {
+                address: sensor.address().unwrap_or_default(),
+                name: name.into(),
+                ..SensorStatus::default()
+            });
+        self.sensors.push(sensor);
+        self.batteries
+            .lock()
+            .expect("battery trackers poisoned")
+            .push(BatteryTracker::default());
+    }
+    /// Settings of the sensor at `index`, in the order added, used for its battery forecast.
+    /// The measurement interval is also read from the sensor after its first successful poll;
+    /// Smart Home integration cannot be read back, so it stays as set here.
+    pub fn set_power_settings(&self, index: usize, settings: PowerSettings) {
+        if let Some(tracker) = self
+            .batteries
+            .lock()
+            .expect("battery trackers poisoned")
+            .get_mut(index)
+        {
+            tracker.set_settings(settings);
+        }
+    }
+    pub fn sensors(&self) -> &[Sensor] {
+        &self.sensors
+    }
+    /// Shared view of the fleet, updated after every poll
+    pub fn status(&self) -> FleetStatus {
+        self.status.clone()
+    }
+    /// Receive the new state of a sensor after each of its polls
+    pub fn subscribe(&self) -> broadcast::Receiver<SensorStatus> {
+        self.updates.subscribe()
+    }
+    /// Read every sensor once
+    pub async fn poll(&self) {
+        for (index, sensor) in self.sensors.iter().enumerate() {
+            let result = sensor.read_current_values().await;
+            let first_success = result.is_ok()
+                && self.status.0.read().expect("fleet status poisoned")[index]
+                    .last_success
+                    .is_none();
+            // a missing serial number only leaves the sensor unlabelled, and a missing
+            // interval only leaves the battery forecast on the default one
+            let (serial, interval) = if first_success {
+                let interval = sensor.measurement_interval().await.ok();
+                (
+                    sensor.serial_number().await.ok().flatten(),
+                    interval.and_then(|i| MeasurementInterval::from_minutes(i.as_secs() / 60)),
+                )
+            } else {
+                (None, None)
+            };
+            let status = self.status.update(index, |status| match result {
+                Ok(readings) => {
+                    if first_success {
+                        status.serial = serial;
+                    }
+                    let now = Local::now();
+                    let mut batteries = self.batteries.lock().expect("battery trackers poisoned");
+                    if let Some(interval) = interval {
+                        let smart_home = batteries[index].settings().smart_home;
+                        batteries[index].set_settings(PowerSettings {
+                            interval,
+                            smart_home,
+                        });
+                    }
+                    batteries[index].record(now, readings.battery);
+                    status.battery = batteries[index].forecast();
+                    status.readings = Some(readings);
+                    status.last_success = Some(now);
+                    status.up = true;
+                }
+                Err(e) => {
+                    status.up = false;
+                    status.errors += 1;
+                    status.last_error = Some(e.to_string());
+                }
+            });
+            // nobody listening is fine
+            let _ = self.updates.send(status);
+        }
+    }
+    /// Poll every `interval`, but at most once a second, until `cancel` is cancelled
+    pub async fn run(&self, interval: Duration, cancel: CancellationToken) {
+        let mut ticks = tokio::time::interval(interval.max(MIN_POLL_INTERVAL));
+        loop {
+            tokio::select! {
+                _ = cancel.cancelled() => break,
+                _ = ticks.tick() => self.poll().await,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sensor::{
+        protocol::{AranetService, CommonService},
+        transport::testing::{FakeTransport, READINGS},
+    };
+
+    #[tokio::test]
+    async fn poll_updates_status() {
+        let mut fleet = Fleet::new();
+        fleet.add(
+            "office",
+            Sensor::from_transport(
+                FakeTransport::default()
+                    .with_value(AranetService::READ_CURRENT_READINGS, READINGS.to_vec())
+                    .with_value(CommonService::READ_SERIAL_NO, b"12345".to_vec()),
+            ),
+        );
+        fleet.add("broken", Sensor::from_transport(FakeTransport::default()));
+        let mut updates = fleet.subscribe();
+
+        fleet.poll().await;
+        fleet.poll().await;
+
+        let status = fleet.status().snapshot();
+        assert_eq!(status[0].name, "office");
+        assert!(status[0].up);
+        assert_eq!(status[0].readings.as_ref().map(|r| r.co2_level), Some(464));
+        assert!(status[0].last_success.is_some());
+        assert_eq!(status[0].battery.map(|b| b.percent), Some(90));
+        assert_eq!(status[0].serial.as_deref(), Some("12345"));
+        assert!(status[1].serial.is_none());
+        assert!(status[1].battery.is_none());
+        assert!(!status[1].up);
+        assert_eq!(status[1].errors, 2);
+        assert!(status[1].last_error.is_some());
+
+        assert_eq!(updates.recv().await.unwrap().name, "office");
+        assert_eq!(updates.recv().await.unwrap().name, "broken");
+    }
+
+    #[tokio::test]
+    async fn battery_forecast_uses_measurement_interval() {
+        let sensor = |interval: u16| {
+            Sensor::from_transport(
+                FakeTransport::default()
+                    .with_value(AranetService::READ_CURRENT_READINGS, READINGS.to_vec())
+                    .with_value(
+                        AranetService::READ_INTERVAL,
+                        interval.to_le_bytes().to_vec(),
+                    ),
+            )
+        };
+        let mut fleet = Fleet::new();
+        fleet.add("busy", sensor(60));
+        fleet.add("calm", sensor(600));
+
+        fleet.poll().await;
+
+        let status = fleet.status().snapshot();
+        let days = |i: usize| status[i].battery.unwrap().days_remaining;
+        assert!(days(0) < days(1));
+        assert_eq!(
+            fleet.batteries.lock().unwrap()[0].settings().interval,
+            MeasurementInterval::OneMinute
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn zero_interval_is_clamped() {
+        let mut fleet = Fleet::new();
+        fleet.add("broken", Sensor::from_transport(FakeTransport::default()));
+        let cancel = CancellationToken::new();
+        let stop = cancel.clone();
+        tokio::join!(fleet.run(Duration::ZERO, cancel), async {
+            tokio::time::sleep(Duration::from_millis(2500)).await;
+            stop.cancel();
+        });
+        assert_eq!(fleet.status().snapshot()[0].errors, 3);
+    }
+}
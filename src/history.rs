@@ -1,241 +1,502 @@
 use self::{
     header::{HistoryHeader, HISTORY_HEADER_SIZE},
-    readings::{HistoryInformation, HistoryReadings, HistoryRequest},
+    readings::HistoryReadings,
 };
 use crate::{
-    error::SensorError,
+    error::{Operation, Packet, SensorError},
     sensor::{
-        protocol::{convert_pressure, convert_temperature, AranetService, LogParameter},
-        Sensor,
+        protocol::{convert_pressure, convert_temperature, malformed, AranetService, LogParameter},
+        with_timeout, Sensor,
     },
 };
-use btleplug::api::{Characteristic, Peripheral, WriteType};
-mod header;
+use aranet4_core::history::{samples, HistoryPacket, HistoryRequest};
+use btleplug::api::WriteType;
+use tokio_util::sync::CancellationToken;
+pub mod export;
+pub mod exposure;
+pub(crate) mod header;
+pub mod occupancy;
 pub mod readings;
 pub mod record;
+pub mod stats;
+pub mod store;
+pub mod sync;
+pub mod ventilation;
 
-impl Sensor {
-    async fn get_temperature_history(
-        &self,
-        write_cmd: &Characteristic,
-        reading_cmd: &Characteristic,
-    ) -> Result<(HistoryInformation, Vec<f32>), SensorError> {
-        let history_request = HistoryRequest {
-            parameter: LogParameter::Temperature,
-            first_index: 1,
+/// Parameters in the order they are downloaded
+const DOWNLOAD_ORDER: [LogParameter; 4] = [
+    LogParameter::Temperature,
+    LogParameter::Humidity,
+    LogParameter::Co2,
+    LogParameter::Pressure,
+];
+
+/// Progress of a history download, reported after every chunk received
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct HistoryProgress {
+    /// Parameter currently being downloaded
+    pub parameter: LogParameter,
+    /// Number of parameters fully downloaded so far
+    pub parameters_done: usize,
+    /// Number of parameters in the download
+    pub parameters_total: usize,
+    /// Chunks received for the current parameter
+    pub chunks: usize,
+    /// Measurements of the current parameter received so far
+    pub received: usize,
+    /// Measurements stored on the device, as reported in each history header
+    pub total_measurements: usize,
+}
+
+impl HistoryProgress {
+    /// Overall completion of the download, from 0.0 to 1.0
+    pub fn fraction(&self) -> f32 {
+        let parameter = if self.total_measurements == 0 {
+            0.0
+        } else {
+            (self.received as f32 / self.total_measurements as f32).min(1.0)
         };
-        self.aranet
-            .write(
-                write_cmd,
-                &history_request.encode(),
-                WriteType::WithoutResponse,
-            )
-            .await?;
-        let mut ret = vec![];
-        let mut header_data: Option<HistoryHeader> = None;
-        while let Ok(bytes) = self.aranet.read(reading_cmd).await {
-            if bytes.len() < HISTORY_HEADER_SIZE {
-                return Err(SensorError::ProtocolError);
-            }
-            if let Some(header) = HistoryHeader::decode(&bytes[..HISTORY_HEADER_SIZE]) {
-                // have we reached the end of the data stream?
-                if header.num_measurements == 0 {
-                    break;
-                }
+        (self.parameters_done as f32 + parameter) / self.parameters_total as f32
+    }
+}
 
-                header_data.get_or_insert(header);
+/// A download of the sensor's history, created with [`Sensor::history`]
+pub struct HistoryDownload<'a> {
+    sensor: &'a Sensor,
+    first_index: u16,
+    cancel: Option<CancellationToken>,
+    progress: Option<Box<dyn FnMut(HistoryProgress) + Send + 'a>>,
+}
 
-                let end = std::mem::size_of::<u16>() * header.num_measurements as usize;
-                let end = std::cmp::min(end, bytes.len());
+/// History bytes received so far, kept so an interrupted download can still be returned
+#[derive(Default)]
+struct RawHistory {
+    header: Option<HistoryHeader>,
+    temperature: Vec<u8>,
+    humidity: Vec<u8>,
+    co2: Vec<u8>,
+    pressure: Vec<u8>,
+}
 
-                let vals: Vec<f32> = bytes[HISTORY_HEADER_SIZE..end]
-                    .chunks_exact(2)
-                    .into_iter()
-                    .map(|x| u16::from_le_bytes([x[0], x[1]]))
-                    .map(convert_temperature)
-                    .collect();
-                ret.extend(vals);
-            } else {
-                break;
-            }
+impl RawHistory {
+    fn samples(&mut self, parameter: LogParameter) -> &mut Vec<u8> {
+        match parameter {
+            LogParameter::Temperature => &mut self.temperature,
+            LogParameter::Humidity => &mut self.humidity,
+            LogParameter::Co2 => &mut self.co2,
+            LogParameter::Pressure => &mut self.pressure,
         }
-        let header = header_data.ok_or(SensorError::ProtocolError)?;
-        Ok((header.into(), ret))
-    }
-    async fn get_pressure_history(
-        &self,
-        write_cmd: &Characteristic,
-        reading_cmd: &Characteristic,
-    ) -> Result<Vec<f32>, SensorError> {
-        let history_request = HistoryRequest {
-            parameter: LogParameter::Pressure,
-            first_index: 1,
+    }
+    /// Convert to readings, aligned to the temperature history
+    fn into_readings(self) -> Option<HistoryReadings> {
+        let header = self.header?;
+        let temperature: Vec<f32> = samples(LogParameter::Temperature, &self.temperature)
+            .map(convert_temperature)
+            .collect();
+        let mut humidity = self.humidity;
+        humidity.truncate(temperature.len());
+        let mut co2: Vec<u16> = samples(LogParameter::Co2, &self.co2).collect();
+        co2.truncate(temperature.len());
+        let mut pressure: Vec<f32> = samples(LogParameter::Pressure, &self.pressure)
+            .map(convert_pressure)
+            .collect();
+        pressure.truncate(temperature.len());
+        Some(HistoryReadings {
+            information: header.into(),
+            temperature,
+            humidity,
+            co2,
+            pressure,
+        })
+    }
+}
+
+impl<'a> HistoryDownload<'a> {
+    /// Download only the measurements from `index` on, where 1 is the oldest measurement in
+    /// the device log and [`Sensor::total_readings`] the newest
+    pub fn starting_at(mut self, index: u16) -> Self {
+        self.first_index = index.max(1);
+        self
+    }
+
+    /// Stop the download when `token` is cancelled.
+    ///
+    /// A cancelled download returns [`SensorError::HistoryInterrupted`] holding the history
+    /// received up to that point, or [`SensorError::Cancelled`] if nothing was received yet.
+    pub fn cancel_on(mut self, token: CancellationToken) -> Self {
+        self.cancel = Some(token);
+        self
+    }
+
+    /// Call `callback` with the download progress after every chunk received
+    pub fn on_progress(mut self, callback: impl FnMut(HistoryProgress) + Send + 'a) -> Self {
+        self.progress = Some(Box::new(callback));
+        self
+    }
+
+    /// Run the download, bounded by the sensor's history timeout
+    pub async fn run(mut self) -> Result<HistoryReadings, SensorError> {
+        let mut raw = RawHistory::default();
+        let cancel = self.cancel.take();
+        let download = with_timeout(
+            self.sensor.timeouts.history,
+            Operation::DownloadHistory,
+            self.collect(&mut raw),
+        );
+        let result = match &cancel {
+            Some(token) => tokio::select! {
+                biased;
+                _ = token.cancelled() => Err(SensorError::Cancelled),
+                result = download => result,
+            },
+            None => download.await,
         };
-        self.aranet
-            .write(
-                write_cmd,
-                &history_request.encode(),
-                WriteType::WithoutResponse,
-            )
-            .await?;
-        let mut ret = vec![];
-        while let Ok(bytes) = self.aranet.read(reading_cmd).await {
-            if bytes.len() < HISTORY_HEADER_SIZE {
-                break;
-            }
-            if let Some(header) = HistoryHeader::decode(&bytes[..HISTORY_HEADER_SIZE]) {
-                // have we reached the end of the data stream?
-                if header.num_measurements == 0 {
-                    break;
+        match result {
+            Ok(()) => raw
+                .into_readings()
+                .ok_or(SensorError::MissingHistoryHeader {
+                    uuid: AranetService::READ_HISTORY_READINGS,
+                    parameter: LogParameter::Temperature,
+                }),
+            Err(cause @ (SensorError::Timeout { .. } | SensorError::Cancelled)) => {
+                match raw.into_readings() {
+                    Some(partial) => Err(SensorError::HistoryInterrupted {
+                        cause: Box::new(cause),
+                        partial: Box::new(partial),
+                    }),
+                    None => Err(cause),
                 }
-                let end = std::mem::size_of::<u16>() * header.num_measurements as usize;
-                let end = std::cmp::min(end, bytes.len());
-
-                let vals: Vec<f32> = bytes[HISTORY_HEADER_SIZE..end]
-                    .chunks_exact(2)
-                    .into_iter()
-                    .map(|x| u16::from_le_bytes([x[0], x[1]]))
-                    .map(convert_pressure)
-                    .collect();
-                ret.extend(vals);
-            } else {
-                break;
             }
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn collect(&mut self, raw: &mut RawHistory) -> Result<(), SensorError> {
+        for (done, parameter) in DOWNLOAD_ORDER.into_iter().enumerate() {
+            self.get_parameter_history(parameter, done, raw).await?;
         }
-        Ok(ret)
+        Ok(())
     }
-    async fn get_humidity_history(
-        &self,
-        write_cmd: &Characteristic,
-        reading_cmd: &Characteristic,
-    ) -> Result<Vec<u8>, SensorError> {
+
+    /// Request the history of a single parameter and collect the raw sample bytes
+    async fn get_parameter_history(
+        &mut self,
+        parameter: LogParameter,
+        parameters_done: usize,
+        raw: &mut RawHistory,
+    ) -> Result<(), SensorError> {
         let history_request = HistoryRequest {
-            parameter: LogParameter::Humidity,
-            first_index: 1,
+            parameter,
+            first_index: self.first_index,
         };
-        self.aranet
+        self.sensor
             .write(
-                write_cmd,
+                AranetService::WRITE_CMD,
                 &history_request.encode(),
                 WriteType::WithoutResponse,
+                Operation::RequestHistory(parameter),
             )
             .await?;
-        let mut ret = vec![];
-        while let Ok(bytes) = self.aranet.read(reading_cmd).await {
+        let mut chunks = 0;
+        let mut received = 0;
+        loop {
+            let bytes = self
+                .sensor
+                .read(
+                    AranetService::READ_HISTORY_READINGS,
+                    Operation::ReadHistory(parameter),
+                )
+                .await?;
             if bytes.len() < HISTORY_HEADER_SIZE {
+                return Err(SensorError::TruncatedHistoryPacket {
+                    uuid: AranetService::READ_HISTORY_READINGS,
+                    parameter,
+                    expected: HISTORY_HEADER_SIZE,
+                    actual: bytes.len(),
+                    packet: Packet(bytes),
+                });
+            }
+            let packet = HistoryPacket::decode(&bytes).map_err(|e| {
+                malformed(
+                    AranetService::READ_HISTORY_READINGS,
+                    Operation::ReadHistory(parameter),
+                    &bytes,
+                    e,
+                )
+            })?;
+            let header = packet.header;
+            if header.parameter != parameter {
+                return Err(SensorError::UnexpectedHistoryParameter {
+                    expected: parameter,
+                    actual: header.parameter,
+                    packet: Packet(bytes),
+                });
+            }
+            // have we reached the end of the data stream?
+            if header.num_measurements == 0 {
                 break;
             }
-            if let Some(header) = HistoryHeader::decode(&bytes[..HISTORY_HEADER_SIZE]) {
-                // have we reached the end of the data stream?
-                if header.num_measurements == 0 {
-                    break;
-                }
-                let end = std::mem::size_of::<u8>() * header.num_measurements as usize;
-                let end = std::cmp::min(end, bytes.len());
 
-                ret.extend_from_slice(&bytes[HISTORY_HEADER_SIZE..end]);
-            } else {
-                break;
+            chunks += 1;
+            if parameter == LogParameter::Temperature {
+                raw.header.get_or_insert(header);
+            }
+
+            raw.samples(parameter).extend_from_slice(packet.samples);
+
+            received += packet.samples.len() / parameter.sample_size();
+            if let Some(progress) = self.progress.as_mut() {
+                progress(HistoryProgress {
+                    parameter,
+                    parameters_done,
+                    parameters_total: DOWNLOAD_ORDER.len(),
+                    chunks,
+                    received,
+                    total_measurements: header.total_measurements.into(),
+                });
             }
         }
-        Ok(ret)
+        if chunks == 0 {
+            return Err(SensorError::MissingHistoryHeader {
+                uuid: AranetService::READ_HISTORY_READINGS,
+                parameter,
+            });
+        }
+        Ok(())
     }
-    async fn get_co2_history(
-        &self,
-        write_cmd: &Characteristic,
-        reading_cmd: &Characteristic,
-    ) -> Result<Vec<u16>, SensorError> {
-        let history_request = HistoryRequest {
-            parameter: LogParameter::Co2,
+}
+
+impl Sensor {
+    /// Prepare a download of the historical data for this sensor
+    pub fn history(&self) -> HistoryDownload<'_> {
+        HistoryDownload {
+            sensor: self,
             first_index: 1,
-        };
-        self.aranet
-            .write(
-                write_cmd,
-                &history_request.encode(),
-                WriteType::WithoutResponse,
-            )
-            .await?;
-        let mut ret = vec![];
-        while let Ok(bytes) = self.aranet.read(reading_cmd).await {
-            if bytes.len() < HISTORY_HEADER_SIZE {
-                break;
-            }
-            if let Some(header) = HistoryHeader::decode(&bytes[..HISTORY_HEADER_SIZE]) {
-                // have we reached the end of the data stream?
-                if header.num_measurements == 0 {
-                    break;
-                }
-                let end = std::mem::size_of::<u16>() * header.num_measurements as usize;
-                let end = std::cmp::min(end, bytes.len());
-
-                let vals: Vec<u16> = bytes[HISTORY_HEADER_SIZE..end]
-                    .chunks_exact(2)
-                    .into_iter()
-                    .map(|x| u16::from_le_bytes([x[0], x[1]]))
-                    .collect();
-                ret.extend(vals);
-            } else {
-                break;
-            }
+            cancel: None,
+            progress: None,
         }
-        Ok(ret)
     }
 
     /// Get the historical data for this sensor
     pub async fn get_historical_data(&self) -> Result<HistoryReadings, SensorError> {
-        let write_cmd = self
-            .get_characteristic(AranetService::WRITE_CMD)
-            .ok_or(SensorError::CannotFindCharacteristics)?;
-        let reading_cmd = self
-            .get_characteristic(AranetService::READ_HISTORY_READINGS)
-            .ok_or(SensorError::CannotFindCharacteristics)?;
-
-        let (information, temperature) =
-            self.get_temperature_history(write_cmd, reading_cmd).await?;
-        let mut humidity = self.get_humidity_history(write_cmd, reading_cmd).await?;
-        humidity.truncate(temperature.len());
-
-        let mut co2 = self.get_co2_history(write_cmd, reading_cmd).await?;
-        co2.truncate(temperature.len());
-
-        let mut pressure = self.get_pressure_history(write_cmd, reading_cmd).await?;
-        pressure.truncate(temperature.len());
-
-        Ok(HistoryReadings {
-            information,
-            temperature,
-            humidity,
-            co2,
-            pressure,
-        })
+        self.history().run().await
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::sensor::{
+        protocol::Command,
+        transport::testing::{history_packet, FakeTransport},
+    };
+    use std::time::Duration;
 
     #[test]
-    fn history_header_serialize() {
-        let x = HistoryHeader {
+    fn history_data_start() {
+        // the first of two chunks of a 10-measurement history: dating it from the 5
+        // measurements in the chunk put the start 4 intervals too late
+        let header = HistoryHeader {
             parameter: LogParameter::Temperature,
-            interval: 10,
-            total_measurements: 20,
+            interval: 300,
+            total_measurements: 10,
             time_since_last_measurement: 30,
-            first_measure_index: 40,
-            num_measurements: 50,
+            first_measure_index: 1,
+            num_measurements: 5,
         };
-        let bin = bincode::serialize(&x).expect("value to serialize");
-        assert_eq!(bin, &[1u8, 10, 0, 20, 0, 30, 0, 40, 0, 50])
+        let now = chrono::Local::now();
+        let start = header::data_start_at(&header, now).expect("start time");
+        assert_eq!(now - start, chrono::Duration::seconds(30 + 9 * 300));
+    }
+
+    const T: LogParameter = LogParameter::Temperature;
+    const H: LogParameter = LogParameter::Humidity;
+    const C: LogParameter = LogParameter::Co2;
+    const P: LogParameter = LogParameter::Pressure;
+
+    fn fake_sensor() -> FakeTransport {
+        FakeTransport::default()
+            .with_history_packet(history_packet(T, 300, 3, 20, 1, &[440, 460]))
+            .with_history_packet(history_packet(T, 300, 3, 20, 3, &[480]))
+            .with_history_packet(history_packet(H, 300, 3, 20, 1, &[40, 41, 42]))
+            .with_history_packet(history_packet(C, 300, 3, 20, 1, &[500, 600, 700]))
     }
+
+    #[cfg(feature = "serde")]
     #[test]
-    fn history_request_serialize() {
-        let x = HistoryRequest {
-            parameter: LogParameter::Temperature,
-            first_index: 1,
+    fn progress_serde() {
+        let progress = HistoryProgress {
+            parameter: C,
+            parameters_done: 2,
+            parameters_total: 4,
+            chunks: 1,
+            received: 120,
+            total_measurements: 240,
         };
-        let bin = bincode::serialize(&x).expect("value to serialize");
-        assert_eq!(bin, &[1u8, 1, 0])
+        let json = serde_json::to_value(progress).expect("serialize");
+        assert_eq!(json["parameter"], "co2");
+        assert_eq!(json["total_measurements"], 240);
+        let back: HistoryProgress = serde_json::from_value(json).expect("deserialize");
+        assert_eq!(back, progress);
+    }
+
+    #[tokio::test]
+    async fn download_history() {
+        let sensor = Sensor::from_transport(fake_sensor().with_history_packet(history_packet(
+            P,
+            300,
+            3,
+            20,
+            1,
+            &[9800, 9810, 9820],
+        )));
+        let history = sensor.get_historical_data().await.expect("history");
+        assert_eq!(
+            history.temperature,
+            [440, 460, 480].map(convert_temperature).to_vec()
+        );
+        assert_eq!(history.humidity, vec![40, 41, 42]);
+        assert_eq!(history.co2, vec![500, 600, 700]);
+        assert_eq!(history.pressure, vec![980.0, 981.0, 982.0]);
+        assert_eq!(history.information.interval, chrono::Duration::seconds(300));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn operation_timeout_keeps_partial_history() {
+        let sensor = Sensor::from_transport(fake_sensor().hang_after(P));
+        match sensor.get_historical_data().await {
+            Err(SensorError::HistoryInterrupted { cause, partial }) => {
+                assert!(matches!(
+                    *cause,
+                    SensorError::Timeout {
+                        operation: Operation::ReadHistory(LogParameter::Pressure),
+                        ..
+                    }
+                ));
+                assert_eq!(partial.co2, vec![500, 600, 700]);
+                assert!(partial.pressure.is_empty());
+            }
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn download_timeout() {
+        let mut sensor = Sensor::from_transport(FakeTransport::default().hang_after(T));
+        sensor.set_timeouts(crate::sensor::Timeouts {
+            operation: Duration::from_secs(60),
+            history: Duration::from_secs(5),
+        });
+        let err = sensor.get_historical_data().await.unwrap_err();
+        assert!(matches!(
+            err,
+            SensorError::Timeout {
+                operation: Operation::DownloadHistory,
+                ..
+            }
+        ));
+        assert!(err.is_transient());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cancel_keeps_partial_history() {
+        let sensor = Sensor::from_transport(fake_sensor().hang_after(H));
+        let token = CancellationToken::new();
+        let canceller = token.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            canceller.cancel();
+        });
+        match sensor.history().cancel_on(token).run().await {
+            Err(SensorError::HistoryInterrupted { cause, partial }) => {
+                assert!(matches!(*cause, SensorError::Cancelled));
+                assert_eq!(partial.temperature.len(), 3);
+                assert_eq!(partial.as_records().len(), 0);
+            }
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[tokio::test]
+    async fn report_progress() {
+        let sensor = Sensor::from_transport(fake_sensor().with_history_packet(history_packet(
+            P,
+            300,
+            3,
+            20,
+            1,
+            &[9800, 9810, 9820],
+        )));
+        let mut events = vec![];
+        sensor
+            .history()
+            .on_progress(|p| events.push(p))
+            .run()
+            .await
+            .expect("history");
+        assert_eq!(events.len(), 5);
+        assert_eq!(
+            events[0],
+            HistoryProgress {
+                parameter: T,
+                parameters_done: 0,
+                parameters_total: 4,
+                chunks: 1,
+                received: 2,
+                total_measurements: 3,
+            }
+        );
+        assert_eq!((events[1].chunks, events[1].received), (2, 3));
+        assert_eq!(events[2].parameter, H);
+        assert_eq!(events[2].fraction(), 0.5);
+        assert_eq!(events[4].fraction(), 1.0);
+    }
+
+    #[tokio::test]
+    async fn incremental_download() {
+        let transport = std::sync::Arc::new(
+            FakeTransport::default()
+                .with_history_packet(history_packet(T, 300, 3, 20, 3, &[480]))
+                .with_history_packet(history_packet(H, 300, 3, 20, 3, &[42]))
+                .with_history_packet(history_packet(C, 300, 3, 20, 3, &[700]))
+                .with_history_packet(history_packet(P, 300, 3, 20, 3, &[9820])),
+        );
+        let sensor = Sensor::from_transport(transport.clone());
+        let history = sensor
+            .history()
+            .starting_at(3)
+            .run()
+            .await
+            .expect("history");
+        assert_eq!(history.co2, vec![700]);
+        let requests: Vec<Vec<u8>> = transport
+            .writes()
+            .into_iter()
+            .map(|(_, data)| data)
+            .collect();
+        assert_eq!(
+            requests,
+            [T, H, C, P].map(|p| vec![Command::REQUEST_HISTORY, p as u8, 3, 0])
+        );
+    }
+
+    #[tokio::test]
+    async fn replay_recorded_session() {
+        use crate::sensor::recording::{Recording, ReplayTransport};
+        let recording: Recording = include_str!("../testdata/recordings/one-minute-history.txt")
+            .parse()
+            .expect("recording");
+        let sensor = Sensor::from_transport(ReplayTransport::new(recording));
+        let readings = sensor.read_current_values().await.unwrap();
+        assert_eq!(readings.co2_level, 726);
+        assert_eq!(readings.humidity, 41);
+        assert_eq!(sensor.last_update_time().await.unwrap(), Duration::ZERO);
+        let history = sensor.get_historical_data().await.unwrap();
+        assert_eq!(history.information.interval, chrono::Duration::minutes(1));
+        assert_eq!(history.co2, [705, 705, 711, 712, 718, 727, 726]);
+        assert_eq!(history.humidity, [41, 41, 41, 42, 42, 41, 41]);
+        assert_eq!(history.pressure[0], 1009.9);
+        assert!((history.temperature[0] - 71.87).abs() < 0.01);
     }
 }
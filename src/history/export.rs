@@ -0,0 +1,3 @@
+//! Export history to and import it from other formats
+#[cfg(feature = "csv")]
+pub mod csv;
@@ -0,0 +1,433 @@
+//! CSV export and import of history records
+//!
+//! Files written by [`write_records`] start with a header naming each column and its unit, e.g.
+//! `time,co2_ppm,temperature_f,humidity_percent,pressure_hpa`, and store times as RFC 3339.
+//!
+//! [`read_records`] reads those files as well as the CSV export of the Aranet Home app, whose
+//! header looks like
+//! `Time(dd/mm/yyyy),Carbon dioxide(ppm),Temperature(°C),Relative humidity(%),Atmospheric pressure(hPa)`.
+//! Times in Aranet Home exports carry no offset and are read as local time.
+use crate::{
+    history::{
+        readings::HistoryReadings,
+        record::{DataRecord, TimestampedRecord},
+    },
+    sensor::protocol::{celsius_to_fahrenheit, fahrenheit_to_celsius},
+};
+use chrono::{DateTime, Local, NaiveDateTime, TimeZone};
+use std::io;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum CsvError {
+    #[error("CSV Error")]
+    Csv(#[from] ::csv::Error),
+    #[error("IO Error")]
+    Io(#[from] io::Error),
+    #[error("Unrecognized column: {}", .0)]
+    UnknownColumn(String),
+    #[error("Missing column: {:?}", .0)]
+    MissingColumn(Column),
+    #[error("Cannot parse {column:?} value {value:?} on line {line}")]
+    InvalidValue {
+        column: Column,
+        value: String,
+        line: u64,
+    },
+}
+
+/// Unit used for temperature columns
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TemperatureUnit {
+    Fahrenheit,
+    Celsius,
+}
+
+/// Unit used for pressure columns
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PressureUnit {
+    Hectopascal,
+    MillimetersOfMercury,
+}
+
+/// A column of a history CSV file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Column {
+    Time,
+    Co2,
+    Temperature,
+    Humidity,
+    Pressure,
+}
+
+/// Controls how records are written
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CsvOptions {
+    pub temperature_unit: TemperatureUnit,
+    pub pressure_unit: PressureUnit,
+    /// Columns to write, in order
+    pub columns: Vec<Column>,
+}
+impl Default for CsvOptions {
+    fn default() -> Self {
+        Self {
+            temperature_unit: TemperatureUnit::Fahrenheit,
+            pressure_unit: PressureUnit::Hectopascal,
+            columns: vec![
+                Column::Time,
+                Column::Co2,
+                Column::Temperature,
+                Column::Humidity,
+                Column::Pressure,
+            ],
+        }
+    }
+}
+
+/// Millimeters of mercury per hectopascal
+const MMHG_PER_HPA: f32 = 0.750_062;
+
+/// Write the history as CSV, one row per measurement
+pub fn write_history<W: io::Write>(
+    writer: W,
+    history: &HistoryReadings,
+    options: &CsvOptions,
+) -> Result<(), CsvError> {
+    write_records(writer, &history.as_timestamped_records(), options)
+}
+
+/// Write timestamped records as CSV, one row per record
+pub fn write_records<W: io::Write>(
+    writer: W,
+    records: &[TimestampedRecord],
+    options: &CsvOptions,
+) -> Result<(), CsvError> {
+    let mut writer = ::csv::Writer::from_writer(writer);
+    writer.write_record(options.columns.iter().map(|c| header(*c, options)))?;
+    for record in records {
+        writer.write_record(options.columns.iter().map(|c| field(record, *c, options)))?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+fn header(column: Column, options: &CsvOptions) -> &'static str {
+    match column {
+        Column::Time => "time",
+        Column::Co2 => "co2_ppm",
+        Column::Temperature => match options.temperature_unit {
+            TemperatureUnit::Fahrenheit => "temperature_f",
+            TemperatureUnit::Celsius => "temperature_c",
+        },
+        Column::Humidity => "humidity_percent",
+        Column::Pressure => match options.pressure_unit {
+            PressureUnit::Hectopascal => "pressure_hpa",
+            PressureUnit::MillimetersOfMercury => "pressure_mmhg",
+        },
+    }
+}
+
+fn field(record: &TimestampedRecord, column: Column, options: &CsvOptions) -> String {
+    let data = &record.record;
+    match column {
+        Column::Time => record.time.to_rfc3339(),
+        Column::Co2 => data.co2.to_string(),
+        Column::Temperature => match options.temperature_unit {
+            TemperatureUnit::Fahrenheit => data.temperature.to_string(),
+            TemperatureUnit::Celsius => format!("{:.2}", fahrenheit_to_celsius(data.temperature)),
+        },
+        Column::Humidity => data.humidity.to_string(),
+        Column::Pressure => match options.pressure_unit {
+            PressureUnit::Hectopascal => data.pressure.to_string(),
+            PressureUnit::MillimetersOfMercury => format!("{:.2}", data.pressure * MMHG_PER_HPA),
+        },
+    }
+}
+
+/// How the values of an input column are interpreted
+#[derive(Debug, Clone, Copy)]
+enum Field {
+    Time(TimeFormat),
+    Co2,
+    Temperature(TemperatureUnit),
+    Humidity,
+    Pressure(PressureUnit),
+}
+impl Field {
+    fn column(&self) -> Column {
+        match self {
+            Field::Time(_) => Column::Time,
+            Field::Co2 => Column::Co2,
+            Field::Temperature(_) => Column::Temperature,
+            Field::Humidity => Column::Humidity,
+            Field::Pressure(_) => Column::Pressure,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum TimeFormat {
+    Rfc3339,
+    DayFirst,
+    MonthFirst,
+}
+
+fn parse_header(name: &str) -> Result<Field, CsvError> {
+    let field = match name.trim().to_lowercase().as_str() {
+        "time" => Field::Time(TimeFormat::Rfc3339),
+        "time(dd/mm/yyyy)" => Field::Time(TimeFormat::DayFirst),
+        "time(mm/dd/yyyy)" => Field::Time(TimeFormat::MonthFirst),
+        "co2_ppm" | "carbon dioxide(ppm)" => Field::Co2,
+        "temperature_f" | "temperature(°f)" => Field::Temperature(TemperatureUnit::Fahrenheit),
+        "temperature_c" | "temperature(°c)" => Field::Temperature(TemperatureUnit::Celsius),
+        "humidity_percent" | "relative humidity(%)" => Field::Humidity,
+        "pressure_hpa" | "atmospheric pressure(hpa)" => Field::Pressure(PressureUnit::Hectopascal),
+        "pressure_mmhg" | "atmospheric pressure(mmhg)" => {
+            Field::Pressure(PressureUnit::MillimetersOfMercury)
+        }
+        _ => return Err(CsvError::UnknownColumn(name.to_string())),
+    };
+    Ok(field)
+}
+
+fn parse_time(value: &str, format: TimeFormat) -> Option<DateTime<Local>> {
+    let patterns: &[&str] = match format {
+        TimeFormat::Rfc3339 => {
+            return DateTime::parse_from_rfc3339(value)
+                .ok()
+                .map(|t| t.with_timezone(&Local))
+        }
+        TimeFormat::DayFirst => &[
+            "%d/%m/%Y %H:%M:%S",
+            "%d/%m/%Y %I:%M:%S %p",
+            "%d/%m/%Y %H:%M",
+        ],
+        TimeFormat::MonthFirst => &[
+            "%m/%d/%Y %H:%M:%S",
+            "%m/%d/%Y %I:%M:%S %p",
+            "%m/%d/%Y %H:%M",
+        ],
+    };
+    patterns
+        .iter()
+        .find_map(|p| NaiveDateTime::parse_from_str(value, p).ok())
+        .and_then(|naive| Local.from_local_datetime(&naive).earliest())
+}
+
+/// Read records written by [`write_records`] or exported by the Aranet Home app.
+///
+/// Every column must be present, in any order.
+pub fn read_records<R: io::Read>(reader: R) -> Result<Vec<TimestampedRecord>, CsvError> {
+    let mut reader = ::csv::ReaderBuilder::new()
+        .trim(::csv::Trim::All)
+        .from_reader(reader);
+    let fields = reader
+        .headers()?
+        .iter()
+        .map(parse_header)
+        .collect::<Result<Vec<_>, _>>()?;
+    for column in [
+        Column::Time,
+        Column::Co2,
+        Column::Temperature,
+        Column::Humidity,
+        Column::Pressure,
+    ] {
+        if !fields.iter().any(|f| f.column() == column) {
+            return Err(CsvError::MissingColumn(column));
+        }
+    }
+
+    let mut records = vec![];
+    for row in reader.records() {
+        let row = row?;
+        let line = row.position().map(|p| p.line()).unwrap_or_default();
+        let mut time = None;
+        let mut record = DataRecord::default();
+        for (field, value) in fields.iter().zip(row.iter()) {
+            let invalid = || CsvError::InvalidValue {
+                column: field.column(),
+                value: value.to_string(),
+                line,
+            };
+            // NaN and infinities parse as floats, but are never readings
+            let number = || {
+                value
+                    .parse::<f32>()
+                    .ok()
+                    .filter(|n| n.is_finite())
+                    .ok_or_else(invalid)
+            };
+            // rounded to a whole number no greater than `max`, rather than saturating
+            let whole = |max: u16| {
+                let n = number()?.round();
+                if (0.0..=f32::from(max)).contains(&n) {
+                    Ok(n as u16)
+                } else {
+                    Err(invalid())
+                }
+            };
+            match *field {
+                Field::Time(format) => time = Some(parse_time(value, format).ok_or_else(invalid)?),
+                Field::Co2 => record.co2 = whole(u16::MAX)?,
+                Field::Temperature(TemperatureUnit::Fahrenheit) => record.temperature = number()?,
+                Field::Temperature(TemperatureUnit::Celsius) => {
+                    record.temperature = celsius_to_fahrenheit(number()?)
+                }
+                Field::Humidity => record.humidity = whole(100)? as u8,
+                Field::Pressure(PressureUnit::Hectopascal) => record.pressure = number()?,
+                Field::Pressure(PressureUnit::MillimetersOfMercury) => {
+                    record.pressure = number()? / MMHG_PER_HPA
+                }
+            }
+        }
+        if let Some(time) = time {
+            records.push(TimestampedRecord { time, record });
+        }
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Timelike;
+
+    fn records() -> Vec<TimestampedRecord> {
+        let start = Local.with_ymd_and_hms(2024, 4, 25, 18, 8, 4).unwrap();
+        (0..3)
+            .map(|i| TimestampedRecord {
+                time: start + chrono::Duration::minutes(10 * i),
+                record: DataRecord {
+                    temperature: 69.08 + i as f32,
+                    humidity: 30 + i as u8,
+                    pressure: 987.9,
+                    co2: 373 + 10 * i as u16,
+                },
+            })
+            .collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut out = vec![];
+        write_records(&mut out, &records(), &CsvOptions::default()).expect("write");
+        let text = String::from_utf8(out.clone()).unwrap();
+        assert!(text.starts_with("time,co2_ppm,temperature_f,humidity_percent,pressure_hpa\n"));
+        assert_eq!(read_records(out.as_slice()).expect("read"), records());
+    }
+
+    #[test]
+    fn round_trip_converted_units() {
+        let options = CsvOptions {
+            temperature_unit: TemperatureUnit::Celsius,
+            pressure_unit: PressureUnit::MillimetersOfMercury,
+            columns: vec![
+                Column::Pressure,
+                Column::Humidity,
+                Column::Temperature,
+                Column::Co2,
+                Column::Time,
+            ],
+        };
+        let mut out = vec![];
+        write_records(&mut out, &records(), &options).expect("write");
+        let text = String::from_utf8(out.clone()).unwrap();
+        let mut lines = text.lines();
+        assert_eq!(
+            lines.next(),
+            Some("pressure_mmhg,humidity_percent,temperature_c,co2_ppm,time")
+        );
+        assert!(lines.next().unwrap().starts_with("740.99,30,20.60,373,"));
+
+        let back = read_records(out.as_slice()).expect("read");
+        for (read, written) in back.iter().zip(records()) {
+            assert_eq!(read.time, written.time);
+            assert_eq!(read.record.co2, written.record.co2);
+            assert!((read.record.temperature - written.record.temperature).abs() < 0.01);
+            assert!((read.record.pressure - written.record.pressure).abs() < 0.01);
+        }
+    }
+
+    #[test]
+    fn aranet_home_export() {
+        let fixture = include_str!("../../../tests/fixtures/aranet_home_export.csv");
+        let records = read_records(fixture.as_bytes()).expect("read");
+        assert_eq!(records.len(), 4);
+        let first = &records[0];
+        assert_eq!(
+            first.time.naive_local(),
+            NaiveDateTime::parse_from_str("2023-01-07 12:30:10", "%Y-%m-%d %H:%M:%S").unwrap()
+        );
+        assert_eq!(first.record.co2, 612);
+        assert!((first.record.temperature - 71.42).abs() < 0.01);
+        assert_eq!(first.record.humidity, 41);
+        assert_eq!(first.record.pressure, 1012.3);
+        assert_eq!(records[3].time.minute(), 0);
+    }
+
+    #[test]
+    fn aranet_home_export_month_first() {
+        let fixture = include_str!("../../../tests/fixtures/aranet_home_export_us.csv");
+        let records = read_records(fixture.as_bytes()).expect("read");
+        assert_eq!(records.len(), 2);
+        assert_eq!(
+            records[0].time.naive_local().to_string(),
+            "2023-01-13 14:05:00"
+        );
+        assert_eq!(records[0].record.temperature, 70.2);
+        assert!((records[0].record.pressure - 1013.25).abs() < 0.05);
+    }
+
+    #[test]
+    fn invalid_input() {
+        let missing = "time,co2_ppm\n2024-04-25T18:08:04+00:00,400\n";
+        assert!(matches!(
+            read_records(missing.as_bytes()),
+            Err(CsvError::MissingColumn(Column::Temperature))
+        ));
+        let unknown = "time,radon\n";
+        assert!(matches!(
+            read_records(unknown.as_bytes()),
+            Err(CsvError::UnknownColumn(c)) if c == "radon"
+        ));
+        let bad =
+            "time,co2_ppm,temperature_f,humidity_percent,pressure_hpa\nyesterday,400,70,40,1000\n";
+        assert!(matches!(
+            read_records(bad.as_bytes()),
+            Err(CsvError::InvalidValue {
+                column: Column::Time,
+                line: 2,
+                ..
+            })
+        ));
+        let header = "time,co2_ppm,temperature_f,humidity_percent,pressure_hpa\n";
+        for (row, column) in [
+            ("2024-04-25T18:08:04+00:00,-5,70,40,1000", Column::Co2),
+            ("2024-04-25T18:08:04+00:00,70000,70,40,1000", Column::Co2),
+            (
+                "2024-04-25T18:08:04+00:00,400,70,101,1000",
+                Column::Humidity,
+            ),
+            (
+                "2024-04-25T18:08:04+00:00,400,70,NaN,1000",
+                Column::Humidity,
+            ),
+            (
+                "2024-04-25T18:08:04+00:00,400,inf,40,1000",
+                Column::Temperature,
+            ),
+            ("2024-04-25T18:08:04+00:00,400,70,40,NaN", Column::Pressure),
+        ] {
+            let csv = format!("{}{}\n", header, row);
+            assert!(
+                matches!(
+                    read_records(csv.as_bytes()),
+                    Err(CsvError::InvalidValue { column: c, line: 2, .. }) if c == column
+                ),
+                "{}",
+                row
+            );
+        }
+    }
+}
@@ -0,0 +1,309 @@
+//! Time spent in CO2 bands and above thresholds
+//!
+//! Each measurement stands for the time until the next one, but never for longer than the
+//! measurement interval, so gaps in the history are not counted. The interval is taken to be
+//! the shortest spacing between measurements.
+use super::stats::HistoryStats;
+use crate::sensor::protocol::LogParameter;
+use chrono::{DateTime, Datelike, Duration, Local, NaiveTime, Weekday};
+
+/// CO2 thresholds in ppm, splitting levels into bands
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bands {
+    thresholds: Vec<u16>,
+}
+
+impl Bands {
+    /// Bands split at `thresholds`, in any order
+    pub fn new(mut thresholds: Vec<u16>) -> Self {
+        thresholds.sort_unstable();
+        thresholds.dedup();
+        Self { thresholds }
+    }
+    /// The bands of the device's status light: green below 1000 ppm, yellow below 1400 ppm
+    /// and red above
+    pub fn device() -> Self {
+        Self::new(vec![1000, 1400])
+    }
+    pub fn thresholds(&self) -> &[u16] {
+        &self.thresholds
+    }
+    /// Index of the band containing `co2`; a level equal to a threshold is in the band above
+    fn band(&self, co2: f64) -> usize {
+        self.thresholds
+            .iter()
+            .take_while(|t| co2 >= f64::from(**t))
+            .count()
+    }
+}
+
+impl Default for Bands {
+    fn default() -> Self {
+        Self::device()
+    }
+}
+
+/// Weekly occupied hours
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Schedule {
+    periods: Vec<(Weekday, NaiveTime, NaiveTime)>,
+}
+
+impl Schedule {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// `start` to `end` from Monday to Friday
+    pub fn weekdays(start: NaiveTime, end: NaiveTime) -> Self {
+        [
+            Weekday::Mon,
+            Weekday::Tue,
+            Weekday::Wed,
+            Weekday::Thu,
+            Weekday::Fri,
+        ]
+        .into_iter()
+        .fold(Self::new(), |schedule, day| schedule.with(day, start, end))
+    }
+    /// Add the period from `start`, inclusive, to `end`, exclusive, on `day`
+    pub fn with(mut self, day: Weekday, start: NaiveTime, end: NaiveTime) -> Self {
+        self.periods.push((day, start, end));
+        self
+    }
+    pub fn contains(&self, time: DateTime<Local>) -> bool {
+        let (day, time) = (time.weekday(), time.time());
+        self.periods
+            .iter()
+            .any(|(d, start, end)| *d == day && *start <= time && time < *end)
+    }
+}
+
+/// Time spent in one band
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BandExposure {
+    /// Lowest level in the band, `None` for the lowest band
+    #[cfg_attr(feature = "serde", serde(rename = "from_ppm"))]
+    pub from: Option<u16>,
+    /// Lowest level above the band, `None` for the highest band
+    #[cfg_attr(feature = "serde", serde(rename = "to_ppm"))]
+    pub to: Option<u16>,
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "time_seconds", with = "crate::serde_seconds::duration")
+    )]
+    pub time: Duration,
+}
+
+/// Time spent at or above one threshold
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Exceedance {
+    #[cfg_attr(feature = "serde", serde(rename = "threshold_ppm"))]
+    pub threshold: u16,
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "time_seconds", with = "crate::serde_seconds::duration")
+    )]
+    pub time: Duration,
+    /// Number of continuous periods at or above the threshold
+    pub events: usize,
+    /// The longest of those periods
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "longest_seconds", with = "crate::serde_seconds::duration")
+    )]
+    pub longest: Duration,
+    /// When the longest period started
+    pub longest_start: Option<DateTime<Local>>,
+}
+
+/// Result of [`co2_exposure`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ExposureReport {
+    /// Time covered by the report, the sum of the times in all bands
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "total_seconds", with = "crate::serde_seconds::duration")
+    )]
+    pub total: Duration,
+    /// One entry per band, lowest first
+    pub bands: Vec<BandExposure>,
+    /// One entry per threshold, lowest first
+    pub exceedances: Vec<Exceedance>,
+}
+
+/// Time spent in each of `bands` and above each of their thresholds. With a `schedule`, only
+/// measurements in occupied hours are counted, and leaving them ends an exceedance.
+pub fn co2_exposure<H: HistoryStats + ?Sized>(
+    history: &H,
+    bands: &Bands,
+    schedule: Option<&Schedule>,
+) -> ExposureReport {
+    let interval = history.interval();
+    let thresholds = bands.thresholds();
+    let mut report = ExposureReport {
+        total: Duration::zero(),
+        bands: (0..=thresholds.len())
+            .map(|i| BandExposure {
+                from: i.checked_sub(1).map(|i| thresholds[i]),
+                to: thresholds.get(i).copied(),
+                time: Duration::zero(),
+            })
+            .collect(),
+        exceedances: thresholds
+            .iter()
+            .map(|threshold| Exceedance {
+                threshold: *threshold,
+                time: Duration::zero(),
+                events: 0,
+                longest: Duration::zero(),
+                longest_start: None,
+            })
+            .collect(),
+    };
+    // start and length of the current run above each threshold
+    let mut runs: Vec<Option<(DateTime<Local>, Duration)>> = vec![None; thresholds.len()];
+    for i in 0..history.len() {
+        let time = history.time(i);
+        let next = (i + 1 < history.len()).then(|| history.time(i + 1) - time);
+        let duration = next.map_or(interval, |next| next.min(interval));
+        let band = if schedule.is_none_or(|s| s.contains(time)) {
+            bands.band(history.value(i, LogParameter::Co2))
+        } else {
+            // not counted, and ends every run
+            runs.fill(None);
+            continue;
+        };
+        report.total += duration;
+        report.bands[band].time += duration;
+        for (threshold, run) in runs.iter_mut().enumerate() {
+            let exceedance = &mut report.exceedances[threshold];
+            if band <= threshold {
+                *run = None;
+                continue;
+            }
+            exceedance.time += duration;
+            let (start, length) = run.get_or_insert_with(|| {
+                exceedance.events += 1;
+                (time, Duration::zero())
+            });
+            *length += duration;
+            if *length > exceedance.longest {
+                exceedance.longest = *length;
+                exceedance.longest_start = Some(*start);
+            }
+            // a gap in the history ends the run
+            if next.is_some_and(|next| next > interval) {
+                *run = None;
+            }
+        }
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::record::{DataRecord, TimestampedRecord};
+    use chrono::TimeZone;
+
+    /// Monday 15 January 2024
+    fn time(hour: u32, minute: u32) -> DateTime<Local> {
+        Local
+            .with_ymd_and_hms(2024, 1, 15, hour, minute, 0)
+            .unwrap()
+    }
+
+    fn records(start: DateTime<Local>, co2: &[u16]) -> Vec<TimestampedRecord> {
+        co2.iter()
+            .enumerate()
+            .map(|(i, co2)| TimestampedRecord {
+                time: start + Duration::minutes(10) * i as i32,
+                record: DataRecord {
+                    co2: *co2,
+                    ..Default::default()
+                },
+            })
+            .collect()
+    }
+
+    #[test]
+    fn device_bands() {
+        let history = records(time(9, 0), &[800, 1000, 1200, 1500, 900, 1400, 1450, 600]);
+        let report = co2_exposure(history.as_slice(), &Bands::device(), None);
+        assert_eq!(report.total, Duration::minutes(80));
+        let times: Vec<_> = report.bands.iter().map(|b| b.time).collect();
+        assert_eq!(
+            times,
+            [
+                Duration::minutes(30),
+                Duration::minutes(20),
+                Duration::minutes(30)
+            ]
+        );
+        assert_eq!(
+            (report.bands[0].from, report.bands[0].to),
+            (None, Some(1000))
+        );
+        assert_eq!(report.bands[2].from, Some(1400));
+
+        let above_1000 = report.exceedances[0];
+        assert_eq!(above_1000.threshold, 1000);
+        assert_eq!(above_1000.time, Duration::minutes(50));
+        assert_eq!(above_1000.events, 2);
+        assert_eq!(above_1000.longest, Duration::minutes(30));
+        assert_eq!(above_1000.longest_start, Some(time(9, 10)));
+
+        let above_1400 = report.exceedances[1];
+        assert_eq!(above_1400.events, 2);
+        assert_eq!(above_1400.longest, Duration::minutes(20));
+        assert_eq!(above_1400.longest_start, Some(time(9, 50)));
+    }
+
+    #[test]
+    fn gaps_split_events() {
+        let mut history = records(time(9, 0), &[1200, 1200]);
+        history.extend(records(time(12, 0), &[1200, 1200, 1200]));
+        let report = co2_exposure(history.as_slice(), &Bands::new(vec![1000]), None);
+        assert_eq!(report.total, Duration::minutes(50));
+        assert_eq!(report.exceedances[0].events, 2);
+        assert_eq!(report.exceedances[0].longest, Duration::minutes(30));
+        assert_eq!(report.exceedances[0].longest_start, Some(time(12, 0)));
+    }
+
+    #[test]
+    fn occupied_hours() {
+        let schedule = Schedule::weekdays(
+            NaiveTime::from_hms_opt(9, 0, 0).unwrap(),
+            NaiveTime::from_hms_opt(17, 0, 0).unwrap(),
+        );
+        assert!(schedule.contains(time(9, 0)));
+        assert!(!schedule.contains(time(17, 0)));
+        // Saturday
+        assert!(!schedule.contains(time(12, 0) + Duration::days(5)));
+
+        // 16:40 to 17:20, high throughout
+        let history = records(time(16, 40), &[1500, 1500, 1500, 1500, 1500]);
+        let report = co2_exposure(history.as_slice(), &Bands::device(), Some(&schedule));
+        assert_eq!(report.total, Duration::minutes(20));
+        assert_eq!(report.exceedances[1].events, 1);
+        assert_eq!(report.exceedances[1].longest, Duration::minutes(20));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_field_names() {
+        let history = records(time(9, 0), &[800, 1200, 1500]);
+        let report = co2_exposure(history.as_slice(), &Bands::device(), None);
+        let json = serde_json::to_value(&report).expect("serialize");
+        assert_eq!(json["total_seconds"], 1800);
+        assert_eq!(json["bands"][1]["from_ppm"], 1000);
+        assert_eq!(json["bands"][0]["to_ppm"], 1000);
+        assert_eq!(json["exceedances"][0]["threshold_ppm"], 1000);
+        assert_eq!(json["exceedances"][0]["longest_seconds"], 1200);
+        let back: ExposureReport = serde_json::from_value(json).expect("deserialize");
+        assert_eq!(back, report);
+    }
+}
@@ -0,0 +1,170 @@
+//! Number of people in a room, estimated from how fast CO2 builds up
+//!
+//! The CO2 level of a well mixed room follows the mass balance
+//!
+//! ```text
+//! V · dC/dt = N · G + Q · (C_out - C)
+//! ```
+//!
+//! with `V` the room volume, `N` the number of occupants, `G` the CO2 each exhales and
+//! `Q = ACH · V` the ventilation flow. Solved for `N`, with `C` in ppm:
+//!
+//! ```text
+//! N = V · (dC/dt + ACH · (C - C_out)) / (G · 10⁶)
+//! ```
+//!
+//! `dC/dt` is taken from the neighbouring measurements, so in a steady state, with a flat
+//! level, the estimate only depends on how far the level is above the outdoor one.
+use super::stats::HistoryStats;
+use crate::sensor::protocol::LogParameter;
+use chrono::{DateTime, Local};
+
+/// The room and the people in it
+#[derive(Debug, Clone, PartialEq)]
+pub struct OccupancyConfig {
+    /// Room volume in m³
+    pub room_volume: f64,
+    /// CO2 exhaled per person in m³/h
+    pub generation_rate: f64,
+    /// Air changes per hour, e.g. from [`ventilation::estimate`](super::ventilation::estimate)
+    pub ach: f64,
+    /// Outdoor CO2 level in ppm
+    pub outdoor_co2: f64,
+}
+
+impl OccupancyConfig {
+    /// A room of adults doing office work, who exhale 0.0187 m³/h (5.2 mL/s) of CO2 each,
+    /// with 420 ppm outdoors
+    pub fn new(room_volume: f64, ach: f64) -> Self {
+        Self {
+            room_volume,
+            generation_rate: 0.0187,
+            ach,
+            outdoor_co2: 420.0,
+        }
+    }
+}
+
+/// Estimated occupants at the time of a measurement
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Occupancy {
+    pub time: DateTime<Local>,
+    /// Estimated number of people, never negative
+    pub occupants: f64,
+}
+
+/// Occupants at the time of every measurement in `history`, in the same order
+pub fn estimate<H: HistoryStats + ?Sized>(history: &H, config: &OccupancyConfig) -> Vec<Occupancy> {
+    let interval = history.interval();
+    let co2 = |i| history.value(i, LogParameter::Co2);
+    // neighbours separated by a gap in the history are not used for the rate of change
+    let contiguous = |i: usize, j: usize| history.time(j) - history.time(i) <= interval * 3 / 2;
+    let hours =
+        |i: usize, j: usize| (history.time(j) - history.time(i)).num_seconds() as f64 / 3600.0;
+    (0..history.len())
+        .map(|i| {
+            let before = i.checked_sub(1).filter(|before| contiguous(*before, i));
+            let after = Some(i + 1).filter(|after| *after < history.len() && contiguous(i, *after));
+            let rate = match (before.unwrap_or(i), after.unwrap_or(i)) {
+                (from, to) if from == to => 0.0,
+                (from, to) => (co2(to) - co2(from)) / hours(from, to),
+            };
+            let generation =
+                config.room_volume * (rate + config.ach * (co2(i) - config.outdoor_co2)) / 1e6;
+            Occupancy {
+                time: history.time(i),
+                occupants: (generation / config.generation_rate).max(0.0),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::{
+        readings::{HistoryInformation, HistoryReadings},
+        record::{DataRecord, TimestampedRecord},
+    };
+    use chrono::{Duration, TimeZone};
+
+    fn start() -> DateTime<Local> {
+        Local.with_ymd_and_hms(2024, 1, 15, 8, 0, 0).unwrap()
+    }
+
+    /// Integrate the mass balance in one-minute steps for a room with `occupants` people
+    /// during each hour, sampling the level every five minutes as the sensor would
+    fn simulate(config: &OccupancyConfig, occupants: &[f64]) -> HistoryReadings {
+        let mut level = config.outdoor_co2;
+        let mut co2 = vec![];
+        for minute in 0..occupants.len() * 60 {
+            if minute % 5 == 0 {
+                co2.push(level.round() as u16);
+            }
+            let people = occupants[minute / 60];
+            let flow = config.ach * config.room_volume;
+            let change = (people * config.generation_rate * 1e6
+                + flow * (config.outdoor_co2 - level))
+                / config.room_volume;
+            level += change / 60.0;
+        }
+        HistoryReadings {
+            information: HistoryInformation::new(Duration::minutes(5), start()),
+            temperature: vec![68.0; co2.len()],
+            humidity: vec![40; co2.len()],
+            pressure: vec![1000.0; co2.len()],
+            co2,
+        }
+    }
+
+    #[test]
+    fn simulated_meeting_room() {
+        let config = OccupancyConfig::new(60.0, 2.0);
+        let occupants = [0.0, 4.0, 4.0, 10.0, 2.0, 0.0];
+        let history = simulate(&config, &occupants);
+        let estimate = estimate(&history, &config);
+        assert_eq!(estimate.len(), history.co2.len());
+        assert_eq!(estimate[12].time, start() + Duration::hours(1));
+        for (i, occupancy) in estimate.iter().enumerate() {
+            // skip the measurements next to a change, where the rate mixes both hours
+            if i % 12 == 0 || i % 12 == 11 {
+                continue;
+            }
+            let expected = occupants[i / 12];
+            assert!(
+                (occupancy.occupants - expected).abs() < 0.35,
+                "measurement {i}: {} people instead of {expected}",
+                occupancy.occupants
+            );
+        }
+    }
+
+    #[test]
+    fn steady_state_and_gaps() {
+        let config = OccupancyConfig::new(50.0, 1.0);
+        // a steady 5 people give 5 · 0.0187 m³/h / 50 m³/h = 1870 ppm above outdoor
+        let records: Vec<_> = [0, 5, 60]
+            .into_iter()
+            .map(|minutes| TimestampedRecord {
+                time: start() + Duration::minutes(minutes),
+                record: DataRecord {
+                    co2: 2290,
+                    ..Default::default()
+                },
+            })
+            .collect();
+        let estimate = estimate(records.as_slice(), &config);
+        assert!(estimate.iter().all(|o| (o.occupants - 5.0).abs() < 0.01));
+
+        // below the outdoor level, nobody is there
+        let empty = [TimestampedRecord {
+            time: start(),
+            record: DataRecord {
+                co2: 380,
+                ..Default::default()
+            },
+        }];
+        assert_eq!(super::estimate(empty.as_slice(), &config)[0].occupants, 0.0);
+    }
+}
@@ -1,33 +1,41 @@
-use super::{header::HistoryHeader, record::DataRecord};
-use crate::sensor::protocol::LogParameter;
+use super::{
+    header::{get_data_start, HistoryHeader},
+    record::{DataRecord, TimestampedRecord},
+};
 use chrono::Local;
-use serde::Serialize;
-
-#[derive(Debug, Serialize)]
-pub(super) struct HistoryRequest {
-    pub parameter: LogParameter,
-    pub first_index: u16,
-}
-
-impl HistoryRequest {
-    pub fn encode(&self) -> Vec<u8> {
-        let mut data: Vec<u8> = vec![0x61];
-        data.extend(bincode::serialize(self).unwrap());
-        data
-    }
-}
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 
 /// Metadata about a [`HistoryReadings`]
-#[allow(unused)]
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct HistoryInformation {
+    /// Time between measurements, serialized as whole seconds
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "interval_seconds", with = "crate::serde_seconds::duration")
+    )]
     pub interval: chrono::Duration,
+    /// Approximate time of the first measurement, serialized as RFC 3339
     beginning: chrono::DateTime<Local>,
 }
+impl HistoryInformation {
+    pub fn new(interval: chrono::Duration, beginning: chrono::DateTime<Local>) -> Self {
+        Self {
+            interval,
+            beginning,
+        }
+    }
+    /// Approximate time of the first measurement
+    pub fn beginning(&self) -> chrono::DateTime<Local> {
+        self.beginning
+    }
+}
+
 impl From<HistoryHeader> for HistoryInformation {
     fn from(header: HistoryHeader) -> Self {
         let interval = chrono::Duration::seconds(header.interval.into());
-        let beginning = header.get_data_start().unwrap_or_else(chrono::Local::now);
+        let beginning = get_data_start(&header).unwrap_or_else(chrono::Local::now);
         Self {
             interval,
             beginning,
@@ -37,11 +45,20 @@ impl From<HistoryHeader> for HistoryInformation {
 
 /// Historical Readings from Sensor
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct HistoryReadings {
     pub information: HistoryInformation,
+    /// Temperature in Fahrenheit
+    #[cfg_attr(feature = "serde", serde(rename = "temperature_f"))]
     pub temperature: Vec<f32>,
+    /// Humidity in percent humidity
+    #[cfg_attr(feature = "serde", serde(rename = "humidity_percent"))]
     pub humidity: Vec<u8>,
+    /// CO2 level, expressed in ppm
+    #[cfg_attr(feature = "serde", serde(rename = "co2_ppm"))]
     pub co2: Vec<u16>,
+    /// Pressure in hPa
+    #[cfg_attr(feature = "serde", serde(rename = "pressure_hpa"))]
     pub pressure: Vec<f32>,
 }
 
@@ -64,4 +81,55 @@ impl HistoryReadings {
             })
             .collect()
     }
+    /// Get a view of the data as a vector of [`TimestampedRecord`], spaced by the
+    /// measurement interval from [`HistoryInformation::beginning`]
+    pub fn as_timestamped_records(&self) -> Vec<TimestampedRecord> {
+        let mut time = self.information.beginning;
+        self.as_records()
+            .into_iter()
+            .map(|record| {
+                let timestamped = TimestampedRecord { time, record };
+                time += self.information.interval;
+                timestamped
+            })
+            .collect()
+    }
+}
+
+#[cfg(all(test, feature = "serde"))]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn serde_round_trip() {
+        let beginning = Local.with_ymd_and_hms(2024, 4, 25, 18, 8, 4).unwrap();
+        let readings = HistoryReadings {
+            information: HistoryInformation {
+                interval: chrono::Duration::seconds(600),
+                beginning,
+            },
+            temperature: vec![69.08],
+            humidity: vec![30],
+            co2: vec![373],
+            pressure: vec![987.9],
+        };
+        let json = serde_json::to_value(&readings).expect("serialize");
+        assert_eq!(json["information"]["interval_seconds"], 600);
+        let timestamp = json["information"]["beginning"]
+            .as_str()
+            .expect("timestamp");
+        assert_eq!(
+            chrono::DateTime::parse_from_rfc3339(timestamp).expect("RFC 3339"),
+            beginning
+        );
+        assert_eq!(json["co2_ppm"][0], 373);
+        let record = serde_json::to_value(readings.as_records()[0]).expect("serialize");
+        assert_eq!(record["humidity_percent"], 30);
+
+        let back: HistoryReadings = serde_json::from_value(json).expect("deserialize");
+        assert_eq!(back.information.beginning(), beginning);
+        assert_eq!(back.information.interval, readings.information.interval);
+        assert_eq!(back.pressure, readings.pressure);
+    }
 }
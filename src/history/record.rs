@@ -1,18 +1,46 @@
+use chrono::Local;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 use std::fmt;
 
-#[derive(Debug, Default)]
+/// A single measurement from the sensor history
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct DataRecord {
+    /// Temperature in Fahrenheit
+    #[cfg_attr(feature = "serde", serde(rename = "temperature_f"))]
     pub temperature: f32,
+    /// Humidity in percent humidity
+    #[cfg_attr(feature = "serde", serde(rename = "humidity_percent"))]
     pub humidity: u8,
+    /// Pressure in hPa
+    #[cfg_attr(feature = "serde", serde(rename = "pressure_hpa"))]
     pub pressure: f32,
+    /// CO2 level, expressed in ppm
+    #[cfg_attr(feature = "serde", serde(rename = "co2_ppm"))]
     pub co2: u16,
 }
 impl fmt::Display for DataRecord {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
-            "CO2: {}ppm, Temperature: {}F, Pressure : {}kpa, Humidity : {}",
+            "CO2: {}ppm, Temperature: {}F, Pressure : {}hPa, Humidity : {}",
             self.co2, self.temperature, self.pressure, self.humidity,
         )
     }
 }
+
+/// A [`DataRecord`] with the approximate time it was measured
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct TimestampedRecord {
+    /// Time of the measurement, serialized as RFC 3339
+    pub time: chrono::DateTime<Local>,
+    #[cfg_attr(feature = "serde", serde(flatten))]
+    pub record: DataRecord,
+}
+impl fmt::Display for TimestampedRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.time.to_rfc3339(), self.record)
+    }
+}
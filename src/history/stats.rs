@@ -0,0 +1,381 @@
+//! Statistics, resampling and rolling averages over history
+//!
+//! [`HistoryStats`] is implemented for [`HistoryReadings`] and for slices of
+//! [`TimestampedRecord`], such as those returned by
+//! [`SensorLog::records`](super::store::SensorLog::records). Values are read in place, one
+//! [`LogParameter`] at a time; only percentiles and medians sort a copy of the values.
+use super::{readings::HistoryReadings, record::TimestampedRecord};
+use crate::sensor::protocol::LogParameter;
+use chrono::{DateTime, Duration, Local, TimeZone};
+
+/// Summary statistics of one parameter
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Summary {
+    pub count: usize,
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+    /// Population standard deviation
+    pub std_dev: f64,
+}
+
+/// How the values in a resampling bucket are combined
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Aggregation {
+    Mean,
+    Min,
+    Max,
+    Median,
+    /// Percentile from 0 to 100
+    Percentile(f64),
+    First,
+    Last,
+}
+
+/// A value at a point in time
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Sample {
+    pub time: DateTime<Local>,
+    pub value: f64,
+}
+
+/// One bucket of resampled history
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Bucket {
+    /// Start of the bucket
+    pub start: DateTime<Local>,
+    /// Number of measurements in the bucket
+    pub count: usize,
+    pub value: f64,
+}
+
+/// Percentile from 0 to 100 of sorted values, interpolating linearly between the closest
+/// ranks (the method of Excel's `PERCENTILE.INC` and NumPy's default)
+fn percentile_of_sorted(sorted: &[f64], percentile: f64) -> Option<f64> {
+    if sorted.is_empty() {
+        return None;
+    }
+    let rank = percentile.clamp(0.0, 100.0) / 100.0 * (sorted.len() - 1) as f64;
+    let (below, above) = (rank.floor() as usize, rank.ceil() as usize);
+    Some(sorted[below] + (sorted[above] - sorted[below]) * (rank - below as f64))
+}
+
+fn sorted(values: impl Iterator<Item = f64>) -> Vec<f64> {
+    let mut values: Vec<f64> = values.collect();
+    values.sort_by(f64::total_cmp);
+    values
+}
+
+impl Aggregation {
+    fn apply(&self, values: &[f64]) -> Option<f64> {
+        let (first, last) = (values.first()?, values.last()?);
+        Some(match self {
+            Aggregation::Mean => values.iter().sum::<f64>() / values.len() as f64,
+            Aggregation::Min => values.iter().copied().fold(f64::INFINITY, f64::min),
+            Aggregation::Max => values.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+            Aggregation::Median => percentile_of_sorted(&sorted(values.iter().copied()), 50.0)?,
+            Aggregation::Percentile(p) => {
+                percentile_of_sorted(&sorted(values.iter().copied()), *p)?
+            }
+            Aggregation::First => *first,
+            Aggregation::Last => *last,
+        })
+    }
+}
+
+/// Start of the bucket of length `every` containing `time`. Buckets are aligned on local
+/// time, so hourly buckets start on the hour and daily ones at midnight.
+fn bucket_start(time: DateTime<Local>, every: Duration) -> DateTime<Local> {
+    let naive = time.naive_local();
+    let seconds = naive.and_utc().timestamp();
+    let every = every.num_seconds().max(1);
+    let start = seconds - seconds.rem_euclid(every);
+    let start = DateTime::from_timestamp(start, 0).map_or(naive, |start| start.naive_utc());
+    Local
+        .from_local_datetime(&start)
+        .earliest()
+        // the start falls in a DST gap: use the first time that exists
+        .unwrap_or(time)
+}
+
+/// Statistics over a series of timestamped measurements, oldest first
+pub trait HistoryStats {
+    /// Number of measurements
+    fn len(&self) -> usize;
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// Time of measurement `index`
+    fn time(&self, index: usize) -> DateTime<Local>;
+    /// Value of `parameter` in measurement `index`, in the units of [`DataRecord`]
+    ///
+    /// [`DataRecord`]: super::record::DataRecord
+    fn value(&self, index: usize, parameter: LogParameter) -> f64;
+
+    /// All values of `parameter`
+    fn values(&self, parameter: LogParameter) -> impl Iterator<Item = f64> + '_ {
+        (0..self.len()).map(move |i| self.value(i, parameter))
+    }
+    /// Measurement interval, taken to be the shortest positive spacing between measurements.
+    /// Zero with fewer than two distinct times.
+    fn interval(&self) -> Duration {
+        (1..self.len())
+            .map(|i| self.time(i) - self.time(i - 1))
+            .filter(|spacing| *spacing > Duration::zero())
+            .min()
+            .unwrap_or_else(Duration::zero)
+    }
+    /// Min, max, mean and standard deviation of `parameter`, `None` without measurements
+    fn summary(&self, parameter: LogParameter) -> Option<Summary> {
+        let count = self.len();
+        if count == 0 {
+            return None;
+        }
+        let mean = self.values(parameter).sum::<f64>() / count as f64;
+        let variance = self
+            .values(parameter)
+            .map(|v| (v - mean).powi(2))
+            .sum::<f64>()
+            / count as f64;
+        Some(Summary {
+            count,
+            min: self.values(parameter).fold(f64::INFINITY, f64::min),
+            max: self.values(parameter).fold(f64::NEG_INFINITY, f64::max),
+            mean,
+            std_dev: variance.sqrt(),
+        })
+    }
+    /// Percentiles from 0 to 100 of `parameter`, in the order requested. Empty without
+    /// measurements.
+    fn percentiles(&self, parameter: LogParameter, percentiles: &[f64]) -> Vec<f64> {
+        let sorted = sorted(self.values(parameter));
+        percentiles
+            .iter()
+            .filter_map(|p| percentile_of_sorted(&sorted, *p))
+            .collect()
+    }
+    /// Combine the values of `parameter` in buckets of length `every`, such as
+    /// `Duration::hours(1)` or `Duration::days(1)`. Buckets without measurements are left out.
+    fn resample(
+        &self,
+        parameter: LogParameter,
+        every: Duration,
+        aggregation: Aggregation,
+    ) -> Vec<Bucket> {
+        let mut buckets = vec![];
+        let mut values = vec![];
+        let mut current = None;
+        let mut close = |start: Option<DateTime<Local>>, values: &mut Vec<f64>| {
+            if let Some((start, value)) = start.zip(aggregation.apply(values)) {
+                buckets.push(Bucket {
+                    start,
+                    count: values.len(),
+                    value,
+                });
+            }
+            values.clear();
+        };
+        for i in 0..self.len() {
+            let start = bucket_start(self.time(i), every);
+            if current.is_some_and(|current| current != start) {
+                close(current, &mut values);
+            }
+            current = Some(start);
+            values.push(self.value(i, parameter));
+        }
+        close(current, &mut values);
+        buckets
+    }
+    /// Trailing average of `parameter` over `window`: each measurement is averaged with
+    /// those taken less than `window` before it
+    fn rolling_mean(&self, parameter: LogParameter, window: Duration) -> Vec<Sample> {
+        let mut samples = Vec::with_capacity(self.len());
+        let (mut first, mut sum) = (0, 0.0);
+        for i in 0..self.len() {
+            let time = self.time(i);
+            sum += self.value(i, parameter);
+            while time - self.time(first) >= window && first < i {
+                sum -= self.value(first, parameter);
+                first += 1;
+            }
+            samples.push(Sample {
+                time,
+                value: sum / (i + 1 - first) as f64,
+            });
+        }
+        samples
+    }
+}
+
+impl HistoryStats for HistoryReadings {
+    fn len(&self) -> usize {
+        [
+            self.temperature.len(),
+            self.humidity.len(),
+            self.co2.len(),
+            self.pressure.len(),
+        ]
+        .into_iter()
+        .min()
+        .unwrap_or_default()
+    }
+    fn time(&self, index: usize) -> DateTime<Local> {
+        self.information.beginning() + self.information.interval * index as i32
+    }
+    fn value(&self, index: usize, parameter: LogParameter) -> f64 {
+        match parameter {
+            LogParameter::Temperature => self.temperature[index].into(),
+            LogParameter::Humidity => self.humidity[index].into(),
+            LogParameter::Pressure => self.pressure[index].into(),
+            LogParameter::Co2 => self.co2[index].into(),
+        }
+    }
+}
+
+impl HistoryStats for [TimestampedRecord] {
+    fn len(&self) -> usize {
+        <[TimestampedRecord]>::len(self)
+    }
+    fn time(&self, index: usize) -> DateTime<Local> {
+        self[index].time
+    }
+    fn value(&self, index: usize, parameter: LogParameter) -> f64 {
+        let record = &self[index].record;
+        match parameter {
+            LogParameter::Temperature => record.temperature.into(),
+            LogParameter::Humidity => record.humidity.into(),
+            LogParameter::Pressure => record.pressure.into(),
+            LogParameter::Co2 => record.co2.into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::readings::HistoryInformation;
+
+    fn time(hour: u32, minute: u32) -> DateTime<Local> {
+        Local
+            .with_ymd_and_hms(2024, 1, 15, hour, minute, 0)
+            .unwrap()
+    }
+
+    /// Measurements every 20 minutes from 10:20
+    fn history(co2: &[u16]) -> HistoryReadings {
+        HistoryReadings {
+            information: HistoryInformation::new(Duration::minutes(20), time(10, 20)),
+            temperature: vec![68.0; co2.len()],
+            humidity: (0..co2.len() as u8).collect(),
+            co2: co2.to_vec(),
+            pressure: vec![1000.5; co2.len()],
+        }
+    }
+
+    #[test]
+    fn summary_and_percentiles() {
+        let empty = history(&[]);
+        assert_eq!(empty.summary(LogParameter::Co2), None);
+        assert!(empty.percentiles(LogParameter::Co2, &[50.0]).is_empty());
+        let history = history(&[400, 420, 410, 450, 430]);
+        let summary = history.summary(LogParameter::Co2).unwrap();
+        assert_eq!((summary.count, summary.min, summary.max), (5, 400.0, 450.0));
+        assert_eq!(summary.mean, 422.0);
+        assert!((summary.std_dev - 17.204650534).abs() < 1e-6);
+        assert_eq!(
+            history.percentiles(LogParameter::Co2, &[0.0, 50.0, 90.0, 100.0]),
+            [400.0, 420.0, 442.0, 450.0]
+        );
+        assert_eq!(
+            history.summary(LogParameter::Pressure).unwrap().mean,
+            1000.5
+        );
+    }
+
+    #[test]
+    fn interval_is_shortest_positive_spacing() {
+        assert_eq!(history(&[]).interval(), Duration::zero());
+        assert_eq!(history(&[400]).interval(), Duration::zero());
+        assert_eq!(history(&[400, 410, 420]).interval(), Duration::minutes(20));
+        // a repeated time and a gap
+        let records: Vec<TimestampedRecord> = [time(10, 0), time(10, 0), time(10, 5), time(11, 0)]
+            .into_iter()
+            .map(|time| TimestampedRecord {
+                time,
+                record: Default::default(),
+            })
+            .collect();
+        assert_eq!(records.interval(), Duration::minutes(5));
+    }
+
+    #[test]
+    fn hourly_resampling() {
+        // 10:20, 10:40 | 11:00, 11:20, 11:40 | 12:00
+        let history = history(&[400, 410, 420, 430, 440, 450]);
+        let mean = history.resample(LogParameter::Co2, Duration::hours(1), Aggregation::Mean);
+        assert_eq!(
+            mean,
+            [
+                Bucket {
+                    start: time(10, 0),
+                    count: 2,
+                    value: 405.0
+                },
+                Bucket {
+                    start: time(11, 0),
+                    count: 3,
+                    value: 430.0
+                },
+                Bucket {
+                    start: time(12, 0),
+                    count: 1,
+                    value: 450.0
+                },
+            ]
+        );
+        let max = history.resample(LogParameter::Co2, Duration::hours(1), Aggregation::Max);
+        assert_eq!(
+            max.iter().map(|b| b.value).collect::<Vec<_>>(),
+            [410.0, 440.0, 450.0]
+        );
+        let daily = history.resample(
+            LogParameter::Humidity,
+            Duration::days(1),
+            Aggregation::Percentile(50.0),
+        );
+        assert_eq!(daily.len(), 1);
+        assert_eq!(daily[0].start, time(0, 0));
+        assert_eq!(daily[0].value, 2.5);
+    }
+
+    #[test]
+    fn records_with_gaps() {
+        let records: Vec<_> = history(&[400, 410, 420, 430, 440, 450])
+            .as_timestamped_records()
+            .into_iter()
+            .filter(|r| r.time.format("%H").to_string() != "11")
+            .collect();
+        let hourly = records.resample(LogParameter::Co2, Duration::hours(1), Aggregation::Last);
+        assert_eq!(
+            hourly
+                .iter()
+                .map(|b| (b.start, b.value))
+                .collect::<Vec<_>>(),
+            [(time(10, 0), 410.0), (time(12, 0), 450.0)]
+        );
+    }
+
+    #[test]
+    fn rolling_mean() {
+        let history = history(&[400, 410, 420, 430, 440]);
+        let rolling = history.rolling_mean(LogParameter::Co2, Duration::hours(1));
+        assert_eq!(
+            rolling.iter().map(|s| s.value).collect::<Vec<_>>(),
+            [400.0, 405.0, 410.0, 420.0, 430.0]
+        );
+        assert_eq!(rolling[4].time, time(11, 40));
+    }
+}
@@ -0,0 +1,569 @@
+//! Local store of downloaded history, merged across overlapping downloads
+//!
+//! Every sensor, keyed by serial number, has one append-only file in the store directory.
+//! The file starts with [`MAGIC`] and a format version, followed by fixed-size records:
+//!
+//! | bytes | content                                   |
+//! |-------|-------------------------------------------|
+//! | 8     | measurement time, Unix seconds, i64 LE    |
+//! | 2     | measurement interval in seconds, u16 LE   |
+//! | 2     | CO2 in ppm, u16 LE                        |
+//! | 4     | temperature in Fahrenheit, f32 LE         |
+//! | 1     | humidity in percent                       |
+//! | 4     | pressure in hPa, f32 LE                   |
+//! | 4     | CRC-32 of the preceding 21 bytes, u32 LE  |
+//!
+//! Appends are a single write followed by a sync. A record torn by a crash fails its checksum
+//! and is cut off, along with anything after it, the next time the file is opened.
+use super::{
+    readings::HistoryReadings,
+    record::{DataRecord, TimestampedRecord},
+};
+use byteorder::{ByteOrder, LittleEndian};
+use chrono::{DateTime, Duration, Local, TimeZone};
+use std::collections::{hash_map::Entry, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+/// First bytes of every store file
+pub const MAGIC: [u8; 4] = *b"A4HS";
+/// Format version written by this version of the crate
+pub const FORMAT_VERSION: u16 = 1;
+const FILE_HEADER_SIZE: usize = MAGIC.len() + 2;
+const RECORD_SIZE: usize = 25;
+const EXTENSION: &str = "a4h";
+
+#[derive(Debug, Error)]
+pub enum StoreError {
+    #[error("IO Error")]
+    Io(#[from] io::Error),
+    #[error("{} is not a history store file", .0.display())]
+    NotAStore(PathBuf),
+    #[error("{} has unsupported format version {version}", path.display())]
+    UnsupportedVersion { path: PathBuf, version: u16 },
+    #[error("Serial number {:?} cannot be used as a file name", .0)]
+    InvalidSerial(String),
+}
+
+/// CRC-32 (IEEE)
+pub(super) fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// A record as kept in the store
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct StoredRecord {
+    /// Unix seconds
+    time: i64,
+    interval: u16,
+    record: DataRecord,
+}
+
+impl StoredRecord {
+    fn encode(&self, out: &mut Vec<u8>) {
+        let start = out.len();
+        out.extend_from_slice(&self.time.to_le_bytes());
+        out.extend_from_slice(&self.interval.to_le_bytes());
+        out.extend_from_slice(&self.record.co2.to_le_bytes());
+        out.extend_from_slice(&self.record.temperature.to_le_bytes());
+        out.push(self.record.humidity);
+        out.extend_from_slice(&self.record.pressure.to_le_bytes());
+        let crc = crc32(&out[start..]);
+        out.extend_from_slice(&crc.to_le_bytes());
+    }
+    /// Decode one record, or `None` if its checksum does not match
+    fn decode(bytes: &[u8; RECORD_SIZE]) -> Option<Self> {
+        let (data, crc) = bytes.split_at(RECORD_SIZE - 4);
+        if crc32(data) != LittleEndian::read_u32(crc) {
+            return None;
+        }
+        Some(Self {
+            time: LittleEndian::read_i64(&data[0..8]),
+            interval: LittleEndian::read_u16(&data[8..10]),
+            record: DataRecord {
+                co2: LittleEndian::read_u16(&data[10..12]),
+                temperature: LittleEndian::read_f32(&data[12..16]),
+                humidity: data[16],
+                pressure: LittleEndian::read_f32(&data[17..21]),
+            },
+        })
+    }
+    fn timestamped(&self) -> TimestampedRecord {
+        TimestampedRecord {
+            time: Local
+                .timestamp_opt(self.time, 0)
+                .single()
+                .unwrap_or_default(),
+            record: self.record,
+        }
+    }
+}
+
+/// Outcome of merging a download into a [`SensorLog`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MergeSummary {
+    /// Records that were not in the store yet
+    pub added: usize,
+    /// Records already in the store
+    pub duplicates: usize,
+    /// Correction applied to the downloaded times to line them up with the stored ones
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "shift_seconds", with = "crate::serde_seconds::duration")
+    )]
+    pub shift: Duration,
+}
+
+/// Stretch of time with measurements missing from the store
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Gap {
+    /// Time of the last record before the gap
+    pub after: DateTime<Local>,
+    /// Time of the first record after the gap
+    pub before: DateTime<Local>,
+    /// Approximate number of measurements missing
+    pub missing: u32,
+}
+
+/// Stored history of one sensor
+#[derive(Debug)]
+pub struct SensorLog {
+    path: PathBuf,
+    /// Sorted by time
+    records: Vec<StoredRecord>,
+}
+
+impl SensorLog {
+    /// Open the file at `path`, creating it if needed and cutting off a torn tail
+    fn open(path: PathBuf) -> Result<Self, StoreError> {
+        if !path.exists() {
+            // write the header to a temporary file so a crash never leaves a headerless store
+            let temporary = path.with_extension("tmp");
+            let mut file = File::create(&temporary)?;
+            file.write_all(&MAGIC)?;
+            file.write_all(&FORMAT_VERSION.to_le_bytes())?;
+            file.sync_all()?;
+            fs::rename(&temporary, &path)?;
+        }
+        let mut bytes = vec![];
+        File::open(&path)?.read_to_end(&mut bytes)?;
+        if bytes.len() < FILE_HEADER_SIZE || bytes[..MAGIC.len()] != MAGIC {
+            return Err(StoreError::NotAStore(path));
+        }
+        let version = u16::from_le_bytes([bytes[4], bytes[5]]);
+        if version != FORMAT_VERSION {
+            return Err(StoreError::UnsupportedVersion { path, version });
+        }
+
+        let mut records = vec![];
+        let mut valid = FILE_HEADER_SIZE;
+        for chunk in bytes[FILE_HEADER_SIZE..].chunks(RECORD_SIZE) {
+            match chunk.try_into().ok().and_then(StoredRecord::decode) {
+                Some(record) => records.push(record),
+                None => break,
+            }
+            valid += RECORD_SIZE;
+        }
+        if valid < bytes.len() {
+            let file = OpenOptions::new().write(true).open(&path)?;
+            file.set_len(valid as u64)?;
+            file.sync_all()?;
+        }
+        records.sort_by_key(|r| r.time);
+        Ok(Self { path, records })
+    }
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+    pub fn first(&self) -> Option<TimestampedRecord> {
+        self.records.first().map(StoredRecord::timestamped)
+    }
+    pub fn last(&self) -> Option<TimestampedRecord> {
+        self.records.last().map(StoredRecord::timestamped)
+    }
+    /// Every stored record, oldest first
+    pub fn records(&self) -> Vec<TimestampedRecord> {
+        self.records.iter().map(StoredRecord::timestamped).collect()
+    }
+    /// Stored records measured between `from` and `to`, inclusive
+    pub fn range(&self, from: DateTime<Local>, to: DateTime<Local>) -> Vec<TimestampedRecord> {
+        let start = self.records.partition_point(|r| r.time < from.timestamp());
+        let end = self.records.partition_point(|r| r.time <= to.timestamp());
+        self.records
+            .get(start..end)
+            .unwrap_or_default()
+            .iter()
+            .map(StoredRecord::timestamped)
+            .collect()
+    }
+    /// Places where consecutive records are more than one and a half intervals apart
+    pub fn gaps(&self) -> Vec<Gap> {
+        self.records
+            .windows(2)
+            .filter_map(|pair| {
+                let (before, after) = (pair[0], pair[1]);
+                let interval = i64::from(after.interval.max(1));
+                let elapsed = after.time - before.time;
+                (elapsed * 2 > interval * 3).then(|| Gap {
+                    after: before.timestamped().time,
+                    before: after.timestamped().time,
+                    missing: ((elapsed + interval / 2) / interval - 1) as u32,
+                })
+            })
+            .collect()
+    }
+    /// Merge a downloaded window of the device log
+    pub fn merge(&mut self, history: &HistoryReadings) -> Result<MergeSummary, StoreError> {
+        self.merge_records(
+            history.information.interval,
+            &history.as_timestamped_records(),
+        )
+    }
+    /// Merge records measured every `interval`.
+    ///
+    /// Download times are only accurate to about an interval, so the same measurement can
+    /// arrive with a different time in every download. The downloaded times are first shifted
+    /// by the offset at which most records line up with stored records of equal value, then
+    /// any record within half an interval of a stored one is treated as already stored.
+    pub fn merge_records(
+        &mut self,
+        interval: Duration,
+        records: &[TimestampedRecord],
+    ) -> Result<MergeSummary, StoreError> {
+        let interval_secs = interval.num_seconds().clamp(1, u16::MAX.into());
+        let shift = self.estimate_shift(interval_secs, records);
+
+        // sorted like `records`, and only kept once written, so a failed write leaves the log
+        // as it was and a retried merge adds the same records
+        let mut added: Vec<StoredRecord> = vec![];
+        let mut duplicates = 0;
+        for record in records {
+            let time = record.time.timestamp() + shift;
+            let nearest = nearest(&self.records, time)
+                .into_iter()
+                .chain(nearest(&added, time));
+            if nearest.min().is_some_and(|d| d * 2 < interval_secs) {
+                duplicates += 1;
+                continue;
+            }
+            let at = added.partition_point(|r| r.time <= time);
+            added.insert(
+                at,
+                StoredRecord {
+                    time,
+                    interval: interval_secs as u16,
+                    record: record.record,
+                },
+            );
+        }
+        self.append(&added)?;
+        for stored in &added {
+            let at = self.records.partition_point(|r| r.time <= stored.time);
+            self.records.insert(at, *stored);
+        }
+        Ok(MergeSummary {
+            added: added.len(),
+            duplicates,
+            shift: Duration::seconds(shift),
+        })
+    }
+    /// Most common offset between downloaded records and stored records of equal value less
+    /// than an interval away, or 0 if nothing lines up
+    fn estimate_shift(&self, interval: i64, records: &[TimestampedRecord]) -> i64 {
+        let mut votes: HashMap<i64, usize> = HashMap::new();
+        for record in records {
+            let time = record.time.timestamp();
+            let start = self.records.partition_point(|r| r.time < time - interval);
+            for stored in self.records[start..]
+                .iter()
+                .take_while(|r| r.time <= time + interval)
+            {
+                if stored.record == record.record {
+                    *votes.entry(stored.time - time).or_default() += 1;
+                }
+            }
+        }
+        votes
+            .into_iter()
+            .max_by_key(|(shift, count)| (*count, -shift.abs()))
+            .map(|(shift, _)| shift)
+            .unwrap_or(0)
+    }
+    fn append(&self, records: &[StoredRecord]) -> Result<(), StoreError> {
+        if records.is_empty() {
+            return Ok(());
+        }
+        let mut bytes = Vec::with_capacity(records.len() * RECORD_SIZE);
+        for record in records {
+            record.encode(&mut bytes);
+        }
+        let mut file = OpenOptions::new().append(true).open(&self.path)?;
+        file.write_all(&bytes)?;
+        file.sync_data()?;
+        Ok(())
+    }
+}
+
+/// Directory of [`SensorLog`]s, one per sensor serial number
+#[derive(Debug)]
+pub struct HistoryStore {
+    dir: PathBuf,
+    sensors: HashMap<String, SensorLog>,
+}
+
+impl HistoryStore {
+    /// Open the store in `dir`, creating the directory if needed
+    pub fn open(dir: impl Into<PathBuf>) -> Result<Self, StoreError> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(Self {
+            dir,
+            sensors: HashMap::new(),
+        })
+    }
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+    /// Serial numbers of the sensors with stored history
+    pub fn serials(&self) -> Result<Vec<String>, StoreError> {
+        let mut serials = vec![];
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|e| e == EXTENSION) {
+                if let Some(stem) = path.file_stem().and_then(|s| s.to_str()) {
+                    serials.push(stem.to_string());
+                }
+            }
+        }
+        serials.sort();
+        Ok(serials)
+    }
+    /// Path of the file with `extension` for the sensor with this serial number
+    pub(super) fn path_for(&self, serial: &str, extension: &str) -> Result<PathBuf, StoreError> {
+        let valid = !serial.is_empty()
+            && serial
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !valid {
+            return Err(StoreError::InvalidSerial(serial.to_string()));
+        }
+        Ok(self.dir.join(format!("{}.{}", serial, extension)))
+    }
+    /// The stored history of a sensor, loaded on first use
+    pub fn sensor(&mut self, serial: &str) -> Result<&mut SensorLog, StoreError> {
+        let path = self.path_for(serial, EXTENSION)?;
+        match self.sensors.entry(serial.to_string()) {
+            Entry::Occupied(entry) => Ok(entry.into_mut()),
+            Entry::Vacant(entry) => Ok(entry.insert(SensorLog::open(path)?)),
+        }
+    }
+    /// Merge a download into the history of the sensor with this serial number
+    pub fn merge(
+        &mut self,
+        serial: &str,
+        history: &HistoryReadings,
+    ) -> Result<MergeSummary, StoreError> {
+        self.sensor(serial)?.merge(history)
+    }
+}
+
+/// Distance in seconds to the closest of `records`, sorted by time
+fn nearest(records: &[StoredRecord], time: i64) -> Option<i64> {
+    let at = records.partition_point(|r| r.time < time);
+    let after = records.get(at).map(|r| r.time - time);
+    let before = at
+        .checked_sub(1)
+        .and_then(|i| records.get(i))
+        .map(|r| time - r.time);
+    after.into_iter().chain(before).min()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    fn start() -> DateTime<Local> {
+        Local.with_ymd_and_hms(2024, 4, 25, 18, 0, 0).unwrap()
+    }
+
+    /// Records every 5 minutes from `start() + offset`, with CO2 values `co2`
+    fn window(offset_secs: i64, co2: &[u16]) -> Vec<TimestampedRecord> {
+        co2.iter()
+            .enumerate()
+            .map(|(i, co2)| TimestampedRecord {
+                time: start() + Duration::seconds(offset_secs + 300 * i as i64),
+                record: DataRecord {
+                    co2: *co2,
+                    temperature: 70.5,
+                    humidity: 40,
+                    pressure: 1001.2,
+                },
+            })
+            .collect()
+    }
+
+    #[test]
+    fn crc() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn merges_jittered_windows() {
+        let dir = TempDir::new("store-merge");
+        let mut store = HistoryStore::open(&dir.0).unwrap();
+        let interval = Duration::seconds(300);
+        let log = store.sensor("12345").unwrap();
+        let summary = log
+            .merge_records(interval, &window(0, &[400, 410, 420, 430]))
+            .unwrap();
+        assert_eq!(summary.added, 4);
+
+        // the next download starts two records in, with its times 170 seconds late
+        let summary = log
+            .merge_records(interval, &window(600 + 170, &[420, 430, 440, 450]))
+            .unwrap();
+        assert_eq!(
+            summary,
+            MergeSummary {
+                added: 2,
+                duplicates: 2,
+                shift: Duration::seconds(-170),
+            }
+        );
+        let co2: Vec<u16> = log.records().iter().map(|r| r.record.co2).collect();
+        assert_eq!(co2, [400, 410, 420, 430, 440, 450]);
+        assert_eq!(log.last().unwrap().time, start() + Duration::seconds(1500));
+        assert!(log.gaps().is_empty());
+
+        // reopening reads the same records back
+        let mut store = HistoryStore::open(&dir.0).unwrap();
+        assert_eq!(store.serials().unwrap(), ["12345"]);
+        assert_eq!(store.sensor("12345").unwrap().len(), 6);
+    }
+
+    #[test]
+    fn failed_append_leaves_log_unchanged() {
+        let dir = TempDir::new("store-failed-append");
+        let mut store = HistoryStore::open(&dir.0).unwrap();
+        let interval = Duration::seconds(300);
+        let log = store.sensor("12345").unwrap();
+        let header = fs::read(log.path()).unwrap();
+        fs::remove_file(log.path()).unwrap();
+
+        assert!(log
+            .merge_records(interval, &window(0, &[400, 410]))
+            .is_err());
+        assert!(log.is_empty());
+
+        fs::write(log.path(), header).unwrap();
+        let summary = log
+            .merge_records(interval, &window(0, &[400, 410]))
+            .unwrap();
+        assert_eq!(summary.added, 2);
+        let mut store = HistoryStore::open(&dir.0).unwrap();
+        assert_eq!(store.sensor("12345").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn gaps_and_ranges() {
+        let dir = TempDir::new("store-gaps");
+        let mut store = HistoryStore::open(&dir.0).unwrap();
+        let log = store.sensor("12345").unwrap();
+        let interval = Duration::seconds(300);
+        log.merge_records(interval, &window(0, &[400, 410]))
+            .unwrap();
+        log.merge_records(interval, &window(1800, &[500, 510]))
+            .unwrap();
+
+        assert_eq!(
+            log.gaps(),
+            [Gap {
+                after: start() + Duration::seconds(300),
+                before: start() + Duration::seconds(1800),
+                missing: 4,
+            }]
+        );
+        let range = log.range(
+            start() + Duration::seconds(300),
+            start() + Duration::seconds(1800),
+        );
+        let co2: Vec<u16> = range.iter().map(|r| r.record.co2).collect();
+        assert_eq!(co2, [410, 500]);
+    }
+
+    #[test]
+    fn recovers_from_torn_append() {
+        let dir = TempDir::new("store-torn");
+        let mut store = HistoryStore::open(&dir.0).unwrap();
+        let path = {
+            let log = store.sensor("12345").unwrap();
+            log.merge_records(Duration::seconds(300), &window(0, &[400, 410]))
+                .unwrap();
+            log.path().to_path_buf()
+        };
+        // a crash in the middle of appending a third record
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[0xaa; RECORD_SIZE - 3]).unwrap();
+        drop(file);
+
+        let mut store = HistoryStore::open(&dir.0).unwrap();
+        let log = store.sensor("12345").unwrap();
+        assert_eq!(log.len(), 2);
+        assert_eq!(
+            fs::metadata(&path).unwrap().len(),
+            (FILE_HEADER_SIZE + 2 * RECORD_SIZE) as u64
+        );
+        log.merge_records(Duration::seconds(300), &window(600, &[420]))
+            .unwrap();
+        assert_eq!(
+            HistoryStore::open(&dir.0)
+                .unwrap()
+                .sensor("12345")
+                .unwrap()
+                .len(),
+            3
+        );
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        let dir = TempDir::new("store-version");
+        let mut store = HistoryStore::open(&dir.0).unwrap();
+        assert!(matches!(
+            store.sensor("../etc"),
+            Err(StoreError::InvalidSerial(_))
+        ));
+        fs::write(dir.0.join("old.a4h"), b"A4HS\x02\x00").unwrap();
+        assert!(matches!(
+            store.sensor("old"),
+            Err(StoreError::UnsupportedVersion { version: 2, .. })
+        ));
+        fs::write(dir.0.join("text.a4h"), b"hello world").unwrap();
+        assert!(matches!(
+            store.sensor("text"),
+            Err(StoreError::NotAStore(_))
+        ));
+    }
+}
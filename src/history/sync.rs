@@ -0,0 +1,475 @@
+//! Keeping a [`HistoryStore`] in step with the device log
+//!
+//! The device numbers its log from 1, the oldest measurement, to [`Sensor::total_readings`],
+//! the newest, and has no clock of its own. After every sync the index and time of the newest
+//! stored measurement are saved next to the store, so the next sync can work out how many
+//! measurements were taken since and download only those.
+//!
+//! Indices are only stable until the log is full. From then on every new measurement pushes
+//! the oldest one out and shifts the others down, so new measurements are counted from the
+//! elapsed time instead. Changing the measurement interval clears the log.
+use super::{
+    readings::{HistoryInformation, HistoryReadings},
+    store::{crc32, HistoryStore, MergeSummary, StoreError},
+};
+use crate::{error::SensorError, sensor::Sensor};
+use byteorder::{ByteOrder, LittleEndian};
+use chrono::{DateTime, Duration, Local, TimeZone};
+use std::fs;
+use thiserror::Error;
+
+/// Most measurements the device log holds
+pub const LOG_CAPACITY: u16 = 2016;
+
+const MAGIC: [u8; 4] = *b"A4SY";
+const FORMAT_VERSION: u16 = 1;
+const STATE_SIZE: usize = 22;
+const EXTENSION: &str = "sync";
+
+#[derive(Debug, Error)]
+pub enum SyncError {
+    #[error("Sensor error")]
+    Sensor(#[from] SensorError),
+    #[error("History store error")]
+    Store(#[from] StoreError),
+    #[error("The sensor did not report a serial number")]
+    MissingSerial,
+}
+
+/// Where the last sync of a sensor left off
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SyncState {
+    /// Measurement interval in seconds
+    pub interval: u16,
+    /// Index of the newest measurement synced, at the time of the sync
+    pub last_index: u16,
+    /// Time of the newest measurement synced
+    pub last_time: DateTime<Local>,
+}
+
+impl SyncState {
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        bytes.extend_from_slice(&self.interval.to_le_bytes());
+        bytes.extend_from_slice(&self.last_index.to_le_bytes());
+        bytes.extend_from_slice(&self.last_time.timestamp().to_le_bytes());
+        let crc = crc32(&bytes);
+        bytes.extend_from_slice(&crc.to_le_bytes());
+        bytes
+    }
+    /// Decode a saved state, or `None` if it is damaged or from another format version
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != STATE_SIZE || bytes[..4] != MAGIC {
+            return None;
+        }
+        let (data, crc) = bytes.split_at(STATE_SIZE - 4);
+        if crc32(data) != LittleEndian::read_u32(crc)
+            || LittleEndian::read_u16(&data[4..6]) != FORMAT_VERSION
+        {
+            return None;
+        }
+        Some(Self {
+            interval: LittleEndian::read_u16(&data[6..8]),
+            last_index: LittleEndian::read_u16(&data[8..10]),
+            last_time: Local
+                .timestamp_opt(LittleEndian::read_i64(&data[10..18]), 0)
+                .single()?,
+        })
+    }
+}
+
+impl HistoryStore {
+    /// Where the last sync of the sensor with this serial number left off.
+    ///
+    /// A damaged state is treated as missing, so the next sync downloads the whole log and
+    /// relies on the store to drop what it already has.
+    pub fn sync_state(&self, serial: &str) -> Result<Option<SyncState>, StoreError> {
+        let path = self.path_for(serial, EXTENSION)?;
+        match fs::read(path) {
+            Ok(bytes) => Ok(SyncState::decode(&bytes)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+    /// Save where a sync left off, replacing the previous state atomically
+    pub fn set_sync_state(&self, serial: &str, state: &SyncState) -> Result<(), StoreError> {
+        let path = self.path_for(serial, EXTENSION)?;
+        let temporary = path.with_extension("sync.tmp");
+        fs::write(&temporary, state.encode())?;
+        fs::File::open(&temporary)?.sync_all()?;
+        fs::rename(&temporary, &path)?;
+        Ok(())
+    }
+}
+
+/// Why a sync could not continue from where the last one left off
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogReset {
+    /// The measurement interval changed, which clears the log
+    IntervalChanged { from: Duration, to: Duration },
+    /// The log no longer lines up with the last sync, e.g. after the sensor was reset
+    Cleared,
+}
+
+/// Measurements that left the device log before they were synced
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DataLoss {
+    /// Time of the newest measurement synced before the loss
+    pub after: DateTime<Local>,
+    /// Time of the oldest measurement still in the log
+    pub before: DateTime<Local>,
+    /// Approximate number of measurements lost
+    pub measurements: u32,
+}
+
+/// Outcome of [`Sensor::sync`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyncReport {
+    pub serial: String,
+    /// Index the download started at, or `None` if there was nothing new
+    pub first_index: Option<u16>,
+    /// Measurements downloaded
+    pub downloaded: usize,
+    pub merge: MergeSummary,
+    pub reset: Option<LogReset>,
+    /// Set when the sync came too late to get every measurement since the last one
+    pub lost: Option<DataLoss>,
+}
+
+/// Where a sync starts in the device log
+struct Plan {
+    first_index: u16,
+    /// Time of the measurement at `first_index`
+    beginning: DateTime<Local>,
+    reset: Option<LogReset>,
+    lost: Option<DataLoss>,
+}
+
+/// Whole intervals in `elapsed`, rounded to the nearest
+fn intervals(elapsed: Duration, interval: Duration) -> i64 {
+    let interval = interval.num_seconds().max(1);
+    (elapsed.num_seconds() + interval / 2).div_euclid(interval)
+}
+
+/// Loss between the last synced measurement and the oldest one still on the device
+fn loss(previous: &SyncState, oldest: DateTime<Local>) -> Option<DataLoss> {
+    let interval = Duration::seconds(previous.interval.into());
+    let measurements = intervals(oldest - previous.last_time, interval) - 1;
+    (measurements > 0).then_some(DataLoss {
+        after: previous.last_time,
+        before: oldest,
+        measurements: measurements as u32,
+    })
+}
+
+/// Decide where to start downloading, given the device log of `total` measurements whose
+/// newest was taken at `newest`
+fn plan(previous: Option<SyncState>, interval: u16, total: u16, newest: DateTime<Local>) -> Plan {
+    let step = Duration::seconds(interval.into());
+    let time_of = |index: u16| newest - step * i32::from(total - index.min(total));
+    let from_start = |reset, lost| Plan {
+        first_index: 1,
+        beginning: time_of(1),
+        reset,
+        lost,
+    };
+    let previous = match previous {
+        Some(previous) => previous,
+        None => return from_start(None, None),
+    };
+    if previous.interval != interval {
+        let reset = LogReset::IntervalChanged {
+            from: Duration::seconds(previous.interval.into()),
+            to: step,
+        };
+        return from_start(Some(reset), loss(&previous, time_of(1)));
+    }
+
+    let first_index = if total < LOG_CAPACITY {
+        // the log has not wrapped, so the last synced measurement is still at its index
+        let consistent = total >= previous.last_index
+            && (time_of(previous.last_index) - previous.last_time)
+                .num_seconds()
+                .abs()
+                < step.num_seconds();
+        if !consistent {
+            return from_start(Some(LogReset::Cleared), loss(&previous, time_of(1)));
+        }
+        i64::from(previous.last_index) + 1
+    } else {
+        let new = intervals(newest - previous.last_time, step);
+        if new < 0 {
+            return from_start(Some(LogReset::Cleared), loss(&previous, time_of(1)));
+        }
+        i64::from(total) - new + 1
+    };
+    if first_index < 1 {
+        return from_start(None, loss(&previous, time_of(1)));
+    }
+    Plan {
+        first_index: first_index as u16,
+        beginning: previous.last_time + step,
+        reset: None,
+        lost: None,
+    }
+}
+
+impl Sensor {
+    /// Download the measurements taken since the last sync into `store`.
+    ///
+    /// The sensor is identified by its serial number. The first sync, and any sync after the
+    /// log was cleared, downloads the whole log. A failed sync leaves the saved state alone, so
+    /// the next one starts from the same place.
+    pub async fn sync(&self, store: &mut HistoryStore) -> Result<SyncReport, SyncError> {
+        self.sync_at(store, Local::now()).await
+    }
+
+    pub(crate) async fn sync_at(
+        &self,
+        store: &mut HistoryStore,
+        now: DateTime<Local>,
+    ) -> Result<SyncReport, SyncError> {
+        let serial = self
+            .device_info()
+            .await?
+            .serial_number
+            .filter(|s| !s.is_empty())
+            .ok_or(SyncError::MissingSerial)?;
+        let interval = self.measurement_interval().await?.as_secs().max(1) as u16;
+        let total = self.total_readings().await?;
+        let since_update = self.last_update_time().await?;
+        let newest = now - Duration::seconds(since_update.as_secs() as i64);
+
+        let previous = store.sync_state(&serial)?;
+        let plan = plan(previous, interval, total, newest);
+        let mut report = SyncReport {
+            serial,
+            first_index: None,
+            downloaded: 0,
+            merge: MergeSummary::default(),
+            reset: plan.reset,
+            lost: plan.lost,
+        };
+        if plan.first_index > total {
+            return Ok(report);
+        }
+
+        let downloaded = self.history().starting_at(plan.first_index).run().await?;
+        let step = Duration::seconds(interval.into());
+        let history = HistoryReadings {
+            // keep to the saved clock relation rather than the download's own estimate
+            information: HistoryInformation::new(step, plan.beginning),
+            ..downloaded
+        };
+        let count = history.as_records().len();
+        report.first_index = Some(plan.first_index);
+        report.downloaded = count;
+        report.merge = store.merge(&report.serial, &history)?;
+        if count > 0 {
+            let state = SyncState {
+                interval,
+                last_index: plan.first_index + count as u16 - 1,
+                last_time: plan.beginning + step * (count as i32 - 1),
+            };
+            store.set_sync_state(&report.serial, &state)?;
+        }
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sensor::{
+        protocol::{AranetService, Command, CommonService, LogParameter},
+        transport::testing::{history_packet, FakeTransport},
+    };
+    use crate::testing::TempDir;
+    use std::sync::Arc;
+
+    fn now() -> DateTime<Local> {
+        Local.with_ymd_and_hms(2024, 4, 25, 18, 0, 20).unwrap()
+    }
+
+    /// A sensor with `total` measurements, the newest taken 20 seconds ago, answering history
+    /// requests with `count` measurements from `first_index`. CO2 values count up from 400 at
+    /// `first_index`.
+    fn device(interval: u16, total: u16, first_index: u16, count: u16) -> Arc<FakeTransport> {
+        let mut fake = FakeTransport::default()
+            .with_value(CommonService::READ_SERIAL_NO, b"12345".to_vec())
+            .with_value(
+                AranetService::READ_INTERVAL,
+                interval.to_le_bytes().to_vec(),
+            )
+            .with_value(
+                AranetService::READ_TOTAL_READINGS,
+                total.to_le_bytes().to_vec(),
+            )
+            .with_value(AranetService::READ_SECONDS_SINCE_UPDATE, vec![20, 0]);
+        let values: Vec<u16> = (0..count).map(|i| 400 + i).collect();
+        for parameter in [
+            LogParameter::Temperature,
+            LogParameter::Humidity,
+            LogParameter::Co2,
+            LogParameter::Pressure,
+        ] {
+            for (chunk, samples) in values.chunks(200).enumerate() {
+                let index = first_index + 200 * chunk as u16;
+                let samples: Vec<u16> = match parameter {
+                    LogParameter::Co2 => samples.to_vec(),
+                    LogParameter::Humidity => vec![40; samples.len()],
+                    _ => vec![400; samples.len()],
+                };
+                fake = fake.with_history_packet(history_packet(
+                    parameter, interval, total, 20, index, &samples,
+                ));
+            }
+        }
+        Arc::new(fake)
+    }
+
+    fn requested_index(transport: &FakeTransport) -> Option<u16> {
+        transport
+            .writes()
+            .iter()
+            .find(|(_, data)| data[0] == Command::REQUEST_HISTORY)
+            .map(|(_, data)| u16::from_le_bytes([data[2], data[3]]))
+    }
+
+    async fn sync(
+        store: &mut HistoryStore,
+        transport: &Arc<FakeTransport>,
+        now: DateTime<Local>,
+    ) -> SyncReport {
+        Sensor::from_transport(transport.clone())
+            .sync_at(store, now)
+            .await
+            .expect("sync")
+    }
+
+    #[tokio::test]
+    async fn continues_from_last_index() {
+        let dir = TempDir::new("sync-continue");
+        let mut store = HistoryStore::open(&dir.0).unwrap();
+
+        let report = sync(&mut store, &device(300, 3, 1, 3), now()).await;
+        assert_eq!(report.first_index, Some(1));
+        assert_eq!(report.downloaded, 3);
+        assert_eq!(report.merge.added, 3);
+        let state = store.sync_state("12345").unwrap().unwrap();
+        assert_eq!(state.last_index, 3);
+        assert_eq!(state.last_time, now() - Duration::seconds(20));
+
+        // ten minutes later, two more measurements
+        let later = now() + Duration::seconds(600);
+        let transport = device(300, 5, 4, 2);
+        let report = sync(&mut store, &transport, later).await;
+        assert_eq!(requested_index(&transport), Some(4));
+        assert_eq!((report.downloaded, report.merge.added), (2, 2));
+        assert_eq!((report.reset, report.lost), (None, None));
+        let log = store.sensor("12345").unwrap();
+        assert_eq!(log.len(), 5);
+        assert!(log.gaps().is_empty());
+        assert_eq!(log.last().unwrap().time, later - Duration::seconds(20));
+
+        // nothing new yet
+        let transport = device(300, 5, 6, 0);
+        let report = sync(&mut store, &transport, later + Duration::seconds(60)).await;
+        assert_eq!(report.first_index, None);
+        assert_eq!(requested_index(&transport), None);
+    }
+
+    #[tokio::test]
+    async fn wrapped_log() {
+        let dir = TempDir::new("sync-wrapped");
+        let mut store = HistoryStore::open(&dir.0).unwrap();
+        let synced = now() - Duration::seconds(20);
+        let state = SyncState {
+            interval: 60,
+            last_index: LOG_CAPACITY,
+            last_time: synced,
+        };
+        store.set_sync_state("12345", &state).unwrap();
+
+        // ten measurements later the log is still full, so they are its last ten entries
+        let later = now() + Duration::seconds(600);
+        let transport = device(60, LOG_CAPACITY, LOG_CAPACITY - 9, 10);
+        let report = sync(&mut store, &transport, later).await;
+        assert_eq!(requested_index(&transport), Some(LOG_CAPACITY - 9));
+        assert_eq!(report.downloaded, 10);
+        assert_eq!(report.lost, None);
+        let state = store.sync_state("12345").unwrap().unwrap();
+        assert_eq!(state.last_time, later - Duration::seconds(20));
+
+        // 2020 measurements later, four of them have already been pushed out
+        let late = later + Duration::seconds(2020 * 60);
+        let transport = device(60, LOG_CAPACITY, 1, LOG_CAPACITY);
+        let report = sync(&mut store, &transport, late).await;
+        assert_eq!(requested_index(&transport), Some(1));
+        assert_eq!(report.downloaded, usize::from(LOG_CAPACITY));
+        assert_eq!(
+            report.lost,
+            Some(DataLoss {
+                after: later - Duration::seconds(20),
+                before: late - Duration::seconds(20 + 2015 * 60),
+                measurements: 4,
+            })
+        );
+        let log = store.sensor("12345").unwrap();
+        assert_eq!(log.gaps().len(), 1);
+        assert_eq!(log.gaps()[0].missing, 4);
+    }
+
+    #[tokio::test]
+    async fn interval_change_resets_log() {
+        let dir = TempDir::new("sync-interval");
+        let mut store = HistoryStore::open(&dir.0).unwrap();
+        sync(&mut store, &device(300, 3, 1, 3), now()).await;
+
+        // an hour later the interval was changed to one minute, 5 minutes ago
+        let later = now() + Duration::seconds(3600);
+        let transport = device(60, 5, 1, 5);
+        let report = sync(&mut store, &transport, later).await;
+        assert_eq!(requested_index(&transport), Some(1));
+        assert_eq!(
+            report.reset,
+            Some(LogReset::IntervalChanged {
+                from: Duration::seconds(300),
+                to: Duration::seconds(60),
+            })
+        );
+        // from the last synced measurement to the first one in the new log
+        let lost = report.lost.expect("data loss");
+        assert_eq!(lost.after, now() - Duration::seconds(20));
+        assert_eq!(lost.before, later - Duration::seconds(20 + 4 * 60));
+        assert_eq!(lost.measurements, 10);
+        assert_eq!(store.sync_state("12345").unwrap().unwrap().interval, 60);
+    }
+
+    #[tokio::test]
+    async fn cleared_log() {
+        let dir = TempDir::new("sync-cleared");
+        let mut store = HistoryStore::open(&dir.0).unwrap();
+        sync(&mut store, &device(300, 10, 1, 10), now()).await;
+
+        let transport = device(300, 4, 1, 4);
+        let report = sync(&mut store, &transport, now() + Duration::seconds(1200)).await;
+        assert_eq!(report.reset, Some(LogReset::Cleared));
+        assert_eq!(requested_index(&transport), Some(1));
+        assert_eq!(report.lost, None);
+    }
+
+    #[test]
+    fn state_round_trip() {
+        let state = SyncState {
+            interval: 300,
+            last_index: 2016,
+            last_time: now(),
+        };
+        let mut bytes = state.encode();
+        assert_eq!(SyncState::decode(&bytes), Some(state));
+        bytes[8] ^= 1;
+        assert_eq!(SyncState::decode(&bytes), None);
+    }
+}
@@ -0,0 +1,285 @@
+//! Air changes per hour from the decay of CO2 after a room empties
+//!
+//! Without people in a room, the CO2 level decays exponentially towards the outdoor level:
+//!
+//! ```text
+//! C(t) = C_out + (C_0 - C_out) · exp(-ACH · t)    t in hours
+//! ```
+//!
+//! so `ln(C(t) - C_out)` falls on a line with slope `-ACH`. [`estimate`] finds periods of
+//! steady decline in a history, fits that line to each by least squares, and combines the
+//! periods weighted by the precision of their fits.
+use super::stats::HistoryStats;
+use crate::sensor::protocol::LogParameter;
+use chrono::{DateTime, Duration, Local};
+
+/// Fraction of its starting excess over the outdoor level a decay has to lose
+const MIN_DECAY_RATIO: f64 = 0.75;
+/// Smallest standard error used to weight a period, so perfect fits don't take all weight
+const MIN_STD_ERROR: f64 = 1e-3;
+
+/// Where decay periods are looked for, and the room they are in
+#[derive(Debug, Clone, PartialEq)]
+pub struct VentilationConfig {
+    /// Outdoor CO2 level in ppm, which the room decays towards
+    pub outdoor_co2: f64,
+    /// Least excess over the outdoor level, in ppm, at the start of a decay
+    pub min_excess: f64,
+    /// A decay ends once the excess over the outdoor level falls below this, in ppm, as the
+    /// fit gets dominated by sensor noise close to the outdoor level
+    pub floor_excess: f64,
+    /// Rise in ppm between measurements tolerated as noise within a decay
+    pub noise: f64,
+    /// Shortest decay period used
+    pub min_duration: Duration,
+    /// Room volume in m³, to also estimate the ventilation flow rate
+    pub room_volume: Option<f64>,
+}
+
+impl Default for VentilationConfig {
+    fn default() -> Self {
+        Self {
+            outdoor_co2: 420.0,
+            min_excess: 200.0,
+            floor_excess: 50.0,
+            noise: 10.0,
+            min_duration: Duration::minutes(30),
+            room_volume: None,
+        }
+    }
+}
+
+/// One decay period and its fit
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DecayPeriod {
+    pub start: DateTime<Local>,
+    pub end: DateTime<Local>,
+    /// Number of measurements fitted
+    pub samples: usize,
+    /// Air changes per hour
+    pub ach: f64,
+    /// Standard error of `ach`
+    pub std_error: f64,
+    /// Coefficient of determination of the fit, 1 for a perfect exponential decay
+    pub r_squared: f64,
+}
+
+/// Result of [`estimate`]
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct VentilationEstimate {
+    /// Air changes per hour, the weighted mean over all periods
+    pub ach: f64,
+    /// Standard error of `ach`
+    pub std_error: f64,
+    /// Ventilation flow rate in m³/h, when the room volume is known
+    #[cfg_attr(feature = "serde", serde(rename = "flow_rate_m3_per_hour"))]
+    pub flow_rate: Option<f64>,
+    /// The decay periods the estimate is based on, oldest first
+    pub periods: Vec<DecayPeriod>,
+}
+
+impl VentilationEstimate {
+    /// 95% confidence interval of `ach`
+    pub fn confidence_interval(&self) -> (f64, f64) {
+        (
+            self.ach - 1.96 * self.std_error,
+            self.ach + 1.96 * self.std_error,
+        )
+    }
+}
+
+/// Least squares fit of `ln(excess)` against hours since the start of the period
+fn fit<H: HistoryStats + ?Sized>(
+    history: &H,
+    first: usize,
+    last: usize,
+    outdoor: f64,
+) -> Option<DecayPeriod> {
+    let start = history.time(first);
+    let points: Vec<(f64, f64)> = (first..=last)
+        .map(|i| {
+            let hours = (history.time(i) - start).num_seconds() as f64 / 3600.0;
+            (hours, (history.value(i, LogParameter::Co2) - outdoor).ln())
+        })
+        .collect();
+    let n = points.len() as f64;
+    let mean_t = points.iter().map(|(t, _)| t).sum::<f64>() / n;
+    let mean_y = points.iter().map(|(_, y)| y).sum::<f64>() / n;
+    let sxx: f64 = points.iter().map(|(t, _)| (t - mean_t).powi(2)).sum();
+    let sxy: f64 = points
+        .iter()
+        .map(|(t, y)| (t - mean_t) * (y - mean_y))
+        .sum();
+    let syy: f64 = points.iter().map(|(_, y)| (y - mean_y).powi(2)).sum();
+    if points.len() < 3 || sxx == 0.0 || syy == 0.0 {
+        return None;
+    }
+    let slope = sxy / sxx;
+    let residuals = syy - slope * sxy;
+    Some(DecayPeriod {
+        start,
+        end: history.time(last),
+        samples: points.len(),
+        ach: -slope,
+        std_error: (residuals.max(0.0) / (n - 2.0) / sxx).sqrt(),
+        r_squared: 1.0 - residuals / syy,
+    })
+}
+
+/// Periods of steady CO2 decline in `history`, each fitted to an exponential decay
+pub fn decay_periods<H: HistoryStats + ?Sized>(
+    history: &H,
+    config: &VentilationConfig,
+) -> Vec<DecayPeriod> {
+    let interval = history.interval();
+    let excess = |i| history.value(i, LogParameter::Co2) - config.outdoor_co2;
+    let continues = |i: usize| {
+        history.time(i + 1) - history.time(i) <= interval * 3 / 2
+            && excess(i + 1) <= excess(i) + config.noise
+            && excess(i + 1) >= config.floor_excess
+    };
+    let mut periods = vec![];
+    let mut first = 0;
+    while first + 1 < history.len() {
+        // a decay starts where the level first falls from above the minimum excess
+        if excess(first) < config.min_excess || excess(first + 1) >= excess(first) {
+            first += 1;
+            continue;
+        }
+        let mut last = first;
+        while last + 1 < history.len() && continues(last) {
+            last += 1;
+        }
+        if history.time(last) - history.time(first) >= config.min_duration
+            && excess(last) <= excess(first) * MIN_DECAY_RATIO
+        {
+            periods.extend(fit(history, first, last, config.outdoor_co2));
+        }
+        first = last.max(first + 1);
+    }
+    periods
+}
+
+/// Air changes per hour estimated from all decay periods in `history`, or `None` if there
+/// are none
+pub fn estimate<H: HistoryStats + ?Sized>(
+    history: &H,
+    config: &VentilationConfig,
+) -> Option<VentilationEstimate> {
+    let periods = decay_periods(history, config);
+    let weights: Vec<f64> = periods
+        .iter()
+        .map(|p| p.std_error.max(MIN_STD_ERROR).powi(-2))
+        .collect();
+    if periods.is_empty() {
+        return None;
+    }
+    let total: f64 = weights.iter().sum();
+    let ach = periods
+        .iter()
+        .zip(&weights)
+        .map(|(p, w)| p.ach * w)
+        .sum::<f64>()
+        / total;
+    Some(VentilationEstimate {
+        ach,
+        std_error: total.sqrt().recip(),
+        flow_rate: config.room_volume.map(|volume| ach * volume),
+        periods,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::record::{DataRecord, TimestampedRecord};
+    use chrono::TimeZone;
+
+    fn start() -> DateTime<Local> {
+        Local.with_ymd_and_hms(2024, 1, 15, 8, 0, 0).unwrap()
+    }
+
+    /// Five-minute records: two hours of occupancy rising to `peak`, then three hours of
+    /// decay at `ach`, with a small deterministic wobble of up to `noise` ppm
+    fn occupied_then_empty(peak: f64, ach: f64, noise: f64) -> Vec<f64> {
+        let rising = (0..24).map(|i| 420.0 + (peak - 420.0) * i as f64 / 24.0);
+        let decay = (0..36).map(|i| {
+            let hours = i as f64 * 5.0 / 60.0;
+            420.0 + (peak - 420.0) * (-ach * hours).exp()
+        });
+        rising
+            .chain(decay)
+            .enumerate()
+            .map(|(i, co2)| co2 + noise * ((i * 7 % 5) as f64 - 2.0) / 2.0)
+            .collect()
+    }
+
+    fn records(from: DateTime<Local>, co2: &[f64]) -> Vec<TimestampedRecord> {
+        co2.iter()
+            .enumerate()
+            .map(|(i, co2)| TimestampedRecord {
+                time: from + Duration::minutes(5) * i as i32,
+                record: DataRecord {
+                    co2: co2.round() as u16,
+                    ..Default::default()
+                },
+            })
+            .collect()
+    }
+
+    #[test]
+    fn clean_decay() {
+        let history = records(start(), &occupied_then_empty(1600.0, 1.5, 0.0));
+        let config = VentilationConfig {
+            room_volume: Some(50.0),
+            ..Default::default()
+        };
+        let estimate = estimate(history.as_slice(), &config).unwrap();
+        assert_eq!(estimate.periods.len(), 1);
+        let period = estimate.periods[0];
+        assert_eq!(period.start, start() + Duration::hours(2));
+        assert!((estimate.ach - 1.5).abs() < 0.02, "{}", estimate.ach);
+        assert!(period.r_squared > 0.999);
+        assert!((estimate.flow_rate.unwrap() - 75.0).abs() < 1.0);
+        let (low, high) = estimate.confidence_interval();
+        assert!(low <= estimate.ach && estimate.ach <= high);
+    }
+
+    #[test]
+    fn noisy_decays_combined() {
+        let mut co2 = occupied_then_empty(1400.0, 0.8, 8.0);
+        co2.extend(occupied_then_empty(1800.0, 1.2, 8.0));
+        let history = records(start(), &co2);
+        let estimate = estimate(history.as_slice(), &VentilationConfig::default()).unwrap();
+        assert_eq!(estimate.periods.len(), 2);
+        assert!((estimate.periods[0].ach - 0.8).abs() < 0.1);
+        assert!((estimate.periods[1].ach - 1.2).abs() < 0.1);
+        assert!(estimate.periods.iter().all(|p| p.r_squared > 0.95));
+        assert!(estimate.ach > 0.8 && estimate.ach < 1.2);
+        assert!(estimate.std_error > 0.0);
+        assert_eq!(estimate.flow_rate, None);
+    }
+
+    #[test]
+    fn no_decay() {
+        // level with the outdoor air, then a drop too small and too short to fit
+        let mut co2 = vec![430.0; 24];
+        co2.extend([900.0, 850.0, 820.0, 900.0]);
+        let history = records(start(), &co2);
+        assert_eq!(
+            estimate(history.as_slice(), &VentilationConfig::default()),
+            None
+        );
+
+        // a gap in the history ends a decay
+        let decay = occupied_then_empty(1600.0, 1.5, 0.0);
+        let mut history = records(start(), &decay[..28]);
+        history.extend(records(start() + Duration::hours(4), &decay[28..]));
+        let periods = decay_periods(history.as_slice(), &VentilationConfig::default());
+        // only the part after the gap is long enough to fit
+        assert_eq!(periods.len(), 1);
+        assert_eq!(periods[0].start, start() + Duration::hours(4));
+    }
+}
@@ -0,0 +1,281 @@
+//! InfluxDB line protocol output
+//!
+//! Readings and history records become points of one measurement, tagged with the sensor
+//! `address`, `name` and `serial`. With the `influxdb` feature, [`sink::InfluxSink`] writes
+//! them to an InfluxDB 2 server.
+use crate::{history::record::TimestampedRecord, readings::SensorReadings};
+use chrono::{DateTime, Local};
+use std::fmt;
+
+#[cfg(feature = "influxdb")]
+pub mod sink;
+
+/// Measurement name used unless configured otherwise
+pub const DEFAULT_MEASUREMENT: &str = "aranet4";
+
+/// Value of a field in a [`Point`]
+#[derive(Debug, Clone, PartialEq)]
+pub enum FieldValue {
+    Float(f64),
+    Integer(i64),
+    Text(String),
+}
+
+impl From<f32> for FieldValue {
+    fn from(value: f32) -> Self {
+        // go through the shortest decimal form so 66.2f32 is written as 66.2, not 66.19999694824219
+        FieldValue::Float(value.to_string().parse().unwrap_or(value.into()))
+    }
+}
+impl From<f64> for FieldValue {
+    fn from(value: f64) -> Self {
+        FieldValue::Float(value)
+    }
+}
+impl From<u8> for FieldValue {
+    fn from(value: u8) -> Self {
+        FieldValue::Integer(value.into())
+    }
+}
+impl From<u16> for FieldValue {
+    fn from(value: u16) -> Self {
+        FieldValue::Integer(value.into())
+    }
+}
+impl From<i64> for FieldValue {
+    fn from(value: i64) -> Self {
+        FieldValue::Integer(value)
+    }
+}
+impl From<&str> for FieldValue {
+    fn from(value: &str) -> Self {
+        FieldValue::Text(value.to_string())
+    }
+}
+
+/// One line of line protocol
+#[derive(Debug, Clone, PartialEq)]
+pub struct Point {
+    pub measurement: String,
+    pub tags: Vec<(String, String)>,
+    pub fields: Vec<(String, FieldValue)>,
+    /// Written with nanosecond precision, or left to the server if `None`
+    pub time: Option<DateTime<Local>>,
+}
+
+impl Point {
+    pub fn new(measurement: impl Into<String>) -> Self {
+        Self {
+            measurement: measurement.into(),
+            tags: vec![],
+            fields: vec![],
+            time: None,
+        }
+    }
+    /// Add a tag. Empty values are left out, as InfluxDB rejects them.
+    pub fn tag(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        let value = value.into();
+        if !value.is_empty() {
+            self.tags.push((key.into(), value));
+        }
+        self
+    }
+    /// Add a field. NaN and infinite floats are left out when written, as InfluxDB rejects
+    /// them.
+    pub fn field(mut self, key: impl Into<String>, value: impl Into<FieldValue>) -> Self {
+        self.fields.push((key.into(), value.into()));
+        self
+    }
+    pub fn at(mut self, time: DateTime<Local>) -> Self {
+        self.time = Some(time);
+        self
+    }
+}
+
+/// Write `value` with `special` escaped. Line breaks would end the line, so they are written
+/// as spaces.
+fn escape(out: &mut fmt::Formatter, value: &str, special: &[char]) -> fmt::Result {
+    for c in value.chars() {
+        let c = if matches!(c, '\n' | '\r') { ' ' } else { c };
+        if special.contains(&c) {
+            write!(out, "\\")?;
+        }
+        write!(out, "{}", c)?;
+    }
+    Ok(())
+}
+
+const MEASUREMENT_SPECIAL: &[char] = &[',', '=', ' '];
+const KEY_SPECIAL: &[char] = &[',', '=', ' '];
+const STRING_SPECIAL: &[char] = &['"', '\\'];
+
+impl fmt::Display for Point {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        escape(f, &self.measurement, MEASUREMENT_SPECIAL)?;
+        let mut tags: Vec<_> = self.tags.iter().collect();
+        // InfluxDB recommends sorted tags for write performance
+        tags.sort();
+        for (key, value) in tags {
+            write!(f, ",")?;
+            escape(f, key, KEY_SPECIAL)?;
+            write!(f, "=")?;
+            escape(f, value, KEY_SPECIAL)?;
+        }
+        let fields = self
+            .fields
+            .iter()
+            .filter(|(_, value)| !matches!(value, FieldValue::Float(v) if !v.is_finite()));
+        for (i, (key, value)) in fields.enumerate() {
+            write!(f, "{}", if i == 0 { " " } else { "," })?;
+            escape(f, key, KEY_SPECIAL)?;
+            write!(f, "=")?;
+            match value {
+                FieldValue::Float(v) => write!(f, "{}", v)?,
+                FieldValue::Integer(v) => write!(f, "{}i", v)?,
+                FieldValue::Text(v) => {
+                    write!(f, "\"")?;
+                    escape(f, v, STRING_SPECIAL)?;
+                    write!(f, "\"")?;
+                }
+            }
+        }
+        if let Some(nanos) = self.time.and_then(|t| t.timestamp_nanos_opt()) {
+            write!(f, " {}", nanos)?;
+        }
+        Ok(())
+    }
+}
+
+/// Tags identifying the sensor a point came from
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct SensorTags {
+    pub address: Option<String>,
+    pub name: Option<String>,
+    pub serial: Option<String>,
+}
+
+impl SensorTags {
+    fn point(&self, measurement: &str) -> Point {
+        let tags = [
+            ("address", &self.address),
+            ("name", &self.name),
+            ("serial", &self.serial),
+        ];
+        tags.into_iter()
+            .fold(Point::new(measurement), |point, (key, value)| {
+                point.tag(key, value.clone().unwrap_or_default())
+            })
+    }
+}
+
+impl From<&crate::fleet::SensorStatus> for SensorTags {
+    fn from(status: &crate::fleet::SensorStatus) -> Self {
+        let non_empty = |s: &String| Some(s.clone()).filter(|s| !s.is_empty());
+        Self {
+            address: non_empty(&status.address),
+            name: non_empty(&status.name),
+            serial: status.serial.clone(),
+        }
+    }
+}
+
+/// Point for a set of current readings taken at `time`
+pub fn readings_point(
+    measurement: &str,
+    tags: &SensorTags,
+    readings: &SensorReadings,
+    time: DateTime<Local>,
+) -> Point {
+    tags.point(measurement)
+        .field("co2_ppm", readings.co2_level)
+        .field("temperature_f", readings.temperature)
+        .field("humidity_percent", readings.humidity)
+        .field("pressure_hpa", readings.pressure)
+        .field("battery_percent", readings.battery)
+        .at(time)
+}
+
+/// Point for one history record
+pub fn record_point(measurement: &str, tags: &SensorTags, record: &TimestampedRecord) -> Point {
+    let r = &record.record;
+    tags.point(measurement)
+        .field("co2_ppm", r.co2)
+        .field("temperature_f", r.temperature)
+        .field("humidity_percent", r.humidity)
+        .field("pressure_hpa", r.pressure)
+        .at(record.time)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::record::DataRecord;
+    use chrono::{TimeZone, Utc};
+
+    fn time() -> DateTime<Local> {
+        Utc.timestamp_opt(1714068484, 123_456_789)
+            .unwrap()
+            .with_timezone(&Local)
+    }
+
+    #[test]
+    fn escaping() {
+        let point = Point::new("air quality,indoor")
+            .tag("room name", "living room,1=a")
+            .tag("empty", "")
+            .field("note", "say \"hi\" \\ bye")
+            .field("co2 ppm", 464u16)
+            .at(time());
+        assert_eq!(
+            point.to_string(),
+            "air\\ quality\\,indoor,room\\ name=living\\ room\\,1\\=a \
+             note=\"say \\\"hi\\\" \\\\ bye\",co2\\ ppm=464i 1714068484123456789"
+        );
+    }
+
+    #[test]
+    fn line_breaks_and_non_finite_floats() {
+        let point = Point::new("air\nquality=ok")
+            .tag("room\r\nname", "living\nroom")
+            .field("temperature", f64::NAN)
+            .field("pressure", f64::INFINITY)
+            .field("humidity", 40.5);
+        assert_eq!(
+            point.to_string(),
+            "air\\ quality\\=ok,room\\ \\ name=living\\ room humidity=40.5"
+        );
+    }
+
+    #[test]
+    fn sorted_tags_without_time() {
+        let point = Point::new("m")
+            .tag("b", "2")
+            .tag("a", "1")
+            .field("v", 1.5f64);
+        assert_eq!(point.to_string(), "m,a=1,b=2 v=1.5");
+    }
+
+    #[test]
+    fn record_lines() {
+        let tags = SensorTags {
+            address: Some("AA:BB:CC:DD:EE:FF".to_string()),
+            name: Some("Aranet4 1A2B3".to_string()),
+            serial: None,
+        };
+        let record = TimestampedRecord {
+            time: time(),
+            record: DataRecord {
+                temperature: 66.2,
+                humidity: 30,
+                pressure: 987.5,
+                co2: 373,
+            },
+        };
+        assert_eq!(
+            record_point(DEFAULT_MEASUREMENT, &tags, &record).to_string(),
+            "aranet4,address=AA:BB:CC:DD:EE:FF,name=Aranet4\\ 1A2B3 \
+             co2_ppm=373i,temperature_f=66.2,humidity_percent=30i,pressure_hpa=987.5 \
+             1714068484123456789"
+        );
+    }
+}
@@ -0,0 +1,407 @@
+//! Writing points to the InfluxDB 2 `/api/v2/write` endpoint
+use super::{readings_point, record_point, Point, SensorTags};
+use crate::{fleet::SensorStatus, history::record::TimestampedRecord, readings::SensorReadings};
+use chrono::{DateTime, Local};
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio_util::sync::CancellationToken;
+
+#[derive(Debug, Error)]
+pub enum InfluxError {
+    #[error("HTTP error")]
+    Http(#[from] reqwest::Error),
+    #[error("InfluxDB rejected the write with status {status}: {body}")]
+    Rejected { status: u16, body: String },
+}
+
+impl InfluxError {
+    /// Whether the write may succeed if retried later
+    pub fn is_transient(&self) -> bool {
+        match self {
+            InfluxError::Http(e) => !e.is_builder(),
+            InfluxError::Rejected { status, .. } => *status == 429 || *status >= 500,
+        }
+    }
+}
+
+/// Where and how an [`InfluxSink`] writes
+#[derive(Debug, Clone, PartialEq)]
+pub struct InfluxConfig {
+    /// Base URL of the server, e.g. `http://localhost:8086`
+    pub url: String,
+    pub org: String,
+    pub bucket: String,
+    /// API token, sent as `Authorization: Token ...`
+    pub token: Option<String>,
+    pub measurement: String,
+    /// Most lines sent in one request
+    pub batch_size: usize,
+    /// Attempts after the first failed one, before the lines are kept for the next write
+    pub retries: u32,
+    /// Delay before the first retry, doubled for every following one
+    pub retry_delay: Duration,
+    /// Most unsent lines kept during an outage. The oldest are dropped first.
+    pub max_pending: usize,
+}
+
+impl Default for InfluxConfig {
+    fn default() -> Self {
+        Self {
+            url: "http://localhost:8086".to_string(),
+            org: String::new(),
+            bucket: String::new(),
+            token: None,
+            measurement: super::DEFAULT_MEASUREMENT.to_string(),
+            batch_size: 5000,
+            retries: 3,
+            retry_delay: Duration::from_secs(1),
+            max_pending: 100_000,
+        }
+    }
+}
+
+struct PendingLine {
+    sensor: String,
+    time: Option<DateTime<Local>>,
+    line: String,
+}
+
+/// Batching writer for an InfluxDB 2 bucket.
+///
+/// Lines that cannot be written after the configured retries are kept and sent ahead of the
+/// next write. The newest time written for each sensor is remembered, so history downloaded
+/// after a longer outage can be passed to [`backfill`](Self::backfill) to fill the gap.
+pub struct InfluxSink {
+    client: reqwest::Client,
+    config: InfluxConfig,
+    pending: VecDeque<PendingLine>,
+    last_written: HashMap<String, DateTime<Local>>,
+}
+
+fn sensor_key(tags: &SensorTags) -> String {
+    tags.address
+        .clone()
+        .or_else(|| tags.name.clone())
+        .unwrap_or_default()
+}
+
+impl InfluxSink {
+    pub fn new(config: InfluxConfig) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            config,
+            pending: VecDeque::new(),
+            last_written: HashMap::new(),
+        }
+    }
+    pub fn config(&self) -> &InfluxConfig {
+        &self.config
+    }
+    /// Number of lines waiting to be written
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+    /// Time of the newest point written for the sensor with this address, or name if it has
+    /// no address
+    pub fn last_written(&self, sensor: &str) -> Option<DateTime<Local>> {
+        self.last_written.get(sensor).copied()
+    }
+    /// Set the time of the newest point written for a sensor, e.g. as saved by a previous run
+    pub fn set_last_written(&mut self, sensor: impl Into<String>, time: DateTime<Local>) {
+        self.last_written.insert(sensor.into(), time);
+    }
+    fn queue(&mut self, sensor: &str, points: impl IntoIterator<Item = Point>) {
+        for point in points {
+            self.pending.push_back(PendingLine {
+                sensor: sensor.to_string(),
+                time: point.time,
+                line: point.to_string(),
+            });
+        }
+        while self.pending.len() > self.config.max_pending {
+            self.pending.pop_front();
+        }
+    }
+    /// Write points from one sensor, along with anything still pending
+    pub async fn write(
+        &mut self,
+        tags: &SensorTags,
+        points: impl IntoIterator<Item = Point>,
+    ) -> Result<(), InfluxError> {
+        self.queue(&sensor_key(tags), points);
+        self.flush().await
+    }
+    pub async fn write_readings(
+        &mut self,
+        tags: &SensorTags,
+        readings: &SensorReadings,
+        time: DateTime<Local>,
+    ) -> Result<(), InfluxError> {
+        let point = readings_point(&self.config.measurement, tags, readings, time);
+        self.write(tags, [point]).await
+    }
+    /// Write the history records newer than the last point written for this sensor
+    pub async fn backfill(
+        &mut self,
+        tags: &SensorTags,
+        records: &[TimestampedRecord],
+    ) -> Result<(), InfluxError> {
+        let since = self.last_written(&sensor_key(tags));
+        let points: Vec<_> = records
+            .iter()
+            .filter(|r| since.is_none_or(|since| r.time > since))
+            .map(|r| record_point(&self.config.measurement, tags, r))
+            .collect();
+        self.write(tags, points).await
+    }
+    /// Send every pending line, in batches
+    pub async fn flush(&mut self) -> Result<(), InfluxError> {
+        while !self.pending.is_empty() {
+            let count = self.pending.len().min(self.config.batch_size.max(1));
+            let body = self
+                .pending
+                .iter()
+                .take(count)
+                .map(|p| p.line.as_str())
+                .collect::<Vec<_>>()
+                .join("\n");
+            match self.send_with_retry(body).await {
+                Ok(()) => {}
+                Err(e) if e.is_transient() => return Err(e),
+                Err(e) => {
+                    // the server will never accept this batch
+                    self.pending.drain(..count);
+                    return Err(e);
+                }
+            }
+            for sent in self.pending.drain(..count) {
+                if let Some(time) = sent.time {
+                    let newest = self.last_written.entry(sent.sensor).or_insert(time);
+                    *newest = (*newest).max(time);
+                }
+            }
+        }
+        Ok(())
+    }
+    async fn send_with_retry(&self, body: String) -> Result<(), InfluxError> {
+        let mut delay = self.config.retry_delay;
+        let mut attempt = 0;
+        loop {
+            match self.send(body.clone()).await {
+                Err(e) if e.is_transient() && attempt < self.config.retries => {
+                    attempt += 1;
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                }
+                result => return result,
+            }
+        }
+    }
+    async fn send(&self, body: String) -> Result<(), InfluxError> {
+        let url = format!("{}/api/v2/write", self.config.url.trim_end_matches('/'));
+        let mut request = self
+            .client
+            .post(url)
+            .query(&[
+                ("org", self.config.org.as_str()),
+                ("bucket", self.config.bucket.as_str()),
+                ("precision", "ns"),
+            ])
+            .header("Content-Type", "text/plain; charset=utf-8")
+            .body(body);
+        if let Some(token) = &self.config.token {
+            request = request.header("Authorization", format!("Token {}", token));
+        }
+        let response = request.send().await?;
+        let status = response.status();
+        if status.is_success() {
+            Ok(())
+        } else {
+            Err(InfluxError::Rejected {
+                status: status.as_u16(),
+                body: response.text().await.unwrap_or_default(),
+            })
+        }
+    }
+    /// Write every update from a [`Fleet`](crate::fleet::Fleet) until `cancel` is cancelled
+    /// or the fleet is dropped. Transient failures leave the lines pending for the next
+    /// update; other failures end the loop. Lines still pending at the end are flushed once
+    /// more before returning.
+    pub async fn run(
+        &mut self,
+        mut updates: broadcast::Receiver<SensorStatus>,
+        cancel: CancellationToken,
+    ) -> Result<(), InfluxError> {
+        loop {
+            let status = tokio::select! {
+                _ = cancel.cancelled() => break,
+                update = updates.recv() => match update {
+                    Ok(status) => status,
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => break,
+                },
+            };
+            let (readings, time) = match (&status.readings, status.last_success, status.up) {
+                (Some(readings), Some(time), true) => (readings, time),
+                _ => continue,
+            };
+            match self
+                .write_readings(&SensorTags::from(&status), readings, time)
+                .await
+            {
+                Err(e) if !e.is_transient() => return Err(e),
+                _ => {}
+            }
+        }
+        self.flush().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fleet::Fleet;
+    use crate::history::record::DataRecord;
+    use crate::sensor::{
+        protocol::{AranetService, CommonService},
+        transport::testing::{FakeTransport, READINGS},
+        Sensor,
+    };
+    use crate::testing::HttpStandIn;
+    use chrono::TimeZone;
+
+    fn config(url: String) -> InfluxConfig {
+        InfluxConfig {
+            url,
+            org: "home".to_string(),
+            bucket: "air quality".to_string(),
+            token: Some("secret".to_string()),
+            batch_size: 2,
+            retries: 1,
+            retry_delay: Duration::from_millis(1),
+            ..InfluxConfig::default()
+        }
+    }
+
+    fn tags() -> SensorTags {
+        SensorTags {
+            address: Some("AA:BB:CC:DD:EE:FF".to_string()),
+            name: Some("office".to_string()),
+            serial: Some("12345".to_string()),
+        }
+    }
+
+    fn records(minutes: &[u32]) -> Vec<TimestampedRecord> {
+        minutes
+            .iter()
+            .map(|m| TimestampedRecord {
+                time: Local.with_ymd_and_hms(2024, 4, 25, 18, *m, 0).unwrap(),
+                record: DataRecord {
+                    co2: 400 + *m as u16,
+                    ..DataRecord::default()
+                },
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn writes_batches() {
+        let server = HttpStandIn::start(&[]).await;
+        let mut sink = InfluxSink::new(config(server.url()));
+        sink.backfill(&tags(), &records(&[0, 1, 2])).await.unwrap();
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        let request = &requests[0];
+        assert_eq!(request.method, "POST");
+        assert_eq!(
+            request.path,
+            "/api/v2/write?org=home&bucket=air+quality&precision=ns"
+        );
+        assert_eq!(request.header("authorization"), Some("Token secret"));
+        let body = request.body_text();
+        let lines: Vec<&str> = body.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with(
+            "aranet4,address=AA:BB:CC:DD:EE:FF,name=office,serial=12345 co2_ppm=400i,"
+        ));
+        let nanos = records(&[0])[0].time.timestamp_nanos_opt().unwrap();
+        assert!(lines[0].ends_with(&format!(" {}", nanos)));
+        assert_eq!(requests[1].body_text().lines().count(), 1);
+        assert_eq!(
+            sink.last_written("AA:BB:CC:DD:EE:FF"),
+            Some(records(&[2])[0].time)
+        );
+    }
+
+    #[tokio::test]
+    async fn retries_and_backfills_after_outage() {
+        // the second write fails and so does its one retry
+        let server = HttpStandIn::start(&[204, 503, 503]).await;
+        let mut sink = InfluxSink::new(InfluxConfig {
+            batch_size: 100,
+            ..config(server.url())
+        });
+        sink.backfill(&tags(), &records(&[0])).await.unwrap();
+        assert_eq!(server.requests().len(), 1);
+
+        let err = sink.backfill(&tags(), &records(&[0, 1])).await.unwrap_err();
+        assert!(err.is_transient());
+        assert_eq!(server.requests().len(), 3);
+        assert_eq!(sink.pending(), 1);
+
+        // the downloaded history overlaps what was kept and what was already written; the
+        // repeated line is harmless as InfluxDB keeps one point per series and time
+        sink.backfill(&tags(), &records(&[0, 1, 2])).await.unwrap();
+        let requests = server.requests();
+        assert_eq!(requests.len(), 4);
+        let body = requests[3].body_text();
+        let co2: Vec<&str> = body
+            .lines()
+            .map(|l| l.split(' ').nth(1).unwrap().split(',').next().unwrap())
+            .collect();
+        assert_eq!(co2, ["co2_ppm=401i", "co2_ppm=401i", "co2_ppm=402i"]);
+        assert_eq!(sink.pending(), 0);
+    }
+
+    #[tokio::test]
+    async fn drops_rejected_batches() {
+        let server = HttpStandIn::start(&[400]).await;
+        let mut sink = InfluxSink::new(config(server.url()));
+        let err = sink.backfill(&tags(), &records(&[0])).await.unwrap_err();
+        assert!(matches!(err, InfluxError::Rejected { status: 400, .. }));
+        assert_eq!(server.requests().len(), 1);
+        assert_eq!(sink.pending(), 0);
+        assert_eq!(sink.last_written("AA:BB:CC:DD:EE:FF"), None);
+    }
+
+    #[tokio::test]
+    async fn run_flushes_pending_lines_before_returning() {
+        // the only update fails along with its retry
+        let server = HttpStandIn::start(&[503, 503]).await;
+        let mut fleet = Fleet::new();
+        fleet.add(
+            "office",
+            Sensor::from_transport(
+                FakeTransport::default()
+                    .with_value(AranetService::READ_CURRENT_READINGS, READINGS.to_vec())
+                    .with_value(CommonService::READ_SERIAL_NO, b"12345".to_vec()),
+            ),
+        );
+        let updates = fleet.subscribe();
+        fleet.poll().await;
+        drop(fleet);
+
+        let mut sink = InfluxSink::new(config(server.url()));
+        sink.run(updates, CancellationToken::new()).await.unwrap();
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 3);
+        assert!(requests[2]
+            .body_text()
+            .starts_with("aranet4,name=office,serial=12345 co2_ppm=464i,"));
+        assert_eq!(sink.pending(), 0);
+    }
+}
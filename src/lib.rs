@@ -1,7 +1,28 @@
+pub mod alert;
+pub mod battery;
+pub mod btsnoop;
 pub mod error;
+pub mod fleet;
 pub mod history;
+pub mod influx;
+pub mod psychrometrics;
 pub mod readings;
 pub mod sensor;
+pub mod simulator;
+
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+#[cfg(feature = "prometheus")]
+pub mod prometheus;
+#[cfg(feature = "serde")]
+mod serde_seconds;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+#[cfg(feature = "webhook")]
+pub mod webhook;
+
+#[cfg(test)]
+mod testing;
 
 #[cfg(test)]
 mod tests {
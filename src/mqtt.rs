@@ -0,0 +1,692 @@
+//! Publishing readings to an MQTT broker, with Home Assistant discovery
+//!
+//! Each sensor's readings are published as JSON to its state topic, by default
+//! `{prefix}/{sensor_id}/state`, where `sensor_id` is the Bluetooth address without colons.
+//! When discovery is enabled, the first readings of a sensor are preceded by retained Home
+//! Assistant config messages, by default on
+//! `{discovery_prefix}/sensor/aranet4_{sensor_id}/{entity}/config`, so the sensor shows up
+//! automatically. The availability topic, by default `{prefix}/status`, carries
+//! `online`/`offline` and is also registered as the last will, so Home Assistant marks the
+//! sensors unavailable if the publisher disappears. After the connection to the broker is
+//! re-established, `online` and the discovery configs are published again.
+//!
+//! All three topics are templates in [`MqttConfig`]; each `{placeholder}` above is replaced by
+//! its value.
+use crate::fleet::SensorStatus;
+use async_trait::async_trait;
+use serde_json::json;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::sync::{
+    broadcast::{self, error::RecvError},
+    Notify,
+};
+use tokio_util::sync::CancellationToken;
+
+const ONLINE: &str = "online";
+const OFFLINE: &str = "offline";
+
+#[derive(Debug, Error)]
+pub enum MqttError {
+    #[error("MQTT client error")]
+    Client(#[from] rumqttc::ClientError),
+    #[error("Cannot encode payload")]
+    Json(#[from] serde_json::Error),
+}
+
+/// MQTT delivery guarantee
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum QoS {
+    AtMostOnce,
+    #[default]
+    AtLeastOnce,
+    ExactlyOnce,
+}
+
+impl From<QoS> for rumqttc::QoS {
+    fn from(qos: QoS) -> Self {
+        match qos {
+            QoS::AtMostOnce => rumqttc::QoS::AtMostOnce,
+            QoS::AtLeastOnce => rumqttc::QoS::AtLeastOnce,
+            QoS::ExactlyOnce => rumqttc::QoS::ExactlyOnce,
+        }
+    }
+}
+
+/// Topics and delivery options of an [`MqttPublisher`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MqttConfig {
+    /// Value of `{prefix}` in the topic templates
+    pub topic_prefix: String,
+    /// Home Assistant discovery prefix, or `None` to skip discovery
+    pub discovery_prefix: Option<String>,
+    /// Topic of a sensor's readings, with `{prefix}` and `{sensor_id}`
+    pub state_topic_template: String,
+    /// Topic carrying `online` or `offline`, with `{prefix}`
+    pub availability_topic_template: String,
+    /// Topic of the discovery config of each entity of a sensor, with `{discovery_prefix}`,
+    /// `{prefix}`, `{sensor_id}` and `{entity}`, the entity key such as `co2`
+    pub discovery_topic_template: String,
+    /// QoS of every message
+    pub qos: QoS,
+    /// Whether state messages are retained. Discovery and availability messages always are.
+    pub retain: bool,
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        Self {
+            topic_prefix: "aranet4".to_string(),
+            discovery_prefix: Some("homeassistant".to_string()),
+            state_topic_template: "{prefix}/{sensor_id}/state".to_string(),
+            availability_topic_template: "{prefix}/status".to_string(),
+            discovery_topic_template:
+                "{discovery_prefix}/sensor/aranet4_{sensor_id}/{entity}/config".to_string(),
+            qos: QoS::default(),
+            retain: false,
+        }
+    }
+}
+
+impl MqttConfig {
+    /// Topic carrying `online` or `offline`
+    pub fn availability_topic(&self) -> String {
+        self.availability_topic_template
+            .replace("{prefix}", &self.topic_prefix)
+    }
+    /// Topic the readings of a sensor are published to
+    pub fn state_topic(&self, sensor_id: &str) -> String {
+        self.state_topic_template
+            .replace("{prefix}", &self.topic_prefix)
+            .replace("{sensor_id}", sensor_id)
+    }
+    /// Topic of the discovery config of one entity of a sensor, or `None` without discovery
+    pub fn discovery_topic(&self, sensor_id: &str, entity: &str) -> Option<String> {
+        let discovery_prefix = self.discovery_prefix.as_deref()?;
+        Some(
+            self.discovery_topic_template
+                .replace("{discovery_prefix}", discovery_prefix)
+                .replace("{prefix}", &self.topic_prefix)
+                .replace("{sensor_id}", sensor_id)
+                .replace("{entity}", entity),
+        )
+    }
+}
+
+/// Where an [`MqttPublisher`] sends its messages
+#[async_trait]
+pub trait Broker: Send + Sync {
+    async fn publish(
+        &self,
+        topic: &str,
+        payload: Vec<u8>,
+        qos: QoS,
+        retain: bool,
+    ) -> Result<(), MqttError>;
+}
+
+#[async_trait]
+impl Broker for rumqttc::AsyncClient {
+    async fn publish(
+        &self,
+        topic: &str,
+        payload: Vec<u8>,
+        qos: QoS,
+        retain: bool,
+    ) -> Result<(), MqttError> {
+        rumqttc::AsyncClient::publish(self, topic, qos.into(), retain, payload).await?;
+        Ok(())
+    }
+}
+
+/// A measurement announced to Home Assistant
+struct Entity {
+    key: &'static str,
+    name: &'static str,
+    device_class: &'static str,
+    unit: &'static str,
+    field: &'static str,
+}
+
+const ENTITIES: &[Entity] = &[
+    Entity {
+        key: "co2",
+        name: "CO2",
+        device_class: "carbon_dioxide",
+        unit: "ppm",
+        field: "co2_ppm",
+    },
+    Entity {
+        key: "temperature",
+        name: "Temperature",
+        device_class: "temperature",
+        unit: "°F",
+        field: "temperature_f",
+    },
+    Entity {
+        key: "humidity",
+        name: "Humidity",
+        device_class: "humidity",
+        unit: "%",
+        field: "humidity_percent",
+    },
+    Entity {
+        key: "pressure",
+        name: "Pressure",
+        device_class: "atmospheric_pressure",
+        unit: "hPa",
+        field: "pressure_hpa",
+    },
+    Entity {
+        key: "battery",
+        name: "Battery",
+        device_class: "battery",
+        unit: "%",
+        field: "battery_percent",
+    },
+];
+
+/// Identifier of a sensor in topics and Home Assistant unique ids
+pub fn sensor_id(status: &SensorStatus) -> String {
+    let source = if status.address.is_empty() {
+        &status.name
+    } else {
+        &status.address
+    };
+    source
+        .chars()
+        .filter(|c| *c != ':')
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Publishes sensor readings to MQTT
+pub struct MqttPublisher {
+    broker: Box<dyn Broker>,
+    config: MqttConfig,
+    announced: Mutex<HashSet<String>>,
+    connected: Arc<Notify>,
+}
+
+impl MqttPublisher {
+    pub fn new(broker: impl Broker + 'static, config: MqttConfig) -> Self {
+        Self {
+            broker: Box::new(broker),
+            config,
+            announced: Mutex::new(HashSet::new()),
+            connected: Arc::new(Notify::new()),
+        }
+    }
+    /// Connect to the broker described by `options`, registering the availability topic as
+    /// the last will. The connection is driven by a spawned task that reconnects after errors.
+    pub fn connect(mut options: rumqttc::MqttOptions, config: MqttConfig) -> Self {
+        options.set_last_will(rumqttc::LastWill::new(
+            config.availability_topic(),
+            OFFLINE,
+            config.qos.into(),
+            true,
+        ));
+        let (client, mut event_loop) = rumqttc::AsyncClient::new(options, 16);
+        let publisher = Self::new(client, config);
+        let connected = publisher.connected.clone();
+        tokio::spawn(async move {
+            loop {
+                match event_loop.poll().await {
+                    Ok(rumqttc::Event::Incoming(rumqttc::Packet::ConnAck(_))) => {
+                        connected.notify_one()
+                    }
+                    Ok(_) => {}
+                    Err(rumqttc::ConnectionError::RequestsDone) => break,
+                    Err(_) => tokio::time::sleep(Duration::from_secs(1)).await,
+                }
+            }
+        });
+        publisher
+    }
+    pub fn config(&self) -> &MqttConfig {
+        &self.config
+    }
+    async fn send(&self, topic: &str, payload: Vec<u8>, retain: bool) -> Result<(), MqttError> {
+        self.broker
+            .publish(topic, payload, self.config.qos, retain)
+            .await
+    }
+    /// Mark the sensors as available
+    pub async fn announce_online(&self) -> Result<(), MqttError> {
+        self.send(&self.config.availability_topic(), ONLINE.into(), true)
+            .await
+    }
+    /// Mark the sensors as unavailable, as the last will would
+    pub async fn announce_offline(&self) -> Result<(), MqttError> {
+        self.send(&self.config.availability_topic(), OFFLINE.into(), true)
+            .await
+    }
+    /// Mark the sensors as available again after a (re)connection, replacing the last will the
+    /// broker may have published, and send the discovery configs again with the next readings
+    pub async fn reconnected(&self) -> Result<(), MqttError> {
+        self.announced.lock().expect("poisoned").clear();
+        self.announce_online().await
+    }
+    /// Send the Home Assistant discovery config of a sensor
+    pub async fn announce(&self, status: &SensorStatus) -> Result<(), MqttError> {
+        if self.config.discovery_prefix.is_none() {
+            return Ok(());
+        }
+        let id = sensor_id(status);
+        let device = json!({
+            "identifiers": [format!("aranet4_{}", id)],
+            "connections": if status.address.is_empty() {
+                json!([])
+            } else {
+                json!([["bluetooth", status.address]])
+            },
+            "name": status.name,
+            "manufacturer": "SAF Tehnika",
+            "model": "Aranet4",
+        });
+        for entity in ENTITIES {
+            let config = json!({
+                "name": entity.name,
+                "unique_id": format!("aranet4_{}_{}", id, entity.key),
+                "state_topic": self.config.state_topic(&id),
+                "value_template": format!("{{{{ value_json.{} }}}}", entity.field),
+                "device_class": entity.device_class,
+                "unit_of_measurement": entity.unit,
+                "state_class": "measurement",
+                "availability_topic": self.config.availability_topic(),
+                "device": device,
+            });
+            let Some(topic) = self.config.discovery_topic(&id, entity.key) else {
+                return Ok(());
+            };
+            self.send(&topic, serde_json::to_vec(&config)?, true)
+                .await?;
+        }
+        Ok(())
+    }
+    /// Publish the readings of a sensor, announcing it first if this is its first readings.
+    /// Statuses without readings are skipped.
+    pub async fn publish(&self, status: &SensorStatus) -> Result<(), MqttError> {
+        let readings = match (&status.readings, status.up) {
+            (Some(readings), true) => readings,
+            _ => return Ok(()),
+        };
+        let id = sensor_id(status);
+        let announced = self.announced.lock().expect("poisoned").contains(&id);
+        if !announced {
+            self.announce(status).await?;
+            self.announced.lock().expect("poisoned").insert(id.clone());
+        }
+        self.send(
+            &self.config.state_topic(&id),
+            serde_json::to_vec(readings)?,
+            self.config.retain,
+        )
+        .await
+    }
+    /// Publish every update from a [`Fleet`](crate::fleet::Fleet) until `cancel` is
+    /// cancelled or the fleet is dropped, then mark the sensors as unavailable.
+    /// Every (re)connection of a publisher created by [`connect`](Self::connect) is followed
+    /// by [`reconnected`](Self::reconnected).
+    pub async fn run(
+        &self,
+        mut updates: broadcast::Receiver<SensorStatus>,
+        cancel: CancellationToken,
+    ) -> Result<(), MqttError> {
+        self.announce_online().await?;
+        loop {
+            let status = tokio::select! {
+                _ = cancel.cancelled() => break,
+                _ = self.connected.notified() => {
+                    self.reconnected().await?;
+                    continue;
+                }
+                update = updates.recv() => match update {
+                    Ok(status) => status,
+                    // missed updates are superseded by the next ones
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => break,
+                },
+            };
+            self.publish(&status).await?;
+        }
+        self.announce_offline().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        fleet::Fleet,
+        sensor::{
+            protocol::AranetService,
+            transport::testing::{FakeTransport, READINGS},
+            Sensor,
+        },
+    };
+    use serde_json::Value;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+        sync::mpsc,
+    };
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct Message {
+        topic: String,
+        payload: Vec<u8>,
+        qos: QoS,
+        retain: bool,
+    }
+
+    impl Message {
+        fn json(&self) -> Value {
+            serde_json::from_slice(&self.payload).unwrap()
+        }
+    }
+
+    /// Broker stand-in recording every published message
+    #[derive(Default, Clone)]
+    struct RecordingBroker(Arc<Mutex<Vec<Message>>>);
+
+    impl RecordingBroker {
+        fn messages(&self) -> Vec<Message> {
+            self.0.lock().unwrap().clone()
+        }
+    }
+
+    #[async_trait]
+    impl Broker for RecordingBroker {
+        async fn publish(
+            &self,
+            topic: &str,
+            payload: Vec<u8>,
+            qos: QoS,
+            retain: bool,
+        ) -> Result<(), MqttError> {
+            self.0.lock().unwrap().push(Message {
+                topic: topic.to_string(),
+                payload,
+                qos,
+                retain,
+            });
+            Ok(())
+        }
+    }
+
+    async fn read_packet(stream: &mut TcpStream) -> std::io::Result<(u8, Vec<u8>)> {
+        let header = stream.read_u8().await?;
+        let (mut len, mut shift) = (0usize, 0);
+        loop {
+            let byte = stream.read_u8().await?;
+            len |= usize::from(byte & 0x7f) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        let mut body = vec![0; len];
+        stream.read_exact(&mut body).await?;
+        Ok((header, body))
+    }
+
+    /// Minimal MQTT 3.1.1 broker accepting QoS 0 messages, tagging each with the index of the
+    /// connection it arrived on. Every value sent on `disconnect` drops the current connection.
+    async fn fake_broker(
+        listener: TcpListener,
+        messages: mpsc::UnboundedSender<(usize, Message)>,
+        mut disconnect: mpsc::Receiver<()>,
+    ) -> std::io::Result<()> {
+        for connection in 0.. {
+            let (mut stream, _) = listener.accept().await?;
+            loop {
+                let (header, body) = tokio::select! {
+                    _ = disconnect.recv() => break,
+                    packet = read_packet(&mut stream) => match packet {
+                        Ok(packet) => packet,
+                        Err(_) => break,
+                    },
+                };
+                match header >> 4 {
+                    // CONNECT
+                    1 => stream.write_all(&[0x20, 0x02, 0x00, 0x00]).await?,
+                    // PUBLISH
+                    3 => {
+                        let len = usize::from(u16::from_be_bytes([body[0], body[1]]));
+                        let message = Message {
+                            topic: String::from_utf8(body[2..2 + len].to_vec()).unwrap(),
+                            payload: body[2 + len..].to_vec(),
+                            qos: QoS::AtMostOnce,
+                            retain: header & 1 == 1,
+                        };
+                        let _ = messages.send((connection, message));
+                    }
+                    // PINGREQ
+                    12 => stream.write_all(&[0xd0, 0x00]).await?,
+                    _ => {}
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Receive the messages of `connection` up to the first one on `topic`
+    async fn receive_until(
+        messages: &mut mpsc::UnboundedReceiver<(usize, Message)>,
+        connection: usize,
+        topic: &str,
+    ) -> Vec<Message> {
+        let mut received = vec![];
+        loop {
+            let (from, message) = messages.recv().await.unwrap();
+            if from != connection {
+                continue;
+            }
+            let done = message.topic == topic;
+            received.push(message);
+            if done {
+                return received;
+            }
+        }
+    }
+
+    fn office() -> Sensor {
+        Sensor::from_transport(
+            FakeTransport::default()
+                .with_value(AranetService::READ_CURRENT_READINGS, READINGS.to_vec()),
+        )
+    }
+
+    #[test]
+    fn sensor_ids() {
+        let status = SensorStatus {
+            address: "AA:BB:CC:DD:EE:FF".to_string(),
+            name: "Office".to_string(),
+            ..SensorStatus::default()
+        };
+        assert_eq!(sensor_id(&status), "aabbccddeeff");
+        let status = SensorStatus {
+            name: "Aranet4 1A2B3".to_string(),
+            ..SensorStatus::default()
+        };
+        assert_eq!(sensor_id(&status), "aranet4_1a2b3");
+    }
+
+    #[tokio::test]
+    async fn publishes_discovery_then_state() {
+        let mut fleet = Fleet::new();
+        fleet.add("office", office());
+        fleet.add("cellar", Sensor::from_transport(FakeTransport::default()));
+        let broker = RecordingBroker::default();
+        let publisher = MqttPublisher::new(
+            broker.clone(),
+            MqttConfig {
+                retain: true,
+                qos: QoS::ExactlyOnce,
+                ..MqttConfig::default()
+            },
+        );
+        let cancel = CancellationToken::new();
+        let updates = fleet.subscribe();
+
+        fleet.poll().await;
+        fleet.poll().await;
+        drop(fleet);
+        publisher.run(updates, cancel).await.unwrap();
+
+        let messages = broker.messages();
+        let topics: Vec<&str> = messages.iter().map(|m| m.topic.as_str()).collect();
+        assert_eq!(
+            topics,
+            [
+                "aranet4/status",
+                "homeassistant/sensor/aranet4_office/co2/config",
+                "homeassistant/sensor/aranet4_office/temperature/config",
+                "homeassistant/sensor/aranet4_office/humidity/config",
+                "homeassistant/sensor/aranet4_office/pressure/config",
+                "homeassistant/sensor/aranet4_office/battery/config",
+                "aranet4/office/state",
+                "aranet4/office/state",
+                "aranet4/status",
+            ]
+        );
+        assert_eq!(messages[0].payload, b"online");
+        assert_eq!(messages[8].payload, b"offline");
+        assert!(messages
+            .iter()
+            .all(|m| m.qos == QoS::ExactlyOnce && m.retain));
+
+        let co2 = messages[1].json();
+        assert_eq!(co2["device_class"], "carbon_dioxide");
+        assert_eq!(co2["unit_of_measurement"], "ppm");
+        assert_eq!(co2["state_topic"], "aranet4/office/state");
+        assert_eq!(co2["value_template"], "{{ value_json.co2_ppm }}");
+        assert_eq!(co2["availability_topic"], "aranet4/status");
+        assert_eq!(co2["unique_id"], "aranet4_office_co2");
+        assert_eq!(messages[2].json()["unit_of_measurement"], "°F");
+
+        let state = messages[6].json();
+        assert_eq!(state["co2_ppm"], 464);
+        assert_eq!(state["humidity_percent"], 36);
+    }
+
+    #[tokio::test]
+    async fn discovery_can_be_disabled() {
+        let broker = RecordingBroker::default();
+        let publisher = MqttPublisher::new(
+            broker.clone(),
+            MqttConfig {
+                topic_prefix: "home/co2".to_string(),
+                discovery_prefix: None,
+                ..MqttConfig::default()
+            },
+        );
+        let status = SensorStatus {
+            name: "office".to_string(),
+            readings: Some(office().read_current_values().await.unwrap()),
+            up: true,
+            ..SensorStatus::default()
+        };
+        publisher.publish(&status).await.unwrap();
+
+        let messages = broker.messages();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].topic, "home/co2/office/state");
+        assert_eq!(messages[0].qos, QoS::AtLeastOnce);
+        assert!(!messages[0].retain);
+    }
+
+    #[tokio::test]
+    async fn topics_follow_the_templates() {
+        let broker = RecordingBroker::default();
+        let publisher = MqttPublisher::new(
+            broker.clone(),
+            MqttConfig {
+                topic_prefix: "sensors".to_string(),
+                discovery_prefix: Some("ha".to_string()),
+                state_topic_template: "{prefix}/aranet/{sensor_id}".to_string(),
+                availability_topic_template: "{prefix}/aranet/availability".to_string(),
+                discovery_topic_template: "{discovery_prefix}/sensor/{sensor_id}_{entity}/config"
+                    .to_string(),
+                ..MqttConfig::default()
+            },
+        );
+        let status = SensorStatus {
+            name: "office".to_string(),
+            readings: Some(office().read_current_values().await.unwrap()),
+            up: true,
+            ..SensorStatus::default()
+        };
+        publisher.announce_online().await.unwrap();
+        publisher.publish(&status).await.unwrap();
+
+        let messages = broker.messages();
+        assert_eq!(messages[0].topic, "sensors/aranet/availability");
+        assert_eq!(messages[1].topic, "ha/sensor/office_co2/config");
+        let co2: serde_json::Value = serde_json::from_slice(&messages[1].payload).unwrap();
+        assert_eq!(co2["state_topic"], "sensors/aranet/office");
+        assert_eq!(co2["availability_topic"], "sensors/aranet/availability");
+        assert_eq!(messages.last().unwrap().topic, "sensors/aranet/office");
+    }
+
+    #[tokio::test]
+    async fn republishes_after_reconnect() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (messages_tx, mut messages) = mpsc::unbounded_channel();
+        let (disconnect, disconnect_rx) = mpsc::channel(1);
+        tokio::spawn(fake_broker(listener, messages_tx, disconnect_rx));
+
+        let mut fleet = Fleet::new();
+        fleet.add("office", office());
+        let publisher = Arc::new(MqttPublisher::connect(
+            rumqttc::MqttOptions::new("aranet4", "127.0.0.1", port),
+            MqttConfig {
+                qos: QoS::AtMostOnce,
+                ..MqttConfig::default()
+            },
+        ));
+        let cancel = CancellationToken::new();
+        let run = tokio::spawn({
+            let (publisher, updates, cancel) =
+                (publisher.clone(), fleet.subscribe(), cancel.clone());
+            async move { publisher.run(updates, cancel).await }
+        });
+
+        fleet.poll().await;
+        receive_until(&mut messages, 0, "aranet4/office/state").await;
+
+        disconnect.send(()).await.unwrap();
+        let online = receive_until(&mut messages, 1, "aranet4/status").await;
+        assert_eq!(online.len(), 1);
+        assert_eq!(online[0].payload, b"online");
+        assert!(online[0].retain);
+
+        fleet.poll().await;
+        let messages = receive_until(&mut messages, 1, "aranet4/office/state").await;
+        let topics: Vec<&str> = messages.iter().map(|m| m.topic.as_str()).collect();
+        assert_eq!(
+            topics,
+            [
+                "homeassistant/sensor/aranet4_office/co2/config",
+                "homeassistant/sensor/aranet4_office/temperature/config",
+                "homeassistant/sensor/aranet4_office/humidity/config",
+                "homeassistant/sensor/aranet4_office/pressure/config",
+                "homeassistant/sensor/aranet4_office/battery/config",
+                "aranet4/office/state",
+            ]
+        );
+
+        cancel.cancel();
+        run.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,290 @@
+//! Prometheus exporter serving the state of a [`Fleet`](crate::fleet::Fleet)
+//!
+//! Every sensor is labelled with its `address` and `name`. Sensors that have never been read
+//! successfully only report the scrape-health metrics.
+use crate::{
+    fleet::{FleetStatus, SensorStatus},
+    sensor::protocol::fahrenheit_to_celsius,
+};
+use std::fmt::Write as _;
+use std::io;
+use std::time::Duration;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tokio_util::sync::CancellationToken;
+
+/// Largest request accepted, in bytes
+const MAX_REQUEST: usize = 8192;
+
+/// How long a client may take to send its request before the connection is closed
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+struct Metric {
+    name: &'static str,
+    help: &'static str,
+    kind: &'static str,
+    value: fn(&SensorStatus) -> Option<f64>,
+}
+
+const METRICS: &[Metric] = &[
+    Metric {
+        name: "aranet4_co2_ppm",
+        help: "CO2 concentration in parts per million",
+        kind: "gauge",
+        value: |s| s.readings.as_ref().map(|r| r.co2_level.into()),
+    },
+    Metric {
+        name: "aranet4_temperature_celsius",
+        help: "Temperature in degrees Celsius",
+        kind: "gauge",
+        value: |s| {
+            s.readings
+                .as_ref()
+                .map(|r| fahrenheit_to_celsius(r.temperature).into())
+        },
+    },
+    Metric {
+        name: "aranet4_humidity_percent",
+        help: "Relative humidity in percent",
+        kind: "gauge",
+        value: |s| s.readings.as_ref().map(|r| r.humidity.into()),
+    },
+    Metric {
+        name: "aranet4_pressure_hpa",
+        help: "Atmospheric pressure in hectopascals",
+        kind: "gauge",
+        value: |s| s.readings.as_ref().map(|r| r.pressure.into()),
+    },
+    Metric {
+        name: "aranet4_battery_percent",
+        help: "Remaining battery charge in percent",
+        kind: "gauge",
+        value: |s| s.readings.as_ref().map(|r| r.battery.into()),
+    },
+    Metric {
+        name: "aranet4_battery_days_remaining",
+        help: "Days until the batteries are expected to run out",
+        kind: "gauge",
+        value: |s| s.battery.map(|b| b.days_remaining),
+    },
+    Metric {
+        name: "aranet4_battery_drain_percent_per_day",
+        help: "Battery drain in percent per day, measured or expected from the settings",
+        kind: "gauge",
+        value: |s| s.battery.map(|b| b.drain_per_day),
+    },
+    Metric {
+        name: "aranet4_up",
+        help: "Whether the most recent read of the sensor succeeded",
+        kind: "gauge",
+        value: |s| Some(if s.up { 1.0 } else { 0.0 }),
+    },
+    Metric {
+        name: "aranet4_last_success_timestamp_seconds",
+        help: "Unix time of the last successful read of the sensor",
+        kind: "gauge",
+        value: |s| s.last_success.map(|t| t.timestamp_millis() as f64 / 1000.0),
+    },
+    Metric {
+        name: "aranet4_read_errors_total",
+        help: "Failed reads of the sensor",
+        kind: "counter",
+        value: |s| Some(s.errors as f64),
+    },
+];
+
+/// Escape a label value for the Prometheus text format
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Render the state of the sensors in the Prometheus text exposition format
+pub fn render(statuses: &[SensorStatus]) -> String {
+    let mut out = String::new();
+    for metric in METRICS {
+        let _ = writeln!(out, "# HELP {} {}", metric.name, metric.help);
+        let _ = writeln!(out, "# TYPE {} {}", metric.name, metric.kind);
+        for status in statuses {
+            if let Some(value) = (metric.value)(status) {
+                let _ = writeln!(
+                    out,
+                    "{}{{address=\"{}\",name=\"{}\"}} {}",
+                    metric.name,
+                    escape(&status.address),
+                    escape(&status.name),
+                    value
+                );
+            }
+        }
+    }
+    out
+}
+
+/// Serve `/metrics` on `listener` until `cancel` is cancelled
+pub async fn serve(
+    listener: TcpListener,
+    status: FleetStatus,
+    cancel: CancellationToken,
+) -> io::Result<()> {
+    loop {
+        let (stream, _) = tokio::select! {
+            _ = cancel.cancelled() => return Ok(()),
+            accepted = listener.accept() => accepted?,
+        };
+        let status = status.clone();
+        tokio::spawn(async move {
+            // a failed scrape only affects that client
+            let _ = respond(stream, &status).await;
+        });
+    }
+}
+
+async fn read_request(stream: &mut TcpStream) -> io::Result<Vec<u8>> {
+    let mut request = vec![];
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < MAX_REQUEST {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+    Ok(request)
+}
+
+async fn respond(mut stream: TcpStream, status: &FleetStatus) -> io::Result<()> {
+    // dropping the stream on timeout closes the connection
+    let request = tokio::time::timeout(REQUEST_TIMEOUT, read_request(&mut stream))
+        .await
+        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+    let request = String::from_utf8_lossy(&request);
+    let mut request_line = request.lines().next().unwrap_or_default().split(' ');
+    let (method, path) = (request_line.next(), request_line.next());
+
+    let (code, content_type, body) = match (method, path) {
+        (Some("GET"), Some("/metrics")) => (
+            "200 OK",
+            "text/plain; version=0.0.4",
+            render(&status.snapshot()),
+        ),
+        (Some("GET"), _) => ("404 Not Found", "text/plain", "Not Found\n".to_string()),
+        _ => (
+            "405 Method Not Allowed",
+            "text/plain",
+            "Method Not Allowed\n".to_string(),
+        ),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        code,
+        content_type,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        fleet::Fleet,
+        sensor::{
+            protocol::AranetService,
+            transport::testing::{FakeTransport, READINGS},
+            Sensor,
+        },
+    };
+
+    async fn scrape(addr: std::net::SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).as_bytes())
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[test]
+    fn label_escaping() {
+        let status = SensorStatus {
+            name: "Lab \"A\"\\1".to_string(),
+            ..SensorStatus::default()
+        };
+        assert!(
+            render(&[status]).contains("aranet4_up{address=\"\",name=\"Lab \\\"A\\\"\\\\1\"} 0\n")
+        );
+    }
+
+    #[tokio::test]
+    async fn scrape_fake_sensor() {
+        let mut fleet = Fleet::new();
+        fleet.add(
+            "office",
+            Sensor::from_transport(
+                FakeTransport::default()
+                    .with_value(AranetService::READ_CURRENT_READINGS, READINGS.to_vec()),
+            ),
+        );
+        fleet.add("cellar", Sensor::from_transport(FakeTransport::default()));
+        fleet.poll().await;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let cancel = CancellationToken::new();
+        let server = tokio::spawn(serve(listener, fleet.status(), cancel.clone()));
+
+        let response = scrape(addr, "/metrics").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("# TYPE aranet4_co2_ppm gauge\n"));
+        assert!(response.contains("aranet4_co2_ppm{address=\"\",name=\"office\"} 464\n"));
+        let celsius: f64 = response
+            .lines()
+            .find_map(|l| {
+                l.strip_prefix("aranet4_temperature_celsius{address=\"\",name=\"office\"} ")
+            })
+            .and_then(|v| v.parse().ok())
+            .expect("temperature metric");
+        assert!((celsius - 19.0).abs() < 0.01);
+        assert!(response.contains("aranet4_humidity_percent{address=\"\",name=\"office\"} 36\n"));
+        assert!(response.contains("aranet4_battery_percent{address=\"\",name=\"office\"} 90\n"));
+        assert!(response.contains("aranet4_battery_days_remaining{address=\"\",name=\"office\"} "));
+        assert!(!response.contains("aranet4_battery_days_remaining{address=\"\",name=\"cellar\"}"));
+        assert!(response.contains("aranet4_up{address=\"\",name=\"office\"} 1\n"));
+        assert!(response.contains("aranet4_up{address=\"\",name=\"cellar\"} 0\n"));
+        assert!(response.contains("aranet4_read_errors_total{address=\"\",name=\"cellar\"} 1\n"));
+        assert!(!response.contains("aranet4_co2_ppm{address=\"\",name=\"cellar\"}"));
+
+        let response = scrape(addr, "/").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        cancel.cancel();
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn silent_client_is_disconnected() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let cancel = CancellationToken::new();
+        let server = tokio::spawn(serve(listener, Fleet::new().status(), cancel.clone()));
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let start = tokio::time::Instant::now();
+        let mut response = vec![];
+        stream.read_to_end(&mut response).await.unwrap();
+        assert!(response.is_empty());
+        assert!(start.elapsed() >= REQUEST_TIMEOUT);
+
+        cancel.cancel();
+        server.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,254 @@
+//! Values derived from temperature, relative humidity and pressure
+//!
+//! Import [`Psychrometrics`] to get them on [`SensorReadings`] and [`DataRecord`]. Temperatures
+//! follow the rest of the crate and are in Fahrenheit; the functions of this module work in
+//! Celsius.
+//!
+//! Saturation vapour pressure uses the Magnus form with the coefficients of Alduchov and
+//! Eskridge (1996), which is within 0.1% of the WMO tables between -40 °C and 50 °C:
+//!
+//! ```text
+//! es(T) = 6.1094 · exp(17.625 · T / (T + 243.04))    hPa, T in °C
+//! e     = RH / 100 · es(T)
+//! ```
+use crate::{
+    history::record::DataRecord,
+    readings::SensorReadings,
+    sensor::protocol::{celsius_to_fahrenheit, fahrenheit_to_celsius},
+};
+
+const MAGNUS_A: f32 = 17.625;
+const MAGNUS_B: f32 = 243.04;
+const MAGNUS_C: f32 = 6.1094;
+/// Specific gas constant of water vapour, J/(kg·K)
+const WATER_VAPOUR_GAS_CONSTANT: f32 = 461.5;
+/// Ratio of the molar masses of water and dry air
+const MOLAR_MASS_RATIO: f32 = 0.621945;
+/// Sea level pressure of the ICAO standard atmosphere, hPa
+pub const STANDARD_PRESSURE: f32 = 1013.25;
+
+/// Saturation vapour pressure over water in hPa
+pub fn saturation_vapour_pressure(celsius: f32) -> f32 {
+    MAGNUS_C * (MAGNUS_A * celsius / (celsius + MAGNUS_B)).exp()
+}
+
+/// Partial pressure of water vapour in hPa
+pub fn vapour_pressure(celsius: f32, relative_humidity: f32) -> f32 {
+    relative_humidity / 100.0 * saturation_vapour_pressure(celsius)
+}
+
+/// Dew point in °C, the Magnus formula solved for the temperature at which `e = es`.
+/// `None` for 0% humidity, where there is no dew point.
+pub fn dew_point(celsius: f32, relative_humidity: f32) -> Option<f32> {
+    if relative_humidity <= 0.0 {
+        return None;
+    }
+    let gamma = (relative_humidity / 100.0).ln() + MAGNUS_A * celsius / (MAGNUS_B + celsius);
+    Some(MAGNUS_B * gamma / (MAGNUS_A - gamma))
+}
+
+/// Absolute humidity in g/m³, from the ideal gas law: `e / (Rv · T)`
+pub fn absolute_humidity(celsius: f32, relative_humidity: f32) -> f32 {
+    let pascal = vapour_pressure(celsius, relative_humidity) * 100.0;
+    pascal / (WATER_VAPOUR_GAS_CONSTANT * (celsius + 273.15)) * 1000.0
+}
+
+/// Humidity ratio (mixing ratio) in kg of water per kg of dry air: `0.621945 · e / (p - e)`
+pub fn humidity_ratio(celsius: f32, relative_humidity: f32, pressure: f32) -> f32 {
+    let e = vapour_pressure(celsius, relative_humidity);
+    MOLAR_MASS_RATIO * e / (pressure - e)
+}
+
+/// Heat index in °F, following the US National Weather Service.
+///
+/// Steadman's simple formula is used when it gives less than 80 °F, and the Rothfusz
+/// regression, with the NWS adjustments for very dry and very humid air, above that.
+pub fn heat_index_fahrenheit(fahrenheit: f32, relative_humidity: f32) -> f32 {
+    let (t, rh) = (fahrenheit, relative_humidity);
+    let simple = 0.5 * (t + 61.0 + (t - 68.0) * 1.2 + rh * 0.094);
+    if (simple + t) / 2.0 < 80.0 {
+        return simple;
+    }
+    let mut hi = -42.379 + 2.049_015_3 * t + 10.143_331 * rh
+        - 0.224_755_4 * t * rh
+        - 0.006_837_83 * t * t
+        - 0.054_817_17 * rh * rh
+        + 0.001_228_74 * t * t * rh
+        + 0.000_852_82 * t * rh * rh
+        - 0.000_001_99 * t * t * rh * rh;
+    if rh < 13.0 && (80.0..=112.0).contains(&t) {
+        hi -= (13.0 - rh) / 4.0 * ((17.0 - (t - 95.0).abs()) / 17.0).sqrt();
+    } else if rh > 85.0 && (80.0..=87.0).contains(&t) {
+        hi += (rh - 85.0) / 10.0 * (87.0 - t) / 5.0;
+    }
+    hi
+}
+
+/// Altitude in metres at which the standard atmosphere has this pressure in hPa:
+/// `44307.694 · (1 - (p / 1013.25)^0.190284)`.
+///
+/// Weather moves this by about 8 m per hPa, so it is only an estimate of the real altitude.
+pub fn pressure_altitude(pressure: f32) -> f32 {
+    44_307.694 * (1.0 - (pressure / STANDARD_PRESSURE).powf(0.190_284))
+}
+
+/// Derived values for anything with a temperature, relative humidity and pressure
+pub trait Psychrometrics {
+    /// Temperature in Fahrenheit
+    fn temperature_fahrenheit(&self) -> f32;
+    /// Relative humidity in percent
+    fn relative_humidity(&self) -> f32;
+    /// Pressure in hPa
+    fn pressure_hpa(&self) -> f32;
+
+    /// Dew point in Fahrenheit, `None` at 0% humidity
+    fn dew_point(&self) -> Option<f32> {
+        dew_point(
+            fahrenheit_to_celsius(self.temperature_fahrenheit()),
+            self.relative_humidity(),
+        )
+        .map(celsius_to_fahrenheit)
+    }
+    /// Absolute humidity in g/m³
+    fn absolute_humidity(&self) -> f32 {
+        absolute_humidity(
+            fahrenheit_to_celsius(self.temperature_fahrenheit()),
+            self.relative_humidity(),
+        )
+    }
+    /// Humidity ratio in kg of water per kg of dry air, at the measured pressure
+    fn humidity_ratio(&self) -> f32 {
+        humidity_ratio(
+            fahrenheit_to_celsius(self.temperature_fahrenheit()),
+            self.relative_humidity(),
+            self.pressure_hpa(),
+        )
+    }
+    /// Heat index in Fahrenheit
+    fn heat_index(&self) -> f32 {
+        heat_index_fahrenheit(self.temperature_fahrenheit(), self.relative_humidity())
+    }
+    /// Estimated altitude in metres, see [`pressure_altitude`]
+    fn pressure_altitude(&self) -> f32 {
+        pressure_altitude(self.pressure_hpa())
+    }
+}
+
+impl Psychrometrics for SensorReadings {
+    fn temperature_fahrenheit(&self) -> f32 {
+        self.temperature
+    }
+    fn relative_humidity(&self) -> f32 {
+        self.humidity.into()
+    }
+    fn pressure_hpa(&self) -> f32 {
+        self.pressure
+    }
+}
+
+impl Psychrometrics for DataRecord {
+    fn temperature_fahrenheit(&self) -> f32 {
+        self.temperature
+    }
+    fn relative_humidity(&self) -> f32 {
+        self.humidity.into()
+    }
+    fn pressure_hpa(&self) -> f32 {
+        self.pressure
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f32, expected: f32, tolerance: f32) {
+        assert!(
+            (actual - expected).abs() <= tolerance,
+            "{actual} is not within {tolerance} of {expected}"
+        );
+    }
+
+    #[test]
+    fn dew_point_table() {
+        // (°C, %RH, dew point °C) from psychrometric tables
+        for (t, rh, expected) in [
+            (20.0, 50.0, 9.3),
+            (25.0, 60.0, 16.7),
+            (30.0, 80.0, 26.2),
+            (10.0, 90.0, 8.4),
+            (0.0, 70.0, -4.8),
+            (22.0, 100.0, 22.0),
+        ] {
+            assert_close(dew_point(t, rh).unwrap(), expected, 0.15);
+        }
+        assert_eq!(dew_point(20.0, 0.0), None);
+    }
+
+    #[test]
+    fn absolute_humidity_table() {
+        // saturated air, g/m³
+        for (t, expected) in [(0.0, 4.85), (10.0, 9.4), (20.0, 17.3), (30.0, 30.4)] {
+            assert_close(absolute_humidity(t, 100.0), expected, 0.15);
+        }
+        assert_close(absolute_humidity(20.0, 50.0), 8.65, 0.05);
+    }
+
+    #[test]
+    fn humidity_ratio_table() {
+        // ASHRAE Fundamentals, saturated air at 101.325 kPa
+        for (t, expected) in [(0.0, 0.003789), (20.0, 0.014758), (30.0, 0.027329)] {
+            assert_close(
+                humidity_ratio(t, 100.0, STANDARD_PRESSURE),
+                expected,
+                0.0002,
+            );
+        }
+    }
+
+    #[test]
+    fn heat_index_table() {
+        // (°F, %RH, heat index °F) from the NWS heat index chart
+        for (t, rh, expected) in [
+            (80.0, 40.0, 80.0),
+            (90.0, 60.0, 100.0),
+            (100.0, 40.0, 109.0),
+            (86.0, 90.0, 105.0),
+            (104.0, 10.0, 98.0),
+        ] {
+            assert_close(heat_index_fahrenheit(t, rh), expected, 1.0);
+        }
+        // below the regression range the index is close to the temperature
+        assert_close(heat_index_fahrenheit(70.0, 50.0), 69.0, 0.5);
+    }
+
+    #[test]
+    fn pressure_altitude_table() {
+        // ICAO standard atmosphere
+        for (p, expected) in [
+            (1013.25, 0.0),
+            (898.76, 1000.0),
+            (795.01, 2000.0),
+            (540.20, 5000.0),
+        ] {
+            assert_close(pressure_altitude(p), expected, 5.0);
+        }
+    }
+
+    #[test]
+    fn readings_and_records() {
+        let readings = SensorReadings::new(464, 68.0, 1013.25, 50, 90, 1);
+        assert_close(readings.dew_point().unwrap(), 48.7, 0.3);
+        assert_close(readings.absolute_humidity(), 8.65, 0.05);
+        assert_close(readings.pressure_altitude(), 0.0, 0.5);
+
+        let record = DataRecord {
+            temperature: 90.0,
+            humidity: 60,
+            pressure: 1013.25,
+            co2: 500,
+        };
+        assert_close(record.heat_index(), 100.0, 1.0);
+        assert_close(record.humidity_ratio(), 0.0182, 0.0005);
+    }
+}
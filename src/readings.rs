@@ -1,3 +1,5 @@
 //! Describes readings retreived from the sensor
+mod advertisement;
 mod data;
+pub use advertisement::{Advertisement, MANUFACTURER_ID};
 pub use data::SensorReadings;
@@ -0,0 +1,82 @@
+use super::SensorReadings;
+use aranet4_core::advertisement;
+use std::time::Duration;
+
+pub use aranet4_core::advertisement::MANUFACTURER_ID;
+
+/// Readings broadcast by an Aranet4 with Smart Home integration enabled
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Advertisement {
+    pub readings: SensorReadings,
+    /// Time between measurements
+    #[cfg_attr(
+        feature = "serde",
+        serde(
+            rename = "interval_seconds",
+            with = "crate::serde_seconds::std_duration"
+        )
+    )]
+    pub interval: Duration,
+    /// Time since the readings were measured, when the advertisement was sent
+    #[cfg_attr(
+        feature = "serde",
+        serde(
+            rename = "seconds_since_update",
+            with = "crate::serde_seconds::std_duration"
+        )
+    )]
+    pub since_update: Duration,
+    /// Increases with every measurement, wrapping around
+    pub counter: u8,
+}
+
+impl Advertisement {
+    /// Decode manufacturer specific data following [`MANUFACTURER_ID`].
+    ///
+    /// Returns `None` for data without readings, as sent with Smart Home integration off.
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        let advertisement = advertisement::Advertisement::decode(data).ok()?;
+        let detailed = advertisement.readings;
+        Some(Self {
+            readings: detailed.readings.into(),
+            interval: Duration::from_secs(detailed.interval.into()),
+            since_update: Duration::from_secs(detailed.since_update.into()),
+            counter: advertisement.counter,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sensor::transport::testing::READINGS;
+
+    #[test]
+    fn decode() {
+        let data = [
+            &[0x22, 0x13, 0x04, 0x01, 0x00, 0x0c, 0x0f, 0x01][..], // flags and version
+            &READINGS,
+            &[0x2c, 0x01, 0x3c, 0x00, 0x07, 0x00],
+        ]
+        .concat();
+        let advertisement = Advertisement::decode(&data).unwrap();
+        assert_eq!(advertisement.readings.co2_level, 464);
+        assert_eq!(advertisement.readings.battery, 90);
+        assert_eq!(advertisement.interval, Duration::from_secs(300));
+        assert_eq!(advertisement.since_update, Duration::from_secs(60));
+        assert_eq!(advertisement.counter, 7);
+
+        // integration off: only flags and version are sent
+        assert!(Advertisement::decode(&[0x02, 0x13, 0x04, 0x01, 0x00, 0x0c, 0x0f]).is_none());
+        assert!(Advertisement::decode(&data[..21]).is_none());
+
+        #[cfg(feature = "serde")]
+        {
+            let json = serde_json::to_value(&advertisement).expect("serialize");
+            assert_eq!(json["interval_seconds"], 300);
+            assert_eq!(json["seconds_since_update"], 60);
+            assert_eq!(json["readings"]["co2_ppm"], 464);
+        }
+    }
+}
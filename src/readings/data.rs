@@ -1,24 +1,31 @@
-use byteorder::{LittleEndian, ReadBytesExt};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 use std::fmt;
-use std::io::Cursor;
 
 use crate::{
-    error::SensorError,
-    sensor::protocol::{convert_pressure, convert_temperature},
+    error::{Operation, SensorError},
+    sensor::protocol::{convert_pressure, convert_temperature, malformed, AranetService},
 };
+use aranet4_core::readings::Readings;
 
 /// One-time readings from sensor
-#[derive(Clone, Default)]
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct SensorReadings {
     /// CO2 level, expressed in ppm
+    #[cfg_attr(feature = "serde", serde(rename = "co2_ppm"))]
     pub co2_level: u16,
     /// Temperature in Fahrenheit
+    #[cfg_attr(feature = "serde", serde(rename = "temperature_f"))]
     pub temperature: f32,
-    /// Pressure in kpa
+    /// Pressure in hPa
+    #[cfg_attr(feature = "serde", serde(rename = "pressure_hpa"))]
     pub pressure: f32,
     /// Humidity in percent humidity
+    #[cfg_attr(feature = "serde", serde(rename = "humidity_percent"))]
     pub humidity: u8,
     /// Battery percent
+    #[cfg_attr(feature = "serde", serde(rename = "battery_percent"))]
     pub battery: u8,
     status_color: u8,
 }
@@ -26,30 +33,84 @@ impl fmt::Display for SensorReadings {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
-            "CO2: {}ppm, Temperature: {}F, Pressure : {}kpa, Humidity : {}, Battery: {}, Status Color: {}",
+            "CO2: {}ppm, Temperature: {}F, Pressure : {}hPa, Humidity : {}, Battery: {}, Status Color: {}",
             self.co2_level, self.temperature, self.pressure, self.humidity, self.battery, self.status_color
         )
     }
 }
 
 impl SensorReadings {
-    /// construct a `SensorReadings` from a raw bytestream retrieved from the sensor
-    pub(crate) fn from_raw(bytes: Vec<u8>) -> Result<SensorReadings, SensorError> {
-        let mut reader = Cursor::new(bytes);
-        let co2_level = reader.read_u16::<LittleEndian>()?;
-        let temperature = reader.read_u16::<LittleEndian>()?;
-        let pressure = reader.read_u16::<LittleEndian>()?;
-        let humidity = reader.read_u8()?;
-        let battery = reader.read_u8()?;
-        let status_color = reader.read_u8()?;
-
-        Ok(SensorReadings {
+    /// Readings from already decoded values, e.g. ones read back from storage
+    pub fn new(
+        co2_level: u16,
+        temperature: f32,
+        pressure: f32,
+        humidity: u8,
+        battery: u8,
+        status_color: u8,
+    ) -> Self {
+        Self {
             co2_level,
-            temperature: convert_temperature(temperature),
-            pressure: convert_pressure(pressure),
+            temperature,
+            pressure,
             humidity,
             battery,
             status_color,
-        })
+        }
+    }
+    /// Colour of the status light: 1 green, 2 yellow, 3 red
+    pub fn status_color(&self) -> u8 {
+        self.status_color
+    }
+    /// construct a `SensorReadings` from a raw bytestream retrieved from the sensor
+    pub(crate) fn from_raw(bytes: Vec<u8>) -> Result<SensorReadings, SensorError> {
+        Readings::decode(&bytes)
+            .map(SensorReadings::from)
+            .map_err(|e| {
+                malformed(
+                    AranetService::READ_CURRENT_READINGS,
+                    Operation::ReadCurrentValues,
+                    &bytes,
+                    e,
+                )
+            })
+    }
+}
+
+impl From<Readings> for SensorReadings {
+    fn from(readings: Readings) -> Self {
+        SensorReadings {
+            co2_level: readings.co2,
+            temperature: convert_temperature(readings.temperature),
+            pressure: convert_pressure(readings.pressure),
+            humidity: readings.humidity,
+            battery: readings.battery,
+            status_color: readings.status,
+        }
+    }
+}
+
+#[cfg(all(test, feature = "serde"))]
+mod tests {
+    use super::*;
+    use crate::sensor::transport::testing::READINGS;
+
+    #[test]
+    fn serde_field_names() {
+        let readings = SensorReadings::from_raw(READINGS.to_vec()).expect("readings");
+        let json = serde_json::to_value(&readings).expect("serialize");
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "co2_ppm": 464,
+                "temperature_f": readings.temperature,
+                "pressure_hpa": readings.pressure,
+                "humidity_percent": 36,
+                "battery_percent": 90,
+                "status_color": 1,
+            })
+        );
+        let back: SensorReadings = serde_json::from_value(json).expect("deserialize");
+        assert_eq!(back.to_string(), readings.to_string());
     }
 }
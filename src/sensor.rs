@@ -1,33 +1,53 @@
 //! Sensor abstractions
-use crate::{error::SensorError, readings::SensorReadings};
+use crate::{
+    error::{Operation, SensorError},
+    readings::SensorReadings,
+};
 use btleplug::{
-    api::{BDAddr, Central, Characteristic, Manager as _, Peripheral as _, ScanFilter},
+    api::{BDAddr, Central, Manager as _, Peripheral as _, ScanFilter, WriteType},
     platform::{Adapter, Manager, Peripheral},
 };
-use byteorder::{LittleEndian, ReadBytesExt};
-use std::collections::BTreeSet;
-use std::io::Cursor;
+use std::future::Future;
 use std::time::Duration;
 use tokio::time;
 use uuid::Uuid;
 
 pub(crate) mod protocol;
-use protocol::AranetService;
+pub mod recording;
+pub mod settings;
+pub mod transport;
+pub use protocol::LogParameter;
+use protocol::{AranetService, PacketReader};
+use recording::{Recorder, RecordingTransport};
+use transport::{BtleTransport, Transport};
+
+/// Limits on how long the sensor may take to respond
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeouts {
+    /// Maximum duration of a single read, write, connect or discovery
+    pub operation: Duration,
+    /// Maximum duration of a complete history download
+    pub history: Duration,
+}
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            operation: Duration::from_secs(10),
+            history: Duration::from_secs(300),
+        }
+    }
+}
 
 pub struct Sensor {
-    pub(crate) aranet: Peripheral,
-    characteristics: BTreeSet<Characteristic>,
+    transport: Box<dyn Transport>,
+    pub(crate) timeouts: Timeouts,
 }
 
 impl Sensor {
-    async fn find_sensor_by_name(central: &Adapter) -> Result<Peripheral, SensorError> {
+    async fn find_sensor_by_name(central: &Adapter, name: &str) -> Result<Peripheral, SensorError> {
         for p in central.peripherals().await? {
             if let Some(peripheral) = p.properties().await? {
-                if peripheral
-                    .local_name
-                    .iter()
-                    .any(|name| name.contains("Aranet4"))
-                {
+                if peripheral.local_name.iter().any(|n| n.contains(name)) {
                     return Ok(p);
                 }
             }
@@ -45,59 +65,243 @@ impl Sensor {
         }
         Err(SensorError::CannotFindAddress(addr.to_string()))
     }
-    async fn init(central: &Adapter, addr: Option<String>) -> Result<Sensor, SensorError> {
-        let aranet = if let Some(address) = addr {
-            Sensor::find_sensor_by_addr(central, &address).await?
-        } else {
-            Sensor::find_sensor_by_name(central).await?
+    async fn init(
+        central: &Adapter,
+        selector: &SensorSelector,
+        timeouts: Timeouts,
+    ) -> Result<Sensor, SensorError> {
+        let aranet = match selector {
+            SensorSelector::Any => Sensor::find_sensor_by_name(central, "Aranet4").await?,
+            SensorSelector::Address(address) => {
+                Sensor::find_sensor_by_addr(central, address).await?
+            }
+            SensorSelector::Name(name) => Sensor::find_sensor_by_name(central, name).await?,
         };
 
-        aranet.connect().await?;
-        aranet.discover_services().await?;
-        let chars = aranet.characteristics();
+        with_timeout(timeouts.operation, Operation::Connect, async {
+            Ok(aranet.connect().await?)
+        })
+        .await?;
+        with_timeout(timeouts.operation, Operation::DiscoverServices, async {
+            Ok(aranet.discover_services().await?)
+        })
+        .await?;
 
         Ok(Sensor {
-            aranet,
-            characteristics: chars,
+            transport: Box::new(BtleTransport::new(aranet)),
+            timeouts,
         })
     }
-    pub(crate) fn get_characteristic(&self, uuid: Uuid) -> Option<&Characteristic> {
-        self.characteristics.iter().find(|c| c.uuid == uuid)
+    /// Create a sensor that communicates over the given transport
+    pub fn from_transport(transport: impl Transport + 'static) -> Sensor {
+        Sensor {
+            transport: Box::new(transport),
+            timeouts: Timeouts::default(),
+        }
     }
-    pub async fn read_current_values(&self) -> Result<SensorReadings, SensorError> {
-        if let Some(cmd_chars) = self.get_characteristic(AranetService::READ_CURRENT_READINGS) {
-            let vals = self.aranet.read(cmd_chars).await?;
-            SensorReadings::from_raw(vals)
-        } else {
-            Err(SensorError::CannotFindCharacteristics)
+    /// Record every read and write made from now on into `recorder`
+    pub fn record(self, recorder: Recorder) -> Sensor {
+        Sensor {
+            transport: Box::new(RecordingTransport::new(self.transport, recorder)),
+            timeouts: self.timeouts,
         }
     }
-    pub async fn last_update_time(&self) -> Result<Duration, SensorError> {
-        if let Some(cmd_chars) = self.get_characteristic(AranetService::READ_SECONDS_SINCE_UPDATE) {
-            let bytes = self.aranet.read(cmd_chars).await?;
-            let mut reader = Cursor::new(bytes);
-            let seconds_ago = reader.read_u16::<LittleEndian>()?;
-            Ok(Duration::from_secs(seconds_ago.into()))
+    /// Bluetooth address of the sensor, if the transport knows it
+    pub fn address(&self) -> Option<String> {
+        self.transport.address()
+    }
+    /// The timeouts currently applied to sensor operations
+    pub fn timeouts(&self) -> Timeouts {
+        self.timeouts
+    }
+    /// Change the timeouts applied to sensor operations
+    pub fn set_timeouts(&mut self, timeouts: Timeouts) {
+        self.timeouts = timeouts;
+    }
+    /// Read a characteristic, bounded by the operation timeout
+    pub(crate) async fn read(
+        &self,
+        uuid: Uuid,
+        operation: Operation,
+    ) -> Result<Vec<u8>, SensorError> {
+        self.require_characteristic(uuid, operation)?;
+        with_timeout(
+            self.timeouts.operation,
+            operation,
+            self.transport.read(uuid),
+        )
+        .await
+    }
+    /// Write a characteristic, bounded by the operation timeout
+    pub(crate) async fn write(
+        &self,
+        uuid: Uuid,
+        data: &[u8],
+        write_type: WriteType,
+        operation: Operation,
+    ) -> Result<(), SensorError> {
+        self.require_characteristic(uuid, operation)?;
+        with_timeout(
+            self.timeouts.operation,
+            operation,
+            self.transport.write(uuid, data, write_type),
+        )
+        .await
+    }
+    pub(crate) fn require_characteristic(
+        &self,
+        uuid: Uuid,
+        operation: Operation,
+    ) -> Result<(), SensorError> {
+        if self.transport.has_characteristic(uuid) {
+            Ok(())
         } else {
-            Err(SensorError::CannotFindCharacteristics)
+            Err(SensorError::CannotFindCharacteristic { uuid, operation })
         }
     }
+    pub async fn read_current_values(&self) -> Result<SensorReadings, SensorError> {
+        let vals = self
+            .read(
+                AranetService::READ_CURRENT_READINGS,
+                Operation::ReadCurrentValues,
+            )
+            .await?;
+        SensorReadings::from_raw(vals)
+    }
+    pub async fn last_update_time(&self) -> Result<Duration, SensorError> {
+        let operation = Operation::ReadLastUpdateTime;
+        let bytes = self
+            .read(AranetService::READ_SECONDS_SINCE_UPDATE, operation)
+            .await?;
+        let mut reader =
+            PacketReader::new(&bytes, AranetService::READ_SECONDS_SINCE_UPDATE, operation);
+        let seconds_ago = reader.read_u16("seconds_since_update")?;
+        Ok(Duration::from_secs(seconds_ago.into()))
+    }
+}
+
+/// Fail with [`SensorError::Timeout`] if `fut` does not complete within `limit`
+pub(crate) async fn with_timeout<T>(
+    limit: Duration,
+    operation: Operation,
+    fut: impl Future<Output = Result<T, SensorError>>,
+) -> Result<T, SensorError> {
+    time::timeout(limit, fut)
+        .await
+        .map_err(|_| SensorError::Timeout {
+            operation,
+            after: limit,
+        })?
+}
+
+/// Which sensor to connect to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SensorSelector {
+    /// The first Aranet4 found
+    Any,
+    /// The sensor with this Bluetooth address, e.g. `AA:BB:CC:DD:EE:FF`
+    Address(String),
+    /// The first sensor whose advertised name contains this string
+    Name(String),
+}
+
+/// An Aranet4 found while scanning
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DiscoveredSensor {
+    pub address: String,
+    pub name: Option<String>,
+    /// Signal strength in dBm
+    pub rssi: Option<i16>,
 }
 
 pub struct SensorManager {}
 impl SensorManager {
+    /// Connect to the sensor with address `addr`, or to the first Aranet4 found
     pub async fn init(addr: Option<String>) -> Result<Sensor, SensorError> {
+        let selector = addr.map_or(SensorSelector::Any, SensorSelector::Address);
+        SensorManager::connect(&selector).await
+    }
+    /// Like [`SensorManager::init`], applying `timeouts` from the connection on
+    pub async fn init_with_timeouts(
+        addr: Option<String>,
+        timeouts: Timeouts,
+    ) -> Result<Sensor, SensorError> {
+        let selector = addr.map_or(SensorSelector::Any, SensorSelector::Address);
+        SensorManager::connect_with_timeouts(&selector, timeouts).await
+    }
+    /// Connect to the sensor picked by `selector`
+    pub async fn connect(selector: &SensorSelector) -> Result<Sensor, SensorError> {
+        SensorManager::connect_with_timeouts(selector, Timeouts::default()).await
+    }
+    /// Connect to the sensor picked by `selector`, bounding connection and service discovery
+    /// by the operation timeout of `timeouts` and applying them to the returned sensor
+    pub async fn connect_with_timeouts(
+        selector: &SensorSelector,
+        timeouts: Timeouts,
+    ) -> Result<Sensor, SensorError> {
+        let central = SensorManager::scan_for(Duration::from_secs(2)).await?;
+        Sensor::init(&central, selector, timeouts).await
+    }
+    /// List the Aranet4 sensors advertising within `duration`
+    pub async fn scan(duration: Duration) -> Result<Vec<DiscoveredSensor>, SensorError> {
+        let central = SensorManager::scan_for(duration).await?;
+        let mut found = vec![];
+        for p in central.peripherals().await? {
+            if let Some(properties) = p.properties().await? {
+                let is_aranet = properties.services.contains(&AranetService::UUID)
+                    || properties
+                        .local_name
+                        .iter()
+                        .any(|name| name.contains("Aranet4"));
+                if is_aranet {
+                    found.push(DiscoveredSensor {
+                        address: properties.address.to_string(),
+                        name: properties.local_name,
+                        rssi: properties.rssi,
+                    });
+                }
+            }
+        }
+        Ok(found)
+    }
+    /// Start scanning on the first bluetooth adapter and wait for advertisements
+    async fn scan_for(duration: Duration) -> Result<Adapter, SensorError> {
         let manager = Manager::new().await?;
 
         // get the first bluetooth adapter
         if let Some(central) = manager.adapters().await?.into_iter().next() {
             central.start_scan(ScanFilter::default()).await?;
-            time::sleep(Duration::from_secs(2)).await;
-
-            let sensor = Sensor::init(&central, addr).await?;
-            Ok(sensor)
+            time::sleep(duration).await;
+            Ok(central)
         } else {
             Err(SensorError::CreationError)
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use transport::testing::{FakeTransport, READINGS};
+
+    #[tokio::test]
+    async fn read_through_transport() {
+        let sensor = Sensor::from_transport(
+            FakeTransport::default()
+                .with_value(AranetService::READ_CURRENT_READINGS, READINGS.to_vec()),
+        );
+        let readings = sensor.read_current_values().await.expect("readings");
+        assert_eq!(readings.co2_level, 464);
+        assert_eq!(readings.humidity, 36);
+        assert_eq!(readings.battery, 90);
+        let err = sensor.last_update_time().await.unwrap_err();
+        assert!(matches!(
+            err,
+            SensorError::CannotFindCharacteristic {
+                uuid: AranetService::READ_SECONDS_SINCE_UPDATE,
+                operation: Operation::ReadLastUpdateTime,
+            }
+        ));
+    }
+}
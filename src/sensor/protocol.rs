@@ -1,7 +1,10 @@
 #![allow(unused)]
-use serde_repr::{Deserialize_repr, Serialize_repr};
+use aranet4_core::{DecodeError, Reader};
 
+use crate::error::{Operation, Packet, SensorError};
 use uuid::Uuid;
+
+pub use aranet4_core::{history::LogParameter, settings::Command};
 #[non_exhaustive]
 pub struct AranetService;
 #[non_exhaustive]
@@ -12,6 +15,9 @@ pub struct CommonService;
 impl AranetService {
     pub const UUID: Uuid = Uuid::from_u128(0xf0cd1400_95da_4f4b_9ac8_aa55d312af0c);
     pub const READ_CURRENT_READINGS: Uuid = Uuid::from_u128(0xf0cd1503_95da_4f4b_9ac8_aa55d312af0c);
+    /// Current readings followed by the interval and the seconds since the last update
+    pub const READ_CURRENT_READINGS_DETAILED: Uuid =
+        Uuid::from_u128(0xf0cd3001_95da_4f4b_9ac8_aa55d312af0c);
     pub const READ_INTERVAL: Uuid = Uuid::from_u128(0xf0cd2002_95da_4f4b_9ac8_aa55d312af0c);
     pub const READ_SECONDS_SINCE_UPDATE: Uuid =
         Uuid::from_u128(0xf0cd2004_95da_4f4b_9ac8_aa55d312af0c);
@@ -36,22 +42,70 @@ impl CommonService {
     pub const READ_BATTERY: Uuid = Uuid::from_u128(0x00002a19_0000_1000_8000_00805f9b34fb);
 }
 
-#[derive(Debug, Serialize_repr, Deserialize_repr, PartialEq, Eq, Clone, Copy)]
-#[repr(u8)]
-pub enum LogParameter {
-    Temperature = 1,
-    Humidity = 2,
-    Pressure = 3,
-    Co2 = 4,
-}
-
 /// Convert a temperature from u16 representation to Fahrenheit
 pub(crate) fn convert_temperature(temp: u16) -> f32 {
-    (temp as f32 / 20.0) * 1.8 + 32_f32
+    celsius_to_fahrenheit(aranet4_core::readings::temperature_celsius(temp))
+}
+/// Convert a temperature in Fahrenheit to Celsius
+pub(crate) fn fahrenheit_to_celsius(f: f32) -> f32 {
+    (f - 32.0) / 1.8
+}
+/// Convert a temperature in Celsius to Fahrenheit
+pub(crate) fn celsius_to_fahrenheit(c: f32) -> f32 {
+    c * 1.8 + 32.0
 }
 /// Convert a pressure from u16 representation
 pub(crate) fn convert_pressure(pressure: u16) -> f32 {
-    pressure as f32 / 10.0
+    aranet4_core::readings::pressure_hpa(pressure)
+}
+
+/// Error for a packet from `uuid` that `aranet4_core` could not decode
+pub(crate) fn malformed(
+    uuid: Uuid,
+    operation: Operation,
+    bytes: &[u8],
+    error: DecodeError,
+) -> SensorError {
+    let kind = match error {
+        DecodeError::Truncated { .. } => std::io::ErrorKind::UnexpectedEof,
+        DecodeError::Invalid { .. } => std::io::ErrorKind::InvalidData,
+    };
+    SensorError::MalformedPacket {
+        uuid,
+        operation,
+        field: error.field(),
+        packet: Packet(bytes.to_vec()),
+        source: std::io::Error::new(kind, error),
+    }
+}
+
+/// Reads little-endian fields from a packet, naming the field that failed on error
+pub(crate) struct PacketReader<'a> {
+    reader: Reader<'a>,
+    bytes: &'a [u8],
+    uuid: Uuid,
+    operation: Operation,
+}
+
+impl<'a> PacketReader<'a> {
+    pub(crate) fn new(bytes: &'a [u8], uuid: Uuid, operation: Operation) -> Self {
+        Self {
+            reader: Reader::new(bytes),
+            bytes,
+            uuid,
+            operation,
+        }
+    }
+    pub(crate) fn read_u8(&mut self, field: &'static str) -> Result<u8, SensorError> {
+        self.reader
+            .u8(field)
+            .map_err(|e| malformed(self.uuid, self.operation, self.bytes, e))
+    }
+    pub(crate) fn read_u16(&mut self, field: &'static str) -> Result<u16, SensorError> {
+        self.reader
+            .u16(field)
+            .map_err(|e| malformed(self.uuid, self.operation, self.bytes, e))
+    }
 }
 
 #[cfg(test)]
@@ -59,12 +113,21 @@ mod tests {
     use super::*;
 
     #[test]
-    fn log_parameter_serialize() {
-        let x = LogParameter::Co2;
-        let bin = bincode::serialize(&x).expect("value to serialize");
-        assert_eq!(&bin, &[4u8]);
-        let bin = &[3u8];
-        let x: LogParameter = bincode::deserialize(bin).expect("value to deserialize");
-        assert_eq!(x, LogParameter::Pressure);
+    fn packet_reader_names_field() {
+        let bytes = [0x10, 0x02, 0x05];
+        let mut reader = PacketReader::new(
+            &bytes,
+            AranetService::READ_CURRENT_READINGS,
+            Operation::ReadCurrentValues,
+        );
+        assert_eq!(reader.read_u16("co2_level").unwrap(), 0x0210);
+        let err = reader.read_u16("temperature").unwrap_err();
+        match err {
+            SensorError::MalformedPacket { field, packet, .. } => {
+                assert_eq!(field, "temperature");
+                assert_eq!(packet, Packet(vec![0x10, 0x02, 0x05]));
+            }
+            e => panic!("unexpected error {:?}", e),
+        }
     }
 }
@@ -0,0 +1,791 @@
+//! Recording the traffic between a [`Sensor`](super::Sensor) and its device, and replaying it
+//!
+//! A [`RecordingTransport`] passes every read and write through to another transport and logs
+//! it to a [`Recorder`], with the time it started relative to the start of the recording. A
+//! [`ReplayTransport`] answers from a [`Recording`] instead of a device, in the same order and
+//! optionally with the same timing, so a session captured in the field can be replayed as a
+//! regression test. The crate polls the device rather than subscribing to notifications, so
+//! reads and writes are all the traffic there is.
+//!
+//! Recordings are plain text, one line per event:
+//!
+//! ```text
+//! # aranet4 recording 1
+//! address AA:BB:CC:DD:EE:FF
+//! characteristic f0cd1503-95da-4f4b-9ac8-aa55d312af0c
+//! 12 read f0cd1503-95da-4f4b-9ac8-aa55d312af0c d0017c011c26245a01
+//! 40 write f0cd1402-95da-4f4b-9ac8-aa55d312af0c 61010100
+//! 95 read f0cd2005-95da-4f4b-9ac8-aa55d312af0c - error not-connected Bluetooth Error: Not connected
+//! 130 read f0cd2005-95da-4f4b-9ac8-aa55d312af0c - unanswered
+//! ```
+//!
+//! Times are milliseconds since the start of the recording and data is hex, `-` when empty.
+//! `write` is a write without response and `write-request` one with a response. A failed
+//! operation ends in `error`, the kind of Bluetooth error and its message, and one abandoned
+//! before the device answered, for example on a timeout, in `unanswered`. The kinds are
+//! `device-not-found`, `not-connected`, `permission-denied`, `not-supported`,
+//! `timed-out:<milliseconds>` and `other`. `characteristic` lines list the characteristics
+//! the device was found to have.
+use super::transport::Transport;
+use crate::error::SensorError;
+use async_trait::async_trait;
+use btleplug::api::WriteType;
+use std::{
+    collections::BTreeSet,
+    fmt,
+    fs::File,
+    io::{self, LineWriter, Write},
+    path::Path,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use thiserror::Error;
+use tokio::time::Instant;
+use uuid::Uuid;
+
+/// First line of every recording
+const HEADER: &str = "# aranet4 recording 1";
+
+#[derive(Debug, Error)]
+pub enum RecordingError {
+    #[error("Cannot read or write recording")]
+    Io(#[from] io::Error),
+    #[error("Invalid recording at line {line}: {message}")]
+    Parse { line: usize, message: String },
+}
+
+/// What an event did to a characteristic
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write(WriteType),
+}
+
+/// The Bluetooth error a failed operation ended with, so a replay can fail the same way
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureKind {
+    DeviceNotFound,
+    NotConnected,
+    PermissionDenied,
+    NotSupported,
+    TimedOut(Duration),
+    /// Any other error, including those that did not come from Bluetooth
+    Other,
+}
+
+impl FailureKind {
+    fn of(error: &SensorError) -> Self {
+        match error {
+            SensorError::BluetoothError(e) => match e {
+                btleplug::Error::DeviceNotFound => FailureKind::DeviceNotFound,
+                btleplug::Error::NotConnected => FailureKind::NotConnected,
+                btleplug::Error::PermissionDenied => FailureKind::PermissionDenied,
+                btleplug::Error::NotSupported(_) => FailureKind::NotSupported,
+                btleplug::Error::TimedOut(after) => FailureKind::TimedOut(*after),
+                _ => FailureKind::Other,
+            },
+            _ => FailureKind::Other,
+        }
+    }
+
+    /// The error to fail a replayed operation with
+    fn error(self, message: &str) -> SensorError {
+        match self {
+            FailureKind::DeviceNotFound => btleplug::Error::DeviceNotFound,
+            FailureKind::NotConnected => btleplug::Error::NotConnected,
+            FailureKind::PermissionDenied => btleplug::Error::PermissionDenied,
+            FailureKind::NotSupported => btleplug::Error::NotSupported(message.to_string()),
+            FailureKind::TimedOut(after) => btleplug::Error::TimedOut(after),
+            FailureKind::Other => btleplug::Error::Other(message.to_string().into()),
+        }
+        .into()
+    }
+}
+
+impl fmt::Display for FailureKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FailureKind::DeviceNotFound => write!(f, "device-not-found"),
+            FailureKind::NotConnected => write!(f, "not-connected"),
+            FailureKind::PermissionDenied => write!(f, "permission-denied"),
+            FailureKind::NotSupported => write!(f, "not-supported"),
+            FailureKind::TimedOut(after) => write!(f, "timed-out:{}", after.as_millis()),
+            FailureKind::Other => write!(f, "other"),
+        }
+    }
+}
+
+impl FromStr for FailureKind {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        Ok(match text {
+            "device-not-found" => FailureKind::DeviceNotFound,
+            "not-connected" => FailureKind::NotConnected,
+            "permission-denied" => FailureKind::PermissionDenied,
+            "not-supported" => FailureKind::NotSupported,
+            "other" => FailureKind::Other,
+            _ => match text.strip_prefix("timed-out:") {
+                Some(millis) => FailureKind::TimedOut(Duration::from_millis(
+                    millis
+                        .parse()
+                        .map_err(|e| format!("invalid timeout: {}", e))?,
+                )),
+                None => return Err(format!("unknown error kind `{}`", text)),
+            },
+        })
+    }
+}
+
+/// How an operation ended
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Done,
+    /// The operation failed with this kind of error and message
+    Failed {
+        kind: FailureKind,
+        message: String,
+    },
+    /// The operation was abandoned before the device answered
+    Unanswered,
+}
+
+/// One read or write
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    /// Time the operation started, since the start of the recording
+    pub at: Duration,
+    pub access: Access,
+    pub uuid: Uuid,
+    /// Bytes read, or written
+    pub data: Vec<u8>,
+    pub outcome: Outcome,
+}
+
+impl Event {
+    /// The operation without its time and outcome, e.g. `read f0cd1503-...`
+    fn operation(&self) -> String {
+        operation(self.access, self.uuid, &self.data)
+    }
+}
+
+fn operation(access: Access, uuid: Uuid, data: &[u8]) -> String {
+    match access {
+        Access::Read => format!("read {}", uuid),
+        Access::Write(WriteType::WithoutResponse) => format!("write {} {}", uuid, hex(data)),
+        Access::Write(WriteType::WithResponse) => format!("write-request {} {}", uuid, hex(data)),
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ", self.at.as_millis())?;
+        match self.access {
+            Access::Read => write!(f, "read {} {}", self.uuid, hex(&self.data))?,
+            Access::Write(_) => write!(f, "{}", self.operation())?,
+        }
+        match &self.outcome {
+            Outcome::Done => Ok(()),
+            Outcome::Failed { kind, message } => write!(f, " error {} {}", kind, message),
+            Outcome::Unanswered => write!(f, " unanswered"),
+        }
+    }
+}
+
+impl FromStr for Event {
+    type Err = String;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let mut fields = line.splitn(5, ' ');
+        let mut field = |name: &str| fields.next().ok_or_else(|| format!("missing {}", name));
+        let at = field("time")?
+            .parse()
+            .map_err(|e| format!("invalid time: {}", e))?;
+        let access = match field("operation")? {
+            "read" => Access::Read,
+            "write" => Access::Write(WriteType::WithoutResponse),
+            "write-request" => Access::Write(WriteType::WithResponse),
+            other => return Err(format!("unknown operation `{}`", other)),
+        };
+        let uuid = field("characteristic")?
+            .parse()
+            .map_err(|e| format!("invalid characteristic: {}", e))?;
+        let data = unhex(field("data")?)?;
+        let outcome = match fields.next() {
+            None => Outcome::Done,
+            Some("unanswered") => Outcome::Unanswered,
+            Some(rest) => match rest.strip_prefix("error ") {
+                Some(error) => {
+                    let (kind, message) = error.split_once(' ').unwrap_or((error, ""));
+                    Outcome::Failed {
+                        kind: kind.parse()?,
+                        message: message.to_string(),
+                    }
+                }
+                None => return Err(format!("unknown outcome `{}`", rest)),
+            },
+        };
+        Ok(Event {
+            at: Duration::from_millis(at),
+            access,
+            uuid,
+            data,
+            outcome,
+        })
+    }
+}
+
+fn hex(data: &[u8]) -> String {
+    if data.is_empty() {
+        return "-".to_string();
+    }
+    data.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn unhex(text: &str) -> Result<Vec<u8>, String> {
+    if text == "-" {
+        return Ok(vec![]);
+    }
+    if text.len() % 2 != 0 {
+        return Err(format!("odd number of hex digits in `{}`", text));
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| {
+            text.get(i..i + 2)
+                .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+                .ok_or_else(|| format!("invalid hex `{}`", text))
+        })
+        .collect()
+}
+
+/// A recorded session with one device
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Recording {
+    pub address: Option<String>,
+    /// Characteristics the device was found to have
+    pub characteristics: BTreeSet<Uuid>,
+    /// Reads and writes in the order they started
+    pub events: Vec<Event>,
+}
+
+impl Recording {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, RecordingError> {
+        std::fs::read_to_string(path)?.parse()
+    }
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), RecordingError> {
+        Ok(std::fs::write(path, self.to_string())?)
+    }
+}
+
+impl fmt::Display for Recording {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}", HEADER)?;
+        if let Some(address) = &self.address {
+            writeln!(f, "address {}", address)?;
+        }
+        for uuid in &self.characteristics {
+            writeln!(f, "characteristic {}", uuid)?;
+        }
+        for event in &self.events {
+            writeln!(f, "{}", event)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Recording {
+    type Err = RecordingError;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let mut recording = Recording::default();
+        for (i, line) in text.lines().enumerate() {
+            let parse_error = |message: String| RecordingError::Parse {
+                line: i + 1,
+                message,
+            };
+            let line = line.trim_end();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Some(address) = line.strip_prefix("address ") {
+                recording.address = Some(address.to_string());
+            } else if let Some(uuid) = line.strip_prefix("characteristic ") {
+                let uuid = uuid
+                    .parse()
+                    .map_err(|e| parse_error(format!("invalid characteristic: {}", e)))?;
+                recording.characteristics.insert(uuid);
+            } else {
+                recording.events.push(line.parse().map_err(parse_error)?);
+            }
+        }
+        // events are logged as they finish, which may not be the order they started in
+        recording.events.sort_by_key(|event| event.at);
+        Ok(recording)
+    }
+}
+
+struct RecorderState {
+    start: Instant,
+    recording: Recording,
+    file: Option<LineWriter<File>>,
+}
+
+impl RecorderState {
+    fn write_line(&mut self, line: impl fmt::Display) {
+        if let Some(file) = &mut self.file {
+            // a recording that cannot be written must not break the session it records
+            if writeln!(file, "{}", line).is_err() {
+                self.file = None;
+            }
+        }
+    }
+}
+
+/// Collects the events of a [`RecordingTransport`]. Clones share the same recording.
+#[derive(Clone)]
+pub struct Recorder(Arc<Mutex<RecorderState>>);
+
+impl Default for Recorder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Recorder {
+    /// Record in memory, starting now
+    pub fn new() -> Self {
+        Self(Arc::new(Mutex::new(RecorderState {
+            start: Instant::now(),
+            recording: Recording::default(),
+            file: None,
+        })))
+    }
+    /// Also write every event to a new file at `path` as it is recorded, so the recording
+    /// survives a crash or a session that never ends
+    pub fn create(path: impl AsRef<Path>) -> Result<Self, RecordingError> {
+        let mut file = LineWriter::new(File::create(path)?);
+        writeln!(file, "{}", HEADER)?;
+        let recorder = Self::new();
+        recorder.state().file = Some(file);
+        Ok(recorder)
+    }
+    /// Everything recorded so far
+    pub fn recording(&self) -> Recording {
+        self.state().recording.clone()
+    }
+    fn state(&self) -> std::sync::MutexGuard<'_, RecorderState> {
+        self.0.lock().expect("recorder poisoned")
+    }
+    /// Time since the start, in the whole milliseconds a recording keeps
+    fn elapsed(&self) -> Duration {
+        Duration::from_millis(self.state().start.elapsed().as_millis() as u64)
+    }
+    fn set_address(&self, address: String) {
+        let mut state = self.state();
+        state.write_line(format_args!("address {}", address));
+        state.recording.address = Some(address);
+    }
+    fn add_characteristic(&self, uuid: Uuid) {
+        let mut state = self.state();
+        if state.recording.characteristics.insert(uuid) {
+            state.write_line(format_args!("characteristic {}", uuid));
+        }
+    }
+    fn push(&self, event: Event) {
+        let mut state = self.state();
+        state.write_line(&event);
+        let events = &mut state.recording.events;
+        let position = events.partition_point(|e| e.at <= event.at);
+        events.insert(position, event);
+    }
+}
+
+/// An operation in progress, recorded as unanswered if it is dropped before it finishes
+struct Pending<'a> {
+    recorder: &'a Recorder,
+    event: Option<Event>,
+}
+
+impl<'a> Pending<'a> {
+    fn start(recorder: &'a Recorder, access: Access, uuid: Uuid, data: &[u8]) -> Self {
+        let event = Event {
+            at: recorder.elapsed(),
+            access,
+            uuid,
+            data: data.to_vec(),
+            outcome: Outcome::Unanswered,
+        };
+        Self {
+            recorder,
+            event: Some(event),
+        }
+    }
+    fn finish(mut self, data: Option<&[u8]>, outcome: Outcome) {
+        if let Some(mut event) = self.event.take() {
+            if let Some(data) = data {
+                event.data = data.to_vec();
+            }
+            event.outcome = outcome;
+            self.recorder.push(event);
+        }
+    }
+}
+
+impl Drop for Pending<'_> {
+    fn drop(&mut self) {
+        if let Some(event) = self.event.take() {
+            self.recorder.push(event);
+        }
+    }
+}
+
+/// The message of `error` and its sources, on one line
+fn describe(error: &SensorError) -> String {
+    let mut message = error.to_string();
+    let mut source = std::error::Error::source(error);
+    while let Some(cause) = source {
+        message = format!("{}: {}", message, cause);
+        source = cause.source();
+    }
+    message.replace(['\r', '\n'], " ")
+}
+
+/// [`Transport`] that records every read and write passed through to `inner`
+pub struct RecordingTransport<T> {
+    inner: T,
+    recorder: Recorder,
+}
+
+impl<T: Transport> RecordingTransport<T> {
+    pub fn new(inner: T, recorder: Recorder) -> Self {
+        if let Some(address) = inner.address() {
+            recorder.set_address(address);
+        }
+        Self { inner, recorder }
+    }
+    pub fn recorder(&self) -> &Recorder {
+        &self.recorder
+    }
+}
+
+#[async_trait]
+impl<T: Transport> Transport for RecordingTransport<T> {
+    fn address(&self) -> Option<String> {
+        self.inner.address()
+    }
+    fn has_characteristic(&self, uuid: Uuid) -> bool {
+        let found = self.inner.has_characteristic(uuid);
+        if found {
+            self.recorder.add_characteristic(uuid);
+        }
+        found
+    }
+    async fn read(&self, uuid: Uuid) -> Result<Vec<u8>, SensorError> {
+        let pending = Pending::start(&self.recorder, Access::Read, uuid, &[]);
+        let result = self.inner.read(uuid).await;
+        match &result {
+            Ok(data) => pending.finish(Some(data), Outcome::Done),
+            Err(e) => pending.finish(
+                None,
+                Outcome::Failed {
+                    kind: FailureKind::of(e),
+                    message: describe(e),
+                },
+            ),
+        }
+        result
+    }
+    async fn write(
+        &self,
+        uuid: Uuid,
+        data: &[u8],
+        write_type: WriteType,
+    ) -> Result<(), SensorError> {
+        let pending = Pending::start(&self.recorder, Access::Write(write_type), uuid, data);
+        let result = self.inner.write(uuid, data, write_type).await;
+        match &result {
+            Ok(()) => pending.finish(None, Outcome::Done),
+            Err(e) => pending.finish(
+                None,
+                Outcome::Failed {
+                    kind: FailureKind::of(e),
+                    message: describe(e),
+                },
+            ),
+        }
+        result
+    }
+}
+
+/// [`Transport`] that answers from a [`Recording`].
+///
+/// Reads and writes must come in the recorded order: the characteristic of a read, and the
+/// characteristic, data and write type of a write must match the next event, or the
+/// operation fails with [`SensorError::ReplayMismatch`]. Failed operations fail again with
+/// the recorded kind of error and message, and unanswered ones never complete.
+pub struct ReplayTransport {
+    recording: Recording,
+    timing: bool,
+    /// Index of the next event, and when the first one was replayed
+    position: Mutex<(usize, Option<Instant>)>,
+}
+
+impl ReplayTransport {
+    pub fn new(recording: Recording) -> Self {
+        Self {
+            recording,
+            timing: false,
+            position: Mutex::new((0, None)),
+        }
+    }
+    /// Wait until the recorded time of each event, relative to the first, before answering
+    pub fn with_timing(mut self) -> Self {
+        self.timing = true;
+        self
+    }
+    /// Number of events not replayed yet
+    pub fn remaining(&self) -> usize {
+        let (next, _) = *self.position.lock().expect("replay position poisoned");
+        self.recording.events.len() - next
+    }
+
+    /// Take the next event if it is the expected operation
+    async fn replay(&self, access: Access, uuid: Uuid, data: &[u8]) -> Result<&Event, SensorError> {
+        let (event, due) =
+            {
+                let mut position = self.position.lock().expect("replay position poisoned");
+                let (next, started) = &mut *position;
+                let actual = operation(access, uuid, data);
+                let event = self.recording.events.get(*next).ok_or_else(|| {
+                    SensorError::ReplayMismatch {
+                        expected: "the end of the recording".to_string(),
+                        actual: actual.clone(),
+                    }
+                })?;
+                let expected = event.operation();
+                if expected != actual {
+                    return Err(SensorError::ReplayMismatch { expected, actual });
+                }
+                *next += 1;
+                let started = *started.get_or_insert_with(Instant::now);
+                let first = self.recording.events[0].at;
+                (event, started + (event.at - first))
+            };
+        if self.timing {
+            tokio::time::sleep_until(due).await;
+        }
+        match &event.outcome {
+            Outcome::Done => Ok(event),
+            Outcome::Failed { kind, message } => Err(kind.error(message)),
+            Outcome::Unanswered => futures::future::pending().await,
+        }
+    }
+}
+
+#[async_trait]
+impl Transport for ReplayTransport {
+    fn address(&self) -> Option<String> {
+        self.recording.address.clone()
+    }
+    fn has_characteristic(&self, uuid: Uuid) -> bool {
+        self.recording.characteristics.contains(&uuid)
+    }
+    async fn read(&self, uuid: Uuid) -> Result<Vec<u8>, SensorError> {
+        let event = self.replay(Access::Read, uuid, &[]).await?;
+        Ok(event.data.clone())
+    }
+    async fn write(
+        &self,
+        uuid: Uuid,
+        data: &[u8],
+        write_type: WriteType,
+    ) -> Result<(), SensorError> {
+        self.replay(Access::Write(write_type), uuid, data)
+            .await
+            .map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        error::Operation,
+        sensor::{protocol::AranetService, transport::testing::FakeTransport, Sensor, Timeouts},
+        simulator::{SimulatedSensor, SimulatorConfig},
+    };
+    use chrono::Duration as ChronoDuration;
+
+    fn simulator() -> SimulatedSensor {
+        SimulatedSensor::new(SimulatorConfig {
+            running_for: ChronoDuration::hours(12),
+            ..SimulatorConfig::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn record_and_replay_session() {
+        let recorder = Recorder::new();
+        let sensor = Sensor::from_transport(simulator()).record(recorder.clone());
+        let readings = sensor.read_current_values().await.unwrap();
+        let history = sensor.get_historical_data().await.unwrap();
+        sensor
+            .set_measurement_interval(crate::sensor::settings::MeasurementInterval::TwoMinutes)
+            .await
+            .unwrap();
+
+        let recording: Recording = recorder.recording().to_string().parse().unwrap();
+        assert_eq!(recording, recorder.recording());
+        assert!(recording
+            .characteristics
+            .contains(&AranetService::READ_HISTORY_READINGS));
+        // one read of the current values, then a request and chunks for each parameter
+        assert_eq!(recording.events[0].access, Access::Read);
+        assert_eq!(recording.events[1].data, vec![0x61, 1, 1, 0]);
+
+        let replay = std::sync::Arc::new(ReplayTransport::new(recording));
+        let sensor = Sensor::from_transport(replay.clone());
+        let replayed = sensor.read_current_values().await.unwrap();
+        assert_eq!(replayed.co2_level, readings.co2_level);
+        let replayed = sensor.get_historical_data().await.unwrap();
+        assert_eq!(replayed.as_records(), history.as_records());
+        assert_eq!(replay.remaining(), 1);
+
+        // anything but the recorded write is a mismatch
+        let err = sensor
+            .set_measurement_interval(crate::sensor::settings::MeasurementInterval::OneMinute)
+            .await
+            .unwrap_err();
+        match err {
+            SensorError::ReplayMismatch { expected, actual } => {
+                assert_eq!(
+                    expected,
+                    format!("write-request {} 9002", AranetService::WRITE_CMD)
+                );
+                assert_eq!(
+                    actual,
+                    format!("write-request {} 9001", AranetService::WRITE_CMD)
+                );
+            }
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failures_and_timeouts() {
+        let recorder = Recorder::new();
+        // history was never requested, so the fake hangs on the history read
+        let fake = FakeTransport::default()
+            .with_value(AranetService::READ_INTERVAL, vec![0x2c])
+            .hang_after(crate::sensor::LogParameter::Temperature);
+        let mut sensor = Sensor::from_transport(fake).record(recorder.clone());
+        sensor.set_timeouts(Timeouts {
+            operation: Duration::from_secs(2),
+            ..Timeouts::default()
+        });
+        assert!(sensor.measurement_interval().await.is_err());
+        tokio::time::advance(Duration::from_secs(1)).await;
+        let err = sensor.get_historical_data().await.unwrap_err();
+        assert!(matches!(err, SensorError::Timeout { .. }));
+
+        let recording = recorder.recording();
+        assert_eq!(recording.events.len(), 3);
+        assert_eq!(recording.events[1].at, Duration::from_secs(1));
+        assert_eq!(recording.events[2].outcome, Outcome::Unanswered);
+        let text = recording.to_string();
+        assert!(text.contains(&format!(
+            "1000 write {} 61010100\n1000 read {} - unanswered\n",
+            AranetService::WRITE_CMD,
+            AranetService::READ_HISTORY_READINGS
+        )));
+
+        // replayed with timing, the same reads fail and time out at the same moments
+        let mut sensor =
+            Sensor::from_transport(ReplayTransport::new(text.parse().unwrap()).with_timing());
+        sensor.set_timeouts(Timeouts {
+            operation: Duration::from_secs(2),
+            ..Timeouts::default()
+        });
+        let start = Instant::now();
+        assert!(matches!(
+            sensor.measurement_interval().await,
+            Err(SensorError::MalformedPacket {
+                operation: Operation::ReadMeasurementInterval,
+                ..
+            })
+        ));
+        let err = sensor.get_historical_data().await.unwrap_err();
+        assert!(matches!(err, SensorError::Timeout { .. }));
+        assert_eq!(start.elapsed(), Duration::from_secs(3));
+    }
+
+    #[test]
+    fn parse_errors() {
+        let text = format!(
+            "{}\n\n12 read {} d0 oops\n",
+            HEADER,
+            AranetService::WRITE_CMD
+        );
+        match text.parse::<Recording>() {
+            Err(RecordingError::Parse { line, message }) => {
+                assert_eq!(line, 3);
+                assert_eq!(message, "unknown outcome `oops`");
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+        let failed: Event =
+            "5 read f0cd2005-95da-4f4b-9ac8-aa55d312af0c - error other Bluetooth Error: gone"
+                .parse()
+                .unwrap();
+        assert_eq!(
+            failed.outcome,
+            Outcome::Failed {
+                kind: FailureKind::Other,
+                message: "Bluetooth Error: gone".into()
+            }
+        );
+        assert!(
+            "5 read f0cd2005-95da-4f4b-9ac8-aa55d312af0c - error Bluetooth Error: gone"
+                .parse::<Event>()
+                .is_err()
+        );
+        assert!("5 read f0cd2005-95da-4f4b-9ac8-aa55d312af0c abc"
+            .parse::<Event>()
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn replayed_failures_keep_their_kind() {
+        for error in [
+            btleplug::Error::DeviceNotFound,
+            btleplug::Error::NotConnected,
+            btleplug::Error::TimedOut(Duration::from_secs(5)),
+            btleplug::Error::PermissionDenied,
+        ] {
+            let error = SensorError::from(error);
+            let event = Event {
+                at: Duration::ZERO,
+                access: Access::Read,
+                uuid: AranetService::READ_INTERVAL,
+                data: Vec::new(),
+                outcome: Outcome::Failed {
+                    kind: FailureKind::of(&error),
+                    message: describe(&error),
+                },
+            };
+            let text = format!("{}\n{}\n", HEADER, event);
+            let replay = ReplayTransport::new(text.parse().unwrap());
+            let replayed = replay.read(AranetService::READ_INTERVAL).await.unwrap_err();
+            assert_eq!(replayed.to_string(), error.to_string());
+            assert_eq!(replayed.is_transient(), error.is_transient());
+            match (replayed, error) {
+                (SensorError::BluetoothError(replayed), SensorError::BluetoothError(error)) => {
+                    assert_eq!(replayed.to_string(), error.to_string())
+                }
+                other => panic!("unexpected errors {:?}", other),
+            }
+        }
+    }
+}
@@ -0,0 +1,155 @@
+//! Reading and changing the sensor configuration
+use super::{
+    protocol::{AranetService, CommonService, GenericService, PacketReader},
+    Sensor,
+};
+use crate::error::{Operation, SensorError};
+use aranet4_core::settings::Setting;
+use btleplug::api::WriteType;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use uuid::Uuid;
+
+pub use aranet4_core::settings::{BluetoothRange, MeasurementInterval};
+
+/// Identification strings reported by the sensor.
+///
+/// Fields are `None` when the platform does not expose the characteristic.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct DeviceInfo {
+    pub name: Option<String>,
+    pub manufacturer: Option<String>,
+    pub model: Option<String>,
+    pub serial_number: Option<String>,
+    pub hardware_revision: Option<String>,
+    pub software_revision: Option<String>,
+}
+
+impl Sensor {
+    /// Read the identification strings of the sensor
+    pub async fn device_info(&self) -> Result<DeviceInfo, SensorError> {
+        Ok(DeviceInfo {
+            name: self.read_string(GenericService::READ_DEVICE_NAME).await?,
+            manufacturer: self
+                .read_string(CommonService::READ_MANUFACTURER_NAME)
+                .await?,
+            model: self.read_string(CommonService::READ_MODEL_NUMBER).await?,
+            serial_number: self.serial_number().await?,
+            hardware_revision: self.read_string(CommonService::READ_HW_REV).await?,
+            software_revision: self.read_string(CommonService::READ_SW_REV).await?,
+        })
+    }
+    /// Read the serial number, `None` when the platform does not expose it
+    pub async fn serial_number(&self) -> Result<Option<String>, SensorError> {
+        self.read_string(CommonService::READ_SERIAL_NO).await
+    }
+    async fn read_string(&self, uuid: Uuid) -> Result<Option<String>, SensorError> {
+        match self.read(uuid, Operation::ReadDeviceInfo).await {
+            Ok(bytes) => Ok(Some(
+                String::from_utf8_lossy(&bytes)
+                    .trim_end_matches('\0')
+                    .to_string(),
+            )),
+            Err(SensorError::CannotFindCharacteristic { .. }) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+    /// Read the time between measurements
+    pub async fn measurement_interval(&self) -> Result<Duration, SensorError> {
+        let operation = Operation::ReadMeasurementInterval;
+        let bytes = self.read(AranetService::READ_INTERVAL, operation).await?;
+        let mut reader = PacketReader::new(&bytes, AranetService::READ_INTERVAL, operation);
+        let seconds = reader.read_u16("interval")?;
+        Ok(Duration::from_secs(seconds.into()))
+    }
+    /// Read the number of measurements stored in the sensor history
+    pub async fn total_readings(&self) -> Result<u16, SensorError> {
+        let operation = Operation::ReadTotalReadings;
+        let bytes = self
+            .read(AranetService::READ_TOTAL_READINGS, operation)
+            .await?;
+        let mut reader = PacketReader::new(&bytes, AranetService::READ_TOTAL_READINGS, operation);
+        reader.read_u16("total_readings")
+    }
+    /// Change the time between measurements.
+    ///
+    /// The sensor clears its history when the interval changes.
+    pub async fn set_measurement_interval(
+        &self,
+        interval: MeasurementInterval,
+    ) -> Result<(), SensorError> {
+        self.change_setting(Setting::Interval(interval)).await
+    }
+    /// Enable or disable Smart Home integration, which broadcasts readings in advertisements
+    pub async fn set_smart_home_integration(&self, enabled: bool) -> Result<(), SensorError> {
+        self.change_setting(Setting::SmartHomeIntegration(enabled))
+            .await
+    }
+    /// Change the Bluetooth transmit range
+    pub async fn set_bluetooth_range(&self, range: BluetoothRange) -> Result<(), SensorError> {
+        self.change_setting(Setting::BluetoothRange(range)).await
+    }
+    async fn change_setting(&self, setting: Setting) -> Result<(), SensorError> {
+        self.write(
+            AranetService::WRITE_CMD,
+            &setting.encode(),
+            WriteType::WithResponse,
+            Operation::WriteSetting,
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sensor::transport::testing::FakeTransport;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn write_settings() {
+        let fake = Arc::new(FakeTransport::default());
+        let sensor = Sensor::from_transport(fake.clone());
+        sensor
+            .set_measurement_interval(MeasurementInterval::FiveMinutes)
+            .await
+            .expect("set interval");
+        sensor
+            .set_smart_home_integration(true)
+            .await
+            .expect("set integration");
+        sensor
+            .set_bluetooth_range(BluetoothRange::Extended)
+            .await
+            .expect("set range");
+        let commands: Vec<Vec<u8>> = fake.writes().into_iter().map(|(_, data)| data).collect();
+        assert_eq!(commands, vec![vec![0x90, 5], vec![0x91, 1], vec![0x92, 1]]);
+    }
+
+    #[tokio::test]
+    async fn read_configuration() {
+        let sensor = Sensor::from_transport(
+            FakeTransport::default()
+                .with_value(AranetService::READ_INTERVAL, vec![0x2c, 0x01])
+                .with_value(AranetService::READ_TOTAL_READINGS, vec![0xe0, 0x07])
+                .with_value(CommonService::READ_SERIAL_NO, b"12345\0".to_vec())
+                .with_value(CommonService::READ_SW_REV, b"v1.4.14".to_vec()),
+        );
+        assert_eq!(
+            sensor.measurement_interval().await.unwrap(),
+            Duration::from_secs(300)
+        );
+        assert_eq!(sensor.total_readings().await.unwrap(), 2016);
+        let info = sensor.device_info().await.expect("device info");
+        assert_eq!(info.serial_number.as_deref(), Some("12345"));
+        assert_eq!(info.software_revision.as_deref(), Some("v1.4.14"));
+        assert_eq!(info.name, None);
+        assert_eq!(
+            MeasurementInterval::from_minutes(10).map(|i| i.as_duration()),
+            Some(Duration::from_secs(600))
+        );
+        assert_eq!(MeasurementInterval::from_minutes(3), None);
+    }
+}
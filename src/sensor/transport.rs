@@ -0,0 +1,232 @@
+//! The link between a [`Sensor`](super::Sensor) and the device it talks to
+use crate::error::SensorError;
+use async_trait::async_trait;
+use btleplug::{
+    api::{Characteristic, Peripheral as _, WriteType},
+    platform::Peripheral,
+};
+use std::collections::BTreeSet;
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// GATT-level access to an Aranet4, keyed by characteristic UUID
+#[async_trait]
+pub trait Transport: Send + Sync {
+    /// Bluetooth address of the device, if known
+    fn address(&self) -> Option<String> {
+        None
+    }
+    /// Whether the device exposes the given characteristic
+    fn has_characteristic(&self, uuid: Uuid) -> bool;
+    /// Read the current value of a characteristic
+    async fn read(&self, uuid: Uuid) -> Result<Vec<u8>, SensorError>;
+    /// Write a value to a characteristic
+    async fn write(
+        &self,
+        uuid: Uuid,
+        data: &[u8],
+        write_type: WriteType,
+    ) -> Result<(), SensorError>;
+}
+
+#[async_trait]
+impl<T: Transport + ?Sized> Transport for Arc<T> {
+    fn address(&self) -> Option<String> {
+        (**self).address()
+    }
+    fn has_characteristic(&self, uuid: Uuid) -> bool {
+        (**self).has_characteristic(uuid)
+    }
+    async fn read(&self, uuid: Uuid) -> Result<Vec<u8>, SensorError> {
+        (**self).read(uuid).await
+    }
+    async fn write(
+        &self,
+        uuid: Uuid,
+        data: &[u8],
+        write_type: WriteType,
+    ) -> Result<(), SensorError> {
+        (**self).write(uuid, data, write_type).await
+    }
+}
+
+#[async_trait]
+impl<T: Transport + ?Sized> Transport for Box<T> {
+    fn address(&self) -> Option<String> {
+        (**self).address()
+    }
+    fn has_characteristic(&self, uuid: Uuid) -> bool {
+        (**self).has_characteristic(uuid)
+    }
+    async fn read(&self, uuid: Uuid) -> Result<Vec<u8>, SensorError> {
+        (**self).read(uuid).await
+    }
+    async fn write(
+        &self,
+        uuid: Uuid,
+        data: &[u8],
+        write_type: WriteType,
+    ) -> Result<(), SensorError> {
+        (**self).write(uuid, data, write_type).await
+    }
+}
+
+/// [`Transport`] backed by a connected btleplug peripheral
+pub struct BtleTransport {
+    peripheral: Peripheral,
+    characteristics: BTreeSet<Characteristic>,
+}
+
+impl BtleTransport {
+    /// Wrap a peripheral whose services have already been discovered
+    pub fn new(peripheral: Peripheral) -> Self {
+        let characteristics = peripheral.characteristics();
+        Self {
+            peripheral,
+            characteristics,
+        }
+    }
+    fn characteristic(&self, uuid: Uuid) -> Result<&Characteristic, SensorError> {
+        self.characteristics
+            .iter()
+            .find(|c| c.uuid == uuid)
+            .ok_or_else(|| {
+                btleplug::Error::NotSupported(format!("characteristic {} not found", uuid)).into()
+            })
+    }
+}
+
+#[async_trait]
+impl Transport for BtleTransport {
+    fn address(&self) -> Option<String> {
+        Some(self.peripheral.address().to_string())
+    }
+    fn has_characteristic(&self, uuid: Uuid) -> bool {
+        self.characteristics.iter().any(|c| c.uuid == uuid)
+    }
+    async fn read(&self, uuid: Uuid) -> Result<Vec<u8>, SensorError> {
+        let characteristic = self.characteristic(uuid)?;
+        Ok(self.peripheral.read(characteristic).await?)
+    }
+    async fn write(
+        &self,
+        uuid: Uuid,
+        data: &[u8],
+        write_type: WriteType,
+    ) -> Result<(), SensorError> {
+        let characteristic = self.characteristic(uuid)?;
+        Ok(self
+            .peripheral
+            .write(characteristic, data, write_type)
+            .await?)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod testing {
+    //! In-memory transport for exercising a [`Sensor`](crate::sensor::Sensor) without a device
+    use super::*;
+    use crate::sensor::protocol::{AranetService, Command, LogParameter};
+    use aranet4_core::history::HistoryHeader;
+    use std::collections::{HashMap, VecDeque};
+    use std::sync::Mutex;
+
+    /// Current readings of 464 ppm, 19 °C, 975.6 hPa, 36% humidity and 90% battery
+    pub(crate) const READINGS: [u8; 9] = [0xd0, 0x01, 0x7c, 0x01, 0x1c, 0x26, 0x24, 0x5a, 0x01];
+
+    /// Serves fixed characteristic values and queued history packets
+    #[derive(Default)]
+    pub(crate) struct FakeTransport {
+        values: HashMap<Uuid, Vec<u8>>,
+        history: Mutex<HashMap<u8, VecDeque<Vec<u8>>>>,
+        requested: Mutex<Option<u8>>,
+        hang_history: Option<LogParameter>,
+        writes: Mutex<Vec<(Uuid, Vec<u8>)>>,
+    }
+
+    impl FakeTransport {
+        pub(crate) fn with_value(mut self, uuid: Uuid, value: Vec<u8>) -> Self {
+            self.values.insert(uuid, value);
+            self
+        }
+        /// Queue a history packet, answered after a request for its parameter
+        pub(crate) fn with_history_packet(self, packet: Vec<u8>) -> Self {
+            self.history
+                .lock()
+                .unwrap()
+                .entry(packet[0])
+                .or_default()
+                .push_back(packet);
+            self
+        }
+        /// Every write received so far
+        pub(crate) fn writes(&self) -> Vec<(Uuid, Vec<u8>)> {
+            self.writes.lock().unwrap().clone()
+        }
+        /// Never answer once the queued history of `parameter` runs out
+        pub(crate) fn hang_after(mut self, parameter: LogParameter) -> Self {
+            self.hang_history = Some(parameter);
+            self
+        }
+    }
+
+    /// Encode a history packet: header followed by little-endian samples
+    pub(crate) fn history_packet(
+        parameter: LogParameter,
+        interval: u16,
+        total: u16,
+        ago: u16,
+        first_index: u16,
+        samples: &[u16],
+    ) -> Vec<u8> {
+        HistoryHeader {
+            parameter,
+            interval,
+            total_measurements: total,
+            time_since_last_measurement: ago,
+            first_measure_index: first_index,
+            num_measurements: 0,
+        }
+        .with_samples(samples)
+    }
+
+    #[async_trait]
+    impl Transport for FakeTransport {
+        fn has_characteristic(&self, uuid: Uuid) -> bool {
+            self.values.contains_key(&uuid)
+                || uuid == AranetService::WRITE_CMD
+                || uuid == AranetService::READ_HISTORY_READINGS
+        }
+        async fn read(&self, uuid: Uuid) -> Result<Vec<u8>, SensorError> {
+            if uuid != AranetService::READ_HISTORY_READINGS {
+                return Ok(self.values[&uuid].clone());
+            }
+            let parameter = self.requested.lock().unwrap().expect("history requested");
+            let next = self
+                .history
+                .lock()
+                .unwrap()
+                .get_mut(&parameter)
+                .and_then(|packets| packets.pop_front());
+            match next {
+                Some(packet) => Ok(packet),
+                None if self.hang_history.map(|p| p as u8) == Some(parameter) => {
+                    futures::future::pending().await
+                }
+                None => Ok(vec![parameter, 0, 0, 0, 0, 0, 0, 0, 0, 0]),
+            }
+        }
+        async fn write(
+            &self,
+            uuid: Uuid,
+            data: &[u8],
+            _write_type: WriteType,
+        ) -> Result<(), SensorError> {
+            self.writes.lock().unwrap().push((uuid, data.to_vec()));
+            if uuid == AranetService::WRITE_CMD && data.first() == Some(&Command::REQUEST_HISTORY) {
+                *self.requested.lock().unwrap() = Some(data[1]);
+            }
+            Ok(())
+        }
+    }
+}
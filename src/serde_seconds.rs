@@ -0,0 +1,43 @@
+//! Durations serialized as whole seconds, for fields renamed with a `_seconds` suffix
+use serde::{Deserialize, Deserializer, Serializer};
+
+/// A `chrono::Duration`
+pub(crate) mod duration {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(duration: &chrono::Duration, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_i64(duration.num_seconds())
+    }
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<chrono::Duration, D::Error> {
+        i64::deserialize(d).map(chrono::Duration::seconds)
+    }
+}
+
+/// A `std::time::Duration`
+pub(crate) mod std_duration {
+    use super::*;
+    use std::time::Duration;
+
+    pub fn serialize<S: Serializer>(duration: &Duration, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_u64(duration.as_secs())
+    }
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Duration, D::Error> {
+        u64::deserialize(d).map(Duration::from_secs)
+    }
+}
+
+/// An optional `std::time::Duration`
+pub(crate) mod option_std_duration {
+    use super::*;
+    use std::time::Duration;
+
+    pub fn serialize<S: Serializer>(duration: &Option<Duration>, s: S) -> Result<S::Ok, S::Error> {
+        match duration {
+            Some(duration) => s.serialize_some(&duration.as_secs()),
+            None => s.serialize_none(),
+        }
+    }
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Duration>, D::Error> {
+        Option::<u64>::deserialize(d).map(|secs| secs.map(Duration::from_secs))
+    }
+}
@@ -0,0 +1,702 @@
+//! A simulated Aranet4, for testing without a device
+//!
+//! [`SimulatedSensor`] answers the same GATT reads and writes as a real sensor, so a
+//! [`Sensor`](crate::sensor::Sensor) made with
+//! [`Sensor::from_transport`](crate::sensor::Sensor::from_transport) can read current values,
+//! download and sync history and change settings against it. Measurements come from a
+//! [`Room`], whose CO2 level follows the mass balance of its occupants and ventilation over a
+//! daily schedule. Every value is a function of the time it was measured, so a measurement
+//! reads the same in the current readings and in every download. Time comes from a
+//! [`Clock`], so tests can move it with a [`FakeClock`](crate::alert::FakeClock).
+use crate::{
+    alert::{Clock, SystemClock},
+    error::SensorError,
+    history::{
+        occupancy::OccupancyConfig,
+        record::{DataRecord, TimestampedRecord},
+    },
+    readings::SensorReadings,
+    sensor::{
+        protocol::{
+            convert_pressure, convert_temperature, AranetService, Command, CommonService,
+            GenericService, LogParameter,
+        },
+        settings::MeasurementInterval,
+        transport::Transport,
+    },
+};
+use aranet4_core::{
+    history::{HistoryHeader, HistoryRequest},
+    readings::Readings,
+    settings::Setting,
+};
+use async_trait::async_trait;
+use btleplug::api::WriteType;
+use chrono::{DateTime, Duration, Local, Timelike};
+use std::f64::consts::PI;
+use std::sync::Mutex;
+use uuid::Uuid;
+
+/// Measurements an Aranet4 keeps, a week at the default interval
+pub const CAPACITY: u16 = 2016;
+/// Largest history packet, header included
+const PACKET_SIZE: usize = 244;
+/// Days of the schedule run before midnight, to start from a settled level
+const SETTLE_DAYS: usize = 3;
+/// Difference between the warmest time, mid-afternoon, and the mean temperature in Celsius
+const TEMPERATURE_SWING: f64 = 1.5;
+/// Difference between the driest time, mid-afternoon, and the mean humidity in percent
+const HUMIDITY_SWING: f64 = 5.0;
+/// Largest difference from the mean pressure in hPa, as weather passes
+const PRESSURE_SWING: f64 = 6.0;
+/// Period of the pressure changes in hours
+const PRESSURE_PERIOD: f64 = 72.0;
+/// CO2 levels from which the status light turns yellow and red
+const YELLOW_CO2: u16 = 1000;
+const RED_CO2: u16 = 1400;
+
+const CHARACTERISTICS: [Uuid; 13] = [
+    AranetService::READ_CURRENT_READINGS,
+    AranetService::READ_INTERVAL,
+    AranetService::READ_SECONDS_SINCE_UPDATE,
+    AranetService::READ_TOTAL_READINGS,
+    AranetService::READ_HISTORY_READINGS,
+    AranetService::WRITE_CMD,
+    GenericService::READ_DEVICE_NAME,
+    CommonService::READ_MANUFACTURER_NAME,
+    CommonService::READ_MODEL_NUMBER,
+    CommonService::READ_SERIAL_NO,
+    CommonService::READ_HW_REV,
+    CommonService::READ_SW_REV,
+    CommonService::READ_BATTERY,
+];
+
+/// The room a simulated sensor measures, on the same schedule every day
+#[derive(Debug, Clone, PartialEq)]
+pub struct Room {
+    /// Volume, ventilation and outdoor CO2 level
+    pub air: OccupancyConfig,
+    /// People in the room during each hour of the day, from midnight
+    pub occupants: [f64; 24],
+    /// Mean temperature in Celsius
+    pub temperature: f64,
+    /// Mean relative humidity in percent
+    pub humidity: f64,
+    /// Mean pressure in hPa
+    pub pressure: f64,
+}
+
+impl Default for Room {
+    fn default() -> Self {
+        Self::bedroom()
+    }
+}
+
+impl Room {
+    /// A 40 m³ bedroom with 0.7 air changes per hour, two people asleep from 22:00 to 7:00,
+    /// one in the evening and nobody during the day
+    pub fn bedroom() -> Self {
+        let mut occupants = [0.0; 24];
+        occupants[..7].fill(2.0);
+        occupants[7] = 1.0;
+        occupants[17..22].fill(1.0);
+        occupants[22..].fill(2.0);
+        Self {
+            air: OccupancyConfig::new(40.0, 0.7),
+            occupants,
+            temperature: 21.0,
+            humidity: 45.0,
+            pressure: 1013.0,
+        }
+    }
+    /// A 150 m³ office with 2 air changes per hour and ten people from 9:00 to 17:00, fewer
+    /// over lunch
+    pub fn office() -> Self {
+        let mut occupants = [0.0; 24];
+        occupants[9..17].fill(10.0);
+        occupants[12] = 4.0;
+        Self {
+            air: OccupancyConfig::new(150.0, 2.0),
+            occupants,
+            temperature: 22.0,
+            humidity: 40.0,
+            pressure: 1013.0,
+        }
+    }
+    /// CO2 level in ppm at `time`
+    pub fn co2_at(&self, time: DateTime<Local>) -> f64 {
+        let hours = hour_of_day(time);
+        let midnight = (0..SETTLE_DAYS * 24).fold(self.air.outdoor_co2, |level, hour| {
+            self.settle(level, hour % 24, 1.0)
+        });
+        let level = (0..hours as usize).fold(midnight, |level, hour| self.settle(level, hour, 1.0));
+        self.settle(level, hours as usize, hours.fract())
+    }
+    /// Temperature in Celsius at `time`
+    pub fn temperature_at(&self, time: DateTime<Local>) -> f64 {
+        self.temperature + TEMPERATURE_SWING * daily(time)
+    }
+    /// Relative humidity in percent at `time`
+    pub fn humidity_at(&self, time: DateTime<Local>) -> f64 {
+        self.humidity - HUMIDITY_SWING * daily(time)
+    }
+    /// Pressure in hPa at `time`
+    pub fn pressure_at(&self, time: DateTime<Local>) -> f64 {
+        let hours = time.timestamp() as f64 / 3600.0;
+        self.pressure + PRESSURE_SWING * (2.0 * PI * hours / PRESSURE_PERIOD).sin()
+    }
+    /// Level after `hours` with the occupants of `hour` in the room, starting from `level`
+    fn settle(&self, level: f64, hour: usize, hours: f64) -> f64 {
+        let air = &self.air;
+        // ppm per hour added by the people in the room
+        let generation = self.occupants[hour] * air.generation_rate * 1e6 / air.room_volume;
+        if air.ach <= 0.0 {
+            return level + generation * hours;
+        }
+        let steady = air.outdoor_co2 + generation / air.ach;
+        steady + (level - steady) * (-air.ach * hours).exp()
+    }
+}
+
+/// Hours since local midnight
+fn hour_of_day(time: DateTime<Local>) -> f64 {
+    f64::from(time.num_seconds_from_midnight()) / 3600.0
+}
+
+/// Daily cycle between -1 early in the morning and 1 at 15:00
+fn daily(time: DateTime<Local>) -> f64 {
+    (2.0 * PI * (hour_of_day(time) - 15.0) / 24.0).cos()
+}
+
+/// Noise between -1 and 1, the same every time for a given seed, time and channel
+fn noise(seed: u64, time: DateTime<Local>, channel: u64) -> f64 {
+    // splitmix64 finalizer
+    let mut z = seed
+        ^ (time.timestamp() as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15)
+        ^ channel.wrapping_mul(0xd6e8_feb8_6659_fd93);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^= z >> 31;
+    (z >> 11) as f64 / (1u64 << 53) as f64 * 2.0 - 1.0
+}
+
+/// What the simulated sensor reports about itself, and the room it measures
+#[derive(Debug, Clone, PartialEq)]
+pub struct SimulatorConfig {
+    pub name: String,
+    pub serial: String,
+    pub address: Option<String>,
+    pub interval: MeasurementInterval,
+    /// Measurements kept in the log; older ones are dropped
+    pub capacity: u16,
+    /// How long the sensor has been measuring when created
+    pub running_for: Duration,
+    /// Battery level in percent
+    pub battery: u8,
+    pub room: Room,
+    /// Varies the measurement noise between sensors
+    pub seed: u64,
+}
+
+impl Default for SimulatorConfig {
+    /// A sensor in a bedroom that has been measuring every five minutes for a week, with a
+    /// full log
+    fn default() -> Self {
+        Self {
+            name: "Aranet4 0SIM1".to_string(),
+            serial: "0SIM1".to_string(),
+            address: None,
+            interval: MeasurementInterval::FiveMinutes,
+            capacity: CAPACITY,
+            running_for: Duration::days(7),
+            battery: 85,
+            room: Room::default(),
+            seed: 0,
+        }
+    }
+}
+
+/// A measurement as the sensor encodes it
+#[derive(Debug, Clone, Copy)]
+struct Measurement {
+    co2: u16,
+    /// Celsius × 20
+    temperature: u16,
+    /// hPa × 10
+    pressure: u16,
+    humidity: u8,
+}
+
+/// History download in progress
+#[derive(Debug, Clone, Copy)]
+struct Request {
+    parameter: LogParameter,
+    next_index: u16,
+}
+
+#[derive(Debug)]
+struct State {
+    interval: MeasurementInterval,
+    /// Time of the first measurement since the log was last cleared
+    started: DateTime<Local>,
+    battery: u8,
+    smart_home: bool,
+    bluetooth_range: u8,
+    request: Option<Request>,
+}
+
+/// The measurement log at one point in time
+#[derive(Debug, Clone, Copy)]
+struct Log {
+    interval: Duration,
+    total: u16,
+    newest: DateTime<Local>,
+    seconds_since_update: u16,
+}
+
+impl Log {
+    /// Time of the measurement at `index`, where 1 is the oldest
+    fn time(&self, index: u16) -> DateTime<Local> {
+        self.newest - self.interval * i32::from(self.total - index)
+    }
+}
+
+impl State {
+    fn log(&self, capacity: u16, now: DateTime<Local>) -> Log {
+        let interval = Duration::from_std(self.interval.as_duration()).expect("short interval");
+        let taken = (now - self.started).num_seconds().max(0) / interval.num_seconds() + 1;
+        let newest = self.started + interval * (taken - 1) as i32;
+        Log {
+            interval,
+            total: taken.min(capacity.into()) as u16,
+            newest,
+            seconds_since_update: (now - newest).num_seconds() as u16,
+        }
+    }
+}
+
+/// An Aranet4 at the GATT level, measuring a simulated [`Room`]
+pub struct SimulatedSensor<C: Clock = SystemClock> {
+    config: SimulatorConfig,
+    clock: C,
+    state: Mutex<State>,
+}
+
+impl SimulatedSensor {
+    pub fn new(config: SimulatorConfig) -> Self {
+        Self::with_clock(config, SystemClock)
+    }
+}
+
+impl Default for SimulatedSensor {
+    fn default() -> Self {
+        Self::new(SimulatorConfig::default())
+    }
+}
+
+impl<C: Clock> SimulatedSensor<C> {
+    pub fn with_clock(config: SimulatorConfig, clock: C) -> Self {
+        let state = State {
+            interval: config.interval,
+            started: clock.now() - config.running_for.max(Duration::zero()),
+            battery: config.battery,
+            smart_home: false,
+            bluetooth_range: 0,
+            request: None,
+        };
+        Self {
+            config,
+            clock,
+            state: Mutex::new(state),
+        }
+    }
+    pub fn config(&self) -> &SimulatorConfig {
+        &self.config
+    }
+    /// Current time between measurements, which a [`Sensor`](crate::sensor::Sensor) may change
+    pub fn interval(&self) -> MeasurementInterval {
+        self.state().interval
+    }
+    /// Whether Smart Home integration was turned on
+    pub fn smart_home(&self) -> bool {
+        self.state().smart_home
+    }
+    pub fn set_battery(&self, percent: u8) {
+        self.state().battery = percent;
+    }
+    /// The readings the sensor currently shows, from its newest measurement
+    pub fn readings(&self) -> SensorReadings {
+        let state = self.state();
+        let log = state.log(self.config.capacity, self.clock.now());
+        let m = self.measure(log.newest);
+        SensorReadings::new(
+            m.co2,
+            convert_temperature(m.temperature),
+            convert_pressure(m.pressure),
+            m.humidity,
+            state.battery,
+            status_color(m.co2),
+        )
+    }
+    /// The measurements in the log, oldest first, as a download would return them
+    pub fn history(&self) -> Vec<TimestampedRecord> {
+        let log = self.state().log(self.config.capacity, self.clock.now());
+        (1..=log.total)
+            .map(|index| {
+                let time = log.time(index);
+                let m = self.measure(time);
+                TimestampedRecord {
+                    time,
+                    record: DataRecord {
+                        temperature: convert_temperature(m.temperature),
+                        humidity: m.humidity,
+                        pressure: convert_pressure(m.pressure),
+                        co2: m.co2,
+                    },
+                }
+            })
+            .collect()
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().expect("simulator state poisoned")
+    }
+
+    fn measure(&self, time: DateTime<Local>) -> Measurement {
+        let room = &self.config.room;
+        let noise = |channel| noise(self.config.seed, time, channel);
+        Measurement {
+            co2: (room.co2_at(time) + 5.0 * noise(0)).round().max(0.0) as u16,
+            temperature: ((room.temperature_at(time) + 0.05 * noise(1)) * 20.0)
+                .round()
+                .max(0.0) as u16,
+            pressure: ((room.pressure_at(time) + 0.1 * noise(2)) * 10.0).round() as u16,
+            humidity: (room.humidity_at(time) + 0.5 * noise(3))
+                .round()
+                .clamp(0.0, 100.0) as u8,
+        }
+    }
+
+    fn current_readings(&self, state: &State, log: &Log) -> Vec<u8> {
+        let m = self.measure(log.newest);
+        Readings {
+            co2: m.co2,
+            temperature: m.temperature,
+            pressure: m.pressure,
+            humidity: m.humidity,
+            battery: state.battery,
+            status: status_color(m.co2),
+        }
+        .encode()
+        .to_vec()
+    }
+
+    /// The next chunk of the requested history, or an empty one once it has all been sent
+    fn history_packet(&self, state: &mut State, log: &Log) -> Result<Vec<u8>, SensorError> {
+        let request = state
+            .request
+            .as_mut()
+            .ok_or_else(|| unsupported("history read before a request"))?;
+        let parameter = request.parameter;
+        let per_packet = (PACKET_SIZE - HistoryHeader::SIZE) / parameter.sample_size();
+        let first = request.next_index;
+        let count = if first > log.total {
+            0
+        } else {
+            (log.total - first + 1).min(per_packet.min(u8::MAX.into()) as u16)
+        };
+        request.next_index = first.saturating_add(count);
+
+        let samples: Vec<u16> = (first..first + count)
+            .map(|index| {
+                let m = self.measure(log.time(index));
+                match parameter {
+                    LogParameter::Temperature => m.temperature,
+                    LogParameter::Humidity => m.humidity.into(),
+                    LogParameter::Pressure => m.pressure,
+                    LogParameter::Co2 => m.co2,
+                }
+            })
+            .collect();
+        let header = HistoryHeader {
+            parameter,
+            interval: log.interval.num_seconds() as u16,
+            total_measurements: log.total,
+            time_since_last_measurement: log.seconds_since_update,
+            first_measure_index: first,
+            num_measurements: 0,
+        };
+        Ok(header.with_samples(&samples))
+    }
+}
+
+fn status_color(co2: u16) -> u8 {
+    match co2 {
+        _ if co2 >= RED_CO2 => 3,
+        _ if co2 >= YELLOW_CO2 => 2,
+        _ => 1,
+    }
+}
+
+fn unsupported(message: impl Into<String>) -> SensorError {
+    btleplug::Error::NotSupported(message.into()).into()
+}
+
+fn not_found(uuid: Uuid) -> SensorError {
+    unsupported(format!("characteristic {} not found", uuid))
+}
+
+#[async_trait]
+impl<C: Clock> Transport for SimulatedSensor<C> {
+    fn address(&self) -> Option<String> {
+        self.config.address.clone()
+    }
+    fn has_characteristic(&self, uuid: Uuid) -> bool {
+        CHARACTERISTICS.contains(&uuid)
+    }
+    async fn read(&self, uuid: Uuid) -> Result<Vec<u8>, SensorError> {
+        let mut state = self.state();
+        let log = state.log(self.config.capacity, self.clock.now());
+        let text = |s: &str| s.as_bytes().to_vec();
+        Ok(match uuid {
+            AranetService::READ_CURRENT_READINGS => self.current_readings(&state, &log),
+            AranetService::READ_INTERVAL => {
+                (log.interval.num_seconds() as u16).to_le_bytes().to_vec()
+            }
+            AranetService::READ_SECONDS_SINCE_UPDATE => {
+                log.seconds_since_update.to_le_bytes().to_vec()
+            }
+            AranetService::READ_TOTAL_READINGS => log.total.to_le_bytes().to_vec(),
+            AranetService::READ_HISTORY_READINGS => self.history_packet(&mut state, &log)?,
+            GenericService::READ_DEVICE_NAME => text(&self.config.name),
+            CommonService::READ_MANUFACTURER_NAME => text("SAF Tehnika"),
+            CommonService::READ_MODEL_NUMBER => text("Aranet4"),
+            CommonService::READ_SERIAL_NO => text(&self.config.serial),
+            CommonService::READ_HW_REV => text("12"),
+            CommonService::READ_SW_REV => text("v1.4.19"),
+            CommonService::READ_BATTERY => vec![state.battery],
+            _ => return Err(not_found(uuid)),
+        })
+    }
+    async fn write(
+        &self,
+        uuid: Uuid,
+        data: &[u8],
+        _write_type: WriteType,
+    ) -> Result<(), SensorError> {
+        if uuid != AranetService::WRITE_CMD {
+            return Err(not_found(uuid));
+        }
+        let malformed = || unsupported(format!("malformed command {:02x?}", data));
+        let mut state = self.state();
+        if data.first() == Some(&Command::REQUEST_HISTORY) {
+            let request = HistoryRequest::decode(data).map_err(|_| malformed())?;
+            state.request = Some(Request {
+                parameter: request.parameter,
+                next_index: request.first_index.max(1),
+            });
+            return Ok(());
+        }
+        match Setting::decode(data).map_err(|_| malformed())? {
+            Setting::Interval(interval) => {
+                // the sensor starts a new log on every interval change
+                if interval != state.interval {
+                    state.interval = interval;
+                    state.started = self.clock.now();
+                }
+            }
+            Setting::SmartHomeIntegration(enabled) => state.smart_home = enabled,
+            Setting::BluetoothRange(range) => state.bluetooth_range = range as u8,
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        alert::FakeClock,
+        fleet::Fleet,
+        history::{store::HistoryStore, HistoryProgress},
+        sensor::Sensor,
+        testing::TempDir,
+    };
+    use chrono::TimeZone;
+    use std::sync::Arc;
+
+    fn at(hour: u32, minute: u32) -> DateTime<Local> {
+        Local
+            .with_ymd_and_hms(2024, 1, 15, hour, minute, 30)
+            .unwrap()
+    }
+
+    fn simulator(clock: &FakeClock) -> Arc<SimulatedSensor<FakeClock>> {
+        Arc::new(SimulatedSensor::with_clock(
+            SimulatorConfig::default(),
+            clock.clone(),
+        ))
+    }
+
+    #[test]
+    fn day_and_night() {
+        let room = Room::bedroom();
+        let night = room.co2_at(at(6, 0));
+        let afternoon = room.co2_at(at(16, 0));
+        assert!((1500.0..1800.0).contains(&night), "{night} ppm at night");
+        assert!(
+            (420.0..500.0).contains(&afternoon),
+            "{afternoon} ppm in the afternoon"
+        );
+        // the same time on another day has the same level
+        assert!((room.co2_at(at(6, 0) + Duration::days(3)) - night).abs() < 1e-6);
+        assert!(room.temperature_at(at(15, 0)) > room.temperature_at(at(4, 0)));
+        assert!(room.humidity_at(at(15, 0)) < room.humidity_at(at(4, 0)));
+
+        let office = Room::office();
+        assert!(office.co2_at(at(11, 59)) > office.co2_at(at(12, 59)));
+        assert!(office.co2_at(at(16, 59)) > 1000.0);
+    }
+
+    #[tokio::test]
+    async fn current_readings() {
+        let clock = FakeClock::new(at(6, 0));
+        let simulator = simulator(&clock);
+        let sensor = Sensor::from_transport(simulator.clone());
+
+        let readings = sensor.read_current_values().await.unwrap();
+        assert!(readings.co2_level > 1400);
+        assert_eq!(readings.status_color(), 3);
+        assert_eq!(readings.battery, 85);
+        assert_eq!(readings.co2_level, simulator.readings().co2_level);
+        assert_eq!(sensor.last_update_time().await.unwrap().as_secs(), 0);
+        assert_eq!(sensor.total_readings().await.unwrap(), CAPACITY);
+        assert_eq!(sensor.measurement_interval().await.unwrap().as_secs(), 300);
+        let info = sensor.device_info().await.unwrap();
+        assert_eq!(info.serial_number.as_deref(), Some("0SIM1"));
+
+        clock.set(at(16, 0));
+        let readings = sensor.read_current_values().await.unwrap();
+        assert!(readings.co2_level < 600);
+        assert_eq!(readings.status_color(), 1);
+    }
+
+    #[tokio::test]
+    async fn history_download() {
+        let clock = FakeClock::new(at(6, 0));
+        let simulator = simulator(&clock);
+        let sensor = Sensor::from_transport(simulator.clone());
+
+        let mut chunks = vec![];
+        let history = sensor
+            .history()
+            .on_progress(|p: HistoryProgress| chunks.push((p.parameter, p.received)))
+            .run()
+            .await
+            .unwrap();
+        let expected = simulator.history();
+        assert_eq!(history.co2.len(), CAPACITY as usize);
+        assert_eq!(
+            history.as_records(),
+            expected.iter().map(|r| r.record).collect::<Vec<_>>()
+        );
+        let co2_chunks = chunks
+            .iter()
+            .filter(|(p, _)| *p == LogParameter::Co2)
+            .count();
+        assert_eq!(co2_chunks, (CAPACITY as usize).div_ceil(117));
+        assert_eq!(
+            chunks
+                .iter()
+                .filter(|(p, _)| *p == LogParameter::Humidity)
+                .count(),
+            (CAPACITY as usize).div_ceil(234)
+        );
+
+        let newest = sensor
+            .history()
+            .starting_at(CAPACITY - 11)
+            .run()
+            .await
+            .unwrap();
+        assert_eq!(newest.co2, history.co2[CAPACITY as usize - 12..]);
+    }
+
+    #[tokio::test]
+    async fn interval_change_clears_log() {
+        let clock = FakeClock::new(at(6, 0));
+        let simulator = simulator(&clock);
+        let sensor = Sensor::from_transport(simulator.clone());
+
+        sensor
+            .set_measurement_interval(MeasurementInterval::OneMinute)
+            .await
+            .unwrap();
+        sensor.set_smart_home_integration(true).await.unwrap();
+        assert_eq!(simulator.interval(), MeasurementInterval::OneMinute);
+        assert!(simulator.smart_home());
+        assert_eq!(sensor.total_readings().await.unwrap(), 1);
+
+        clock.advance(Duration::seconds(200));
+        assert_eq!(sensor.total_readings().await.unwrap(), 4);
+        assert_eq!(sensor.last_update_time().await.unwrap().as_secs(), 20);
+        let history = sensor.get_historical_data().await.unwrap();
+        assert_eq!(history.co2.len(), 4);
+        assert_eq!(history.information.interval, Duration::minutes(1));
+    }
+
+    #[tokio::test]
+    async fn sync_new_measurements() {
+        let dir = TempDir::new("simulator-sync");
+        let mut store = HistoryStore::open(&dir.0).unwrap();
+        let clock = FakeClock::new(at(6, 0));
+        let sensor = Sensor::from_transport(simulator(&clock));
+
+        let report = sensor.sync_at(&mut store, clock.now()).await.unwrap();
+        assert_eq!(report.downloaded, CAPACITY as usize);
+        assert_eq!(report.merge.added, CAPACITY as usize);
+
+        clock.advance(Duration::minutes(30));
+        let report = sensor.sync_at(&mut store, clock.now()).await.unwrap();
+        assert_eq!(report.downloaded, 6);
+        assert_eq!(report.merge.added, 6);
+        assert_eq!(report.merge.duplicates, 0);
+        assert!(report.lost.is_none() && report.reset.is_none());
+    }
+
+    #[tokio::test]
+    async fn fleet_end_to_end() {
+        let clock = FakeClock::new(at(16, 0));
+        let bedroom = simulator(&clock);
+        let office = Arc::new(SimulatedSensor::with_clock(
+            SimulatorConfig {
+                serial: "0SIM2".to_string(),
+                address: Some("00:00:00:00:00:02".to_string()),
+                room: Room::office(),
+                seed: 2,
+                ..SimulatorConfig::default()
+            },
+            clock.clone(),
+        ));
+        let mut fleet = Fleet::new();
+        fleet.add("bedroom", Sensor::from_transport(bedroom.clone()));
+        fleet.add("office", Sensor::from_transport(office.clone()));
+        fleet.poll().await;
+
+        let statuses = fleet.status().snapshot();
+        assert!(statuses.iter().all(|s| s.up));
+        let co2 = |i: usize| statuses[i].readings.as_ref().unwrap().co2_level;
+        assert_eq!(co2(0), bedroom.readings().co2_level);
+        assert_eq!(co2(1), office.readings().co2_level);
+        assert!(co2(1) > co2(0));
+
+        #[cfg(feature = "prometheus")]
+        {
+            let metrics = crate::prometheus::render(&statuses);
+            assert!(metrics.contains(&format!(
+                "aranet4_co2_ppm{{address=\"00:00:00:00:00:02\",name=\"office\"}} {}\n",
+                co2(1)
+            )));
+        }
+    }
+}
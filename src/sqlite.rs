@@ -0,0 +1,515 @@
+//! SQLite storage for readings and history
+//!
+//! Times are stored as Unix seconds and sensors are identified by serial number. The schema:
+//!
+//! ```sql
+//! CREATE TABLE sensors (
+//!     id INTEGER PRIMARY KEY,
+//!     serial TEXT NOT NULL UNIQUE,
+//!     address TEXT,
+//!     name TEXT
+//! );
+//! -- current readings, as returned by Sensor::read_current_values
+//! CREATE TABLE readings (
+//!     sensor_id INTEGER NOT NULL REFERENCES sensors (id),
+//!     time INTEGER NOT NULL,
+//!     co2_ppm INTEGER NOT NULL,
+//!     temperature_f REAL NOT NULL,
+//!     humidity_percent INTEGER NOT NULL,
+//!     pressure_hpa REAL NOT NULL,
+//!     battery_percent INTEGER NOT NULL,
+//!     status_color INTEGER NOT NULL,
+//!     PRIMARY KEY (sensor_id, time)
+//! );
+//! -- measurements downloaded from the device log
+//! CREATE TABLE history (
+//!     sensor_id INTEGER NOT NULL REFERENCES sensors (id),
+//!     time INTEGER NOT NULL,
+//!     interval_seconds INTEGER NOT NULL,
+//!     co2_ppm INTEGER NOT NULL,
+//!     temperature_f REAL NOT NULL,
+//!     humidity_percent INTEGER NOT NULL,
+//!     pressure_hpa REAL NOT NULL,
+//!     PRIMARY KEY (sensor_id, time)
+//! );
+//! -- where the last sync left off, see history::sync
+//! CREATE TABLE sync_state (
+//!     sensor_id INTEGER PRIMARY KEY REFERENCES sensors (id),
+//!     interval_seconds INTEGER NOT NULL,
+//!     last_index INTEGER NOT NULL,
+//!     last_time INTEGER NOT NULL
+//! );
+//! ```
+//!
+//! The schema version is kept in `PRAGMA user_version` and migrations run when the database
+//! is opened. Rows with the same sensor and time as an existing row are ignored, so history
+//! should be inserted with stable times, such as those of a [`HistoryStore`] kept in step by
+//! [`Sensor::sync`](crate::sensor::Sensor::sync).
+//!
+//! [`HistoryStore`]: crate::history::store::HistoryStore
+use crate::{
+    history::{
+        readings::HistoryReadings, record::DataRecord, record::TimestampedRecord, sync::SyncState,
+    },
+    readings::SensorReadings,
+};
+use chrono::{DateTime, Duration, Local, TimeZone};
+use rusqlite::{params, types::Type, Connection, OptionalExtension, Row, RowIndex};
+use std::path::Path;
+use thiserror::Error;
+
+/// Migrations from each schema version to the next; the schema version is their count
+const MIGRATIONS: &[&str] = &["
+    CREATE TABLE sensors (
+        id INTEGER PRIMARY KEY,
+        serial TEXT NOT NULL UNIQUE,
+        address TEXT,
+        name TEXT
+    );
+    CREATE TABLE readings (
+        sensor_id INTEGER NOT NULL REFERENCES sensors (id),
+        time INTEGER NOT NULL,
+        co2_ppm INTEGER NOT NULL,
+        temperature_f REAL NOT NULL,
+        humidity_percent INTEGER NOT NULL,
+        pressure_hpa REAL NOT NULL,
+        battery_percent INTEGER NOT NULL,
+        status_color INTEGER NOT NULL,
+        PRIMARY KEY (sensor_id, time)
+    );
+    CREATE TABLE history (
+        sensor_id INTEGER NOT NULL REFERENCES sensors (id),
+        time INTEGER NOT NULL,
+        interval_seconds INTEGER NOT NULL,
+        co2_ppm INTEGER NOT NULL,
+        temperature_f REAL NOT NULL,
+        humidity_percent INTEGER NOT NULL,
+        pressure_hpa REAL NOT NULL,
+        PRIMARY KEY (sensor_id, time)
+    );
+    CREATE TABLE sync_state (
+        sensor_id INTEGER PRIMARY KEY REFERENCES sensors (id),
+        interval_seconds INTEGER NOT NULL,
+        last_index INTEGER NOT NULL,
+        last_time INTEGER NOT NULL
+    );
+"];
+
+/// Schema version created by this version of the crate
+pub const SCHEMA_VERSION: u32 = MIGRATIONS.len() as u32;
+
+#[derive(Debug, Error)]
+pub enum DatabaseError {
+    #[error("SQLite error")]
+    Sqlite(#[from] rusqlite::Error),
+    #[error("Database schema version {found} is newer than the supported version {supported}")]
+    UnsupportedSchema { found: u32, supported: u32 },
+}
+
+/// A sensor known to the database
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredSensor {
+    pub serial: String,
+    pub address: Option<String>,
+    pub name: Option<String>,
+}
+
+/// Time stored as Unix seconds in column `index` of `row`
+fn time_from<I: RowIndex + Copy>(row: &Row, index: I) -> rusqlite::Result<DateTime<Local>> {
+    let seconds: i64 = row.get(index)?;
+    match Local.timestamp_opt(seconds, 0).single() {
+        Some(time) => Ok(time),
+        None => Err(rusqlite::Error::FromSqlConversionFailure(
+            index.idx(row.as_ref())?,
+            Type::Integer,
+            format!("Invalid timestamp {}", seconds).into(),
+        )),
+    }
+}
+
+fn record_from(row: &Row) -> rusqlite::Result<TimestampedRecord> {
+    Ok(TimestampedRecord {
+        time: time_from(row, "time")?,
+        record: DataRecord {
+            co2: row.get("co2_ppm")?,
+            temperature: row.get("temperature_f")?,
+            humidity: row.get("humidity_percent")?,
+            pressure: row.get("pressure_hpa")?,
+        },
+    })
+}
+
+fn readings_from(row: &Row) -> rusqlite::Result<(DateTime<Local>, SensorReadings)> {
+    Ok((
+        time_from(row, "time")?,
+        SensorReadings::new(
+            row.get("co2_ppm")?,
+            row.get("temperature_f")?,
+            row.get("pressure_hpa")?,
+            row.get("humidity_percent")?,
+            row.get("battery_percent")?,
+            row.get("status_color")?,
+        ),
+    ))
+}
+
+/// Readings and history in a SQLite database
+pub struct Database {
+    connection: Connection,
+}
+
+impl Database {
+    /// Open or create the database at `path`, migrating it to [`SCHEMA_VERSION`]
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, DatabaseError> {
+        Self::from_connection(Connection::open(path)?)
+    }
+    pub fn open_in_memory() -> Result<Self, DatabaseError> {
+        Self::from_connection(Connection::open_in_memory()?)
+    }
+    fn from_connection(mut connection: Connection) -> Result<Self, DatabaseError> {
+        connection.pragma_update(None, "foreign_keys", true)?;
+        let version: u32 = connection.pragma_query_value(None, "user_version", |r| r.get(0))?;
+        if version > SCHEMA_VERSION {
+            return Err(DatabaseError::UnsupportedSchema {
+                found: version,
+                supported: SCHEMA_VERSION,
+            });
+        }
+        for (from, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+            let transaction = connection.transaction()?;
+            transaction.execute_batch(migration)?;
+            transaction.pragma_update(None, "user_version", from as u32 + 1)?;
+            transaction.commit()?;
+        }
+        Ok(Self { connection })
+    }
+    /// The underlying connection, for queries not covered here
+    pub fn connection(&self) -> &Connection {
+        &self.connection
+    }
+    /// Schema version of the open database
+    pub fn schema_version(&self) -> Result<u32, DatabaseError> {
+        Ok(self
+            .connection
+            .pragma_query_value(None, "user_version", |r| r.get(0))?)
+    }
+
+    /// Add a sensor, or update its address and name where given
+    pub fn add_sensor(
+        &self,
+        serial: &str,
+        address: Option<&str>,
+        name: Option<&str>,
+    ) -> Result<(), DatabaseError> {
+        self.connection.execute(
+            "INSERT INTO sensors (serial, address, name) VALUES (?1, ?2, ?3)
+             ON CONFLICT (serial) DO UPDATE SET
+                 address = coalesce(excluded.address, address),
+                 name = coalesce(excluded.name, name)",
+            params![serial, address, name],
+        )?;
+        Ok(())
+    }
+    pub fn sensors(&self) -> Result<Vec<StoredSensor>, DatabaseError> {
+        let mut statement = self
+            .connection
+            .prepare("SELECT serial, address, name FROM sensors ORDER BY serial")?;
+        let sensors = statement
+            .query_map([], |row| {
+                Ok(StoredSensor {
+                    serial: row.get(0)?,
+                    address: row.get(1)?,
+                    name: row.get(2)?,
+                })
+            })?
+            .collect::<Result<_, _>>()?;
+        Ok(sensors)
+    }
+    /// Row id of the sensor with this serial number, adding it if needed
+    fn sensor_id(&self, serial: &str) -> Result<i64, DatabaseError> {
+        self.add_sensor(serial, None, None)?;
+        Ok(self.connection.query_row(
+            "SELECT id FROM sensors WHERE serial = ?1",
+            [serial],
+            |row| row.get(0),
+        )?)
+    }
+
+    /// Store readings taken at `time`
+    pub fn insert_readings(
+        &self,
+        serial: &str,
+        time: DateTime<Local>,
+        readings: &SensorReadings,
+    ) -> Result<(), DatabaseError> {
+        let sensor = self.sensor_id(serial)?;
+        self.connection.execute(
+            "INSERT OR IGNORE INTO readings (sensor_id, time, co2_ppm, temperature_f,
+                 humidity_percent, pressure_hpa, battery_percent, status_color)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                sensor,
+                time.timestamp(),
+                readings.co2_level,
+                readings.temperature,
+                readings.humidity,
+                readings.pressure,
+                readings.battery,
+                readings.status_color(),
+            ],
+        )?;
+        Ok(())
+    }
+    /// Store a downloaded history in one transaction, returning the number of new rows
+    pub fn insert_history(
+        &mut self,
+        serial: &str,
+        history: &HistoryReadings,
+    ) -> Result<usize, DatabaseError> {
+        self.insert_records(
+            serial,
+            history.information.interval,
+            &history.as_timestamped_records(),
+        )
+    }
+    /// Store history records measured every `interval` in one transaction, returning the
+    /// number of new rows
+    pub fn insert_records(
+        &mut self,
+        serial: &str,
+        interval: Duration,
+        records: &[TimestampedRecord],
+    ) -> Result<usize, DatabaseError> {
+        let sensor = self.sensor_id(serial)?;
+        let transaction = self.connection.transaction()?;
+        let mut inserted = 0;
+        {
+            let mut statement = transaction.prepare(
+                "INSERT OR IGNORE INTO history (sensor_id, time, interval_seconds, co2_ppm,
+                     temperature_f, humidity_percent, pressure_hpa)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            )?;
+            for r in records {
+                inserted += statement.execute(params![
+                    sensor,
+                    r.time.timestamp(),
+                    interval.num_seconds(),
+                    r.record.co2,
+                    r.record.temperature,
+                    r.record.humidity,
+                    r.record.pressure,
+                ])?;
+            }
+        }
+        transaction.commit()?;
+        Ok(inserted)
+    }
+
+    /// Readings taken between `from` and `to`, inclusive, oldest first
+    pub fn readings(
+        &self,
+        serial: &str,
+        from: DateTime<Local>,
+        to: DateTime<Local>,
+    ) -> Result<Vec<(DateTime<Local>, SensorReadings)>, DatabaseError> {
+        let mut statement = self.connection.prepare(
+            "SELECT r.* FROM readings r JOIN sensors s ON s.id = r.sensor_id
+             WHERE s.serial = ?1 AND r.time BETWEEN ?2 AND ?3 ORDER BY r.time",
+        )?;
+        let readings = statement
+            .query_map(
+                params![serial, from.timestamp(), to.timestamp()],
+                readings_from,
+            )?
+            .collect::<Result<_, _>>()?;
+        Ok(readings)
+    }
+    /// The most recent readings of a sensor
+    pub fn latest_readings(
+        &self,
+        serial: &str,
+    ) -> Result<Option<(DateTime<Local>, SensorReadings)>, DatabaseError> {
+        Ok(self
+            .connection
+            .query_row(
+                "SELECT r.* FROM readings r JOIN sensors s ON s.id = r.sensor_id
+                 WHERE s.serial = ?1 ORDER BY r.time DESC LIMIT 1",
+                [serial],
+                readings_from,
+            )
+            .optional()?)
+    }
+    /// History records measured between `from` and `to`, inclusive, oldest first
+    pub fn history(
+        &self,
+        serial: &str,
+        from: DateTime<Local>,
+        to: DateTime<Local>,
+    ) -> Result<Vec<TimestampedRecord>, DatabaseError> {
+        let mut statement = self.connection.prepare(
+            "SELECT h.* FROM history h JOIN sensors s ON s.id = h.sensor_id
+             WHERE s.serial = ?1 AND h.time BETWEEN ?2 AND ?3 ORDER BY h.time",
+        )?;
+        let records = statement
+            .query_map(
+                params![serial, from.timestamp(), to.timestamp()],
+                record_from,
+            )?
+            .collect::<Result<_, _>>()?;
+        Ok(records)
+    }
+
+    /// Where the last sync of a sensor left off
+    pub fn sync_state(&self, serial: &str) -> Result<Option<SyncState>, DatabaseError> {
+        Ok(self
+            .connection
+            .query_row(
+                "SELECT y.interval_seconds, y.last_index, y.last_time
+                 FROM sync_state y JOIN sensors s ON s.id = y.sensor_id WHERE s.serial = ?1",
+                [serial],
+                |row| {
+                    Ok(SyncState {
+                        interval: row.get(0)?,
+                        last_index: row.get(1)?,
+                        last_time: time_from(row, 2)?,
+                    })
+                },
+            )
+            .optional()?)
+    }
+    pub fn set_sync_state(&self, serial: &str, state: &SyncState) -> Result<(), DatabaseError> {
+        let sensor = self.sensor_id(serial)?;
+        self.connection.execute(
+            "INSERT OR REPLACE INTO sync_state (sensor_id, interval_seconds, last_index, last_time)
+             VALUES (?1, ?2, ?3, ?4)",
+            params![
+                sensor,
+                state.interval,
+                state.last_index,
+                state.last_time.timestamp()
+            ],
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sensor::transport::testing::READINGS;
+
+    fn time(minute: u32) -> DateTime<Local> {
+        Local.with_ymd_and_hms(2024, 4, 25, 18, minute, 0).unwrap()
+    }
+
+    fn readings() -> SensorReadings {
+        SensorReadings::from_raw(READINGS.to_vec()).unwrap()
+    }
+
+    fn records(minutes: &[u32]) -> Vec<TimestampedRecord> {
+        minutes
+            .iter()
+            .map(|m| TimestampedRecord {
+                time: time(*m),
+                record: DataRecord {
+                    co2: 400 + *m as u16,
+                    temperature: 70.5,
+                    humidity: 40,
+                    pressure: 1001.2,
+                },
+            })
+            .collect()
+    }
+
+    #[test]
+    fn readings_round_trip() {
+        let db = Database::open_in_memory().unwrap();
+        assert_eq!(db.schema_version().unwrap(), SCHEMA_VERSION);
+        db.add_sensor("12345", Some("AA:BB:CC:DD:EE:FF"), Some("office"))
+            .unwrap();
+        db.insert_readings("12345", time(0), &readings()).unwrap();
+        db.insert_readings("12345", time(5), &readings()).unwrap();
+
+        let stored = db.readings("12345", time(0), time(4)).unwrap();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].0, time(0));
+        assert_eq!(stored[0].1.to_string(), readings().to_string());
+        assert_eq!(db.latest_readings("12345").unwrap().unwrap().0, time(5));
+        assert!(db.latest_readings("54321").unwrap().is_none());
+
+        // a later add without a name keeps the old one
+        db.add_sensor("12345", Some("AA:BB:CC:DD:EE:FF"), None)
+            .unwrap();
+        assert_eq!(
+            db.sensors().unwrap(),
+            [StoredSensor {
+                serial: "12345".to_string(),
+                address: Some("AA:BB:CC:DD:EE:FF".to_string()),
+                name: Some("office".to_string()),
+            }]
+        );
+    }
+
+    #[test]
+    fn invalid_stored_time() {
+        let db = Database::open_in_memory().unwrap();
+        db.insert_readings("12345", time(0), &readings()).unwrap();
+        db.connection
+            .execute("UPDATE readings SET time = ?1", [i64::MAX])
+            .unwrap();
+        assert!(matches!(
+            db.latest_readings("12345"),
+            Err(DatabaseError::Sqlite(
+                rusqlite::Error::FromSqlConversionFailure(_, Type::Integer, _)
+            ))
+        ));
+    }
+
+    #[test]
+    fn history_bulk_insert() {
+        let mut db = Database::open_in_memory().unwrap();
+        let interval = Duration::seconds(300);
+        assert_eq!(
+            db.insert_records("12345", interval, &records(&[0, 5, 10]))
+                .unwrap(),
+            3
+        );
+        assert_eq!(
+            db.insert_records("12345", interval, &records(&[10, 15]))
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            db.history("12345", time(5), time(15)).unwrap(),
+            records(&[5, 10, 15])
+        );
+    }
+
+    #[test]
+    fn sync_state_and_migrations() {
+        let path = std::env::temp_dir().join(format!("aranet4-sqlite-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let state = SyncState {
+            interval: 300,
+            last_index: 2016,
+            last_time: time(0),
+        };
+        {
+            let db = Database::open(&path).unwrap();
+            assert_eq!(db.sync_state("12345").unwrap(), None);
+            db.set_sync_state("12345", &state).unwrap();
+        }
+        let db = Database::open(&path).unwrap();
+        assert_eq!(db.sync_state("12345").unwrap(), Some(state));
+
+        db.connection()
+            .pragma_update(None, "user_version", SCHEMA_VERSION + 1)
+            .unwrap();
+        drop(db);
+        assert!(matches!(
+            Database::open(&path),
+            Err(DatabaseError::UnsupportedSchema { .. })
+        ));
+        let _ = std::fs::remove_file(&path);
+    }
+}
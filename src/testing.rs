@@ -0,0 +1,26 @@
+//! Helpers shared by the unit tests
+use std::fs;
+use std::path::PathBuf;
+
+#[cfg(any(feature = "influxdb", feature = "webhook"))]
+mod http;
+#[cfg(any(feature = "influxdb", feature = "webhook"))]
+pub(crate) use http::*;
+
+/// Directory under the system temporary directory, removed when dropped
+pub(crate) struct TempDir(pub PathBuf);
+
+impl TempDir {
+    /// `name` must be unique among the tests, which share the process id
+    pub(crate) fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("aranet4-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        Self(path)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
@@ -0,0 +1,114 @@
+//! Local HTTP server standing in for the services the sinks write to
+use std::sync::{Arc, Mutex};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+/// A request received by an [`HttpStandIn`]
+#[derive(Debug, Clone)]
+pub(crate) struct Request {
+    pub method: String,
+    /// Path including the query string
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+    pub fn body_text(&self) -> String {
+        String::from_utf8(self.body.clone()).expect("body is not UTF-8")
+    }
+}
+
+/// HTTP server recording every request and answering with a scripted list of status codes.
+/// Once the script runs out, requests are answered with `204 No Content`.
+pub(crate) struct HttpStandIn {
+    addr: std::net::SocketAddr,
+    requests: Arc<Mutex<Vec<Request>>>,
+}
+
+impl HttpStandIn {
+    pub async fn start(statuses: &[u16]) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(Mutex::new(vec![]));
+        let script = Arc::new(Mutex::new(statuses.to_vec()));
+        let recorded = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let (recorded, script) = (recorded.clone(), script.clone());
+                tokio::spawn(async move { serve(stream, recorded, script).await });
+            }
+        });
+        Self { addr, requests }
+    }
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+    pub fn requests(&self) -> Vec<Request> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+async fn serve(
+    mut stream: TcpStream,
+    recorded: Arc<Mutex<Vec<Request>>>,
+    script: Arc<Mutex<Vec<u16>>>,
+) {
+    let mut data = vec![];
+    let mut buf = [0u8; 4096];
+    loop {
+        let header_end = data.windows(4).position(|w| w == b"\r\n\r\n");
+        if let Some(end) = header_end {
+            let head = String::from_utf8_lossy(&data[..end]).to_string();
+            let mut lines = head.lines();
+            let mut request_line = lines.next().unwrap_or_default().split(' ');
+            let method = request_line.next().unwrap_or_default().to_string();
+            let path = request_line.next().unwrap_or_default().to_string();
+            let headers: Vec<(String, String)> = lines
+                .filter_map(|line| line.split_once(':'))
+                .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+                .collect();
+            let length: usize = headers
+                .iter()
+                .find(|(key, _)| key.eq_ignore_ascii_case("content-length"))
+                .and_then(|(_, value)| value.parse().ok())
+                .unwrap_or(0);
+            if data.len() >= end + 4 + length {
+                let body = data[end + 4..end + 4 + length].to_vec();
+                recorded.lock().unwrap().push(Request {
+                    method,
+                    path,
+                    headers,
+                    body,
+                });
+                break;
+            }
+        }
+        match stream.read(&mut buf).await {
+            Ok(0) | Err(_) => return,
+            Ok(n) => data.extend_from_slice(&buf[..n]),
+        }
+    }
+    let status = {
+        let mut script = script.lock().unwrap();
+        if script.is_empty() {
+            204
+        } else {
+            script.remove(0)
+        }
+    };
+    let response = format!(
+        "HTTP/1.1 {} Scripted\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        status
+    );
+    let _ = stream.write_all(response.as_bytes()).await;
+    let _ = stream.shutdown().await;
+}
@@ -0,0 +1,438 @@
+//! POSTing readings and alerts to HTTP endpoints as JSON
+//!
+//! Without a template, readings are sent as
+//!
+//! ```json
+//! {"event": "readings", "sensor": "office", "address": "AA:BB:CC:DD:EE:FF",
+//!  "time": "2024-04-25T18:00:00+02:00", "co2_ppm": 464, "temperature_f": 66.2,
+//!  "pressure_hpa": 1001.2, "humidity_percent": 36, "battery_percent": 90, "status_color": 1}
+//! ```
+//!
+//! and alerts as an [`AlertEvent`] with `"event": "alert"`. A [`Template`] replaces this with
+//! any body, filled in from the fields of the default one, e.g. for Slack:
+//!
+//! ```text
+//! {"text": "{{sensor}}: {{rule}} {{kind}} at {{value}}"}
+//! ```
+//!
+//! With a secret, every request carries an `X-Aranet4-Signature: sha256=<hex>` header, the
+//! HMAC-SHA256 of the body.
+use crate::{
+    alert::{AlertError, AlertEvent, AlertSink},
+    fleet::SensorStatus,
+};
+use async_trait::async_trait;
+use hmac::{Hmac, Mac};
+use serde_json::{json, Value};
+use sha2::Sha256;
+use std::{fmt::Write, time::Duration};
+use thiserror::Error;
+use tokio::sync::{
+    broadcast::{self, error::RecvError},
+    mpsc,
+};
+use tokio_util::sync::CancellationToken;
+
+/// Header carrying the signature of the body
+pub const SIGNATURE_HEADER: &str = "X-Aranet4-Signature";
+
+#[derive(Debug, Error)]
+pub enum WebhookError {
+    #[error("HTTP error")]
+    Http(#[from] reqwest::Error),
+    #[error("Webhook rejected the request with status {status}: {body}")]
+    Rejected { status: u16, body: String },
+    #[error("JSON error")]
+    Json(#[from] serde_json::Error),
+}
+
+impl WebhookError {
+    /// Whether the request may succeed if retried later
+    pub fn is_transient(&self) -> bool {
+        match self {
+            WebhookError::Http(e) => !e.is_builder(),
+            WebhookError::Rejected { status, .. } => *status == 429 || *status >= 500,
+            WebhookError::Json(_) => false,
+        }
+    }
+}
+
+/// A request body with `{{field}}` placeholders
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Template(String);
+
+impl Template {
+    pub fn new(text: impl Into<String>) -> Self {
+        Self(text.into())
+    }
+    /// Replace each `{{field}}` by that field of `payload`. Strings are inserted JSON-escaped
+    /// without quotes, so they can go inside a string of the template; other values are
+    /// inserted as JSON. Unknown fields are left empty.
+    pub fn render(&self, payload: &Value) -> String {
+        let mut out = String::with_capacity(self.0.len());
+        let mut rest = self.0.as_str();
+        while let Some(start) = rest.find("{{") {
+            let Some(end) = rest[start..].find("}}") else {
+                break;
+            };
+            out.push_str(&rest[..start]);
+            match payload.get(rest[start + 2..start + end].trim()) {
+                Some(Value::String(s)) => {
+                    let quoted = Value::String(s.clone()).to_string();
+                    out.push_str(&quoted[1..quoted.len() - 1]);
+                }
+                Some(value) => out.push_str(&value.to_string()),
+                None => {}
+            }
+            rest = &rest[start + end + 2..];
+        }
+        out.push_str(rest);
+        out
+    }
+}
+
+/// Where and how a [`WebhookSink`] posts
+#[derive(Debug, Clone, PartialEq)]
+pub struct WebhookConfig {
+    pub url: String,
+    /// Body for readings, or `None` for the default JSON
+    pub readings_template: Option<Template>,
+    /// Body for alerts, or `None` for the default JSON
+    pub alert_template: Option<Template>,
+    /// Key for the HMAC-SHA256 signature, or `None` to leave requests unsigned
+    pub secret: Option<Vec<u8>>,
+    /// Extra headers sent with every request
+    pub headers: Vec<(String, String)>,
+    /// Attempts after the first failed one
+    pub retries: u32,
+    /// Delay before the first retry, doubled for every following one
+    pub retry_delay: Duration,
+    pub timeout: Duration,
+}
+
+impl WebhookConfig {
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            readings_template: None,
+            alert_template: None,
+            secret: None,
+            headers: vec![],
+            retries: 3,
+            retry_delay: Duration::from_secs(1),
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Hex encoded HMAC-SHA256 of `body`
+fn sign(secret: &[u8], body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC takes keys of any size");
+    mac.update(body);
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .fold(String::new(), |mut hex, byte| {
+            let _ = write!(hex, "{:02x}", byte);
+            hex
+        })
+}
+
+/// Default payload for the readings of a sensor, `None` if it has none
+pub fn readings_payload(status: &SensorStatus) -> Result<Option<Value>, WebhookError> {
+    let (Some(readings), Some(time)) = (&status.readings, status.last_success) else {
+        return Ok(None);
+    };
+    let mut payload = json!({
+        "event": "readings",
+        "sensor": status.name,
+        "address": status.address,
+        "time": time,
+    });
+    if let (Some(payload), Value::Object(fields)) =
+        (payload.as_object_mut(), serde_json::to_value(readings)?)
+    {
+        payload.extend(fields);
+    }
+    Ok(Some(payload))
+}
+
+/// Default payload for an alert event
+pub fn alert_payload(event: &AlertEvent) -> Result<Value, WebhookError> {
+    let mut payload = serde_json::to_value(event)?;
+    if let Some(payload) = payload.as_object_mut() {
+        payload.insert("event".to_string(), "alert".into());
+    }
+    Ok(payload)
+}
+
+/// Readings a [`WebhookSink`] failed to post
+#[derive(Debug)]
+pub struct DeliveryFailure {
+    pub status: SensorStatus,
+    pub error: WebhookError,
+}
+
+/// Posts readings and alerts to one endpoint
+pub struct WebhookSink {
+    client: reqwest::Client,
+    config: WebhookConfig,
+}
+
+impl WebhookSink {
+    pub fn new(config: WebhookConfig) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            config,
+        }
+    }
+    pub fn config(&self) -> &WebhookConfig {
+        &self.config
+    }
+    /// Post the latest readings of a sensor, if it has any
+    pub async fn send_readings(&self, status: &SensorStatus) -> Result<(), WebhookError> {
+        match readings_payload(status)? {
+            Some(payload) => {
+                self.post(self.config.readings_template.as_ref(), &payload)
+                    .await
+            }
+            None => Ok(()),
+        }
+    }
+    pub async fn send_alert(&self, event: &AlertEvent) -> Result<(), WebhookError> {
+        self.post(self.config.alert_template.as_ref(), &alert_payload(event)?)
+            .await
+    }
+    async fn post(&self, template: Option<&Template>, payload: &Value) -> Result<(), WebhookError> {
+        let body = match template {
+            Some(template) => template.render(payload),
+            None => payload.to_string(),
+        };
+        let mut delay = self.config.retry_delay;
+        let mut attempt = 0;
+        loop {
+            match self.send(&body).await {
+                Err(e) if e.is_transient() && attempt < self.config.retries => {
+                    attempt += 1;
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                }
+                result => return result,
+            }
+        }
+    }
+    async fn send(&self, body: &str) -> Result<(), WebhookError> {
+        let mut request = self
+            .client
+            .post(&self.config.url)
+            .timeout(self.config.timeout)
+            .header("Content-Type", "application/json")
+            .body(body.to_string());
+        for (name, value) in &self.config.headers {
+            request = request.header(name, value);
+        }
+        if let Some(secret) = &self.config.secret {
+            let signature = format!("sha256={}", sign(secret, body.as_bytes()));
+            request = request.header(SIGNATURE_HEADER, signature);
+        }
+        let response = request.send().await?;
+        let status = response.status();
+        if status.is_success() {
+            Ok(())
+        } else {
+            Err(WebhookError::Rejected {
+                status: status.as_u16(),
+                body: response.text().await.unwrap_or_default(),
+            })
+        }
+    }
+    /// Post every update from a [`Fleet`](crate::fleet::Fleet) until `cancel` is cancelled
+    /// or the fleet is dropped. Updates that cannot be posted, whether rejected or still
+    /// failing after the retries, are reported on `failures` and skipped.
+    pub async fn run(
+        &self,
+        mut updates: broadcast::Receiver<SensorStatus>,
+        failures: mpsc::UnboundedSender<DeliveryFailure>,
+        cancel: CancellationToken,
+    ) {
+        loop {
+            let status = tokio::select! {
+                _ = cancel.cancelled() => break,
+                update = updates.recv() => match update {
+                    Ok(status) => status,
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => break,
+                },
+            };
+            if !status.up {
+                continue;
+            }
+            if let Err(error) = self.send_readings(&status).await {
+                // nobody listening for failures is fine
+                let _ = failures.send(DeliveryFailure { status, error });
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl AlertSink for WebhookSink {
+    async fn send(&self, event: &AlertEvent) -> Result<(), AlertError> {
+        self.send_alert(event)
+            .await
+            .map_err(|e| AlertError::Sink(e.into()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{alert::AlertKind, readings::SensorReadings, testing::HttpStandIn};
+    use chrono::{Local, TimeZone};
+
+    fn config(url: String) -> WebhookConfig {
+        WebhookConfig {
+            retry_delay: Duration::from_millis(1),
+            ..WebhookConfig::new(url)
+        }
+    }
+
+    fn status() -> SensorStatus {
+        SensorStatus {
+            address: "AA:BB:CC:DD:EE:FF".to_string(),
+            name: "office".to_string(),
+            readings: Some(SensorReadings::new(464, 66.5, 1001.25, 36, 90, 1)),
+            last_success: Some(Local.with_ymd_and_hms(2024, 4, 25, 18, 0, 0).unwrap()),
+            up: true,
+            ..Default::default()
+        }
+    }
+
+    fn alert() -> AlertEvent {
+        let time = Local.with_ymd_and_hms(2024, 4, 25, 18, 10, 0).unwrap();
+        AlertEvent {
+            kind: AlertKind::Raised,
+            rule: "co2 \"high\"".to_string(),
+            sensor: "office".to_string(),
+            address: "AA:BB:CC:DD:EE:FF".to_string(),
+            value: Some(1250.0),
+            time,
+            since: time - chrono::Duration::minutes(10),
+        }
+    }
+
+    #[test]
+    fn template_rendering() {
+        let payload = json!({"sensor": "living \"room\"", "co2_ppm": 464, "value": null});
+        let template =
+            Template::new(r#"{"text": "{{sensor}} at {{ co2_ppm }} ppm", "v": {{value}}{{x}}}"#);
+        assert_eq!(
+            template.render(&payload),
+            r#"{"text": "living \"room\" at 464 ppm", "v": null}"#
+        );
+        assert_eq!(Template::new("{{unclosed").render(&payload), "{{unclosed");
+    }
+
+    #[test]
+    fn signature() {
+        // RFC 4231 test case 2
+        assert_eq!(
+            sign(b"Jefe", b"what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[tokio::test]
+    async fn default_readings_payload() {
+        let server = HttpStandIn::start(&[]).await;
+        let mut config = config(server.url() + "/hook");
+        config.secret = Some(b"s3cret".to_vec());
+        config.headers = vec![("X-Room".to_string(), "office".to_string())];
+        let sink = WebhookSink::new(config);
+        sink.send_readings(&status()).await.unwrap();
+        // nothing is sent for a sensor without readings
+        sink.send_readings(&SensorStatus::default()).await.unwrap();
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        let request = &requests[0];
+        assert_eq!(
+            (request.method.as_str(), request.path.as_str()),
+            ("POST", "/hook")
+        );
+        assert_eq!(request.header("content-type"), Some("application/json"));
+        assert_eq!(request.header("x-room"), Some("office"));
+        assert_eq!(
+            request.header(SIGNATURE_HEADER),
+            Some(format!("sha256={}", sign(b"s3cret", &request.body)).as_str())
+        );
+        let body: Value = serde_json::from_str(&request.body_text()).unwrap();
+        assert_eq!(body["event"], "readings");
+        assert_eq!(body["sensor"], "office");
+        assert_eq!(body["co2_ppm"], 464);
+        assert_eq!(body["pressure_hpa"], 1001.25);
+    }
+
+    #[tokio::test]
+    async fn templated_alert_with_retries() {
+        let server = HttpStandIn::start(&[503, 500]).await;
+        let mut config = config(server.url());
+        config.alert_template = Some(Template::new(
+            r#"{"text": "{{sensor}}: {{rule}} {{kind}} at {{value}} ppm"}"#,
+        ));
+        let sink = WebhookSink::new(config);
+        AlertSink::send(&sink, &alert()).await.unwrap();
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 3);
+        let body: Value = serde_json::from_slice(&requests[2].body).unwrap();
+        assert_eq!(body["text"], "office: co2 \"high\" raised at 1250.0 ppm");
+    }
+
+    #[tokio::test]
+    async fn rejected() {
+        let server = HttpStandIn::start(&[400, 503, 503]).await;
+        let sink = WebhookSink::new(config(server.url()));
+        let error = sink.send_alert(&alert()).await.unwrap_err();
+        assert!(matches!(error, WebhookError::Rejected { status: 400, .. }));
+        assert!(!error.is_transient());
+        assert_eq!(server.requests().len(), 1);
+
+        let mut config = config(server.url());
+        config.retries = 1;
+        let sink = WebhookSink::new(config);
+        let error = sink.send_alert(&alert()).await.unwrap_err();
+        assert!(error.is_transient());
+        assert_eq!(server.requests().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn run_skips_rejected_readings() {
+        let server = HttpStandIn::start(&[400]).await;
+        let sink = WebhookSink::new(config(server.url()));
+        let (updates, receiver) = broadcast::channel(8);
+        let (failures, mut failed) = mpsc::unbounded_channel();
+        updates.send(status()).unwrap();
+        updates
+            .send(SensorStatus {
+                name: "cellar".to_string(),
+                ..status()
+            })
+            .unwrap();
+        drop(updates);
+
+        sink.run(receiver, failures, CancellationToken::new()).await;
+
+        let failure = failed.recv().await.unwrap();
+        assert_eq!(failure.status.name, "office");
+        assert!(matches!(
+            failure.error,
+            WebhookError::Rejected { status: 400, .. }
+        ));
+        assert!(failed.recv().await.is_none());
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        let body: Value = serde_json::from_slice(&requests[1].body).unwrap();
+        assert_eq!(body["sensor"], "cellar");
+    }
+}